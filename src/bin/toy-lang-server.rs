@@ -0,0 +1,18 @@
+// toy-lang-server
+// 標準入出力でエディタと通信するLSPサーバー本体
+// 使い方はREADMEの「エディタから使う」を参照
+
+use std::process::ExitCode;
+
+use lsp_learning_rust::server::main_loop::run_stdio;
+
+fn main() -> ExitCode {
+    match run_stdio() {
+        Ok(exit_code) => ExitCode::from(exit_code as u8),
+        Err(err) => {
+            // stdout はプロトコル用なので、エラーは stderr に出す
+            eprintln!("toy-lang-server: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
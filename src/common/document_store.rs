@@ -0,0 +1,332 @@
+// 開いているドキュメントの置き場所
+// didOpen / didChange / didClose で中身を更新し、各機能のリクエストはここから読む
+// - 本文は ropey の Rope で持つので、差分の編集は文書の長さによらず速い
+// - &str と LineIndex は読まれたときに一度だけ作り、次の編集まで使い回す
+// - snapshot() はドキュメントを Arc で共有するだけなので安く作れる。
+//   編集は新しい Document に差し替えるので、スナップショットから見える中身は変わらない
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, OnceLock};
+
+use lsp_types::{Position, PositionEncodingKind, Range, TextDocumentContentChangeEvent, Url};
+use ropey::Rope;
+
+use crate::common::line_index::{char_len, LineIndex};
+
+#[derive(Debug)]
+pub struct Document {
+    language_id: String,
+    version: i32,
+    rope: Rope,
+    // rope から作った本文と LineIndex（編集すると作り直す）
+    text: OnceLock<String>,
+    line_index: OnceLock<LineIndex>,
+}
+
+impl Document {
+    pub fn new(language_id: impl Into<String>, version: i32, text: &str) -> Self {
+        Self::from_rope(language_id.into(), version, Rope::from_str(text))
+    }
+
+    fn from_rope(language_id: String, version: i32, rope: Rope) -> Self {
+        Document {
+            language_id,
+            version,
+            rope,
+            text: OnceLock::new(),
+            line_index: OnceLock::new(),
+        }
+    }
+
+    pub fn language_id(&self) -> &str {
+        &self.language_id
+    }
+
+    pub fn version(&self) -> i32 {
+        self.version
+    }
+
+    pub fn rope(&self) -> &Rope {
+        &self.rope
+    }
+
+    pub fn text(&self) -> &str {
+        self.text.get_or_init(|| self.rope.to_string())
+    }
+
+    // encoding の LineIndex
+    // 最初に頼まれた encoding のものを覚えておく（セッション中 positionEncoding は変わらないので、普通は毎回これが返る）
+    pub fn line_index(&self, encoding: &PositionEncodingKind) -> Cow<'_, LineIndex> {
+        let cached = self.line_index.get_or_init(|| LineIndex::new(self.text(), encoding.clone()));
+        if cached.encoding() == encoding {
+            Cow::Borrowed(cached)
+        } else {
+            Cow::Owned(LineIndex::new(self.text(), encoding.clone()))
+        }
+    }
+}
+
+// Position → rope の文字（char）インデックス
+// LineIndex::offset と同じく、行が無ければ None、列が行末より後ろなら行末にする
+fn char_index(rope: &Rope, position: Position, encoding: &PositionEncodingKind) -> Option<usize> {
+    let line_number = position.line as usize;
+    if line_number >= rope.len_lines() {
+        return None;
+    }
+    let line = rope.line(line_number);
+
+    // 改行文字（"\n" / "\r\n"）は行の中身に含めない
+    let mut line_len = line.len_chars();
+    if line_len > 0 && line.char(line_len - 1) == '\n' {
+        line_len -= 1;
+        if line_len > 0 && line.char(line_len - 1) == '\r' {
+            line_len -= 1;
+        }
+    }
+
+    let mut remaining = position.character as usize;
+    let mut chars = 0;
+    for ch in line.chars().take(line_len) {
+        let units = char_len(ch, encoding);
+        // 文字の途中（サロゲートペアの片割れなど）を指していたら、その文字の先頭にする
+        if remaining < units {
+            break;
+        }
+        remaining -= units;
+        chars += 1;
+    }
+    Some(rope.line_to_char(line_number) + chars)
+}
+
+// ドキュメントを更新できなかった理由
+#[derive(Debug, Clone, PartialEq)]
+pub enum DocumentError {
+    // 開かれていない（もう閉じられた）ドキュメント
+    NotOpen(Url),
+    // いま持っているものより新しくない version（重複・順番違いの通知）
+    OutdatedVersion { uri: Url, current: i32, received: i32 },
+    // ドキュメントの外を指している範囲
+    InvalidRange { uri: Url, range: Range },
+}
+
+impl fmt::Display for DocumentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DocumentError::NotOpen(uri) => write!(f, "{} is not open.", uri),
+            DocumentError::OutdatedVersion { uri, current, received } => {
+                write!(f, "Version {} of {} is not newer than version {}.", received, uri, current)
+            }
+            DocumentError::InvalidRange { uri, range } => write!(
+                f,
+                "Range {}:{}-{}:{} is outside {}.",
+                range.start.line, range.start.character, range.end.line, range.end.character, uri
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DocumentStore {
+    documents: HashMap<Url, Arc<Document>>,
+}
+
+impl DocumentStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // didOpen: 同じ URI のドキュメントがあれば置き換える
+    pub fn open(&mut self, uri: Url, language_id: impl Into<String>, version: i32, text: &str) {
+        self.documents.insert(uri, Arc::new(Document::new(language_id, version, text)));
+    }
+
+    // didClose
+    pub fn close(&mut self, uri: &Url) -> Option<Arc<Document>> {
+        self.documents.remove(uri)
+    }
+
+    // didChange: changes を順番に当てる
+    // 1 つでも当てられなければ何も変えない。範囲は encoding の単位で数える
+    pub fn apply_changes(
+        &mut self,
+        uri: &Url,
+        version: i32,
+        changes: &[TextDocumentContentChangeEvent],
+        encoding: &PositionEncodingKind,
+    ) -> Result<&Document, DocumentError> {
+        let document = self.documents.get(uri).ok_or_else(|| DocumentError::NotOpen(uri.clone()))?;
+        if version <= document.version {
+            return Err(DocumentError::OutdatedVersion { uri: uri.clone(), current: document.version, received: version });
+        }
+
+        // Rope の clone は中身を共有するだけなので安い
+        let mut rope = document.rope.clone();
+        for change in changes {
+            let Some(range) = change.range else {
+                rope = Rope::from_str(&change.text);
+                continue;
+            };
+            // 範囲は、1 つ前までの変更を当てた後のドキュメントを指している
+            let (start, end) = char_index(&rope, range.start, encoding)
+                .zip(char_index(&rope, range.end, encoding))
+                .filter(|(start, end)| start <= end)
+                .ok_or_else(|| DocumentError::InvalidRange { uri: uri.clone(), range })?;
+            rope.remove(start..end);
+            rope.insert(start, &change.text);
+        }
+
+        let document = Arc::new(Document::from_rope(document.language_id.clone(), version, rope));
+        self.documents.insert(uri.clone(), document);
+        Ok(&self.documents[uri])
+    }
+
+    pub fn get(&self, uri: &Url) -> Option<&Document> {
+        self.documents.get(uri).map(|document| document.as_ref())
+    }
+
+    pub fn text(&self, uri: &Url) -> Option<&str> {
+        self.get(uri).map(Document::text)
+    }
+
+    pub fn contains_key(&self, uri: &Url) -> bool {
+        self.documents.contains_key(uri)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Url, &Document)> {
+        self.documents.iter().map(|(uri, document)| (uri, document.as_ref()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.documents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.documents.is_empty()
+    }
+
+    // いまの中身を固定したコピー
+    // 後から didChange が来ても、スナップショットの中身は変わらない
+    pub fn snapshot(&self) -> DocumentStore {
+        self.clone()
+    }
+
+    // self の上に top を重ねたスナップショット（同じ URI があれば top のドキュメントを使う）
+    // ディスクから読んだファイルに、エディタで開いているドキュメントを重ねるのに使う
+    pub fn overlaid_with(&self, top: &DocumentStore) -> DocumentStore {
+        let mut merged = self.clone();
+        merged.documents.extend(top.documents.iter().map(|(uri, document)| (uri.clone(), Arc::clone(document))));
+        merged
+    }
+}
+
+
+// --- Tests --- //
+
+#[cfg(test)]
+mod tests {
+    use super::{DocumentError, DocumentStore};
+    use lsp_types::{Position, PositionEncodingKind, Range, TextDocumentContentChangeEvent, Url};
+    use std::str::FromStr;
+
+    fn ranged(start: (u32, u32), end: (u32, u32), text: &str) -> TextDocumentContentChangeEvent {
+        TextDocumentContentChangeEvent {
+            range: Some(Range::new(Position::new(start.0, start.1), Position::new(end.0, end.1))),
+            range_length: None,
+            text: text.to_string(),
+        }
+    }
+
+    fn uri() -> Url {
+        Url::from_str("file:///test.rs").unwrap()
+    }
+
+    #[test]
+    fn test_open_and_read() {
+        let mut store = DocumentStore::new();
+        store.open(uri(), "rust", 3, "fn main() {}\n");
+
+        let document = store.get(&uri()).expect("the document was opened");
+        assert_eq!(document.language_id(), "rust");
+        assert_eq!(document.version(), 3);
+        assert_eq!(document.text(), "fn main() {}\n");
+        assert_eq!(document.line_index(&PositionEncodingKind::UTF16).line_count(), 2);
+
+        assert!(store.close(&uri()).is_some());
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn test_ranged_changes_across_lines_and_crlf() {
+        let mut store = DocumentStore::new();
+        store.open(uri(), "rust", 1, "let a = 1;\r\nlet b = 2;\r\n");
+
+        let changes = [
+            // 1 行目の末尾から 2 行目の "let " までを消す（列が行末より後ろでも行末として扱う）
+            ranged((0, 100), (1, 4), " "),
+            ranged((0, 0), (0, 3), "const"),
+        ];
+        let document = store.apply_changes(&uri(), 2, &changes, &PositionEncodingKind::UTF16).unwrap();
+
+        assert_eq!(document.text(), "const a = 1; b = 2;\r\n");
+        assert_eq!(document.version(), 2);
+    }
+
+    #[test]
+    fn test_ranges_in_each_encoding() {
+        // "🦀" は UTF-8 で 4 バイト / UTF-16 で 2 / UTF-32 で 1
+        for (encoding, column) in [(PositionEncodingKind::UTF8, 4), (PositionEncodingKind::UTF16, 2), (PositionEncodingKind::UTF32, 1)] {
+            let mut store = DocumentStore::new();
+            store.open(uri(), "rust", 1, "🦀a");
+            store.apply_changes(&uri(), 2, &[ranged((0, column), (0, column + 1), "b")], &encoding).unwrap();
+            assert_eq!(store.text(&uri()), Some("🦀b"), "column {} should be after the crab in {:?}", column, encoding);
+        }
+    }
+
+    #[test]
+    fn test_failed_changes_keep_the_document() {
+        let mut store = DocumentStore::new();
+        store.open(uri(), "rust", 5, "abc");
+
+        let result = store.apply_changes(&uri(), 5, &[ranged((0, 0), (0, 1), "x")], &PositionEncodingKind::UTF16);
+        assert_eq!(result.err(), Some(DocumentError::OutdatedVersion { uri: uri(), current: 5, received: 5 }));
+
+        let changes = [ranged((0, 0), (0, 1), "x"), ranged((3, 0), (3, 0), "y")];
+        let result = store.apply_changes(&uri(), 6, &changes, &PositionEncodingKind::UTF16);
+        assert!(matches!(result, Err(DocumentError::InvalidRange { .. })));
+
+        let result = store.apply_changes(&Url::from_str("file:///other.rs").unwrap(), 1, &[], &PositionEncodingKind::UTF16);
+        assert!(matches!(result, Err(DocumentError::NotOpen(_))));
+
+        assert_eq!(store.text(&uri()), Some("abc"));
+        assert_eq!(store.get(&uri()).unwrap().version(), 5);
+    }
+
+    #[test]
+    fn test_snapshot_is_not_affected_by_later_changes() {
+        let mut store = DocumentStore::new();
+        store.open(uri(), "rust", 1, "fn a() {}");
+        let snapshot = store.snapshot();
+
+        store.apply_changes(&uri(), 2, &[ranged((0, 3), (0, 4), "b")], &PositionEncodingKind::UTF16).unwrap();
+        store.close(&uri());
+
+        assert_eq!(snapshot.text(&uri()), Some("fn a() {}"));
+        assert_eq!(snapshot.get(&uri()).unwrap().version(), 1);
+    }
+
+    #[test]
+    fn test_overlay_prefers_the_top_store() {
+        let mut disk = DocumentStore::new();
+        disk.open(uri(), "rust", 0, "fn on_disk() {}");
+        disk.open(Url::from_str("file:///other.rs").unwrap(), "rust", 0, "fn other() {}");
+        let mut open = DocumentStore::new();
+        open.open(uri(), "rust", 4, "fn edited() {}");
+
+        let merged = disk.overlaid_with(&open);
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged.text(&uri()), Some("fn edited() {}"), "the open document should win over the file on disk");
+        assert_eq!(disk.text(&uri()), Some("fn on_disk() {}"), "the stores themselves should not change");
+    }
+}
@@ -0,0 +1,244 @@
+// 行ごとのオフセットの表
+// LSP の Position は (行, 列) で、列をどの単位で数えるかは initialize で決めた positionEncoding による
+// - UTF-8: バイト数
+// - UTF-16: UTF-16 のコードユニット数（LSP のデフォルト。「あ」は 1、絵文字は 2）
+// - UTF-32: 文字（char）数
+// Rust の &str はバイト単位で扱うので、バイトオフセットと Position の変換は必ずここを通す。
+
+use std::ops::Range as ByteRange;
+
+use lsp_types::{Position, PositionEncodingKind, Range};
+
+// ASCII 以外の文字（エンコーディングによって列の数え方が変わるもの）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct WideChar {
+    // 行の先頭からのバイトオフセット
+    start: usize,
+    len_utf8: usize,
+    len_utf16: usize,
+}
+
+impl WideChar {
+    // この文字が列をいくつ進めるか
+    fn units(&self, encoding: &PositionEncodingKind) -> usize {
+        if *encoding == PositionEncodingKind::UTF8 {
+            self.len_utf8
+        } else if *encoding == PositionEncodingKind::UTF32 {
+            1
+        } else {
+            self.len_utf16
+        }
+    }
+}
+
+// 1 文字が列をいくつ進めるか（positionEncoding の単位）
+pub fn char_len(ch: char, encoding: &PositionEncodingKind) -> usize {
+    if *encoding == PositionEncodingKind::UTF8 {
+        ch.len_utf8()
+    } else if *encoding == PositionEncodingKind::UTF32 {
+        1
+    } else {
+        ch.len_utf16()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineIndex {
+    encoding: PositionEncodingKind,
+    // 各行の先頭のバイトオフセット
+    line_starts: Vec<usize>,
+    // 各行の長さ（改行文字 "\n" / "\r\n" は含まない）
+    line_lens: Vec<usize>,
+    // 各行の ASCII 以外の文字（行内の位置の順）
+    wide_chars: Vec<Vec<WideChar>>,
+    len: usize,
+}
+
+impl LineIndex {
+    pub fn new(text: &str, encoding: PositionEncodingKind) -> Self {
+        let mut line_starts = vec![0];
+        let mut line_lens = Vec::new();
+        let mut wide_chars = vec![Vec::new()];
+
+        for (offset, ch) in text.char_indices() {
+            let line_start = *line_starts.last().unwrap();
+            if ch == '\n' {
+                // "\r\n" の "\r" も行の中身には含めない（str::lines と同じ）
+                let line_end = if text[..offset].ends_with('\r') { offset - 1 } else { offset };
+                line_lens.push(line_end - line_start);
+                line_starts.push(offset + 1);
+                wide_chars.push(Vec::new());
+            } else if !ch.is_ascii() {
+                wide_chars.last_mut().unwrap().push(WideChar {
+                    start: offset - line_start,
+                    len_utf8: ch.len_utf8(),
+                    len_utf16: ch.len_utf16(),
+                });
+            }
+        }
+        line_lens.push(text.len() - line_starts.last().unwrap());
+
+        LineIndex {
+            encoding,
+            line_starts,
+            line_lens,
+            wide_chars,
+            len: text.len(),
+        }
+    }
+
+    pub fn encoding(&self) -> &PositionEncodingKind {
+        &self.encoding
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.line_starts.len()
+    }
+
+    // Position → テキスト全体でのバイトオフセット
+    // 行が存在しなければ None。列が行末より後ろなら行末にする（LSP の仕様どおり）
+    pub fn offset(&self, position: Position) -> Option<usize> {
+        let line_start = *self.line_starts.get(position.line as usize)?;
+        Some(line_start + self.byte_column(position.line, position.character)?)
+    }
+
+    // テキスト全体でのバイトオフセット → Position
+    // テキストの長さを超えるオフセットは末尾として扱う
+    pub fn position(&self, offset: usize) -> Position {
+        let offset = offset.min(self.len);
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        let byte_column = (offset - self.line_starts[line]).min(self.line_lens[line]);
+        Position::new(line as u32, self.character(line as u32, byte_column))
+    }
+
+    pub fn range(&self, range: ByteRange<usize>) -> Range {
+        Range::new(self.position(range.start), self.position(range.end))
+    }
+
+    pub fn byte_range(&self, range: Range) -> Option<ByteRange<usize>> {
+        Some(self.offset(range.start)?..self.offset(range.end)?)
+    }
+
+    // 行内の列（positionEncoding の単位）→ 行の先頭からのバイトオフセット
+    // str::lines() で取り出した行をスライスするときに使う
+    // 文字の途中（サロゲートペアの片割れなど）を指していたら、その文字の先頭にする
+    pub fn byte_column(&self, line: u32, character: u32) -> Option<usize> {
+        let line = line as usize;
+        let line_len = *self.line_lens.get(line)?;
+
+        let mut remaining = character as usize;
+        let mut byte = 0;
+        for wide in &self.wide_chars[line] {
+            // wide の手前までは ASCII なので、1 バイト = 1 単位
+            let ascii_run = wide.start - byte;
+            if remaining <= ascii_run {
+                return Some(byte + remaining);
+            }
+            remaining -= ascii_run;
+            byte = wide.start;
+
+            let units = wide.units(&self.encoding);
+            if remaining < units {
+                return Some(byte);
+            }
+            remaining -= units;
+            byte += wide.len_utf8;
+        }
+        Some((byte + remaining).min(line_len))
+    }
+
+    // 行の先頭からのバイトオフセット → 行内の列（positionEncoding の単位）
+    pub fn character(&self, line: u32, byte_column: usize) -> u32 {
+        let Some(wide_chars) = self.wide_chars.get(line as usize) else {
+            return byte_column as u32;
+        };
+
+        let mut character = byte_column;
+        for wide in wide_chars {
+            if wide.start >= byte_column {
+                break;
+            }
+            if wide.start + wide.len_utf8 > byte_column {
+                // 文字の途中 → その文字の先頭にする
+                character -= byte_column - wide.start;
+                break;
+            }
+            character -= wide.len_utf8 - wide.units(&self.encoding);
+        }
+        character as u32
+    }
+
+    // 同じ行の byte_start..byte_end の長さ（positionEncoding の単位）
+    // セマンティックトークンの length のように、範囲ではなく長さが要る場合に使う
+    pub fn len_between(&self, line: u32, byte_start: usize, byte_end: usize) -> u32 {
+        self.character(line, byte_end) - self.character(line, byte_start)
+    }
+}
+
+
+// --- Tests --- //
+
+#[cfg(test)]
+mod tests {
+    use super::LineIndex;
+    use lsp_types::{Position, PositionEncodingKind, Range};
+
+    // "あ" は UTF-8 で 3 バイト / UTF-16 で 1、"🦀" は UTF-8 で 4 バイト / UTF-16 で 2（サロゲートペア）
+    const TEXT: &str = "let あ = \"🦀\";\nfn main() {}\r\n";
+
+    #[test]
+    fn test_positions_in_each_encoding() {
+        // 'let あ = "🦀";' の 2 つ目の '"' のバイトオフセット
+        let closing_quote = TEXT.rfind('"').unwrap();
+
+        let utf8 = LineIndex::new(TEXT, PositionEncodingKind::UTF8);
+        let utf16 = LineIndex::new(TEXT, PositionEncodingKind::UTF16);
+        let utf32 = LineIndex::new(TEXT, PositionEncodingKind::UTF32);
+
+        assert_eq!(utf8.position(closing_quote), Position::new(0, 15));
+        assert_eq!(utf16.position(closing_quote), Position::new(0, 11));
+        assert_eq!(utf32.position(closing_quote), Position::new(0, 10));
+
+        for index in [&utf8, &utf16, &utf32] {
+            let position = index.position(closing_quote);
+            assert_eq!(index.offset(position), Some(closing_quote), "{:?} should round-trip", index.encoding());
+        }
+    }
+
+    #[test]
+    fn test_lines_and_crlf() {
+        let index = LineIndex::new(TEXT, PositionEncodingKind::UTF16);
+        let fn_offset = TEXT.find("fn").unwrap();
+
+        assert_eq!(index.line_count(), 3, "the text ends with a newline, so the last line is empty");
+        assert_eq!(index.position(fn_offset), Position::new(1, 0));
+        assert_eq!(index.offset(Position::new(1, 0)), Some(fn_offset));
+        assert_eq!(
+            index.offset(Position::new(1, 100)),
+            Some(TEXT.find("\r\n").unwrap()),
+            "columns past the end of the line should clamp before the line break"
+        );
+        assert_eq!(index.offset(Position::new(3, 0)), None, "lines past the end do not exist");
+        assert_eq!(index.position(TEXT.len() + 10), Position::new(2, 0));
+    }
+
+    #[test]
+    fn test_positions_inside_a_character_round_down() {
+        let index = LineIndex::new("🦀x", PositionEncodingKind::UTF16);
+
+        assert_eq!(index.byte_column(0, 1), Some(0), "the middle of a surrogate pair is the start of the character");
+        assert_eq!(index.byte_column(0, 2), Some(4));
+        assert_eq!(index.character(0, 2), 0, "the middle of a UTF-8 sequence is the start of the character");
+        assert_eq!(index.len_between(0, 0, 5), 3);
+    }
+
+    #[test]
+    fn test_ranges() {
+        let index = LineIndex::new("// 日本語のコメント\nlet x = 1;", PositionEncodingKind::UTF16);
+        let text_range = Range::new(Position::new(0, 3), Position::new(0, 6));
+
+        let bytes = index.byte_range(text_range).unwrap();
+        assert_eq!(&"// 日本語のコメント\nlet x = 1;"[bytes.clone()], "日本語");
+        assert_eq!(index.range(bytes), text_range);
+    }
+}
@@ -1 +1,4 @@
 // 共通ユーティリティモジュール
+pub mod document_store;
+pub mod line_index;
+pub mod syntax;
@@ -0,0 +1,390 @@
+// ドキュメントのテキストを字句（トークン）に分ける
+// lesson_2〜4 の解析器は組み立て済みの AST を受け取るので、エディタのテキストに使うときはここで作ったトークンから組み立てる
+// - 空白とコメントは読み飛ばす
+// - 各トークンはテキスト全体でのバイトオフセットの範囲を持つ（Position への変換は LineIndex で行う）
+// - 括弧 ()[]{} の対応をとった木（TokenTree）にもできる。閉じ括弧が足りなくても最後まで読む
+
+use std::ops::Range as ByteRange;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    // 識別子とキーワード（区別は is_keyword で）
+    Ident,
+    Number,
+    // 文字列リテラル（"..." / r"..." / r#"..."# / b"..."）
+    Str,
+    // 文字リテラル（'a'）
+    Char,
+    // ライフタイム（'a）
+    Lifetime,
+    // 記号（"::" "->" "=>" は 1 つのトークンにする）
+    Punct,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token<'a> {
+    pub kind: TokenKind,
+    pub text: &'a str,
+    // テキスト全体でのバイトオフセット
+    pub start: usize,
+}
+
+impl<'a> Token<'a> {
+    pub fn end(&self) -> usize {
+        self.start + self.text.len()
+    }
+
+    pub fn range(&self) -> ByteRange<usize> {
+        self.start..self.end()
+    }
+
+    pub fn is_ident(&self) -> bool {
+        self.kind == TokenKind::Ident && !is_keyword(self.text)
+    }
+
+    pub fn is_keyword(&self, keyword: &str) -> bool {
+        self.kind == TokenKind::Ident && self.text == keyword
+    }
+
+    pub fn is_punct(&self, punct: &str) -> bool {
+        self.kind == TokenKind::Punct && self.text == punct
+    }
+}
+
+const KEYWORDS: [&str; 38] = [
+    "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern", "false", "fn",
+    "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref", "return", "self", "Self",
+    "static", "struct", "super", "trait", "true", "type", "unsafe", "use", "where", "while",
+];
+
+pub fn is_keyword(text: &str) -> bool {
+    KEYWORDS.contains(&text)
+}
+
+pub fn tokenize(text: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut offset = 0;
+
+    while offset < text.len() {
+        let rest = &text[offset..];
+        let ch = rest.chars().next().unwrap();
+
+        if ch.is_whitespace() {
+            offset += ch.len_utf8();
+            continue;
+        }
+        if rest.starts_with("//") {
+            offset += rest.find('\n').unwrap_or(rest.len());
+            continue;
+        }
+        if rest.starts_with("/*") {
+            offset += block_comment_len(rest);
+            continue;
+        }
+
+        let (kind, len) = if let Some(len) = string_len(rest) {
+            (TokenKind::Str, len)
+        } else if ch == '\'' {
+            quote_len(rest)
+        } else if ch.is_ascii_digit() {
+            (TokenKind::Number, number_len(rest))
+        } else if ch.is_alphabetic() || ch == '_' {
+            (TokenKind::Ident, rest.find(|c: char| !c.is_alphanumeric() && c != '_').unwrap_or(rest.len()))
+        } else if ["::", "->", "=>"].iter().any(|punct| rest.starts_with(punct)) {
+            (TokenKind::Punct, 2)
+        } else {
+            (TokenKind::Punct, ch.len_utf8())
+        };
+
+        tokens.push(Token { kind, text: &rest[..len], start: offset });
+        offset += len;
+    }
+    tokens
+}
+
+// "/* ... */" の長さ（入れ子にできる。閉じていなければ最後まで）
+fn block_comment_len(rest: &str) -> usize {
+    let mut depth = 0;
+    let mut offset = 0;
+    while offset < rest.len() {
+        if rest[offset..].starts_with("/*") {
+            depth += 1;
+            offset += 2;
+        } else if rest[offset..].starts_with("*/") {
+            depth -= 1;
+            offset += 2;
+            if depth == 0 {
+                return offset;
+            }
+        } else {
+            offset += rest[offset..].chars().next().unwrap().len_utf8();
+        }
+    }
+    rest.len()
+}
+
+// 数値リテラルの長さ
+// "." は後ろに数字が続くときだけ含める（"0..10" や "1.max(2)" の "." は含めない）
+fn number_len(rest: &str) -> usize {
+    let digits = |text: &str| text.find(|c: char| !c.is_alphanumeric() && c != '_').unwrap_or(text.len());
+    let len = digits(rest);
+    match rest[len..].strip_prefix('.') {
+        Some(fraction) if fraction.starts_with(|c: char| c.is_ascii_digit()) => len + 1 + digits(fraction),
+        _ => len,
+    }
+}
+
+// 文字列リテラルなら、その長さ（閉じていなければ最後まで）
+fn string_len(rest: &str) -> Option<usize> {
+    let after_prefix = rest.strip_prefix('b').unwrap_or(rest);
+    let prefix_len = rest.len() - after_prefix.len();
+
+    if let Some(raw) = after_prefix.strip_prefix('r') {
+        // r"..." / r#"..."#
+        let hashes = raw.len() - raw.trim_start_matches('#').len();
+        let body = raw[hashes..].strip_prefix('"')?;
+        let closing = format!("\"{}", "#".repeat(hashes));
+        let body_len = body.find(&closing).map_or(body.len(), |end| end + closing.len());
+        return Some(prefix_len + 1 + hashes + 1 + body_len);
+    }
+
+    let body = after_prefix.strip_prefix('"')?;
+    let mut escaped = false;
+    for (offset, ch) in body.char_indices() {
+        match ch {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => return Some(prefix_len + 1 + offset + 1),
+            _ => {}
+        }
+    }
+    Some(rest.len())
+}
+
+// "'" で始まるもの: 'a' なら文字リテラル、'a ならライフタイム
+fn quote_len(rest: &str) -> (TokenKind, usize) {
+    let mut chars = rest.char_indices().skip(1);
+    match chars.next() {
+        Some((_, '\\')) => {
+            // エスケープされた文字の次から閉じの "'" を探す
+            let len = rest.get(3..).and_then(|after| after.find('\'')).map_or(rest.len(), |end| 3 + end + 1);
+            (TokenKind::Char, len)
+        }
+        Some((offset, ch)) => match chars.next() {
+            Some((end, '\'')) => (TokenKind::Char, end + 1),
+            _ if ch.is_alphabetic() || ch == '_' => {
+                let len = rest[offset..].find(|c: char| !c.is_alphanumeric() && c != '_').map_or(rest.len(), |end| offset + end);
+                (TokenKind::Lifetime, len)
+            }
+            _ => (TokenKind::Punct, 1),
+        },
+        None => (TokenKind::Punct, 1),
+    }
+}
+
+// 括弧の対応をとったトークンの木
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenTree<'a> {
+    Leaf(Token<'a>),
+    Group {
+        // 開き括弧（"(" / "[" / "{"）
+        open: Token<'a>,
+        children: Vec<TokenTree<'a>>,
+        // 閉じ括弧（無ければ None）
+        close: Option<Token<'a>>,
+    },
+}
+
+impl<'a> TokenTree<'a> {
+    // 木の先頭のトークン
+    pub fn first(&self) -> &Token<'a> {
+        match self {
+            TokenTree::Leaf(token) => token,
+            TokenTree::Group { open, .. } => open,
+        }
+    }
+
+    // 木の末尾のバイトオフセット
+    pub fn end(&self) -> usize {
+        match self {
+            TokenTree::Leaf(token) => token.end(),
+            TokenTree::Group { open, children, close } => close
+                .map(|close| close.end())
+                .or_else(|| children.last().map(TokenTree::end))
+                .unwrap_or(open.end()),
+        }
+    }
+
+    pub fn leaf(&self) -> Option<&Token<'a>> {
+        match self {
+            TokenTree::Leaf(token) => Some(token),
+            TokenTree::Group { .. } => None,
+        }
+    }
+
+    // delimiter（"(" / "[" / "{"）の括弧なら中身
+    pub fn group(&self, delimiter: &str) -> Option<&[TokenTree<'a>]> {
+        match self {
+            TokenTree::Group { open, children, .. } if open.text == delimiter => Some(children),
+            _ => None,
+        }
+    }
+}
+
+// 対応の取れていない括弧のうち最初のもの（閉じられていない開き括弧か、対応する開き括弧が無い閉じ括弧）
+pub fn unbalanced_delimiter<'a>(trees: &[TokenTree<'a>]) -> Option<Token<'a>> {
+    trees.iter().find_map(|tree| match tree {
+        TokenTree::Leaf(token) if token.kind == TokenKind::Punct && [")", "]", "}"].contains(&token.text) => Some(*token),
+        TokenTree::Leaf(_) => None,
+        TokenTree::Group { open, close: None, .. } => Some(*open),
+        TokenTree::Group { children, .. } => unbalanced_delimiter(children),
+    })
+}
+
+pub fn token_trees<'a>(tokens: &[Token<'a>]) -> Vec<TokenTree<'a>> {
+    let mut stack: Vec<(Token<'a>, Vec<TokenTree<'a>>)> = Vec::new();
+    let mut current = Vec::new();
+
+    for token in tokens {
+        match token.kind {
+            TokenKind::Punct if ["(", "[", "{"].contains(&token.text) => {
+                stack.push((*token, std::mem::take(&mut current)));
+            }
+            TokenKind::Punct if [")", "]", "}"].contains(&token.text) => {
+                // 対応する開き括弧が無い閉じ括弧はそのまま葉にする
+                let Some((open, parent)) = stack.pop() else {
+                    current.push(TokenTree::Leaf(*token));
+                    continue;
+                };
+                let children = std::mem::replace(&mut current, parent);
+                current.push(TokenTree::Group { open, children, close: Some(*token) });
+            }
+            _ => current.push(TokenTree::Leaf(*token)),
+        }
+    }
+
+    // 閉じられていない括弧は、最後まで中身に入れて閉じる
+    while let Some((open, parent)) = stack.pop() {
+        let children = std::mem::replace(&mut current, parent);
+        current.push(TokenTree::Group { open, children, close: None });
+    }
+    current
+}
+
+// ";" と、ブロックで終わる文（`if ... {}` / `fn ... {}` など）の切れ目で分ける
+pub fn split_statements<'t, 'a>(trees: &'t [TokenTree<'a>]) -> Vec<&'t [TokenTree<'a>]> {
+    const BLOCK_KEYWORDS: [&str; 14] =
+        ["if", "while", "for", "loop", "match", "unsafe", "fn", "impl", "mod", "trait", "struct", "enum", "async", "extern"];
+
+    let mut statements = Vec::new();
+    let mut start = 0;
+    for (i, tree) in trees.iter().enumerate() {
+        let ends = match tree {
+            TokenTree::Leaf(token) => token.is_punct(";"),
+            TokenTree::Group { open, .. } if open.text == "{" => {
+                let statement = &trees[start..=i];
+                let block_like = statement.len() == 1
+                    || leading_keyword(statement).is_some_and(|keyword| BLOCK_KEYWORDS.contains(&keyword));
+                let continues = trees.get(i + 1).and_then(TokenTree::leaf).is_some_and(|next| {
+                    next.is_keyword("else") || next.is_punct(".") || next.is_punct("?")
+                });
+                block_like && !continues
+            }
+            TokenTree::Group { .. } => false,
+        };
+        if ends {
+            statements.push(&trees[start..=i]);
+            start = i + 1;
+        }
+    }
+    if start < trees.len() {
+        statements.push(&trees[start..]);
+    }
+    statements
+}
+
+// 文の最初のキーワード（属性 `#[...]`、`pub` / `pub(crate)`、ラベル `'a:` は飛ばす）
+pub fn leading_keyword<'a>(statement: &[TokenTree<'a>]) -> Option<&'a str> {
+    let mut trees = statement.iter().peekable();
+    while let Some(tree) = trees.next() {
+        let token = tree.leaf()?;
+        match token.kind {
+            TokenKind::Punct if token.text == "#" => {
+                trees.next_if(|tree| tree.group("[").is_some());
+            }
+            TokenKind::Lifetime => {
+                trees.next_if(|tree| tree.leaf().is_some_and(|token| token.is_punct(":")));
+            }
+            TokenKind::Ident if token.text == "pub" => {
+                trees.next_if(|tree| tree.group("(").is_some());
+            }
+            TokenKind::Ident => return Some(token.text),
+            _ => return None,
+        }
+    }
+    None
+}
+
+
+// --- Tests --- //
+
+#[cfg(test)]
+mod tests {
+    use super::{token_trees, tokenize, unbalanced_delimiter, TokenKind, TokenTree};
+
+    fn kinds_and_texts(text: &str) -> Vec<(TokenKind, &str)> {
+        tokenize(text).into_iter().map(|token| (token.kind, token.text)).collect()
+    }
+
+    #[test]
+    fn test_tokenize_skips_comments_and_keeps_offsets() {
+        let text = "let x = 1; // TODO\n/* a /* nested */ comment */ x::y -> 'a";
+        let tokens = tokenize(text);
+
+        let texts: Vec<&str> = tokens.iter().map(|token| token.text).collect();
+        assert_eq!(texts, vec!["let", "x", "=", "1", ";", "x", "::", "y", "->", "'a"]);
+        assert!(tokens.iter().all(|token| &text[token.range()] == token.text), "offsets should point into the text");
+        assert_eq!(tokens[9].kind, TokenKind::Lifetime);
+        assert!(!tokens[0].is_ident(), "keywords are not identifiers");
+        assert!(tokens[1].is_ident());
+    }
+
+    #[test]
+    fn test_tokenize_literals() {
+        assert_eq!(
+            kinds_and_texts(r##""a \" b" r#"raw "quoted""# 'c' '\'' 0..10 1.5 "🦀""##),
+            vec![
+                (TokenKind::Str, r#""a \" b""#),
+                (TokenKind::Str, r##"r#"raw "quoted""#"##),
+                (TokenKind::Char, "'c'"),
+                (TokenKind::Char, r"'\''"),
+                (TokenKind::Number, "0"),
+                (TokenKind::Punct, "."),
+                (TokenKind::Punct, "."),
+                (TokenKind::Number, "10"),
+                (TokenKind::Number, "1.5"),
+                (TokenKind::Str, "\"🦀\""),
+            ]
+        );
+        assert_eq!(kinds_and_texts("\"unterminated"), vec![(TokenKind::Str, "\"unterminated")], "an open string runs to the end");
+    }
+
+    #[test]
+    fn test_token_trees() {
+        let tokens = tokenize("fn f(a) { g([1]); } }");
+        let trees = token_trees(&tokens);
+
+        assert_eq!(trees.len(), 5, "fn, f, (a), {{...}} and the stray closing brace");
+        let body = trees[3].group("{").expect("the body is a brace group");
+        assert_eq!(body.len(), 3, "g, (...) and ;");
+        assert!(body[1].group("(").unwrap()[0].group("[").is_some());
+        assert!(matches!(trees[4], TokenTree::Leaf(token) if token.text == "}"));
+
+        let unclosed = token_trees(&tokenize("fn f() { let x"));
+        assert_eq!(unclosed[3].end(), "fn f() { let x".len(), "an unclosed group ends at its last child");
+
+        assert_eq!(unbalanced_delimiter(&trees).map(|token| token.start), Some(20), "the stray closing brace");
+        assert_eq!(unbalanced_delimiter(&unclosed).map(|token| token.text), Some("{"));
+        assert_eq!(unbalanced_delimiter(&token_trees(&tokenize("fn f() { g([1]); }"))), None);
+    }
+}
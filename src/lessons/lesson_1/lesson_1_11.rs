@@ -14,6 +14,43 @@
 
 use serde_json::{json, Value};
 
+// JSON-RPC / LSP で決められているエラーコード
+// create_lsp_error_response には `ErrorCode::MethodNotFound.code()` のように渡す
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ErrorCode {
+    // JSON-RPC 2.0 で定義されているもの
+    ParseError,
+    InvalidRequest,
+    MethodNotFound,
+    InvalidParams,
+    InternalError,
+    // LSP で追加されたもの
+    ServerNotInitialized,
+    UnknownErrorCode,
+    RequestFailed,
+    ServerCancelled,
+    ContentModified,
+    RequestCancelled,
+}
+
+impl ErrorCode {
+    pub fn code(self) -> i32 {
+        match self {
+            ErrorCode::ParseError => -32700,
+            ErrorCode::InvalidRequest => -32600,
+            ErrorCode::MethodNotFound => -32601,
+            ErrorCode::InvalidParams => -32602,
+            ErrorCode::InternalError => -32603,
+            ErrorCode::ServerNotInitialized => -32002,
+            ErrorCode::UnknownErrorCode => -32001,
+            ErrorCode::RequestFailed => -32803,
+            ErrorCode::ServerCancelled => -32802,
+            ErrorCode::ContentModified => -32801,
+            ErrorCode::RequestCancelled => -32800,
+        }
+    }
+}
+
 pub fn create_lsp_error_response(id: Value, error_code: i32, error_message: String, error_data: Option<Value>) -> String {
     let error_content = json!({
         "jsonrpc": "2.0",
@@ -33,7 +70,7 @@ pub fn create_lsp_error_response(id: Value, error_code: i32, error_message: Stri
 
 #[cfg(test)]
 mod tests {
-    use super::create_lsp_error_response;
+    use super::{create_lsp_error_response, ErrorCode};
     use serde_json::json;
 
     // Helper function to parse the full message and extract content for testing
@@ -97,4 +134,16 @@ mod tests {
 
         assert_eq!(length_from_header, content_part.len(), "Content-Length must match the actual length of the content.");
     }
+
+    #[test]
+    fn test_error_codes_match_specification() {
+        assert_eq!(ErrorCode::ParseError.code(), -32700);
+        assert_eq!(ErrorCode::InvalidRequest.code(), -32600);
+        assert_eq!(ErrorCode::MethodNotFound.code(), -32601);
+        assert_eq!(ErrorCode::InvalidParams.code(), -32602);
+        assert_eq!(ErrorCode::InternalError.code(), -32603);
+        assert_eq!(ErrorCode::ServerNotInitialized.code() as i64, lsp_types::error_codes::SERVER_NOT_INITIALIZED);
+        assert_eq!(ErrorCode::RequestCancelled.code() as i64, lsp_types::error_codes::REQUEST_CANCELLED);
+        assert_eq!(ErrorCode::ContentModified.code() as i64, lsp_types::error_codes::CONTENT_MODIFIED);
+    }
 }
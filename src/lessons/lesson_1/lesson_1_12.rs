@@ -4,56 +4,152 @@
 
 // Your Task:
 // The function `handle_lsp_lifecycle` simulates a simple LSP server's lifecycle handling.
-// It takes an `LspMessage` (from Lesson 1-9) and a mutable boolean `initialized_state`.
+// It takes an `LspMessage` (from Lesson 1-9) and a mutable `LifecycleState`.
+// A session moves through these states:
+//   Uninitialized --initialize--> Initializing --initialized--> Running
+//   Initializing / Running --shutdown--> ShuttingDown --exit--> Exited (exit code 0)
+//   any other state --exit--> Exited (exit code 1)
 // - If it's an `initialize` request, it should return a success response with `InitializeResult`.
 //   (You can use `lsp_types::InitializeResult::default()` for simplicity).
-//   Also, set `initialized_state` to `true` after sending the response.
-// - If it's an `initialized` notification, it should do nothing (return `None`).
 // - If it's a `shutdown` request, it should return a `null` result response.
-//   (Do NOT set `initialized_state` to `false` here; that happens on `exit`).
-// - If it's an `exit` notification, it should set `initialized_state` to `false` and return `None`.
+// - Requests before `initialize` must fail with ServerNotInitialized (-32002).
+// - Requests after `shutdown` must fail with InvalidRequest (-32600).
+// - Notifications that are not allowed in the current state are dropped (return `None`).
 // - For any other message, return `None`.
 
-use crate::lessons::lesson_1::lesson_1_9::LspMessage;
+use serde_json::Value;
+use crate::lessons::lesson_1::lesson_1_9::{LspMessage, ResponseError};
 use crate::lessons::lesson_1::lesson_1_10::create_lsp_response;
+use crate::lessons::lesson_1::lesson_1_11::ErrorCode;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum LifecycleState {
+    // initialize リクエストを待っている
+    #[default]
+    Uninitialized,
+    // initialize に応答して、initialized 通知を待っている
+    Initializing,
+    // 通常の状態
+    Running,
+    // shutdown リクエストを受け取り、exit 通知を待っている
+    ShuttingDown,
+    // exit 通知を受け取った。shutdown を経由していれば終了コード 0、そうでなければ 1
+    Exited { exit_code: i32 },
+}
+
+impl LifecycleState {
+    // メッセージを受け取ったときの状態遷移
+    // - Ok(true)  → そのメッセージを処理してよい
+    // - Ok(false) → 黙って捨てる（初期化前の通知など）
+    // - Err(..)   → リクエストにエラー応答を返す
+    pub fn on_message(&mut self, message: &LspMessage) -> Result<bool, ResponseError> {
+        match message {
+            LspMessage::Request { method, .. } => self.on_request(method).map(|_| true),
+            LspMessage::Notification { method, .. } => Ok(self.on_notification(method)),
+            // クライアントからの応答は exit 前ならいつでも受け取る
+            LspMessage::Response { .. } => Ok(!self.is_exited()),
+        }
+    }
+
+    fn on_request(&mut self, method: &str) -> Result<(), ResponseError> {
+        match (*self, method) {
+            (LifecycleState::Uninitialized, "initialize") => {
+                *self = LifecycleState::Initializing;
+                Ok(())
+            }
+            (LifecycleState::Uninitialized, _) => {
+                Err(ResponseError::new(ErrorCode::ServerNotInitialized, "Server has not been initialized."))
+            }
+            (LifecycleState::ShuttingDown | LifecycleState::Exited { .. }, _) => {
+                Err(ResponseError::new(ErrorCode::InvalidRequest, "Server is shutting down."))
+            }
+            (LifecycleState::Initializing | LifecycleState::Running, "initialize") => {
+                Err(ResponseError::new(ErrorCode::InvalidRequest, "Server has already been initialized."))
+            }
+            (LifecycleState::Initializing | LifecycleState::Running, "shutdown") => {
+                *self = LifecycleState::ShuttingDown;
+                Ok(())
+            }
+            (LifecycleState::Initializing | LifecycleState::Running, _) => Ok(()),
+        }
+    }
+
+    fn on_notification(&mut self, method: &str) -> bool {
+        match (*self, method) {
+            (LifecycleState::Exited { .. }, _) => false,
+            (LifecycleState::ShuttingDown, "exit") => {
+                *self = LifecycleState::Exited { exit_code: 0 };
+                true
+            }
+            (_, "exit") => {
+                *self = LifecycleState::Exited { exit_code: 1 };
+                true
+            }
+            (LifecycleState::Initializing, "initialized") => {
+                *self = LifecycleState::Running;
+                true
+            }
+            (LifecycleState::Running, "initialized") => false,
+            (LifecycleState::Initializing | LifecycleState::Running, _) => true,
+            // 初期化前と shutdown 後の通知は exit 以外すべて捨てる
+            (LifecycleState::Uninitialized | LifecycleState::ShuttingDown, _) => false,
+        }
+    }
+
+    pub fn is_exited(&self) -> bool {
+        matches!(self, LifecycleState::Exited { .. })
+    }
+
+    // exit 通知を受け取っていれば、プロセスの終了コード
+    pub fn exit_code(&self) -> Option<i32> {
+        match self {
+            LifecycleState::Exited { exit_code } => Some(*exit_code),
+            _ => None,
+        }
+    }
+}
+
+pub fn handle_lsp_lifecycle(message: LspMessage, state: &mut LifecycleState) -> Option<String> {
+    let accepted = match state.on_message(&message) {
+        Ok(accepted) => accepted,
+        Err(err) => {
+            // エラー応答を返せるのはリクエストだけ
+            return match message {
+                LspMessage::Request { id, .. } => Some(err.to_error_response(id)),
+                _ => None,
+            };
+        }
+    };
+    if !accepted {
+        return None;
+    }
 
-pub fn handle_lsp_lifecycle(message: LspMessage, initialized_state: &mut bool) -> Option<String> {
     match message {
         LspMessage::Request { id, method, .. } => { // paramsは使わないので`..`で無視
             match method.as_str() {
                 "initialize" => {
-                    *initialized_state = true;
                     // InitializeResult::default() を使用
                     let init_result = serde_json::to_value(lsp_types::InitializeResult::default()).ok()?;
                     Some(create_lsp_response(id, init_result))
                 },
                 "shutdown" => {
-                    Some(create_lsp_response(id, serde_json::Value::Null))
+                    Some(create_lsp_response(id, Value::Null))
                 },
                 _ => None, // &を削除
             }
         },
-        LspMessage::Notification { method, .. } => { // paramsは使わないので`..`で無視
-            match method.as_str() {
-                "initialized" => {
-                    None
-                },
-                "exit" => {
-                    *initialized_state = false;
-                    None
-                },
-                _ => None, // &を削除
-            }
-        }
+        // 通知 (initialized / exit) の状態遷移は on_message で済んでいるので、返すものは無い
+        LspMessage::Notification { .. } => None,
+        // クライアントからの応答はライフサイクルに関係しない
+        LspMessage::Response { .. } => None,
     }
 }
 
-
 // --- Tests --- //
 
 #[cfg(test)]
 mod tests {
-    use super::handle_lsp_lifecycle;
+    use super::{handle_lsp_lifecycle, LifecycleState};
     use crate::lessons::lesson_1::lesson_1_9::LspMessage;
     use serde_json::{json, Value};
 
@@ -65,17 +161,30 @@ mod tests {
         })
     }
 
+    fn request(id: i64, method: &str) -> LspMessage {
+        LspMessage::Request {
+            id: json!(id),
+            method: method.to_string(),
+            params: if method == "initialize" { Some(dummy_initialize_params()) } else { None },
+        }
+    }
+
+    fn notification(method: &str) -> LspMessage {
+        LspMessage::Notification {
+            method: method.to_string(),
+            params: None,
+        }
+    }
+
+    fn content(response: &str) -> Value {
+        serde_json::from_str(response.split_once("\r\n\r\n").unwrap().1).unwrap()
+    }
+
     #[test]
     fn test_handle_initialize_request() {
-        let mut initialized = false;
-        let id = json!(1);
-        let message = LspMessage::Request {
-            id: id.clone(),
-            method: "initialize".to_string(),
-            params: Some(dummy_initialize_params()),
-        };
+        let mut state = LifecycleState::Uninitialized;
 
-        let response = handle_lsp_lifecycle(message, &mut initialized);
+        let response = handle_lsp_lifecycle(request(1, "initialize"), &mut state);
 
         assert!(response.is_some(), "Initialize request should return a response.");
         let response_str = response.unwrap();
@@ -83,34 +192,34 @@ mod tests {
         assert!(response_str.contains("Content-Length:"));
         assert!(response_str.contains("\"id\":1"));
         assert!(response_str.contains("\"result\":"));
-        assert!(initialized, "Initialized state should be true after initialize.");
+        assert_eq!(state, LifecycleState::Initializing, "State should be Initializing after initialize.");
     }
 
     #[test]
     fn test_handle_initialized_notification() {
-        let mut initialized = false;
-        let message = LspMessage::Notification {
-            method: "initialized".to_string(),
-            params: None,
-        };
+        let mut state = LifecycleState::Initializing;
 
-        let response = handle_lsp_lifecycle(message, &mut initialized);
+        let response = handle_lsp_lifecycle(notification("initialized"), &mut state);
 
         assert!(response.is_none(), "Initialized notification should not return a response.");
-        assert!(!initialized, "Initialized state should not change on initialized notification.");
+        assert_eq!(state, LifecycleState::Running, "State should be Running after initialized.");
+    }
+
+    #[test]
+    fn test_initialized_notification_before_initialize_is_dropped() {
+        let mut state = LifecycleState::Uninitialized;
+
+        let response = handle_lsp_lifecycle(notification("initialized"), &mut state);
+
+        assert!(response.is_none());
+        assert_eq!(state, LifecycleState::Uninitialized, "State should not change before initialize.");
     }
 
     #[test]
     fn test_handle_shutdown_request() {
-        let mut initialized = true;
-        let id = json!(2);
-        let message = LspMessage::Request {
-            id: id.clone(),
-            method: "shutdown".to_string(),
-            params: None,
-        };
+        let mut state = LifecycleState::Running;
 
-        let response = handle_lsp_lifecycle(message, &mut initialized);
+        let response = handle_lsp_lifecycle(request(2, "shutdown"), &mut state);
 
         assert!(response.is_some(), "Shutdown request should return a response.");
         let response_str = response.unwrap();
@@ -118,50 +227,124 @@ mod tests {
         assert!(response_str.contains("Content-Length:"));
         assert!(response_str.contains("\"id\":2"));
         assert!(response_str.contains("\"result\":null"));
-        assert!(initialized, "Initialized state should not change on shutdown request.");
+        assert_eq!(state, LifecycleState::ShuttingDown, "State should be ShuttingDown after shutdown.");
     }
 
     #[test]
-    fn test_handle_exit_notification() {
-        let mut initialized = true;
-        let message = LspMessage::Notification {
-            method: "exit".to_string(),
-            params: None,
-        };
+    fn test_shutdown_while_initializing() {
+        let mut state = LifecycleState::Initializing;
 
-        let response = handle_lsp_lifecycle(message, &mut initialized);
+        let response = handle_lsp_lifecycle(request(2, "shutdown"), &mut state);
+
+        assert!(content(&response.unwrap())["result"].is_null());
+        assert_eq!(state, LifecycleState::ShuttingDown);
+    }
+
+    #[test]
+    fn test_handle_exit_notification_after_shutdown() {
+        let mut state = LifecycleState::ShuttingDown;
+
+        let response = handle_lsp_lifecycle(notification("exit"), &mut state);
 
         assert!(response.is_none(), "Exit notification should not return a response.");
-        assert!(!initialized, "Initialized state should be false after exit notification.");
+        assert_eq!(state, LifecycleState::Exited { exit_code: 0 });
+        assert_eq!(state.exit_code(), Some(0), "Exit after shutdown should exit with code 0.");
+    }
+
+    #[test]
+    fn test_handle_exit_notification_without_shutdown() {
+        for initial in [LifecycleState::Uninitialized, LifecycleState::Initializing, LifecycleState::Running] {
+            let mut state = initial;
+
+            let response = handle_lsp_lifecycle(notification("exit"), &mut state);
+
+            assert!(response.is_none());
+            assert_eq!(state.exit_code(), Some(1), "Exit without shutdown from {:?} should exit with code 1.", initial);
+        }
+    }
+
+    #[test]
+    fn test_request_before_initialize_is_server_not_initialized() {
+        let mut state = LifecycleState::Uninitialized;
+
+        let response = handle_lsp_lifecycle(request(3, "textDocument/hover"), &mut state).expect("should return an error response");
+
+        let error_response = content(&response);
+        assert_eq!(error_response["id"], 3);
+        assert_eq!(error_response["error"]["code"], -32002);
+        assert_eq!(state, LifecycleState::Uninitialized);
+
+        let shutdown = handle_lsp_lifecycle(request(4, "shutdown"), &mut state).unwrap();
+        assert_eq!(content(&shutdown)["error"]["code"], -32002, "shutdown before initialize should also fail.");
+    }
+
+    #[test]
+    fn test_request_after_shutdown_is_invalid_request() {
+        let mut state = LifecycleState::ShuttingDown;
+
+        for (id, method) in [(5, "textDocument/hover"), (6, "shutdown"), (7, "initialize")] {
+            let response = handle_lsp_lifecycle(request(id, method), &mut state).expect("should return an error response");
+
+            let error_response = content(&response);
+            assert_eq!(error_response["id"], id);
+            assert_eq!(error_response["error"]["code"], -32600, "{} after shutdown should be rejected.", method);
+        }
+        assert_eq!(state, LifecycleState::ShuttingDown);
+    }
+
+    #[test]
+    fn test_second_initialize_is_invalid_request() {
+        let mut state = LifecycleState::Running;
+
+        let response = handle_lsp_lifecycle(request(8, "initialize"), &mut state).unwrap();
+
+        assert_eq!(content(&response)["error"]["code"], -32600);
+        assert_eq!(state, LifecycleState::Running);
     }
 
     #[test]
     fn test_handle_other_request() {
-        let mut initialized = true;
-        let id = json!(3);
-        let message = LspMessage::Request {
-            id: id.clone(),
-            method: "textDocument/didOpen".to_string(),
-            params: None,
-        };
+        let mut state = LifecycleState::Running;
 
-        let response = handle_lsp_lifecycle(message, &mut initialized);
+        let response = handle_lsp_lifecycle(request(3, "textDocument/definition"), &mut state);
 
         assert!(response.is_none(), "Other requests should not return a response in this handler.");
-        assert!(initialized, "Initialized state should not change for other requests.");
+        assert_eq!(state, LifecycleState::Running, "State should not change for other requests.");
     }
 
     #[test]
     fn test_handle_other_notification() {
-        let mut initialized = true;
-        let message = LspMessage::Notification {
-            method: "textDocument/didChange".to_string(),
-            params: None,
-        };
+        let mut state = LifecycleState::Running;
 
-        let response = handle_lsp_lifecycle(message, &mut initialized);
+        let response = handle_lsp_lifecycle(notification("textDocument/didChange"), &mut state);
 
         assert!(response.is_none(), "Other notifications should not return a response in this handler.");
-        assert!(initialized, "Initialized state should not change for other notifications.");
+        assert_eq!(state, LifecycleState::Running, "State should not change for other notifications.");
+    }
+
+    #[test]
+    fn test_notifications_are_dropped_outside_running_states() {
+        for initial in [LifecycleState::Uninitialized, LifecycleState::ShuttingDown, LifecycleState::Exited { exit_code: 0 }] {
+            let mut state = initial;
+            assert_eq!(state.on_message(&notification("textDocument/didOpen")), Ok(false), "didOpen should be dropped in {:?}.", initial);
+            assert_eq!(state, initial);
+        }
+
+        let mut state = LifecycleState::Initializing;
+        assert_eq!(state.on_message(&notification("textDocument/didOpen")), Ok(true));
+    }
+
+    #[test]
+    fn test_full_session() {
+        let mut state = LifecycleState::default();
+
+        handle_lsp_lifecycle(request(1, "initialize"), &mut state);
+        handle_lsp_lifecycle(notification("initialized"), &mut state);
+        assert_eq!(state, LifecycleState::Running);
+        handle_lsp_lifecycle(request(2, "shutdown"), &mut state);
+        handle_lsp_lifecycle(notification("exit"), &mut state);
+
+        assert!(state.is_exited());
+        assert_eq!(state.exit_code(), Some(0));
     }
 }
@@ -13,26 +13,37 @@
 // - `source`: "toy-lang-server"
 // Return a `Vec<Diagnostic>` containing all found diagnostics.
 
+// Going further:
+// Users often mark work with other words too ("FIXME", "XXX", ...).
+// `generate_diagnostics_with_keywords` looks for any of the given keywords (still case-insensitive)
+// and names the keyword it found in the message, e.g. "Found a FIXME item.".
+
 use lsp_types::{Diagnostic, DiagnosticSeverity, Position, Range, Url};
 
-pub fn generate_diagnostics(_file_uri: Url, file_content: &str) -> Vec<Diagnostic> {
+pub fn generate_diagnostics(file_uri: Url, file_content: &str) -> Vec<Diagnostic> {
+    generate_diagnostics_with_keywords(file_uri, file_content, &["TODO".to_string()])
+}
+
+// keywords のどれかを含む行を報告する版（generate_diagnostics は "TODO" だけ）
+// 1行に複数のキーワードがあっても、報告するのは keywords の順で最初に見つかったもの1つ
+pub fn generate_diagnostics_with_keywords(_file_uri: Url, file_content: &str, keywords: &[String]) -> Vec<Diagnostic> {
+    let keywords: Vec<(String, &String)> = keywords.iter().map(|keyword| (keyword.to_lowercase(), keyword)).collect();
     file_content
         .lines()
         .enumerate() // (行番号, 行の内容) のイテレータ
         .filter_map(|(row_number, line)| { // 各行をフィルタリングし、Diagnosticを生成
-            if line.to_lowercase().contains("todo") {
-                Some(Diagnostic::new(
-                    Range::new(Position::new(row_number as u32, 0), Position::new(row_number as u32, 0)),
-                    Some(DiagnosticSeverity::WARNING),
-                    None, // code
-                    Some("toy-lang-server".to_string()), // source
-                    "Found a TODO item.".to_string(), // message
-                    None, // tags
-                    None, // related_information
-                ))
-            } else {
-                None // "TODO" が含まれていなければNoneを返し、filter_mapでスキップ
-            }
+            let line = line.to_lowercase();
+            // キーワードが含まれていなければNoneを返し、filter_mapでスキップ
+            let (_, keyword) = keywords.iter().find(|(lowercase, _)| !lowercase.is_empty() && line.contains(lowercase.as_str()))?;
+            Some(Diagnostic::new(
+                Range::new(Position::new(row_number as u32, 0), Position::new(row_number as u32, 0)),
+                Some(DiagnosticSeverity::WARNING),
+                None, // code
+                Some("toy-lang-server".to_string()), // source
+                format!("Found a {} item.", keyword), // message
+                None, // tags
+                None, // related_information
+            ))
         })
         .collect() // 結果をVec<Diagnostic>に収集
 }
@@ -42,7 +53,7 @@ pub fn generate_diagnostics(_file_uri: Url, file_content: &str) -> Vec<Diagnosti
 
 #[cfg(test)]
 mod tests {
-    use super::generate_diagnostics;
+    use super::{generate_diagnostics, generate_diagnostics_with_keywords};
     use lsp_types::{Range, Url, Position};
     use std::str::FromStr;
 
@@ -95,4 +106,18 @@ mod tests {
         assert_eq!(diagnostics.len(), 1, "Should find TODO on the correct line.");
         assert_eq!(diagnostics[0].range.start.line, 1);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_configured_keywords() {
+        let uri = Url::from_str("file:///test.txt").unwrap();
+        let content = "// fixme: First\n// TODO: Second\n// XXX and FIXME";
+        let keywords = vec!["FIXME".to_string(), "XXX".to_string()];
+        let diagnostics = generate_diagnostics_with_keywords(uri, content, &keywords);
+        let found: Vec<(u32, &str)> = diagnostics.iter().map(|d| (d.range.start.line, d.message.as_str())).collect();
+        assert_eq!(
+            found,
+            vec![(0, "Found a FIXME item."), (2, "Found a FIXME item.")],
+            "Only the configured keywords are reported, named as configured, once per line."
+        );
+    }
+}
@@ -11,11 +11,24 @@
 // Example JSON content for a publishDiagnostics notification:
 // {"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{"uri":"file:///a.rs","diagnostics":[]}}
 
+// Going further:
+// `create_publish_diagnostics_notification_with_version` also sends the `version` of the document
+// the diagnostics were computed for, so the editor can ignore diagnostics for text that has changed since.
+
 use serde_json::json;
 use lsp_types::{Diagnostic, Url};
 
 pub fn create_publish_diagnostics_notification(file_uri: Url, diagnostics: Vec<Diagnostic>) -> String {
-    let notification_content = json!({
+    create_publish_diagnostics_notification_with_version(file_uri, diagnostics, None)
+}
+
+// version が None なら params に version を入れない
+pub fn create_publish_diagnostics_notification_with_version(
+    file_uri: Url,
+    diagnostics: Vec<Diagnostic>,
+    version: Option<i32>,
+) -> String {
+    let mut notification_content = json!({
         "jsonrpc": "2.0",
         "method": "textDocument/publishDiagnostics",
         "params": {
@@ -23,7 +36,10 @@ pub fn create_publish_diagnostics_notification(file_uri: Url, diagnostics: Vec<D
             "diagnostics": diagnostics,
         },
     });
-    
+    if let Some(version) = version {
+        notification_content["params"]["version"] = json!(version);
+    }
+
     let notification_str = serde_json::to_string(&notification_content).unwrap();
     format!("Content-Length: {}\r\n\r\n{}", notification_str.len(), notification_str)
 }
@@ -33,7 +49,7 @@ pub fn create_publish_diagnostics_notification(file_uri: Url, diagnostics: Vec<D
 
 #[cfg(test)]
 mod tests {
-    use super::create_publish_diagnostics_notification;
+    use super::{create_publish_diagnostics_notification, create_publish_diagnostics_notification_with_version};
     use lsp_types::{Diagnostic, DiagnosticSeverity, Position, Range, Url};
     use serde_json::json;
     use std::str::FromStr;
@@ -114,4 +130,19 @@ mod tests {
 
         assert_eq!(length_from_header, content_part.len(), "Content-Length must match the actual length of the content.");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_publish_diagnostics_with_version() {
+        let uri = Url::from_str("file:///test.txt").unwrap();
+
+        let with_version = create_publish_diagnostics_notification_with_version(uri.clone(), vec![], Some(7));
+        let content = get_content_from_full_message(&with_version).expect("Failed to get content from notification.");
+        let parsed_content: serde_json::Value = serde_json::from_str(&content).expect("Failed to parse content as JSON.");
+        assert_eq!(parsed_content["params"]["version"], 7, "The document version should be sent.");
+
+        let without_version = create_publish_diagnostics_notification(uri, vec![]);
+        let content = get_content_from_full_message(&without_version).expect("Failed to get content from notification.");
+        let parsed_content: serde_json::Value = serde_json::from_str(&content).expect("Failed to parse content as JSON.");
+        assert!(parsed_content["params"].get("version").is_none(), "The version should be omitted when unknown.");
+    }
+}
@@ -6,37 +6,46 @@
 // Your Task:
 // The function `handle_did_open_notification` takes:
 // - `params_value`: The `serde_json::Value` from the `params` field of a `textDocument/didOpen` notification.
-// - `document_store`: A mutable `std::collections::HashMap<Url, String>` where the server stores document content.
+// - `document_store`: A mutable `DocumentStore` (see `common::document_store`) where the server stores open documents.
 // It should:
-// 1. Extract the `uri` and `text` from the `params_value`.
+// 1. Extract the `uri`, `languageId`, `version` and `text` from the `params_value`.
 //    - The `params_value` for `textDocument/didOpen` typically looks like:
 //      `{"textDocument": {"uri": "file:///a.rs", "languageId": "rust", "version": 1, "text": "fn main() {}\n"}}`
-// 2. Store the `text` in the `document_store` using the `uri` as the key.
+// 2. Open the document in the `document_store` with `DocumentStore::open`.
 // 3. Generate diagnostics for the opened document using `super::lesson_1_13::generate_diagnostics`.
 // 4. Return the generated `Vec<Diagnostic>`.
 // - If parsing `params_value` fails or required fields are missing, return an empty `Vec<Diagnostic>`.
 
 use serde_json::Value;
 use lsp_types::{Diagnostic, Url};
-use std::collections::HashMap;
+use crate::common::document_store::DocumentStore;
 use crate::lessons::lesson_1::lesson_1_13::generate_diagnostics;
 
-pub fn handle_did_open_notification(params_value: &Value, document_store: &mut HashMap<Url, String>) -> Vec<Diagnostic> {
+pub fn handle_did_open_notification(params_value: &Value, document_store: &mut DocumentStore) -> Vec<Diagnostic> {
     // このクロージャの中で ? 演算子を安全に使います。
     // クロージャがNoneを返した場合、最終的にVec::new()が返されます。
     let result: Option<Vec<Diagnostic>> = (|| {
-        let text_document_obj = params_value.get("textDocument")?.as_object()?;
-        let uri_str = text_document_obj.get("uri")?.as_str()?;
-        let text_content = text_document_obj.get("text")?.as_str()?;
-        let uri = Url::parse(uri_str).ok()?;
-
-        document_store.insert(uri.clone(), text_content.to_string());
-        Some(generate_diagnostics(uri, text_content))
+        let uri = open_document(params_value, document_store)?;
+        Some(generate_diagnostics(uri.clone(), document_store.text(&uri)?))
     })(); // ここでクロージャをすぐに実行します
 
     result.unwrap_or_default() // クロージャがNoneを返したらVec::new()を返す
 }
 
+// ドキュメントを document_store に保存するだけの部分（診断は計算しない）
+// 開いたドキュメントの URI を返す
+pub fn open_document(params_value: &Value, document_store: &mut DocumentStore) -> Option<Url> {
+    let text_document_obj = params_value.get("textDocument")?.as_object()?;
+    let uri_str = text_document_obj.get("uri")?.as_str()?;
+    let language_id = text_document_obj.get("languageId")?.as_str()?;
+    let version = text_document_obj.get("version")?.as_i64()? as i32;
+    let text_content = text_document_obj.get("text")?.as_str()?;
+    let uri = Url::parse(uri_str).ok()?;
+
+    document_store.open(uri.clone(), language_id, version, text_content);
+    Some(uri)
+}
+
 
 // --- Tests --- //
 
@@ -44,8 +53,8 @@ pub fn handle_did_open_notification(params_value: &Value, document_store: &mut H
 mod tests {
     use super::handle_did_open_notification;
     use serde_json::{json, Value};
+    use crate::common::document_store::DocumentStore;
     use lsp_types::Url;
-    use std::collections::HashMap;
     use std::str::FromStr;
 
     // Helper to create dummy didOpen params
@@ -62,7 +71,7 @@ mod tests {
 
     #[test]
     fn test_did_open_stores_document_and_generates_diagnostics() {
-        let mut store = HashMap::new();
+        let mut store = DocumentStore::new();
         let uri = Url::from_str("file:///test.rs").unwrap();
         let content = "fn main() {\n// TODO: Implement\n}";
         let params = dummy_did_open_params(uri.as_str(), content);
@@ -70,7 +79,11 @@ mod tests {
         let diagnostics = handle_did_open_notification(&params, &mut store);
 
         // Check if document is stored
-        assert_eq!(store.get(&uri), Some(&content.to_string()), "Document content should be stored in the HashMap.");
+        assert_eq!(store.text(&uri), Some(content), "Document content should be stored in the DocumentStore.");
+
+        let document = store.get(&uri).unwrap();
+        assert_eq!(document.language_id(), "rust", "The languageId should be stored.");
+        assert_eq!(document.version(), 1, "The version should be stored.");
 
         // Check generated diagnostics
         assert_eq!(diagnostics.len(), 1, "Should generate one diagnostic for TODO.");
@@ -80,7 +93,7 @@ mod tests {
 
     #[test]
     fn test_did_open_with_clean_document() {
-        let mut store = HashMap::new();
+        let mut store = DocumentStore::new();
         let uri = Url::from_str("file:///clean.rs").unwrap();
         let content = "fn main() {}\n";
         let params = dummy_did_open_params(uri.as_str(), content);
@@ -88,7 +101,7 @@ mod tests {
         let diagnostics = handle_did_open_notification(&params, &mut store);
 
         // Check if document is stored
-        assert_eq!(store.get(&uri), Some(&content.to_string()), "Document content should be stored in the HashMap.");
+        assert_eq!(store.text(&uri), Some(content), "Document content should be stored in the DocumentStore.");
 
         // Check generated diagnostics
         assert!(diagnostics.is_empty(), "Should generate no diagnostics for clean code.");
@@ -96,7 +109,7 @@ mod tests {
 
     #[test]
     fn test_did_open_with_malformed_params_returns_empty_diagnostics() {
-        let mut store = HashMap::new();
+        let mut store = DocumentStore::new();
         let malformed_params = json!({
             "textDocument": {
                 "uri": "file:///bad.rs",
@@ -112,7 +125,7 @@ mod tests {
 
     #[test]
     fn test_did_open_with_invalid_uri_returns_empty_diagnostics() {
-        let mut store = HashMap::new();
+        let mut store = DocumentStore::new();
         let invalid_uri_params = json!({
             "textDocument": {
                 "uri": "not a valid uri",
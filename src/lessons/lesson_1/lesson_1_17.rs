@@ -6,32 +6,91 @@
 // Your Task:
 // The function `handle_did_change_notification` takes:
 // - `params_value`: The `serde_json::Value` from the `params` field of a `textDocument/didChange` notification.
-// - `document_store`: A mutable `std::collections::HashMap<Url, String>` where the server stores document content.
+// - `document_store`: A mutable `DocumentStore` (see `common::document_store`) where the server stores open documents.
 // It should:
-// 1. Extract the `uri` and `text` (the full new content) from the `params_value`.
+// 1. Extract the `uri`, the `version` and the `contentChanges` from the `params_value`.
 //    - The `params_value` for `textDocument/didChange` typically looks like:
 //      `{"textDocument": {"uri": "file:///a.rs", "version": 2}, "contentChanges": [{"text": "new content"}]}`
-//      Note: For simplicity, we assume `contentChanges` always contains one item with the full new text.
-// 2. Update the `text` in the `document_store` for the given `uri`.
+//    - With `TextDocumentSyncKind::Incremental`, a change may also carry a `range` to replace:
+//      `{"range": {"start": {"line": 0, "character": 3}, "end": {"line": 0, "character": 7}}, "text": "main"}`
+//      A change without a `range` replaces the whole document.
+// 2. Apply every change, in order, with `DocumentStore::apply_changes`.
+//    - Each range refers to the document as it is after the previous changes were applied.
 // 3. Generate diagnostics for the updated document using `super::lesson_1_13::generate_diagnostics`.
 // 4. Return the generated `Vec<Diagnostic>`.
-// - If parsing `params_value` fails or required fields are missing, return an empty `Vec<Diagnostic>`.
+// - If parsing `params_value` fails, required fields are missing or the change cannot be applied,
+//   return an empty `Vec<Diagnostic>`.
+
+// Going further:
+// `handle_did_change_notification_with_encoding` is what a real server needs:
+// - Ranges are counted in the position encoding negotiated at `initialize` (see `common::line_index`).
+// - `textDocument.version` must be greater than the version the store already has.
+//   A duplicate or out-of-order notification is reported as an error instead of corrupting the stored text.
+// - Changes are applied all-or-nothing: if one range is invalid, the stored text is left untouched.
 
 use serde_json::Value;
-use lsp_types::{Diagnostic, Url};
-use std::collections::HashMap;
+use lsp_types::{Diagnostic, PositionEncodingKind, TextDocumentContentChangeEvent, Url};
+use std::fmt;
+use crate::common::document_store::{DocumentError, DocumentStore};
 use crate::lessons::lesson_1::lesson_1_13::generate_diagnostics;
 
-pub fn handle_did_change_notification(params_value: &Value, document_store: &mut HashMap<Url, String>) -> Vec<Diagnostic> {
+// Why a didChange notification could not be applied
+#[derive(Debug, Clone, PartialEq)]
+pub enum DidChangeError {
+    // `textDocument` or `contentChanges` is missing or malformed
+    InvalidParams,
+    // The document store refused the change (not open, outdated version or invalid range)
+    Document(DocumentError),
+}
 
-    let result: Option<Vec<Diagnostic>> = (|| {
-        let uri = Url::parse(params_value.get("textDocument")?.get("uri")?.as_str()?).ok()?;
-        let file_content = params_value.get("contentChanges")?.as_array()?.first()?.get("text")?.as_str()?;
-        document_store.insert(uri.clone(), file_content.to_string());
-        Some(generate_diagnostics(uri, file_content))
-    })();
+impl fmt::Display for DidChangeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DidChangeError::InvalidParams => write!(f, "Invalid didChange params."),
+            DidChangeError::Document(err) => write!(f, "Ignored a didChange notification: {}", err),
+        }
+    }
+}
 
-    result.unwrap_or_default()
+pub fn handle_did_change_notification(params_value: &Value, document_store: &mut DocumentStore) -> Vec<Diagnostic> {
+    handle_did_change_notification_with_encoding(params_value, document_store, &PositionEncodingKind::UTF16).unwrap_or_default()
+}
+
+pub fn handle_did_change_notification_with_encoding(
+    params_value: &Value,
+    document_store: &mut DocumentStore,
+    encoding: &PositionEncodingKind,
+) -> Result<Vec<Diagnostic>, DidChangeError> {
+    let uri = apply_did_change_with_encoding(params_value, document_store, encoding)?;
+    let text = document_store.text(&uri).unwrap_or_default();
+    Ok(generate_diagnostics(uri, text))
+}
+
+// Only applies the changes to the document_store (no diagnostics) and returns the URI of the updated document
+pub fn apply_did_change_with_encoding(
+    params_value: &Value,
+    document_store: &mut DocumentStore,
+    encoding: &PositionEncodingKind,
+) -> Result<Url, DidChangeError> {
+    let text_document = params_value.get("textDocument").ok_or(DidChangeError::InvalidParams)?;
+    let uri = text_document
+        .get("uri")
+        .and_then(Value::as_str)
+        .and_then(|uri| Url::parse(uri).ok())
+        .ok_or(DidChangeError::InvalidParams)?;
+    let version = text_document
+        .get("version")
+        .and_then(Value::as_i64)
+        .ok_or(DidChangeError::InvalidParams)? as i32;
+    let changes: Vec<TextDocumentContentChangeEvent> = params_value
+        .get("contentChanges")
+        .and_then(|changes| serde_json::from_value(changes.clone()).ok())
+        .ok_or(DidChangeError::InvalidParams)?;
+
+    document_store
+        .apply_changes(&uri, version, &changes, encoding)
+        .map_err(DidChangeError::Document)?;
+    Ok(uri)
 }
 
 
@@ -39,10 +98,10 @@ pub fn handle_did_change_notification(params_value: &Value, document_store: &mut
 
 #[cfg(test)]
 mod tests {
-    use super::handle_did_change_notification;
+    use super::{handle_did_change_notification, handle_did_change_notification_with_encoding, DidChangeError};
     use serde_json::{json, Value};
-    use lsp_types::Url;
-    use std::collections::HashMap;
+    use crate::common::document_store::{DocumentError, DocumentStore};
+    use lsp_types::{PositionEncodingKind, Url};
     use std::str::FromStr;
 
     // Helper to create dummy didChange params
@@ -62,19 +121,19 @@ mod tests {
 
     #[test]
     fn test_did_change_updates_document_and_generates_diagnostics() {
-        let mut store = HashMap::new();
+        let mut store = DocumentStore::new();
         let uri = Url::from_str("file:///test.rs").unwrap();
         let initial_content = "fn main() {}\n";
         let updated_content = "fn main() {\n// TODO: Updated\n}";
 
         // Simulate didOpen first
-        store.insert(uri.clone(), initial_content.to_string());
+        store.open(uri.clone(), "rust", 1, initial_content);
 
         let params = dummy_did_change_params(uri.as_str(), updated_content);
         let diagnostics = handle_did_change_notification(&params, &mut store);
 
         // Check if document is updated
-        assert_eq!(store.get(&uri), Some(&updated_content.to_string()), "Document content should be updated in the HashMap.");
+        assert_eq!(store.text(&uri), Some(updated_content), "Document content should be updated in the DocumentStore.");
 
         // Check generated diagnostics for updated content
         assert_eq!(diagnostics.len(), 1, "Should generate one diagnostic for TODO in updated content.");
@@ -84,19 +143,19 @@ mod tests {
 
     #[test]
     fn test_did_change_with_clean_update() {
-        let mut store = HashMap::new();
+        let mut store = DocumentStore::new();
         let uri = Url::from_str("file:///clean.rs").unwrap();
         let initial_content = "// TODO: Old\n";
         let updated_content = "fn main() {}\n";
 
         // Simulate didOpen first
-        store.insert(uri.clone(), initial_content.to_string());
+        store.open(uri.clone(), "rust", 1, initial_content);
 
         let params = dummy_did_change_params(uri.as_str(), updated_content);
         let diagnostics = handle_did_change_notification(&params, &mut store);
 
         // Check if document is updated
-        assert_eq!(store.get(&uri), Some(&updated_content.to_string()), "Document content should be updated in the HashMap.");
+        assert_eq!(store.text(&uri), Some(updated_content), "Document content should be updated in the DocumentStore.");
 
         // Check generated diagnostics
         assert!(diagnostics.is_empty(), "Should generate no diagnostics for clean updated code.");
@@ -104,9 +163,9 @@ mod tests {
 
     #[test]
     fn test_did_change_with_malformed_params_returns_empty_diagnostics() {
-        let mut store = HashMap::new();
+        let mut store = DocumentStore::new();
         let uri = Url::from_str("file:///malformed.rs").unwrap();
-        store.insert(uri.clone(), "initial".to_string());
+        store.open(uri.clone(), "rust", 1, "initial");
 
         let malformed_params = json!({
             "textDocument": {
@@ -117,15 +176,15 @@ mod tests {
 
         let diagnostics = handle_did_change_notification(&malformed_params, &mut store);
 
-        assert_eq!(store.get(&uri), Some(&"initial".to_string()), "Document should not be updated for malformed params.");
+        assert_eq!(store.text(&uri), Some("initial"), "Document should not be updated for malformed params.");
         assert!(diagnostics.is_empty(), "Should return empty diagnostics for malformed params.");
     }
 
     #[test]
     fn test_did_change_with_invalid_uri_returns_empty_diagnostics() {
-        let mut store = HashMap::new();
+        let mut store = DocumentStore::new();
         let uri = Url::from_str("file:///invalid.rs").unwrap();
-        store.insert(uri.clone(), "initial".to_string());
+        store.open(uri.clone(), "rust", 1, "initial");
 
         let invalid_uri_params = json!({
             "textDocument": {
@@ -140,7 +199,131 @@ mod tests {
 
         let diagnostics = handle_did_change_notification(&invalid_uri_params, &mut store);
 
-        assert_eq!(store.get(&uri), Some(&"initial".to_string()), "Document should not be updated for invalid URI.");
+        assert_eq!(store.text(&uri), Some("initial"), "Document should not be updated for invalid URI.");
         assert!(diagnostics.is_empty(), "Should return empty diagnostics for invalid URI.");
     }
+
+    // Helper to create didChange params with ranged changes: (start line, start char, end line, end char, text)
+    fn ranged_did_change_params(uri_str: &str, version: i32, changes: &[(u32, u32, u32, u32, &str)]) -> Value {
+        let content_changes: Vec<Value> = changes
+            .iter()
+            .map(|&(start_line, start_char, end_line, end_char, text)| {
+                json!({
+                    "range": {
+                        "start": {"line": start_line, "character": start_char},
+                        "end": {"line": end_line, "character": end_char}
+                    },
+                    "text": text
+                })
+            })
+            .collect();
+        json!({
+            "textDocument": {"uri": uri_str, "version": version},
+            "contentChanges": content_changes
+        })
+    }
+
+    fn opened(uri: &Url, content: &str) -> DocumentStore {
+        let mut store = DocumentStore::new();
+        store.open(uri.clone(), "rust", 1, content);
+        store
+    }
+
+    #[test]
+    fn test_ranged_changes_are_applied_in_order() {
+        let uri = Url::from_str("file:///incremental.rs").unwrap();
+        let mut store = opened(&uri, "fn main() {\n}\n");
+
+        // The second change refers to the text after the first one
+        let params = ranged_did_change_params(uri.as_str(), 2, &[
+            (0, 11, 0, 11, "\n    let x = 1;"),
+            (1, 8, 1, 9, "y"),
+            (2, 0, 2, 0, "// TODO: more\n"),
+        ]);
+        let diagnostics = handle_did_change_notification_with_encoding(&params, &mut store, &PositionEncodingKind::UTF16).unwrap();
+
+        assert_eq!(store.text(&uri).unwrap(), "fn main() {\n    let y = 1;\n// TODO: more\n}\n", "Changes should be applied one after another.");
+        assert_eq!(store.get(&uri).unwrap().version(), 2, "The new version should be remembered.");
+        assert_eq!(diagnostics.len(), 1, "Diagnostics should be generated for the updated text.");
+        assert_eq!(diagnostics[0].range.start.line, 2);
+    }
+
+    #[test]
+    fn test_ranges_use_the_negotiated_encoding() {
+        let uri = Url::from_str("file:///unicode.rs").unwrap();
+        let content = "let s = \"🦀あ\";";
+
+        // "🦀あ" is columns 9..12 in UTF-16, 9..11 in UTF-32 and 9..16 in UTF-8
+        for (encoding, end) in [(PositionEncodingKind::UTF16, 12), (PositionEncodingKind::UTF32, 11), (PositionEncodingKind::UTF8, 16)] {
+            let mut store = opened(&uri, content);
+            let params = ranged_did_change_params(uri.as_str(), 2, &[(0, 9, 0, end, "crab")]);
+            handle_did_change_notification_with_encoding(&params, &mut store, &encoding).unwrap();
+            assert_eq!(store.text(&uri).unwrap(), "let s = \"crab\";", "The range should be counted in {:?}.", encoding);
+        }
+    }
+
+    #[test]
+    fn test_duplicate_and_out_of_order_versions_are_rejected() {
+        let uri = Url::from_str("file:///versions.rs").unwrap();
+        let mut store = opened(&uri, "abc");
+        let encoding = PositionEncodingKind::UTF16;
+
+        let change = ranged_did_change_params(uri.as_str(), 3, &[(0, 3, 0, 3, "d")]);
+        handle_did_change_notification_with_encoding(&change, &mut store, &encoding).unwrap();
+
+        // The same notification again
+        let result = handle_did_change_notification_with_encoding(&change, &mut store, &encoding);
+        assert_eq!(result, Err(DidChangeError::Document(DocumentError::OutdatedVersion { uri: uri.clone(), current: 3, received: 3 })));
+
+        // A notification that arrived late
+        let late = ranged_did_change_params(uri.as_str(), 2, &[(0, 0, 0, 0, "x")]);
+        let result = handle_did_change_notification_with_encoding(&late, &mut store, &encoding);
+        assert_eq!(result, Err(DidChangeError::Document(DocumentError::OutdatedVersion { uri: uri.clone(), current: 3, received: 2 })));
+
+        assert_eq!(store.text(&uri).unwrap(), "abcd", "Rejected changes should not touch the stored text.");
+        assert_eq!(store.get(&uri).unwrap().version(), 3);
+    }
+
+    #[test]
+    fn test_invalid_range_leaves_document_untouched() {
+        let uri = Url::from_str("file:///range.rs").unwrap();
+        let mut store = opened(&uri, "line 1\nline 2");
+
+        // The first change is valid, but the second points past the last line
+        let params = ranged_did_change_params(uri.as_str(), 2, &[(0, 0, 0, 4, "row"), (5, 0, 5, 1, "?")]);
+        let result = handle_did_change_notification_with_encoding(&params, &mut store, &PositionEncodingKind::UTF16);
+
+        assert!(matches!(result, Err(DidChangeError::Document(DocumentError::InvalidRange { .. }))), "Expected InvalidRange, got {:?}", result);
+        assert_eq!(store.text(&uri).unwrap(), "line 1\nline 2", "Changes should be applied all-or-nothing.");
+        assert_eq!(store.get(&uri).unwrap().version(), 1, "The version should not move forward.");
+    }
+
+    #[test]
+    fn test_change_for_unopened_document_is_reported() {
+        let mut store = DocumentStore::new();
+        let params = dummy_did_change_params("file:///closed.rs", "text");
+
+        let result = handle_did_change_notification_with_encoding(&params, &mut store, &PositionEncodingKind::UTF16);
+
+        assert_eq!(result, Err(DidChangeError::Document(DocumentError::NotOpen(Url::from_str("file:///closed.rs").unwrap()))));
+        assert!(store.is_empty(), "A change should not open a document.");
+    }
+
+    #[test]
+    fn test_full_and_ranged_changes_can_be_mixed() {
+        let mut store = DocumentStore::new();
+        let uri = Url::from_str("file:///mixed.rs").unwrap();
+        store.open(uri.clone(), "rust", 1, "old");
+
+        let params = json!({
+            "textDocument": {"uri": uri.as_str(), "version": 2},
+            "contentChanges": [
+                {"text": "fn a() {}"},
+                {"range": {"start": {"line": 0, "character": 3}, "end": {"line": 0, "character": 4}}, "text": "b"}
+            ]
+        });
+        handle_did_change_notification(&params, &mut store);
+
+        assert_eq!(store.text(&uri).unwrap(), "fn b() {}", "A ranged change should apply to the text set by the full change before it.");
+    }
 }
@@ -5,22 +5,22 @@
 // Your Task:
 // The function `handle_did_close_notification` takes:
 // - `params_value`: The `serde_json::Value` from the `params` field of a `textDocument/didClose` notification.
-// - `document_store`: A mutable `std::collections::HashMap<Url, String>` where the server stores document content.
+// - `document_store`: A mutable `DocumentStore` (see `common::document_store`) where the server stores open documents.
 // It should:
 // 1. Extract the `uri` from the `params_value`.
 //    - The `params_value` for `textDocument/didClose` typically looks like:
 //      `{"textDocument": {"uri": "file:///a.rs"}}`
-// 2. Remove the document from the `document_store` using the `uri` as the key.
+// 2. Close the document in the `document_store` with `DocumentStore::close`.
 // - If parsing `params_value` fails or required fields are missing, do nothing.
 
 use serde_json::Value;
 use lsp_types::Url;
-use std::collections::HashMap;
+use crate::common::document_store::DocumentStore;
 
-pub fn handle_did_close_notification(params_value: &Value, document_store: &mut HashMap<Url, String>) {
+pub fn handle_did_close_notification(params_value: &Value, document_store: &mut DocumentStore) {
     (|| {
         let uri = Url::parse(params_value.get("textDocument")?.get("uri")?.as_str()?).ok()?;
-        document_store.remove(&uri)
+        document_store.close(&uri)
     })();
 }
 
@@ -31,8 +31,8 @@ pub fn handle_did_close_notification(params_value: &Value, document_store: &mut
 mod tests {
     use super::handle_did_close_notification;
     use serde_json::{json, Value};
+    use crate::common::document_store::DocumentStore;
     use lsp_types::Url;
-    use std::collections::HashMap;
     use std::str::FromStr;
 
     // Helper to create dummy didClose params
@@ -46,12 +46,12 @@ mod tests {
 
     #[test]
     fn test_did_close_removes_document_from_store() {
-        let mut store = HashMap::new();
+        let mut store = DocumentStore::new();
         let uri = Url::from_str("file:///test.rs").unwrap();
         let content = "fn main() {}\n";
 
         // Simulate didOpen first
-        store.insert(uri.clone(), content.to_string());
+        store.open(uri.clone(), "rust", 1, content);
         assert!(store.contains_key(&uri), "Document should be in store initially.");
 
         let params = dummy_did_close_params(uri.as_str());
@@ -62,7 +62,7 @@ mod tests {
 
     #[test]
     fn test_did_close_with_non_existent_document() {
-        let mut store = HashMap::new();
+        let mut store = DocumentStore::new();
         let uri = Url::from_str("file:///non_existent.rs").unwrap();
 
         assert!(!store.contains_key(&uri), "Document should not be in store initially.");
@@ -75,9 +75,9 @@ mod tests {
 
     #[test]
     fn test_did_close_with_malformed_params() {
-        let mut store = HashMap::new();
+        let mut store = DocumentStore::new();
         let uri = Url::from_str("file:///malformed.rs").unwrap();
-        store.insert(uri.clone(), "initial".to_string());
+        store.open(uri.clone(), "rust", 1, "initial");
 
         let malformed_params = json!({
             "textDocument": {
@@ -92,9 +92,9 @@ mod tests {
 
     #[test]
     fn test_did_close_with_invalid_uri() {
-        let mut store = HashMap::new();
+        let mut store = DocumentStore::new();
         let uri = Url::from_str("file:///invalid.rs").unwrap();
-        store.insert(uri.clone(), "initial".to_string());
+        store.open(uri.clone(), "rust", 1, "initial");
 
         let invalid_uri_params = json!({
             "textDocument": {
@@ -6,7 +6,7 @@
 // The function `get_hover_info` takes:
 // - `file_uri`: The `Url` of the document.
 // - `position`: The `Position` where the hover request was made.
-// - `document_store`: The `HashMap` containing document content.
+// - `document_store`: The `DocumentStore` containing the open documents.
 // It should:
 // 1. Retrieve the content of `file_uri` from the `document_store`.
 // 2. Find the word at the given `position`.
@@ -15,16 +15,31 @@
 // 5. If the word is "struct", return a Hover with content "Keyword: Structure definition".
 // 6. For any other word or if the document is not found, return `None`.
 
-use lsp_types::{Hover, MarkupContent, MarkupKind, Position, Url};
-use std::collections::HashMap;
+use lsp_types::{Hover, MarkupContent, MarkupKind, Position, PositionEncodingKind, Url};
+use crate::common::document_store::DocumentStore;
 
-pub fn get_hover_info(file_uri: &Url, position: Position, document_store: &HashMap<Url, String>) -> Option<Hover> {
-    let (_, content) = document_store
-        .get(file_uri)?
+pub fn get_hover_info(file_uri: &Url, position: Position, document_store: &DocumentStore) -> Option<Hover> {
+    get_hover_info_with_encoding(file_uri, position, document_store, &PositionEncodingKind::UTF16)
+}
+
+// position.character を encoding の単位で数える版（get_hover_info は LSP のデフォルトの UTF-16）
+pub fn get_hover_info_with_encoding(
+    file_uri: &Url,
+    position: Position,
+    document_store: &DocumentStore,
+    encoding: &PositionEncodingKind,
+) -> Option<Hover> {
+    let document = document_store.get(file_uri)?;
+    let line_index = document.line_index(encoding);
+    let (_, content) = document
+        .text()
         .lines()
         .enumerate()
         .find(|(line_number, _)| *line_number == position.line as usize)?;
-    let remaining_line = content.get(position.character as usize - 1..)?;
+    // カーソルの1文字前から単語を読む（バイト単位で1つ戻ると日本語などの途中になるので、文字単位で戻る）
+    let cursor = line_index.byte_column(position.line, position.character)?;
+    let (word_start, _) = content.get(..cursor)?.char_indices().next_back()?;
+    let remaining_line = content.get(word_start..)?;
     let keyword_end = remaining_line.find(|c: char| !c.is_alphanumeric() && c != '_').unwrap_or(remaining_line.len());
     let keyword = &remaining_line[..keyword_end];
 
@@ -65,15 +80,15 @@ pub fn get_hover_info(file_uri: &Url, position: Position, document_store: &HashM
 
 #[cfg(test)]
 mod tests {
-    use super::get_hover_info;
-    use lsp_types::{MarkupContent, MarkupKind, Position, Url};
-    use std::collections::HashMap;
+    use super::{get_hover_info, get_hover_info_with_encoding};
+    use lsp_types::{MarkupContent, MarkupKind, Position, PositionEncodingKind, Url};
+    use crate::common::document_store::DocumentStore;
     use std::str::FromStr;
 
     // Helper to create a dummy document store
-    fn create_dummy_store(uri_str: &str, content: &str) -> HashMap<Url, String> {
-        let mut store = HashMap::new();
-        store.insert(Url::from_str(uri_str).unwrap(), content.to_string());
+    fn create_dummy_store(uri_str: &str, content: &str) -> DocumentStore {
+        let mut store = DocumentStore::new();
+        store.open(Url::from_str(uri_str).unwrap(), "rust", 1, content);
         store
     }
 
@@ -168,4 +183,21 @@ mod tests {
         let hover_col = get_hover_info(&uri, position_col_out_of_bounds, &store);
         assert!(hover_col.is_none(), "Should return None if column position is out of bounds.");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_hover_after_non_ascii_text() {
+        let uri = Url::from_str("file:///test.rs").unwrap();
+        // "/* 日本語 */ " is 10 UTF-16 code units but 16 bytes
+        let store = create_dummy_store(uri.as_str(), "/* 日本語 */ fn main() {}");
+
+        assert!(get_hover_info(&uri, Position::new(0, 11), &store).is_some(), "UTF-16 columns are the default");
+        assert!(
+            get_hover_info_with_encoding(&uri, Position::new(0, 17), &store, &PositionEncodingKind::UTF8).is_some(),
+            "UTF-8 columns count bytes"
+        );
+        assert!(
+            get_hover_info_with_encoding(&uri, Position::new(0, 11), &store, &PositionEncodingKind::UTF8).is_none(),
+            "Byte 11 is inside the Japanese text"
+        );
+    }
+}
@@ -6,7 +6,7 @@
 // The function `get_definition_location` takes:
 // - `file_uri`: The `Url` of the document where the request was made.
 // - `position`: The `Position` where the request was made.
-// - `document_store`: The `HashMap` containing document content.
+// - `document_store`: The `DocumentStore` containing the open documents.
 // It should:
 // 1. Retrieve the content of `file_uri` from the `document_store`.
 // 2. Find the word at the given `position`.
@@ -14,16 +14,31 @@
 //    For simplicity, assume "my_function" is always defined at line 0, character 0 in the same file.
 // 4. For any other word or if the document is not found, return `None`.
 
-use lsp_types::{Location, Position, Range, Url};
-use std::collections::HashMap;
+use lsp_types::{Location, Position, PositionEncodingKind, Range, Url};
+use crate::common::document_store::DocumentStore;
 
-pub fn get_definition_location(file_uri: &Url, position: Position, document_store: &HashMap<Url, String>) -> Option<Location> {
-    let (_, content) = document_store
-        .get(file_uri)?
+pub fn get_definition_location(file_uri: &Url, position: Position, document_store: &DocumentStore) -> Option<Location> {
+    get_definition_location_with_encoding(file_uri, position, document_store, &PositionEncodingKind::UTF16)
+}
+
+// position.character を encoding の単位で数える版（get_definition_location は UTF-16）
+pub fn get_definition_location_with_encoding(
+    file_uri: &Url,
+    position: Position,
+    document_store: &DocumentStore,
+    encoding: &PositionEncodingKind,
+) -> Option<Location> {
+    let document = document_store.get(file_uri)?;
+    let line_index = document.line_index(encoding);
+    let (_, content) = document
+        .text()
         .lines()
         .enumerate()
         .find(|(line_number, _)| *line_number == position.line as usize)?;
-    let remaining_line = content.get(position.character as usize - 1..)?;
+    // カーソルの1文字前から単語を読む
+    let cursor = line_index.byte_column(position.line, position.character)?;
+    let (word_start, _) = content.get(..cursor)?.char_indices().next_back()?;
+    let remaining_line = content.get(word_start..)?;
     let keyword_end = remaining_line.find(|c: char| !c.is_alphanumeric() && c != '_').unwrap_or(remaining_line.len());
     let keyword = &remaining_line[..keyword_end];
     
@@ -47,13 +62,13 @@ pub fn get_definition_location(file_uri: &Url, position: Position, document_stor
 mod tests {
     use super::get_definition_location;
     use lsp_types::{Location, Position, Range, Url};
-    use std::collections::HashMap;
+    use crate::common::document_store::DocumentStore;
     use std::str::FromStr;
 
     // Helper to create a dummy document store
-    fn create_dummy_store(uri_str: &str, content: &str) -> HashMap<Url, String> {
-        let mut store = HashMap::new();
-        store.insert(Url::from_str(uri_str).unwrap(), content.to_string());
+    fn create_dummy_store(uri_str: &str, content: &str) -> DocumentStore {
+        let mut store = DocumentStore::new();
+        store.open(Url::from_str(uri_str).unwrap(), "rust", 1, content);
         store
     }
 
@@ -6,7 +6,7 @@
 // The function `find_references` takes:
 // - `file_uri`: The `Url` of the document where the request was made.
 // - `position`: The `Position` where the request was made.
-// - `document_store`: The `HashMap` containing document content.
+// - `document_store`: The `DocumentStore` containing the open documents.
 // It should:
 // 1. Retrieve the content of `file_uri` from the `document_store`.
 // 2. Find the word at the given `position`.
@@ -15,18 +15,29 @@
 // 4. Return a `Vec<Location>` for all found references.
 // 5. For any other word, or if the document is not found, return an empty `Vec<Location>`.
 
-use lsp_types::{Location, Position, Range, Url};
-use std::collections::HashMap;
+use lsp_types::{Location, Position, PositionEncodingKind, Range, Url};
+use crate::common::document_store::DocumentStore;
 
-pub fn find_references(file_uri: &Url, position: Position, document_store: &HashMap<Url, String>) -> Vec<Location> {
+pub fn find_references(file_uri: &Url, position: Position, document_store: &DocumentStore) -> Vec<Location> {
+    find_references_with_encoding(file_uri, position, document_store, &PositionEncodingKind::UTF16)
+}
+
+// Position の列を encoding の単位で数える版（find_references は UTF-16）
+pub fn find_references_with_encoding(
+    file_uri: &Url,
+    position: Position,
+    document_store: &DocumentStore,
+    encoding: &PositionEncodingKind,
+) -> Vec<Location> {
     let locations: Option<Vec<Location>> = (|| {
+        let line_index = document_store.get(file_uri)?.line_index(encoding);
         let (_, content)= document_store
-            .get(file_uri)?
+            .text(file_uri)?
             .lines()
             .enumerate()
             .find(|(line_number, _)| *line_number == position.line as usize)?;
 
-        let remaining_line = content.get(position.character as usize..)?;
+        let remaining_line = content.get(line_index.byte_column(position.line, position.character)?..)?;
         let keyword_end = remaining_line.find(|c: char| !c.is_alphanumeric() && c != '_').unwrap_or(remaining_line.len());
         let keyword = &remaining_line[..keyword_end];
 
@@ -34,17 +45,18 @@ pub fn find_references(file_uri: &Url, position: Position, document_store: &Hash
         if keyword == "my_variable" {
             return Some(
                 document_store
-                .get(file_uri)?
+                .text(file_uri)?
                 .lines()
                     .enumerate()
                     .filter_map(|(line_number, line)| {
-                        if let Some(start_idx) = line.find("my_variable").map(|byte_start| line[..byte_start].chars().count()) {
+                        if let Some(byte_start) = line.find("my_variable") {
+                            let line_number = line_number as u32;
                             return Some(
                                 Location::new(
                                     file_uri.clone(),
                                     Range::new(
-                                        Position::new(line_number as u32, start_idx as u32),
-                                        Position::new(line_number as u32, start_idx as u32 + keyword.len() as u32),
+                                        Position::new(line_number, line_index.character(line_number, byte_start)),
+                                        Position::new(line_number, line_index.character(line_number, byte_start + keyword.len())),
                                     ),
                                 )
                             )
@@ -65,15 +77,15 @@ pub fn find_references(file_uri: &Url, position: Position, document_store: &Hash
 
 #[cfg(test)]
 mod tests {
-    use super::find_references;
-    use lsp_types::{Location, Position, Range, Url};
-    use std::collections::HashMap;
+    use super::{find_references, find_references_with_encoding};
+    use lsp_types::{Location, Position, PositionEncodingKind, Range, Url};
+    use crate::common::document_store::DocumentStore;
     use std::str::FromStr;
 
     // Helper to create a dummy document store
-    fn create_dummy_store(uri_str: &str, content: &str) -> HashMap<Url, String> {
-        let mut store = HashMap::new();
-        store.insert(Url::from_str(uri_str).unwrap(), content.to_string());
+    fn create_dummy_store(uri_str: &str, content: &str) -> DocumentStore {
+        let mut store = DocumentStore::new();
+        store.open(Url::from_str(uri_str).unwrap(), "rust", 1, content);
         store
     }
 
@@ -144,4 +156,16 @@ mod tests {
         let references = find_references(&uri, position, &store);
         assert!(references.is_empty(), "Should return empty if position is out of bounds.");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_find_references_after_non_ascii_text() {
+        let uri = Url::from_str("file:///test.rs").unwrap();
+        let store = create_dummy_store(uri.as_str(), "let my_variable = 1; // 🦀\nlet あ = my_variable;");
+
+        let references = find_references(&uri, Position::new(0, 4), &store);
+        assert_eq!(references[1].range, Range::new(Position::new(1, 8), Position::new(1, 19)), "\"あ\" is one UTF-16 code unit");
+
+        let references = find_references_with_encoding(&uri, Position::new(0, 4), &store, &PositionEncodingKind::UTF8);
+        assert_eq!(references[1].range, Range::new(Position::new(1, 10), Position::new(1, 21)), "\"あ\" is three bytes");
+    }
+}
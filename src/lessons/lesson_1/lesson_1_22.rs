@@ -5,7 +5,7 @@
 // Your Task:
 // The function `get_document_symbols` takes:
 // - `file_uri`: The `Url` of the document.
-// - `document_store`: The `HashMap` containing document content.
+// - `document_store`: The `DocumentStore` containing the open documents.
 // It should:
 // 1. Retrieve the content of `file_uri` from the `document_store`.
 // 2. Analyze the content line by line.
@@ -19,14 +19,24 @@
 // 5. Return a `Vec<DocumentSymbol>` containing all found symbols.
 // 6. If the document is not found, return an empty `Vec<DocumentSymbol>`.
 
-use lsp_types::{DocumentSymbol, Range, SymbolKind, Url, Position};
-use std::collections::HashMap;
+use lsp_types::{DocumentSymbol, Range, SymbolKind, Url, Position, PositionEncodingKind};
+use crate::common::document_store::DocumentStore;
 
-pub fn get_document_symbols(file_uri: &Url, document_store: &HashMap<Url, String>) -> Vec<DocumentSymbol> {
+pub fn get_document_symbols(file_uri: &Url, document_store: &DocumentStore) -> Vec<DocumentSymbol> {
+    get_document_symbols_with_encoding(file_uri, document_store, &PositionEncodingKind::UTF16)
+}
+
+// Range の列を encoding の単位で数える版（get_document_symbols は UTF-16）
+pub fn get_document_symbols_with_encoding(
+    file_uri: &Url,
+    document_store: &DocumentStore,
+    encoding: &PositionEncodingKind,
+) -> Vec<DocumentSymbol> {
     let result: Option<Vec<DocumentSymbol>> = (|| {
+        let line_index = document_store.get(file_uri)?.line_index(encoding);
         Some(
             document_store
-            .get(file_uri)?
+            .text(file_uri)?
             .lines()
             .enumerate()
             .filter_map(|(line_number, line)| {
@@ -44,11 +54,11 @@ pub fn get_document_symbols(file_uri: &Url, document_store: &HashMap<Url, String
                         deprecated: None,
                         range: Range::new(
                             Position::new(line_number as u32, 0),
-                            Position::new(line_number as u32, section_length as u32),
+                            Position::new(line_number as u32, line_index.character(line_number as u32, section_length)),
                         ),
                         selection_range: Range::new(
                             Position::new(line_number as u32, "fn ".len() as u32),
-                            Position::new(line_number as u32, "fn ".len() as u32 + keyword.len() as u32),
+                            Position::new(line_number as u32, line_index.character(line_number as u32, "fn ".len() + keyword.len())),
                         ),
                         children: None,
                     });
@@ -69,13 +79,13 @@ pub fn get_document_symbols(file_uri: &Url, document_store: &HashMap<Url, String
 mod tests {
     use super::get_document_symbols;
     use lsp_types::{Range, SymbolKind, Url, Position};
-    use std::collections::HashMap;
+    use crate::common::document_store::DocumentStore;
     use std::str::FromStr;
 
     // Helper to create a dummy document store
-    fn create_dummy_store(uri_str: &str, content: &str) -> HashMap<Url, String> {
-        let mut store = HashMap::new();
-        store.insert(Url::from_str(uri_str).unwrap(), content.to_string());
+    fn create_dummy_store(uri_str: &str, content: &str) -> DocumentStore {
+        let mut store = DocumentStore::new();
+        store.open(Url::from_str(uri_str).unwrap(), "rust", 1, content);
         store
     }
 
@@ -5,7 +5,7 @@
 // Your Task:
 // The function `format_document` takes:
 // - `file_uri`: The `Url` of the document.
-// - `document_store`: The `HashMap` containing document content.
+// - `document_store`: The `DocumentStore` containing the open documents.
 // It should:
 // 1. Retrieve the content of `file_uri` from the `document_store`.
 // 2. For each line in the document, if it has leading or trailing whitespace,
@@ -15,13 +15,23 @@
 // 3. Return a `Vec<TextEdit>` containing all necessary edits to format the document.
 // 4. If the document is not found, return an `empty Vec<TextEdit>`.
 
-use lsp_types::{Position, Range, TextEdit, Url};
-use std::collections::HashMap;
+use lsp_types::{Position, PositionEncodingKind, Range, TextEdit, Url};
+use crate::common::document_store::DocumentStore;
 
-pub fn format_document(file_uri: &Url, document_store: &HashMap<Url, String>) -> Vec<TextEdit> {
+pub fn format_document(file_uri: &Url, document_store: &DocumentStore) -> Vec<TextEdit> {
+    format_document_with_encoding(file_uri, document_store, &PositionEncodingKind::UTF16)
+}
+
+// Range の列を encoding の単位で数える版（format_document は UTF-16）
+pub fn format_document_with_encoding(
+    file_uri: &Url,
+    document_store: &DocumentStore,
+    encoding: &PositionEncodingKind,
+) -> Vec<TextEdit> {
     let result: Option<Vec<TextEdit>> = (|| {
+        let line_index = document_store.get(file_uri)?.line_index(encoding);
         Some(document_store
-            .get(file_uri)?
+            .text(file_uri)?
             .lines()
             .enumerate()
             .filter_map(|(line_number, line)| {
@@ -30,7 +40,7 @@ pub fn format_document(file_uri: &Url, document_store: &HashMap<Url, String>) ->
                     return Some(TextEdit {
                         range: Range::new(
                             Position::new(line_number as u32, 0),
-                            Position::new(line_number as u32, line.len() as u32),
+                            Position::new(line_number as u32, line_index.character(line_number as u32, line.len())),
                         ),
                         new_text: trimmed.to_string(),
                     });
@@ -50,13 +60,13 @@ pub fn format_document(file_uri: &Url, document_store: &HashMap<Url, String>) ->
 mod tests {
     use super::format_document;
     use lsp_types::{Position, Range, TextEdit, Url};
-    use std::collections::HashMap;
+    use crate::common::document_store::DocumentStore;
     use std::str::FromStr;
 
     // Helper to create a dummy document store
-    fn create_dummy_store(uri_str: &str, content: &str) -> HashMap<Url, String> {
-        let mut store = HashMap::new();
-        store.insert(Url::from_str(uri_str).unwrap(), content.to_string());
+    fn create_dummy_store(uri_str: &str, content: &str) -> DocumentStore {
+        let mut store = DocumentStore::new();
+        store.open(Url::from_str(uri_str).unwrap(), "rust", 1, content);
         store
     }
 
@@ -7,7 +7,7 @@
 // - `file_uri`: The `Url` of the document where the request was made.
 // - `position`: The `Position` where the request was made.
 // - `new_name`: The new name for the symbol.
-// - `document_store`: The `HashMap` containing document content.
+// - `document_store`: The `DocumentStore` containing the open documents.
 // It should:
 // 1. Retrieve the content of `file_uri` from the `document_store`.
 // 2. Find the word at the given `position`.
@@ -17,16 +17,29 @@
 //    - Each `TextEdit` should replace the range of "my_variable" with `new_name`.
 // 5. For any other word, or if the document is not found, return `None`.
 
-use lsp_types::{Position, Range, TextEdit, Url, WorkspaceEdit};
+use lsp_types::{Position, PositionEncodingKind, Range, TextEdit, Url, WorkspaceEdit};
 use std::collections::HashMap;
+use crate::common::document_store::DocumentStore;
 
-pub fn prepare_rename(file_uri: &Url, position: Position, new_name: String, document_store: &HashMap<Url, String>) -> Option<WorkspaceEdit> {
+pub fn prepare_rename(file_uri: &Url, position: Position, new_name: String, document_store: &DocumentStore) -> Option<WorkspaceEdit> {
+    prepare_rename_with_encoding(file_uri, position, new_name, document_store, &PositionEncodingKind::UTF16)
+}
+
+// Position の列を encoding の単位で数える版（prepare_rename は UTF-16）
+pub fn prepare_rename_with_encoding(
+    file_uri: &Url,
+    position: Position,
+    new_name: String,
+    document_store: &DocumentStore,
+    encoding: &PositionEncodingKind,
+) -> Option<WorkspaceEdit> {
+    let line_index = document_store.get(file_uri)?.line_index(encoding);
     let (_, content) = document_store
-        .get(file_uri)?
+        .text(file_uri)?
         .lines()
         .enumerate()
         .find(|(line_number, _)| *line_number == position.line as usize)?;
-    let remaining_line = content.get(position.character as usize..)?;
+    let remaining_line = content.get(line_index.byte_column(position.line, position.character)?..)?;
     let keyword_end = remaining_line.find(|c: char| !c.is_alphanumeric() && c != '_').unwrap_or(remaining_line.len());
     let keyword = &remaining_line[..keyword_end];
 
@@ -36,7 +49,7 @@ pub fn prepare_rename(file_uri: &Url, position: Position, new_name: String, docu
 
     let mut changes_hash = HashMap::new();
     let changes = document_store
-        .get(file_uri)?
+        .text(file_uri)?
         .lines()
         .enumerate()
         .filter_map(|(line_number, line)| {
@@ -49,8 +62,8 @@ pub fn prepare_rename(file_uri: &Url, position: Position, new_name: String, docu
                 .map(|starts_at|
                     TextEdit::new(
                         Range::new(
-                            Position::new(line_number as u32, starts_at as u32),
-                            Position::new(line_number as u32, starts_at as u32 + keyword.len() as u32),
+                            Position::new(line_number as u32, line_index.character(line_number as u32, starts_at)),
+                            Position::new(line_number as u32, line_index.character(line_number as u32, starts_at + keyword.len())),
                         ),
                         new_name.clone(),
                     ),
@@ -70,12 +83,13 @@ mod tests {
     use super::prepare_rename;
     use lsp_types::{Position, Range, TextEdit, Url};
     use std::collections::HashMap;
+    use crate::common::document_store::DocumentStore;
     use std::str::FromStr;
 
     // Helper to create a dummy document store
-    fn create_dummy_store(uri_str: &str, content: &str) -> HashMap<Url, String> {
-        let mut store = HashMap::new();
-        store.insert(Url::from_str(uri_str).unwrap(), content.to_string());
+    fn create_dummy_store(uri_str: &str, content: &str) -> DocumentStore {
+        let mut store = DocumentStore::new();
+        store.open(Url::from_str(uri_str).unwrap(), "rust", 1, content);
         store
     }
 
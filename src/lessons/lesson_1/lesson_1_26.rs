@@ -6,7 +6,7 @@
 // The function `get_document_highlights` takes:
 // - `file_uri`: The `Url` of the document where the request was made.
 // - `position`: The `Position` where the request was made.
-// - `document_store`: The `HashMap` containing document content.
+// - `document_store`: The `DocumentStore` containing the open documents.
 // It should:
 // 1. Retrieve the content of `file_uri` from the `document_store`.
 // 2. Find the word at the given `position`.
@@ -16,17 +16,28 @@
 //    - The `kind` can be `DocumentHighlightKind::Text` for simplicity.
 // 5. For any other word, or if the document is not found, return an empty `Vec<DocumentHighlight>`.
 
-use lsp_types::{DocumentHighlight, DocumentHighlightKind, Position, Range, Url};
-use std::collections::HashMap;
+use lsp_types::{DocumentHighlight, DocumentHighlightKind, Position, PositionEncodingKind, Range, Url};
+use crate::common::document_store::DocumentStore;
 
-pub fn get_document_highlights(file_uri: &Url, position: Position, document_store: &HashMap<Url, String>) -> Vec<DocumentHighlight> {
+pub fn get_document_highlights(file_uri: &Url, position: Position, document_store: &DocumentStore) -> Vec<DocumentHighlight> {
+    get_document_highlights_with_encoding(file_uri, position, document_store, &PositionEncodingKind::UTF16)
+}
+
+// Position の列を encoding の単位で数える版（get_document_highlights は UTF-16）
+pub fn get_document_highlights_with_encoding(
+    file_uri: &Url,
+    position: Position,
+    document_store: &DocumentStore,
+    encoding: &PositionEncodingKind,
+) -> Vec<DocumentHighlight> {
     let result: Option<Vec<DocumentHighlight>> = (|| {
+        let line_index = document_store.get(file_uri)?.line_index(encoding);
         let (_, content) = document_store
-            .get(file_uri)?
+            .text(file_uri)?
             .lines()
             .enumerate()
             .find(|(line_number, _)| *line_number as u32 == position.line)?;
-        let remaining_line = content.get(position.character as usize..)?;
+        let remaining_line = content.get(line_index.byte_column(position.line, position.character)?..)?;
         let keyword_end = remaining_line.find(|c: char| !c.is_alphanumeric() && c != '_').unwrap_or(remaining_line.len());
         let keyword = &remaining_line[..keyword_end];
 
@@ -35,7 +46,7 @@ pub fn get_document_highlights(file_uri: &Url, position: Position, document_stor
         }
 
         Some(document_store
-            .get(file_uri)?
+            .text(file_uri)?
             .lines()
             .enumerate()
             .filter_map(|(line_number, line)| {
@@ -48,8 +59,8 @@ pub fn get_document_highlights(file_uri: &Url, position: Position, document_stor
                     .map(|starts_at|
                              DocumentHighlight {
                                  range: Range::new(
-                                     Position::new(line_number as u32, starts_at as u32),
-                                     Position::new(line_number as u32, starts_at as u32 + keyword.len() as u32),
+                                     Position::new(line_number as u32, line_index.character(line_number as u32, starts_at)),
+                                     Position::new(line_number as u32, line_index.character(line_number as u32, starts_at + keyword.len())),
                                  ),
                                  kind: Some(DocumentHighlightKind::TEXT),
                              },
@@ -68,13 +79,13 @@ pub fn get_document_highlights(file_uri: &Url, position: Position, document_stor
 mod tests {
     use super::get_document_highlights;
     use lsp_types::{DocumentHighlight, DocumentHighlightKind, Position, Range, Url};
-    use std::collections::HashMap;
+    use crate::common::document_store::DocumentStore;
     use std::str::FromStr;
 
     // Helper to create a dummy document store
-    fn create_dummy_store(uri_str: &str, content: &str) -> HashMap<Url, String> {
-        let mut store = HashMap::new();
-        store.insert(Url::from_str(uri_str).unwrap(), content.to_string());
+    fn create_dummy_store(uri_str: &str, content: &str) -> DocumentStore {
+        let mut store = DocumentStore::new();
+        store.open(Url::from_str(uri_str).unwrap(), "rust", 1, content);
         store
     }
 
@@ -6,7 +6,7 @@
 // The function `get_inlay_hints` takes:
 // - `file_uri`: The `Url` of the document where the request was made.
 // - `range`: The `Range` for which inlay hints are requested.
-// - `document_store`: The `HashMap` containing document content.
+// - `document_store`: The `DocumentStore` containing the open documents.
 // It should:
 // 1. Retrieve the content of `file_uri` from the `document_store`.
 // 2. Look for variable declarations like "let variable_name = value;" within the given range.
@@ -21,10 +21,20 @@
 // 5. Return a `Vec<InlayHint>` containing all found hints.
 // 6. If the document is not found, return an empty `Vec<InlayHint>`.
 
-use lsp_types::{InlayHint, InlayHintKind, InlayHintLabel, Position, Range, Url};
-use std::collections::HashMap;
+use lsp_types::{InlayHint, InlayHintKind, InlayHintLabel, Position, PositionEncodingKind, Range, Url};
+use crate::common::document_store::DocumentStore;
 
-pub fn get_inlay_hints(file_uri: &Url, range: Range, document_store: &HashMap<Url, String>) -> Vec<InlayHint> {
+pub fn get_inlay_hints(file_uri: &Url, range: Range, document_store: &DocumentStore) -> Vec<InlayHint> {
+    get_inlay_hints_with_encoding(file_uri, range, document_store, &PositionEncodingKind::UTF16)
+}
+
+// InlayHint の位置を encoding の単位で数える版（get_inlay_hints は UTF-16）
+pub fn get_inlay_hints_with_encoding(
+    file_uri: &Url,
+    range: Range,
+    document_store: &DocumentStore,
+    encoding: &PositionEncodingKind,
+) -> Vec<InlayHint> {
     // Hint:
     // 1. Get document content from document_store
     // 2. Iterate through lines within the given range
@@ -33,8 +43,9 @@ pub fn get_inlay_hints(file_uri: &Url, range: Range, document_store: &HashMap<Ur
     // 5. Create InlayHint with position after variable name and type label
 
     let result: Option<Vec<InlayHint>> = (|| {
+        let line_index = document_store.get(file_uri)?.line_index(encoding);
         Some(document_store
-            .get(file_uri)?
+            .text(file_uri)?
             .lines()
             .enumerate()
             .filter_map(|(line_number, line)| {
@@ -65,7 +76,7 @@ pub fn get_inlay_hints(file_uri: &Url, range: Range, document_store: &HashMap<Ur
 
                     return Some(InlayHint {
                         // 変数名の直後の正確な位置を計算
-                        position: Position::new(line_number as u32, (4 + var_name.len()) as u32),
+                        position: Position::new(line_number as u32, line_index.character(line_number as u32, 4 + var_name.len())),
                         label: InlayHintLabel::String(label.to_string()),
                         kind: Some(InlayHintKind::TYPE),
                         text_edits: None,
@@ -91,13 +102,13 @@ pub fn get_inlay_hints(file_uri: &Url, range: Range, document_store: &HashMap<Ur
 mod tests {
     use super::get_inlay_hints;
     use lsp_types::{InlayHintKind, InlayHintLabel, Position, Range, Url};
-    use std::collections::HashMap;
+    use crate::common::document_store::DocumentStore;
     use std::str::FromStr;
 
     // Helper to create a dummy document store
-    fn create_dummy_store(uri_str: &str, content: &str) -> HashMap<Url, String> {
-        let mut store = HashMap::new();
-        store.insert(Url::from_str(uri_str).unwrap(), content.to_string());
+    fn create_dummy_store(uri_str: &str, content: &str) -> DocumentStore {
+        let mut store = DocumentStore::new();
+        store.open(Url::from_str(uri_str).unwrap(), "rust", 1, content);
         store
     }
 
@@ -6,7 +6,7 @@
 // The function `get_completion_items` takes:
 // - `file_uri`: The `Url` of the document where completion was requested.
 // - `position`: The `Position` where completion was triggered.
-// - `document_store`: The `HashMap` containing document content.
+// - `document_store`: The `DocumentStore` containing the open documents.
 // It should:
 // 1. Retrieve the content of `file_uri` from the `document_store`.
 // 2. Find the word being typed at the given `position` (partial word before cursor).
@@ -23,8 +23,37 @@
 // 5. Return a `Vec<CompletionItem>` containing all matching suggestions.
 // 6. If no partial word is found or document doesn't exist, return empty Vec.
 
-use lsp_types::{CompletionItem, CompletionItemKind, Position, Url};
-use std::collections::HashMap;
+// Going further:
+// Editors don't only complete by prefix: typing "fl" should still offer "false".
+// `get_ranked_completion_items_with_encoding` matches the typed word as a fuzzy subsequence of
+// every keyword and type, and ranks the matches with `sortText` (word starts and consecutive
+// characters score higher). Each item carries a `filterText` and a `textEdit` that replaces the
+// typed word, so the client filters and inserts exactly what the server meant.
+// `detail` and `documentation` are left out of the list and filled in by `resolve_completion_item`
+// (`completionItem/resolve`), which the client calls only for the item the user is looking at.
+
+use lsp_types::{
+    CompletionItem, CompletionItemKind, CompletionTextEdit, Documentation, MarkupContent, MarkupKind, Position,
+    PositionEncodingKind, Range, TextEdit, Url,
+};
+use crate::common::document_store::DocumentStore;
+
+// 補完の候補になるキーワードと型（前方一致の補完もあいまい一致の補完も、この表から選ぶ）
+const CANDIDATES: &[(&str, CompletionItemKind)] = &[
+    ("let", CompletionItemKind::KEYWORD),
+    ("loop", CompletionItemKind::KEYWORD),
+    ("fn", CompletionItemKind::KEYWORD),
+    ("for", CompletionItemKind::KEYWORD),
+    ("false", CompletionItemKind::KEYWORD),
+    ("if", CompletionItemKind::KEYWORD),
+    ("impl", CompletionItemKind::KEYWORD),
+    ("struct", CompletionItemKind::KEYWORD),
+    ("true", CompletionItemKind::KEYWORD),
+    ("type", CompletionItemKind::KEYWORD),
+    ("i32", CompletionItemKind::TYPE_PARAMETER),
+    ("String", CompletionItemKind::TYPE_PARAMETER),
+    ("str", CompletionItemKind::TYPE_PARAMETER),
+];
 
 fn new_completion_item(label: &str, kind: CompletionItemKind) -> CompletionItem {
     CompletionItem {
@@ -34,34 +63,39 @@ fn new_completion_item(label: &str, kind: CompletionItemKind) -> CompletionItem
     }
 }
 
-fn get_keyword_completions(prefix: &str) -> Vec<CompletionItem> {
-    let keywords = match prefix {
-        p if p.starts_with("l") => vec!["let", "loop"],
-        p if p.starts_with("f") => vec!["fn", "for", "false"],
-        p if p.starts_with("i") => vec!["if", "impl"],
-        p if p.starts_with("s") => vec!["struct"],
-        p if p.starts_with("t") => vec!["true", "type"],
-        _ => vec![]
+// 表の中から、kind の候補で prefix と最初の文字が同じもの（大文字・小文字は区別しない）
+fn get_completions_of_kind(prefix: &str, kind: CompletionItemKind) -> Vec<CompletionItem> {
+    let Some(first) = prefix.chars().next() else {
+        return vec![];
     };
-    
-    keywords.into_iter()
-        .map(|keyword| new_completion_item(keyword, CompletionItemKind::KEYWORD))
+
+    CANDIDATES.iter()
+        .filter(|(label, candidate_kind)| {
+            *candidate_kind == kind && label.chars().next().is_some_and(|c| c.eq_ignore_ascii_case(&first))
+        })
+        .map(|(label, kind)| new_completion_item(label, *kind))
         .collect()
 }
 
+fn get_keyword_completions(prefix: &str) -> Vec<CompletionItem> {
+    get_completions_of_kind(prefix, CompletionItemKind::KEYWORD)
+}
+
 fn get_type_completions(prefix: &str) -> Vec<CompletionItem> {
-    let types = match prefix {
-        p if p.starts_with("i") => vec!["i32"],
-        p if p.starts_with("s") => vec!["String", "str"],
-        _ => vec![]
-    };
-    
-    types.into_iter()
-        .map(|type_name| new_completion_item(type_name, CompletionItemKind::TYPE_PARAMETER))
-        .collect()
+    get_completions_of_kind(prefix, CompletionItemKind::TYPE_PARAMETER)
+}
+
+pub fn get_completion_items(file_uri: &Url, position: Position, document_store: &DocumentStore) -> Vec<CompletionItem> {
+    get_completion_items_with_encoding(file_uri, position, document_store, &PositionEncodingKind::UTF16)
 }
 
-pub fn get_completion_items(file_uri: &Url, position: Position, document_store: &HashMap<Url, String>) -> Vec<CompletionItem> {
+// position.character を encoding の単位で数える版（get_completion_items は UTF-16）
+pub fn get_completion_items_with_encoding(
+    file_uri: &Url,
+    position: Position,
+    document_store: &DocumentStore,
+    encoding: &PositionEncodingKind,
+) -> Vec<CompletionItem> {
     // Hint:
     // 1. Get document content from document_store
     // 2. Extract the line at the given position
@@ -70,7 +104,7 @@ pub fn get_completion_items(file_uri: &Url, position: Position, document_store:
     // 5. Create CompletionItem objects for matching suggestions
     let content: Option<&str> = (|| {
         let (_, content) = document_store
-            .get(file_uri)?
+            .text(file_uri)?
             .lines()
             .enumerate()
             .find(|(line_number, _)| *line_number == position.line as usize )?;
@@ -78,20 +112,9 @@ pub fn get_completion_items(file_uri: &Url, position: Position, document_store:
     })();
 
     let completion_items: Option<Vec<CompletionItem>> = (|| {
-        let before_cursor = content?.get(..position.character as usize)?;
-        let mut start = position.character as usize;
-
-        // 後ろから英数字・アンダースコアを辿る
-        for (i, ch) in before_cursor.char_indices().rev() {
-            if ch.is_alphanumeric() || ch == '_' {
-                start = i;
-            } else {
-                break;
-            }
-        }
-
-        let partial = if start < position.character as usize {
-            Some(before_cursor[start..].to_string())
+        let (start, cursor) = typed_word(content?, document_store, file_uri, position, encoding)?;
+        let partial = if start < cursor {
+            Some(content?[start..cursor].to_string())
         } else {
             None // 部分単語が見つからない
         };
@@ -111,20 +134,148 @@ pub fn get_completion_items(file_uri: &Url, position: Position, document_store:
     completion_items.unwrap_or_default()
 }
 
+// カーソルの直前で入力中の単語の範囲（行の先頭からのバイトオフセットで start..cursor）
+fn typed_word(
+    line: &str,
+    document_store: &DocumentStore,
+    file_uri: &Url,
+    position: Position,
+    encoding: &PositionEncodingKind,
+) -> Option<(usize, usize)> {
+    // カーソル位置（行の先頭からのバイトオフセット）
+    let cursor = document_store.get(file_uri)?.line_index(encoding).byte_column(position.line, position.character)?;
+    let before_cursor = line.get(..cursor)?;
+    let mut start = cursor;
+
+    // 後ろから英数字・アンダースコアを辿る
+    for (i, ch) in before_cursor.char_indices().rev() {
+        if ch.is_alphanumeric() || ch == '_' {
+            start = i;
+        } else {
+            break;
+        }
+    }
+    Some((start, cursor))
+}
+
+// 入力中の単語をあいまい一致（部分列）で探し、スコアの高い順に sortText を付けた候補を返す
+// 各候補の textEdit は入力中の単語を置き換える。detail と documentation は resolve_completion_item で埋める
+pub fn get_ranked_completion_items_with_encoding(
+    file_uri: &Url,
+    position: Position,
+    document_store: &DocumentStore,
+    encoding: &PositionEncodingKind,
+) -> Vec<CompletionItem> {
+    let completion_items: Option<Vec<CompletionItem>> = (|| {
+        let document = document_store.get(file_uri)?;
+        let line = document.text().lines().nth(position.line as usize)?;
+        let (start, cursor) = typed_word(line, document_store, file_uri, position, encoding)?;
+        let typed = &line[start..cursor];
+        if typed.is_empty() {
+            return None;
+        }
+
+        let line_index = document.line_index(encoding);
+        let range = Range::new(
+            Position::new(position.line, line_index.character(position.line, start)),
+            Position::new(position.line, line_index.character(position.line, cursor)),
+        );
+
+        let mut matches: Vec<(u32, &str, CompletionItemKind)> = CANDIDATES
+            .iter()
+            .filter_map(|(label, kind)| Some((fuzzy_score(typed, label)?, *label, *kind)))
+            .collect();
+        // スコアの高い順、同じなら短い順・名前順
+        matches.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.len().cmp(&b.1.len())).then(a.1.cmp(b.1)));
+
+        let items = matches
+            .into_iter()
+            .enumerate()
+            .map(|(rank, (_, label, kind))| CompletionItem {
+                sort_text: Some(format!("{:04}", rank)),
+                filter_text: Some(label.to_string()),
+                text_edit: Some(CompletionTextEdit::Edit(TextEdit::new(range, label.to_string()))),
+                ..new_completion_item(label, kind)
+            })
+            .collect();
+        Some(items)
+    })();
+
+    completion_items.unwrap_or_default()
+}
+
+// typed の文字が candidate に順番どおり現れれば（大文字・小文字は区別しない）スコアを返す
+// - 1文字一致するごとに 1 点
+// - 候補の先頭で一致すれば 8 点、直前の文字に続けて一致すれば 4 点、大文字・小文字まで同じなら 1 点を足す
+fn fuzzy_score(typed: &str, candidate: &str) -> Option<u32> {
+    let mut candidate_chars = candidate.chars().enumerate();
+    let mut previous: Option<usize> = None;
+    let mut score = 0;
+
+    for typed_char in typed.chars() {
+        let (index, candidate_char) = candidate_chars
+            .by_ref()
+            .find(|(_, candidate_char)| candidate_char.to_lowercase().eq(typed_char.to_lowercase()))?;
+        score += 1;
+        if index == 0 {
+            score += 8;
+        } else if previous == Some(index - 1) {
+            score += 4;
+        }
+        if candidate_char == typed_char {
+            score += 1;
+        }
+        previous = Some(index);
+    }
+    Some(score)
+}
+
+// completionItem/resolve: 一覧では省いた detail と documentation（Markdown）を埋める
+// 知らない候補はそのまま返す
+pub fn resolve_completion_item(item: CompletionItem) -> CompletionItem {
+    let (detail, documentation) = match item.label.as_str() {
+        "false" => ("keyword", "The boolean value `false`."),
+        "fn" => ("keyword", "Declares a function.\n\n```rust\nfn name(arg: Type) -> Return {\n    body\n}\n```"),
+        "for" => ("keyword", "Loops over the items of an iterator.\n\n```rust\nfor item in iter {\n    body\n}\n```"),
+        "if" => ("keyword", "Runs a block only when a condition holds.\n\n```rust\nif condition {\n    body\n}\n```"),
+        "impl" => ("keyword", "Implements methods or a trait for a type."),
+        "let" => ("keyword", "Binds a value to a name.\n\n```rust\nlet name = value;\n```"),
+        "loop" => ("keyword", "Repeats a block until `break`."),
+        "struct" => ("keyword", "Declares a structure with named fields."),
+        "true" => ("keyword", "The boolean value `true`."),
+        "type" => ("keyword", "Declares a type alias.\n\n```rust\ntype Name = Type;\n```"),
+        "i32" => ("primitive type", "The 32-bit signed integer type."),
+        "str" => ("primitive type", "String slices, usually seen as `&str`."),
+        "String" => ("struct String", "A growable, owned UTF-8 string."),
+        _ => return item,
+    };
+
+    CompletionItem {
+        detail: item.detail.or_else(|| Some(detail.to_string())),
+        documentation: item.documentation.or_else(|| {
+            Some(Documentation::MarkupContent(MarkupContent { kind: MarkupKind::Markdown, value: documentation.to_string() }))
+        }),
+        ..item
+    }
+}
+
 
 // --- Tests --- //
 
 #[cfg(test)]
 mod tests {
-    use super::get_completion_items;
-    use lsp_types::{CompletionItemKind, Position, Url};
-    use std::collections::HashMap;
+    use super::{get_completion_items, get_ranked_completion_items_with_encoding, resolve_completion_item};
+    use lsp_types::{
+        CompletionItem, CompletionItemKind, CompletionTextEdit, Documentation, Position, PositionEncodingKind, Range,
+        TextEdit, Url,
+    };
+    use crate::common::document_store::DocumentStore;
     use std::str::FromStr;
 
     // Helper to create a dummy document store
-    fn create_dummy_store(uri_str: &str, content: &str) -> HashMap<Url, String> {
-        let mut store = HashMap::new();
-        store.insert(Url::from_str(uri_str).unwrap(), content.to_string());
+    fn create_dummy_store(uri_str: &str, content: &str) -> DocumentStore {
+        let mut store = DocumentStore::new();
+        store.open(Url::from_str(uri_str).unwrap(), "rust", 1, content);
         store
     }
 
@@ -246,4 +397,50 @@ mod tests {
         // Note: We'll implement case-insensitive matching
         assert!(labels.contains(&"let") || labels.contains(&"loop"), "Should handle uppercase input");
     }
+
+    #[test]
+    fn test_ranked_completion_matches_fuzzily() {
+        let uri = Url::from_str("file:///test.rs").unwrap();
+        let store = create_dummy_store(uri.as_str(), "let x = fl");
+        let position = Position::new(0, 10); // After "fl"
+
+        let items = get_ranked_completion_items_with_encoding(&uri, position, &store, &PositionEncodingKind::UTF16);
+        let labels: Vec<&str> = items.iter().map(|item| item.label.as_str()).collect();
+        assert_eq!(labels, vec!["false"], "'fl' is a subsequence of 'false' only");
+
+        let item = &items[0];
+        assert_eq!(item.filter_text.as_deref(), Some("false"));
+        assert_eq!(
+            item.text_edit,
+            Some(CompletionTextEdit::Edit(TextEdit::new(Range::new(Position::new(0, 8), Position::new(0, 10)), "false".to_string())))
+        );
+        assert!(item.detail.is_none() && item.documentation.is_none(), "details are left to completionItem/resolve");
+    }
+
+    #[test]
+    fn test_ranked_completion_sort_text() {
+        let uri = Url::from_str("file:///test.rs").unwrap();
+        let store = create_dummy_store(uri.as_str(), "s");
+        let position = Position::new(0, 1);
+
+        let mut items = get_ranked_completion_items_with_encoding(&uri, position, &store, &PositionEncodingKind::UTF16);
+        items.sort_by(|a, b| a.sort_text.cmp(&b.sort_text));
+        let labels: Vec<&str> = items.iter().map(|item| item.label.as_str()).collect();
+        // 先頭の大文字・小文字まで一致する短い候補が先、途中で一致するだけの候補（false）が後
+        assert_eq!(labels, vec!["str", "struct", "String", "false"]);
+    }
+
+    #[test]
+    fn test_resolve_completion_item() {
+        let item = CompletionItem { label: "let".to_string(), kind: Some(CompletionItemKind::KEYWORD), ..Default::default() };
+        let resolved = resolve_completion_item(item);
+        assert_eq!(resolved.detail.as_deref(), Some("keyword"));
+        match resolved.documentation {
+            Some(Documentation::MarkupContent(content)) => assert!(content.value.contains("let name = value;")),
+            other => panic!("expected Markdown documentation, got {:?}", other),
+        }
+
+        let unknown = CompletionItem { label: "unknown".to_string(), ..Default::default() };
+        assert_eq!(resolve_completion_item(unknown.clone()), unknown);
+    }
 }
\ No newline at end of file
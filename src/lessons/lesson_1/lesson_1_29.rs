@@ -6,7 +6,7 @@
 // The function `get_signature_help` takes:
 // - `file_uri`: The `Url` of the document where signature help was requested.
 // - `position`: The `Position` where signature help was triggered.
-// - `document_store`: The `HashMap` containing document content.
+// - `document_store`: The `DocumentStore` containing the open documents.
 // It should:
 // 1. Retrieve the content of `file_uri` from the `document_store`.
 // 2. Find if the cursor is inside a function call (look for "function_name(" pattern).
@@ -22,8 +22,8 @@
 //    - `active_parameter`: Index of the parameter currently being typed
 // 6. Return `Some(SignatureHelp)` if inside a known function call, `None` otherwise.
 
-use lsp_types::{ParameterInformation, Position, SignatureHelp, SignatureInformation, Url};
-use std::collections::HashMap;
+use lsp_types::{ParameterInformation, Position, PositionEncodingKind, SignatureHelp, SignatureInformation, Url};
+use crate::common::document_store::DocumentStore;
 
 fn find_function_call(line: &str, cursor_pos: usize) -> Option<(String, String)> {
     let before_cursor = &line[..cursor_pos];
@@ -104,12 +104,24 @@ fn count_active_parameter(inside_call: &str) -> u32 {
     comma_count // 0番目のパラメータから始まるため、カンマの数 = パラメータインデックス
 }
 
-pub fn get_signature_help(file_uri: &Url, position: Position, document_store: &HashMap<Url, String>) -> Option<SignatureHelp> {
+pub fn get_signature_help(file_uri: &Url, position: Position, document_store: &DocumentStore) -> Option<SignatureHelp> {
+    get_signature_help_with_encoding(file_uri, position, document_store, &PositionEncodingKind::UTF16)
+}
+
+// position.character を encoding の単位で数える版（get_signature_help は UTF-16）
+pub fn get_signature_help_with_encoding(
+    file_uri: &Url,
+    position: Position,
+    document_store: &DocumentStore,
+    encoding: &PositionEncodingKind,
+) -> Option<SignatureHelp> {
     // エラーハンドリングの改善
-    let content = document_store.get(file_uri)?;
-    let line = content.lines().nth(position.line as usize)?;
+    let document = document_store.get(file_uri)?;
+    let line = document.text().lines().nth(position.line as usize)?;
+    // 行の先頭からのバイトオフセット（文字の境界で、行の長さを超えない）
+    let cursor = document.line_index(encoding).byte_column(position.line, position.character)?;
 
-    let (fn_name, inside_call) = find_function_call(line, position.character as usize)?;
+    let (fn_name, inside_call) = find_function_call(line, cursor)?;
 
     match fn_name.as_str() {
         "println!" => Some(create_signature_help(
@@ -143,13 +155,13 @@ pub fn get_signature_help(file_uri: &Url, position: Position, document_store: &H
 mod tests {
     use super::get_signature_help;
     use lsp_types::{Position, Url};
-    use std::collections::HashMap;
+    use crate::common::document_store::DocumentStore;
     use std::str::FromStr;
 
     // Helper to create a dummy document store
-    fn create_dummy_store(uri_str: &str, content: &str) -> HashMap<Url, String> {
-        let mut store = HashMap::new();
-        store.insert(Url::from_str(uri_str).unwrap(), content.to_string());
+    fn create_dummy_store(uri_str: &str, content: &str) -> DocumentStore {
+        let mut store = DocumentStore::new();
+        store.open(Url::from_str(uri_str).unwrap(), "rust", 1, content);
         store
     }
 
@@ -5,7 +5,7 @@
 // Your Task:
 // The function `workspace_symbol` takes:
 // - `query`: The search query string (e.g., "main", "calc", "User").
-// - `document_store`: The `HashMap` containing all documents in the workspace.
+// - `document_store`: The `DocumentStore` containing all documents in the workspace.
 // It should:
 // 1. Search through all documents in the workspace for symbols matching the query.
 // 2. Look for function definitions (lines starting with "fn ") and struct definitions (lines starting with "struct ").
@@ -17,8 +17,14 @@
 // 5. Return a `Vec<SymbolInformation>` containing all matching symbols.
 // 6. If no symbols match or the query is empty, return an empty Vec.
 
-use lsp_types::{Location, Position, Range, SymbolInformation, SymbolKind, Url};
-use std::collections::HashMap;
+// Going further:
+// A real server also indexes files on disk that the editor has not opened.
+// `all_symbols_in_document` collects every symbol of a file once, so the index can answer
+// each query with `symbol_matches` instead of reading the file again.
+
+use lsp_types::{Location, Position, PositionEncodingKind, Range, SymbolInformation, SymbolKind, Url};
+use crate::common::document_store::DocumentStore;
+use crate::common::line_index::LineIndex;
 
 fn extract_fn_name(line: &str) -> Option<String> {
     let trimmed = line.trim_start();
@@ -62,6 +68,7 @@ fn create_symbol_info(
     uri: &Url,
     line_number: u32,
     line_content: &str,
+    line_index: &LineIndex,
 ) -> SymbolInformation {
     SymbolInformation {
         name,
@@ -72,7 +79,7 @@ fn create_symbol_info(
             uri: uri.clone(),
             range: Range::new(
                 Position::new(line_number, 0),
-                Position::new(line_number, line_content.len() as u32),
+                Position::new(line_number, line_index.character(line_number, line_content.len())),
             ),
         },
         container_name: None,
@@ -81,57 +88,104 @@ fn create_symbol_info(
 
 pub fn workspace_symbol(
     query: &str,
-    document_store: &HashMap<Url, String>,
+    document_store: &DocumentStore,
 ) -> Vec<SymbolInformation> {
+    workspace_symbol_with_cancellation(query, document_store, || false).unwrap_or_default()
+}
+
+// workspace_symbol のキャンセル可能版
+// ファイルを1つ調べるごとに is_cancelled() を確認し、キャンセルされていたら途中で諦めて None を返す
+pub fn workspace_symbol_with_cancellation(
+    query: &str,
+    document_store: &DocumentStore,
+    is_cancelled: impl Fn() -> bool,
+) -> Option<Vec<SymbolInformation>> {
     // 早期リターン: 空のクエリ
+    if query.is_empty() {
+        return Some(Vec::new());
+    }
+
+    let mut results = Vec::new();
+
+    // ワークスペース内の全ファイルを反復処理
+    for (uri, document) in document_store.iter() {
+        if is_cancelled() {
+            return None;
+        }
+        results.extend(workspace_symbol_in_document(query, uri, document.text(), &PositionEncodingKind::UTF16));
+    }
+
+    Some(results)
+}
+
+// 1つのドキュメントから query に一致するシンボルを探す
+// ドキュメントごとに結果を返せるので、進捗の報告や部分的な結果の送信に使える
+// Range の列は encoding の単位で数える
+pub fn workspace_symbol_in_document(
+    query: &str,
+    uri: &Url,
+    content: &str,
+    encoding: &PositionEncodingKind,
+) -> Vec<SymbolInformation> {
     if query.is_empty() {
         return Vec::new();
     }
 
     let query_lower = query.to_lowercase();
+
+    // ファイル全体の事前チェック（パフォーマンス最適化）
+    if !content.to_lowercase().contains(&query_lower) {
+        return Vec::new();
+    }
+
+    all_symbols_in_document(uri, content, encoding)
+        .into_iter()
+        .filter(|symbol| symbol_matches(query, &symbol.name))
+        .collect()
+}
+
+// シンボルの名前が query に一致するか（大文字・小文字は区別しない部分一致。空の query には何も一致しない）
+pub fn symbol_matches(query: &str, name: &str) -> bool {
+    !query.is_empty() && name.to_lowercase().contains(&query.to_lowercase())
+}
+
+// 1つのドキュメントのシンボル（関数・構造体）をすべて集める
+// ワークスペースの索引は、これをファイルごとに持っておき、検索のたびに symbol_matches で絞り込む
+pub fn all_symbols_in_document(uri: &Url, content: &str, encoding: &PositionEncodingKind) -> Vec<SymbolInformation> {
+    let line_index = LineIndex::new(content, encoding.clone());
     let mut results = Vec::new();
 
-    // ワークスペース内の全ファイルを反復処理
-    for (uri, content) in document_store {
-        // ファイル全体の事前チェック（パフォーマンス最適化）
-        if !content.to_lowercase().contains(&query_lower) {
+    // 各行を処理（行番号付き）
+    for (line_number, line) in content.lines().enumerate() {
+        let trimmed = line.trim_start();
+
+        // 効率的な早期フィルタリング
+        if !(trimmed.starts_with("fn ") || trimmed.starts_with("struct ")) {
             continue;
         }
 
-        // 各行を処理（行番号付き）
-        for (line_number, line) in content.lines().enumerate() {
-            let trimmed = line.trim_start();
-
-            // 効率的な早期フィルタリング
-            if !(trimmed.starts_with("fn ") || trimmed.starts_with("struct ")) {
-                continue;
-            }
-
-            // 関数定義の処理
-            if let Some(fn_name) = extract_fn_name(line) {
-                if fn_name.to_lowercase().contains(&query_lower) {
-                    results.push(create_symbol_info(
-                        fn_name,
-                        SymbolKind::FUNCTION,
-                        uri,
-                        line_number as u32,
-                        line,
-                    ));
-                }
-            }
-
-            // 構造体定義の処理
-            if let Some(struct_name) = extract_struct_name(line) {
-                if struct_name.to_lowercase().contains(&query_lower) {
-                    results.push(create_symbol_info(
-                        struct_name,
-                        SymbolKind::STRUCT,
-                        uri,
-                        line_number as u32,
-                        line,
-                    ));
-                }
-            }
+        // 関数定義の処理
+        if let Some(fn_name) = extract_fn_name(line) {
+            results.push(create_symbol_info(
+                fn_name,
+                SymbolKind::FUNCTION,
+                uri,
+                line_number as u32,
+                line,
+                &line_index,
+            ));
+        }
+
+        // 構造体定義の処理
+        if let Some(struct_name) = extract_struct_name(line) {
+            results.push(create_symbol_info(
+                struct_name,
+                SymbolKind::STRUCT,
+                uri,
+                line_number as u32,
+                line,
+                &line_index,
+            ));
         }
     }
 
@@ -142,31 +196,41 @@ pub fn workspace_symbol(
 
 #[cfg(test)]
 mod tests {
-    use super::workspace_symbol;
-    use lsp_types::{Position, SymbolKind, Url};
-    use std::collections::HashMap;
+    use super::{
+        all_symbols_in_document, symbol_matches, workspace_symbol, workspace_symbol_in_document,
+        workspace_symbol_with_cancellation,
+    };
+    use lsp_types::{Position, PositionEncodingKind, SymbolKind, Url};
+    use std::cell::Cell;
+    use crate::common::document_store::DocumentStore;
     use std::str::FromStr;
 
     // Helper to create a workspace with multiple files
-    fn create_workspace() -> HashMap<Url, String> {
-        let mut workspace = HashMap::new();
+    fn create_workspace() -> DocumentStore {
+        let mut workspace = DocumentStore::new();
 
         // mod
-        workspace.insert(
+        workspace.open(
             Url::from_str("file:///src/mod").unwrap(),
-            "fn main() {\n    println!(\"Hello World\");\n}\n\nfn calculate(x: i32) -> i32 {\n    x * 2\n}".to_string()
+            "rust",
+            1,
+            "fn main() {\n    println!(\"Hello World\");\n}\n\nfn calculate(x: i32) -> i32 {\n    x * 2\n}",
         );
 
         // user.rs
-        workspace.insert(
+        workspace.open(
             Url::from_str("file:///src/user.rs").unwrap(),
-            "struct User {\n    name: String,\n    age: u32,\n}\n\nfn create_user() -> User {\n    User { name: \"test\".to_string(), age: 25 }\n}".to_string()
+            "rust",
+            1,
+            "struct User {\n    name: String,\n    age: u32,\n}\n\nfn create_user() -> User {\n    User { name: \"test\".to_string(), age: 25 }\n}",
         );
 
         // utils.rs
-        workspace.insert(
+        workspace.open(
             Url::from_str("file:///src/utils.rs").unwrap(),
-            "struct Calculator {\n    value: f64,\n}\n\nfn add_numbers(a: i32, b: i32) -> i32 {\n    a + b\n}".to_string()
+            "rust",
+            1,
+            "struct Calculator {\n    value: f64,\n}\n\nfn add_numbers(a: i32, b: i32) -> i32 {\n    a + b\n}",
         );
 
         workspace
@@ -305,7 +369,7 @@ mod tests {
 
     #[test]
     fn test_workspace_symbol_empty_workspace() {
-        let empty_workspace = HashMap::new();
+        let empty_workspace = DocumentStore::new();
         let symbols = workspace_symbol("anything", &empty_workspace);
 
         assert!(
@@ -313,4 +377,57 @@ mod tests {
             "Should return empty for empty workspace"
         );
     }
+
+    #[test]
+    fn test_workspace_symbol_cancelled_before_start() {
+        let workspace = create_workspace();
+        let symbols = workspace_symbol_with_cancellation("Calculator", &workspace, || true);
+
+        assert!(symbols.is_none(), "Cancelled search should not return partial results");
+    }
+
+    #[test]
+    fn test_workspace_symbol_cancelled_midway() {
+        let workspace = create_workspace();
+        let checks = Cell::new(0);
+        // 2ファイル目を調べる前にキャンセルされる
+        let symbols = workspace_symbol_with_cancellation("a", &workspace, || {
+            checks.set(checks.get() + 1);
+            checks.get() > 1
+        });
+
+        assert!(symbols.is_none(), "Cancelled search should not return partial results");
+        assert_eq!(checks.get(), 2, "Search should stop as soon as cancellation is observed");
+    }
+
+    #[test]
+    fn test_workspace_symbol_not_cancelled_matches_plain_search() {
+        let workspace = create_workspace();
+        let symbols = workspace_symbol_with_cancellation("Calculator", &workspace, || false);
+
+        assert_eq!(symbols.map(|symbols| symbols.len()), Some(workspace_symbol("Calculator", &workspace).len()));
+    }
+
+    #[test]
+    fn test_workspace_symbol_in_document_matches_workspace_search() {
+        let workspace = create_workspace();
+        let per_document: usize = workspace
+            .iter()
+            .map(|(uri, document)| workspace_symbol_in_document("a", uri, document.text(), &PositionEncodingKind::UTF16).len())
+            .sum();
+
+        assert_eq!(per_document, workspace_symbol("a", &workspace).len(), "Searching document by document should find the same symbols");
+        assert!(workspace_symbol_in_document("", &Url::from_str("file:///src/mod").unwrap(), "fn main() {}", &PositionEncodingKind::UTF16).is_empty());
+    }
+
+    #[test]
+    fn test_all_symbols_in_document() {
+        let uri = Url::from_str("file:///src/lib.rs").unwrap();
+        let symbols = all_symbols_in_document(&uri, "struct User {}\nfn main() {}\nlet x = 1;", &PositionEncodingKind::UTF16);
+
+        let names: Vec<&str> = symbols.iter().map(|symbol| symbol.name.as_str()).collect();
+        assert_eq!(names, vec!["User", "main"], "every function and struct should be collected without a query");
+        assert!(symbol_matches("US", "User"), "matching should ignore case");
+        assert!(!symbol_matches("", "User"), "an empty query matches nothing");
+    }
 }
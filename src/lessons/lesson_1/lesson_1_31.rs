@@ -5,7 +5,7 @@
 // Your Task:
 // The function `call_hierarchy_incoming_calls` takes:
 // - `target_function`: The name of the function for which to find incoming calls.
-// - `document_store`: The `HashMap` containing all documents in the workspace.
+// - `document_store`: The `DocumentStore` containing all documents in the workspace.
 // It should:
 // 1. Search through all documents to find calls to the target function.
 // 2. Look for patterns like "target_function(" in the code.
@@ -16,14 +16,18 @@
 // 5. Return a `Vec<CallHierarchyIncomingCall>` containing all incoming calls.
 // 6. If no calls are found, return an empty Vec.
 
-use lsp_types::{CallHierarchyIncomingCall, CallHierarchyItem, Position, Range, SymbolKind, Url};
+use lsp_types::{CallHierarchyIncomingCall, CallHierarchyItem, Position, PositionEncodingKind, Range, SymbolKind, Url};
 use std::collections::HashMap;
+use crate::common::document_store::DocumentStore;
+use crate::common::line_index::LineIndex;
 
 #[derive(Clone)]
 struct FnRange {
     pub fn_name: String,
     pub url: Url,
     pub range: Range,
+    // 関数名の部分
+    pub selection_range: Range,
 }
 
 struct FnRangeMap {
@@ -56,43 +60,73 @@ impl FnRangeMap {
 
 pub fn call_hierarchy_incoming_calls(
     target_function: &str,
-    document_store: &HashMap<Url, String>,
+    document_store: &DocumentStore,
 ) -> Vec<CallHierarchyIncomingCall> {
+    call_hierarchy_incoming_calls_with_cancellation(target_function, document_store, || false).unwrap_or_default()
+}
+
+// call_hierarchy_incoming_calls のキャンセル可能版
+// ファイルを1つ調べるごとに is_cancelled() を確認し、キャンセルされていたら途中で諦めて None を返す
+pub fn call_hierarchy_incoming_calls_with_cancellation(
+    target_function: &str,
+    document_store: &DocumentStore,
+    is_cancelled: impl Fn() -> bool,
+) -> Option<Vec<CallHierarchyIncomingCall>> {
     // Hint:
     // 1. Search through all files for calls to target_function
     // 2. For each call found, determine the containing function
     // 3. Group calls by their containing function
     // 4. Create CallHierarchyIncomingCall objects for each calling function
+    let mut incoming_calls = Vec::new();
+
+    for (url, document) in document_store.iter() {
+        if is_cancelled() {
+            return None;
+        }
+        // 呼び出し元の関数は (関数名, ファイル) でまとめるので、ファイルごとに調べても結果は同じ
+        incoming_calls.extend(call_hierarchy_incoming_calls_in_document(
+            target_function,
+            url,
+            document.text(),
+            &PositionEncodingKind::UTF16,
+        ));
+    }
+
+    Some(incoming_calls)
+}
+
+// 1つのドキュメントの中から target_function の呼び出し元を探す
+// ドキュメントごとに結果を返せるので、進捗の報告や部分的な結果の送信に使える
+// Range の列は encoding の単位で数える
+pub fn call_hierarchy_incoming_calls_in_document(
+    target_function: &str,
+    url: &Url,
+    content: &str,
+    encoding: &PositionEncodingKind,
+) -> Vec<CallHierarchyIncomingCall> {
     let search_pattern = format!("{}(", target_function);
+    let line_index = LineIndex::new(content, encoding.clone());
 
-    let fn_ranges = document_store
-        .iter()
-        .filter_map(|(url, content)| {
-            Some(
-                content
-                    .lines()
-                    .enumerate()
-                    .filter_map(|(line_number, line)| {
-                        // より正確な関数呼び出し検出
-                        if line.trim_start().starts_with("fn ") {
-                            return None; // 関数定義行は除外
-                        }
-
-                        if let Some(pos) = line.find(&search_pattern) {
-                            // 関数名の境界チェック
-                            let is_valid_call = pos == 0
-                                || !line.chars().nth(pos - 1).unwrap_or(' ').is_alphanumeric();
-
-                            if is_valid_call {
-                                return find_containing_function(url, &content, line_number);
-                            }
-                        }
-                        None
-                    })
-                    .collect::<Vec<FnRange>>(),
-            )
+    let fn_ranges = content
+        .lines()
+        .enumerate()
+        .filter_map(|(line_number, line)| {
+            // より正確な関数呼び出し検出
+            if line.trim_start().starts_with("fn ") {
+                return None; // 関数定義行は除外
+            }
+
+            if let Some(pos) = line.find(&search_pattern) {
+                // 関数名の境界チェック
+                let is_valid_call = pos == 0
+                    || !line.chars().nth(pos - 1).unwrap_or(' ').is_alphanumeric();
+
+                if is_valid_call {
+                    return find_containing_function(url, content, line_number, &line_index);
+                }
+            }
+            None
         })
-        .flatten()
         .collect::<Vec<FnRange>>();
 
     let fn_range_map = FnRangeMap::from(fn_ranges);
@@ -101,7 +135,7 @@ pub fn call_hierarchy_incoming_calls(
         .fn_range_map
         .iter()
         .filter_map(|((fn_name, url), fn_ranges)| {
-            let first_range = fn_ranges.first()?.range;
+            let first = fn_ranges.first()?;
 
             Some(CallHierarchyIncomingCall {
                 from: CallHierarchyItem {
@@ -110,14 +144,8 @@ pub fn call_hierarchy_incoming_calls(
                     tags: None,
                     detail: None,
                     uri: url.clone(),
-                    range: first_range,
-                    selection_range: Range::new(
-                        first_range.start,
-                        Position::new(
-                            first_range.start.line,
-                            first_range.start.character + fn_name.len() as u32,
-                        ),
-                    ),
+                    range: first.range,
+                    selection_range: first.selection_range,
                     data: None,
                 },
                 from_ranges: fn_range_map.ranges(fn_name, url)?,
@@ -127,7 +155,7 @@ pub fn call_hierarchy_incoming_calls(
 }
 
 // Helper function to find the containing function for a given line
-fn find_containing_function(url: &Url, content: &str, target_line: usize) -> Option<FnRange> {
+fn find_containing_function(url: &Url, content: &str, target_line: usize, line_index: &LineIndex) -> Option<FnRange> {
     let lines: Vec<&str> = content.lines().collect();
 
     for line_number in (0..=target_line).rev() {
@@ -138,13 +166,18 @@ fn find_containing_function(url: &Url, content: &str, target_line: usize) -> Opt
         }
 
         let fn_name = extract_fn_name(line)?;
+        let name_start = line.find(fn_name)?;
+        let name_position = |byte_column: usize| {
+            Position::new(line_number as u32, line_index.character(line_number as u32, byte_column))
+        };
         return Some(FnRange {
             fn_name: fn_name.to_string(),
             url: url.clone(),
             range: Range::new(
-                Position::new(line_number as u32, line.find(fn_name)? as u32),
+                name_position(name_start),
                 Position::new(find_fn_end(content, line_number)? as u32, 0),
             ),
+            selection_range: Range::new(name_position(name_start), name_position(name_start + fn_name.len())),
         });
     }
 
@@ -177,31 +210,41 @@ fn find_fn_end(content: &str, start_line_number: usize) -> Option<usize> {
 
 #[cfg(test)]
 mod tests {
-    use super::call_hierarchy_incoming_calls;
-    use lsp_types::{SymbolKind, Url};
-    use std::collections::HashMap;
+    use super::{
+        call_hierarchy_incoming_calls, call_hierarchy_incoming_calls_in_document,
+        call_hierarchy_incoming_calls_with_cancellation,
+    };
+    use lsp_types::{PositionEncodingKind, SymbolKind, Url};
+    use std::cell::Cell;
+    use crate::common::document_store::DocumentStore;
     use std::str::FromStr;
 
     // Helper to create a workspace with function calls
-    fn create_call_hierarchy_workspace() -> HashMap<Url, String> {
-        let mut workspace = HashMap::new();
+    fn create_call_hierarchy_workspace() -> DocumentStore {
+        let mut workspace = DocumentStore::new();
 
         // mod - calls helper and calculate
-        workspace.insert(
+        workspace.open(
             Url::from_str("file:///src/mod").unwrap(),
-            "fn main() {\n    let result = calculate(10);\n    helper();\n    println!(\"Result: {}\", result);\n}\n\nfn another_func() {\n    calculate(20);\n}".to_string()
+            "rust",
+            1,
+            "fn main() {\n    let result = calculate(10);\n    helper();\n    println!(\"Result: {}\", result);\n}\n\nfn another_func() {\n    calculate(20);\n}",
         );
 
         // utils.rs - has calculate function and calls helper
-        workspace.insert(
+        workspace.open(
             Url::from_str("file:///src/utils.rs").unwrap(),
-            "fn calculate(x: i32) -> i32 {\n    helper();\n    x * 2\n}\n\nfn helper() {\n    println!(\"Helper called\");\n}".to_string()
+            "rust",
+            1,
+            "fn calculate(x: i32) -> i32 {\n    helper();\n    x * 2\n}\n\nfn helper() {\n    println!(\"Helper called\");\n}",
         );
 
         // lib.rs - calls calculate from different functions
-        workspace.insert(
+        workspace.open(
             Url::from_str("file:///src/lib.rs").unwrap(),
-            "fn process_data() {\n    let value = calculate(5);\n    println!(\"Processed: {}\", value);\n}\n\nfn batch_process() {\n    for i in 0..10 {\n        calculate(i);\n    }\n}".to_string()
+            "rust",
+            1,
+            "fn process_data() {\n    let value = calculate(5);\n    println!(\"Processed: {}\", value);\n}\n\nfn batch_process() {\n    for i in 0..10 {\n        calculate(i);\n    }\n}",
         );
 
         workspace
@@ -324,7 +367,7 @@ mod tests {
 
     #[test]
     fn test_call_hierarchy_empty_workspace() {
-        let empty_workspace = HashMap::new();
+        let empty_workspace = DocumentStore::new();
         let calls = call_hierarchy_incoming_calls("any_function", &empty_workspace);
 
         assert!(calls.is_empty(), "Should return empty for empty workspace");
@@ -332,10 +375,12 @@ mod tests {
 
     #[test]
     fn test_call_hierarchy_function_calls_itself() {
-        let mut workspace = HashMap::new();
-        workspace.insert(
+        let mut workspace = DocumentStore::new();
+        workspace.open(
             Url::from_str("file:///src/recursive.rs").unwrap(),
-            "fn factorial(n: u32) -> u32 {\n    if n <= 1 {\n        1\n    } else {\n        n * factorial(n - 1)\n    }\n}".to_string()
+            "rust",
+            1,
+            "fn factorial(n: u32) -> u32 {\n    if n <= 1 {\n        1\n    } else {\n        n * factorial(n - 1)\n    }\n}",
         );
 
         let calls = call_hierarchy_incoming_calls("factorial", &workspace);
@@ -351,4 +396,36 @@ mod tests {
             "Should find recursive call from 'factorial'"
         );
     }
+
+    #[test]
+    fn test_incoming_calls_cancelled() {
+        let workspace = create_call_hierarchy_workspace();
+        let checks = Cell::new(0);
+        let calls = call_hierarchy_incoming_calls_with_cancellation("helper", &workspace, || {
+            checks.set(checks.get() + 1);
+            checks.get() > 1
+        });
+
+        assert!(calls.is_none(), "Cancelled search should not return partial results");
+        assert_eq!(checks.get(), 2, "Search should stop as soon as cancellation is observed");
+    }
+
+    #[test]
+    fn test_incoming_calls_not_cancelled_matches_plain_search() {
+        let workspace = create_call_hierarchy_workspace();
+        let calls = call_hierarchy_incoming_calls_with_cancellation("helper", &workspace, || false);
+
+        assert_eq!(calls.map(|calls| calls.len()), Some(call_hierarchy_incoming_calls("helper", &workspace).len()));
+    }
+
+    #[test]
+    fn test_incoming_calls_in_document_matches_workspace_search() {
+        let workspace = create_call_hierarchy_workspace();
+        let per_document: usize = workspace
+            .iter()
+            .map(|(url, document)| call_hierarchy_incoming_calls_in_document("helper", url, document.text(), &PositionEncodingKind::UTF16).len())
+            .sum();
+
+        assert_eq!(per_document, call_hierarchy_incoming_calls("helper", &workspace).len());
+    }
 }
@@ -18,9 +18,17 @@
 // 5. すべてのセマンティックトークンを含む `SemanticTokens` を返します
 // 6. トークンが見つからない場合は、空のトークンリストを返します
 
-use lsp_types::{SemanticTokens};
+use lsp_types::{PositionEncodingKind, SemanticTokens};
+use crate::common::line_index::LineIndex;
 
 pub fn provide_semantic_tokens(content: &str) -> SemanticTokens {
+    provide_semantic_tokens_with_encoding(content, &PositionEncodingKind::UTF16)
+}
+
+// 列（delta_start）と長さ（length）を encoding の単位で数える版（provide_semantic_tokens は UTF-16）
+// 以下の start_pos / end_pos は行の先頭からのバイトオフセット（end_pos はトークンの直後）
+pub fn provide_semantic_tokens_with_encoding(content: &str, encoding: &PositionEncodingKind) -> SemanticTokens {
+    let line_index = LineIndex::new(content, encoding.clone());
     let mut tokens = Vec::new();
     let mut prev_line = 0u32;
     let mut prev_start = 0u32;
@@ -30,15 +38,18 @@ pub fn provide_semantic_tokens(content: &str) -> SemanticTokens {
         let mut char_indices = line.char_indices().peekable();
         
         while let Some((start_pos, ch)) = char_indices.next() {
+            let line_number = line_number as u32;
+            // この行でのトークンの開始位置（encoding の単位）
+            let start = line_index.character(line_number, start_pos);
             match ch {
                 // 文字列リテラルの処理
                 '"' => {
-                    let mut end_pos = start_pos;
+                    let mut end_pos = start_pos + ch.len_utf8();
                     let mut escaped = false;
                     
                     // 文字列の終端を見つける
                     while let Some((pos, next_ch)) = char_indices.next() {
-                        end_pos = pos;
+                        end_pos = pos + next_ch.len_utf8();
                         if escaped {
                             escaped = false;
                             continue;
@@ -50,87 +61,87 @@ pub fn provide_semantic_tokens(content: &str) -> SemanticTokens {
                         }
                     }
                     
-                    let length = end_pos - start_pos + 1;
+                    let length = line_index.len_between(line_number, start_pos, end_pos);
                     let (delta_line, delta_start) = calculate_delta(
-                        line_number as u32, start_pos as u32, prev_line, prev_start
+                        line_number, start, prev_line, prev_start
                     );
                     
                     tokens.push(lsp_types::SemanticToken {
                         delta_line,
                         delta_start,
-                        length: length as u32,
+                        length,
                         token_type: 3, // STRING
                         token_modifiers_bitset: 0,
                     });
                     
-                    prev_line = line_number as u32;
-                    prev_start = start_pos as u32;
+                    prev_line = line_number;
+                    prev_start = start;
                 }
                 
                 // 数値リテラルの処理
                 c if c.is_ascii_digit() => {
-                    let mut end_pos = start_pos;
+                    let mut end_pos = start_pos + ch.len_utf8();
                     
                     // 数値の終端を見つける
                     while let Some((pos, next_ch)) = char_indices.peek() {
                         if next_ch.is_ascii_digit() {
-                            end_pos = *pos;
+                            end_pos = *pos + next_ch.len_utf8();
                             char_indices.next();
                         } else {
                             break;
                         }
                     }
                     
-                    let length = end_pos - start_pos + 1;
+                    let length = line_index.len_between(line_number, start_pos, end_pos);
                     let (delta_line, delta_start) = calculate_delta(
-                        line_number as u32, start_pos as u32, prev_line, prev_start
+                        line_number, start, prev_line, prev_start
                     );
                     
                     tokens.push(lsp_types::SemanticToken {
                         delta_line,
                         delta_start,
-                        length: length as u32,
+                        length,
                         token_type: 4, // NUMBER
                         token_modifiers_bitset: 0,
                     });
                     
-                    prev_line = line_number as u32;
-                    prev_start = start_pos as u32;
+                    prev_line = line_number;
+                    prev_start = start;
                 }
                 
                 // 識別子（キーワード、関数名、変数名）の処理
                 c if is_identifier_start(c) => {
-                    let mut end_pos = start_pos;
+                    let mut end_pos = start_pos + ch.len_utf8();
                     
                     // 識別子の終端を見つける
                     while let Some((pos, next_ch)) = char_indices.peek() {
                         if is_identifier_char(*next_ch) {
-                            end_pos = *pos;
+                            end_pos = *pos + next_ch.len_utf8();
                             char_indices.next();
                         } else {
                             break;
                         }
                     }
                     
-                    let token_text = &line[start_pos..=end_pos];
+                    let token_text = &line[start_pos..end_pos];
 
                     if let Some((token_type, token_literal)) = get_token_type(token_text, prev_token_literal.as_str()) {
                         prev_token_literal = token_literal;
-                        let length = end_pos - start_pos + 1;
+                        let length = line_index.len_between(line_number, start_pos, end_pos);
                         let (delta_line, delta_start) = calculate_delta(
-                            line_number as u32, start_pos as u32, prev_line, prev_start
+                            line_number, start, prev_line, prev_start
                         );
                         
                         tokens.push(lsp_types::SemanticToken {
                             delta_line,
                             delta_start,
-                            length: length as u32,
+                            length,
                             token_type,
                             token_modifiers_bitset: 0,
                         });
                         
-                        prev_line = line_number as u32;
-                        prev_start = start_pos as u32;
+                        prev_line = line_number;
+                        prev_start = start;
                     }
                 }
                 
@@ -201,7 +212,8 @@ fn is_identifier_start(c: char) -> bool {
 
 #[cfg(test)]
 mod tests {
-    use super::provide_semantic_tokens;
+    use super::{provide_semantic_tokens, provide_semantic_tokens_with_encoding};
+    use lsp_types::PositionEncodingKind;
 
     // 基本的なRustコードのサンプルを作成
     fn create_sample_rust_code() -> String {
@@ -352,4 +364,21 @@ mod tests {
         
         assert!(token_types.len() >= 3, "少なくとも3種類のトークンタイプが検出されるべきです");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_semantic_tokens_non_ascii() {
+        let code = "let 名前 = \"日本語\";";
+
+        // UTF-16 では "名前" は2、"\"日本語\"" は5
+        let tokens = provide_semantic_tokens(code);
+        let (name, string) = (&tokens.data[1], &tokens.data[2]);
+        assert_eq!((name.delta_start, name.length), (4, 2));
+        assert_eq!((string.delta_start, string.length), (5, 5), "delta_start は \"名前\" の先頭からの差分です");
+
+        // UTF-8 ではバイト数で数える
+        let tokens = provide_semantic_tokens_with_encoding(code, &PositionEncodingKind::UTF8);
+        let (name, string) = (&tokens.data[1], &tokens.data[2]);
+        assert_eq!((name.delta_start, name.length), (4, 6));
+        assert_eq!((string.delta_start, string.length), (9, 11));
+    }
+}
@@ -15,6 +15,11 @@
 
 
 pub fn format_document(content: &str) -> String {
+    format_document_with_indent(content, 4)
+}
+
+// 1つのインデントレベルを indent_width 個のスペースにする版（format_document は4つ）
+pub fn format_document_with_indent(content: &str, indent_width: usize) -> String {
     // ヒント：
     // 1. 各行を処理してインデントレベルを計算
     // 2. `{` と `}` でインデントレベルを調整
@@ -31,12 +36,12 @@ pub fn format_document(content: &str) -> String {
             let current_level = next_level;
             if line.contains('{') {
                 next_level += 1;
-                format!("{}{}", create_indent(current_level), line.trim_start())
+                format!("{}{}", create_indent(current_level, indent_width), line.trim_start())
             } else if line.contains('}') {
                 next_level -= 1;
-                format!("{}{}", create_indent(next_level), line.trim_start())
+                format!("{}{}", create_indent(next_level, indent_width), line.trim_start())
             } else {
-                format!("{}{}", create_indent(current_level), line.trim_start())
+                format!("{}{}", create_indent(current_level, indent_width), line.trim_start())
             }
         })
         .collect::<Vec<String>>()
@@ -46,8 +51,8 @@ pub fn format_document(content: &str) -> String {
 }
 
 // 指定されたレベルでインデント文字列を作成
-fn create_indent(level: i32) -> String {
-    " ".repeat((level * 4).max(0) as usize)
+fn create_indent(level: i32, indent_width: usize) -> String {
+    " ".repeat(level.max(0) as usize * indent_width)
 }
 
 
@@ -55,7 +60,7 @@ fn create_indent(level: i32) -> String {
 
 #[cfg(test)]
 mod tests {
-    use super::format_document;
+    use super::{format_document, format_document_with_indent};
 
     #[test]
     fn test_format_simple_function() {
@@ -119,4 +124,13 @@ mod tests {
         
         assert_eq!(result, expected, "空のコンテンツは空文字列を返すべきです");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_format_with_indent_width() {
+        let input = "fn test() {\nif true {\nlet x = 1;\n}\n}";
+        let expected = "fn test() {\n  if true {\n    let x = 1;\n  }\n}";
+        let result = format_document_with_indent(input, 2);
+
+        assert_eq!(result, expected, "1つのインデントレベルは指定した幅のスペースになるべきです");
+    }
+}
@@ -15,13 +15,20 @@
 // 4. すべての折りたたみ範囲を返します
 
 use std::str::Lines;
-use lsp_types::{FoldingRange, FoldingRangeKind};
+use lsp_types::{FoldingRange, FoldingRangeKind, PositionEncodingKind};
+use crate::common::line_index::LineIndex;
 
 pub fn provide_folding_ranges(content: &str) -> Vec<FoldingRange> {
+    provide_folding_ranges_with_encoding(content, &PositionEncodingKind::UTF16)
+}
+
+// start_character / end_character を encoding の単位で数える版（provide_folding_ranges は UTF-16）
+pub fn provide_folding_ranges_with_encoding(content: &str, encoding: &PositionEncodingKind) -> Vec<FoldingRange> {
     // ヒント：
     // 1. 各行を処理してブロックの開始を検出
     // 2. 対応する終了ブレースを見つける
     // 3. FoldingRange オブジェクトを作成
+    let line_index = LineIndex::new(content, encoding.clone());
     let mut current_line_number = 0;
     let mut folding_ranges = vec![];
 
@@ -36,9 +43,12 @@ pub fn provide_folding_ranges(content: &str) -> Vec<FoldingRange> {
             folding_ranges.push(
                 FoldingRange {
                     start_line: current_line_number as u32,
-                    start_character: Some(line.find('{').unwrap_or_default() as u32),
+                    start_character: Some(line_index.character(current_line_number as u32, line.find('{').unwrap_or_default())),
                     end_line: rbrace_line_number as u32,
-                    end_character: Some(content.lines().nth(rbrace_line_number).unwrap_or_default().find('}').unwrap_or_default() as u32),
+                    end_character: Some(line_index.character(
+                        rbrace_line_number as u32,
+                        content.lines().nth(rbrace_line_number).unwrap_or_default().find('}').unwrap_or_default(),
+                    )),
                     kind: Some(FoldingRangeKind::Region),
                     collapsed_text: None,
                 }
@@ -13,21 +13,49 @@
 // 3. `SelectionRange` オブジェクトを作成します
 // 4. 階層的な選択範囲を返します
 
-use lsp_types::{Position, Range, SelectionRange};
+use lsp_types::{Position, PositionEncodingKind, Range, SelectionRange};
+use crate::common::line_index::LineIndex;
 
 pub fn provide_selection_ranges(content: &str, positions: &[Position]) -> Vec<SelectionRange> {
+    provide_selection_ranges_with_encoding(content, positions, &PositionEncodingKind::UTF16)
+}
+
+// Position の列を encoding の単位で数える版（provide_selection_ranges は UTF-16）
+// 以下のヘルパーは列を文字（char）単位で数えるので、UTF-32 の Position に変換してから渡し、結果を encoding に戻す
+pub fn provide_selection_ranges_with_encoding(
+    content: &str,
+    positions: &[Position],
+    encoding: &PositionEncodingKind,
+) -> Vec<SelectionRange> {
     // ヒント：
     // 1. 各位置について現在の単語を検出
     // 2. 段階的に選択範囲を拡張
     // 3. SelectionRange の階層構造を作成
+    let line_index = LineIndex::new(content, encoding.clone());
+    let chars = LineIndex::new(content, PositionEncodingKind::UTF32);
+
     positions
         .iter()
         .filter_map(|position| {
-            provide_selection_range(content, *position)
+            let position = chars.position(line_index.offset(*position)?);
+            let selection_range = provide_selection_range(content, position)?;
+            convert_selection_range(selection_range, &chars, &line_index)
         })
         .collect::<Vec<SelectionRange>>()
 }
 
+// SelectionRange の木の Range を from のエンコーディングから to のエンコーディングに変換する
+fn convert_selection_range(selection_range: SelectionRange, from: &LineIndex, to: &LineIndex) -> Option<SelectionRange> {
+    let parent = match selection_range.parent {
+        Some(parent) => Some(Box::new(convert_selection_range(*parent, from, to)?)),
+        None => None,
+    };
+    Some(SelectionRange {
+        range: to.range(from.byte_range(selection_range.range)?),
+        parent,
+    })
+}
+
 fn provide_selection_range(content: &str, position: Position) -> Option<SelectionRange> {
     let word_range = select_word_at_position(content, position)?;
     let statement_range = expand_to_statement(content, word_range)?;
@@ -102,10 +130,8 @@ fn expand_to_statement(content: &str, word_range: Range) -> Option<Range> {
 fn expand_to_block(content: &str, statement_range: Range) -> Option<Range> {
     let mut block_starts_at = Position::new(0, 0);
     for line_number in (0..statement_range.start.line).rev() {
-        let lbrace_pos = content
-            .lines()
-            .nth(line_number as usize)?
-            .find('{');
+        let line = content.lines().nth(line_number as usize)?;
+        let lbrace_pos = line.find('{').map(|byte| line[..byte].chars().count());
         if lbrace_pos.is_some() {
             block_starts_at = Position::new(line_number, lbrace_pos? as u32);
             break;
@@ -114,10 +140,8 @@ fn expand_to_block(content: &str, statement_range: Range) -> Option<Range> {
 
     let mut block_ends_at = Position::new(0, 0);
     for line_number in statement_range.start.line..content.lines().count() as u32 {
-        let rbrace_pos = content
-            .lines()
-            .nth(line_number as usize)?
-            .find('}');
+        let line = content.lines().nth(line_number as usize)?;
+        let rbrace_pos = line.find('}').map(|byte| line[..byte].chars().count());
         if rbrace_pos.is_some() {
             block_ends_at = Position::new(line_number, rbrace_pos? as u32);
             break;
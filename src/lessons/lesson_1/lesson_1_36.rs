@@ -14,19 +14,38 @@
 // 3. 関数の上にCodeLensを配置します
 // 4. すべてのCodeLensを返します
 
-use lsp_types::{CodeLens, Command, Position, Range};
+use lsp_types::{CodeLens, Command, Position, PositionEncodingKind, Range};
+use serde::Deserialize;
+use crate::common::line_index::LineIndex;
 
 pub fn provide_code_lenses(content: &str) -> Vec<CodeLens> {
+    provide_code_lenses_with_encoding(content, &PositionEncodingKind::UTF16)
+}
+
+// kinds に含まれる種類の関数にだけ CodeLens を付ける版（provide_code_lenses_with_encoding はすべての種類）
+pub fn provide_code_lenses_for(content: &str, encoding: &PositionEncodingKind, kinds: &[FunctionType]) -> Vec<CodeLens> {
+    find_function_definitions(content, &LineIndex::new(content, encoding.clone()))
+        .into_iter()
+        .filter(|(function_type, _)| kinds.contains(function_type))
+        .map(|(_, lens)| lens)
+        .collect()
+}
+
+// Range の列を encoding の単位で数える版（provide_code_lenses は UTF-16）
+pub fn provide_code_lenses_with_encoding(content: &str, encoding: &PositionEncodingKind) -> Vec<CodeLens> {
     // ヒント：
     // 1. 各行を処理して関数定義を検出
     // 2. 関数の種類を判定（test, main, 通常）
     // 3. 適切なCommandを作成
     // 4. CodeLensオブジェクトを作成
-    find_function_definitions(content)
+    find_function_definitions(content, &LineIndex::new(content, encoding.clone()))
+        .into_iter()
+        .map(|(_, lens)| lens)
+        .collect()
 }
 
-// 関数定義行を検出する
-fn find_function_definitions(content: &str) -> Vec<CodeLens> {
+// 関数定義行を検出する（CodeLens と、それを付けた関数の種類）
+fn find_function_definitions(content: &str, line_index: &LineIndex) -> Vec<(FunctionType, CodeLens)> {
     // 戻り値: (行番号, 関数名, FunctionType) のタプルのベクター
     content
         .lines()
@@ -37,36 +56,37 @@ fn find_function_definitions(content: &str) -> Vec<CodeLens> {
 
                 if line_number >= 1 && content.lines().nth(line_number - 1)?.starts_with("#[test]")
                 {
-                    return Some(CodeLens {
-                        range: fn_range(line_number, fn_name.len()),
+                    return Some((FunctionType::Test, CodeLens {
+                        range: fn_range(line_index, line_number, fn_name.len()),
                         command: create_command_for_function(FunctionType::Test, fn_name).into(),
                         data: None,
-                    });
+                    }));
                 }
 
                 return if fn_name == "main" {
-                    Some(CodeLens {
-                        range: fn_range(line_number, fn_name.len()),
+                    Some((FunctionType::Main, CodeLens {
+                        range: fn_range(line_index, line_number, fn_name.len()),
                         command: create_command_for_function(FunctionType::Main, fn_name).into(),
                         data: None,
-                    })
+                    }))
                 } else {
-                    Some(CodeLens {
-                        range: fn_range(line_number, fn_name.len()),
+                    Some((FunctionType::Regular, CodeLens {
+                        range: fn_range(line_index, line_number, fn_name.len()),
                         command: create_command_for_function(FunctionType::Regular, fn_name).into(),
                         data: None,
-                    })
+                    }))
                 };
             }
             None
         })
-        .collect::<Vec<CodeLens>>()
+        .collect::<Vec<(FunctionType, CodeLens)>>()
 }
 
-fn fn_range(line_number: usize, fn_len: usize) -> Range {
+fn fn_range(line_index: &LineIndex, line_number: usize, fn_len: usize) -> Range {
+    let line_number = line_number as u32;
     Range::new(
-        Position::new(line_number as u32, 3),
-        Position::new(line_number as u32, 3 + fn_len as u32),
+        Position::new(line_number, 3),
+        Position::new(line_number, line_index.character(line_number, 3 + fn_len)),
     )
 }
 
@@ -77,10 +97,14 @@ fn extract_fn_name(line: &str) -> Option<&str> {
 }
 
 // 関数タイプの列挙型
-#[derive(Debug, PartialEq)]
-enum FunctionType {
+// 設定（サーバーの codeLens.kinds）では "test" / "main" / "references" と書く
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum FunctionType {
+    #[serde(rename = "test")]
     Test,    // テスト関数
+    #[serde(rename = "main")]
     Main,    // main関数
+    #[serde(rename = "references")]
     Regular, // 通常の関数
 }
 
@@ -109,7 +133,8 @@ fn create_command_for_function(function_type: FunctionType, function_name: &str)
 
 #[cfg(test)]
 mod tests {
-    use super::provide_code_lenses;
+    use super::{provide_code_lenses, provide_code_lenses_for, FunctionType};
+    use lsp_types::PositionEncodingKind;
 
     #[test]
     fn test_main_function_code_lens() {
@@ -247,4 +272,22 @@ mod tests {
             "終了位置は開始位置より後"
         );
     }
+
+    #[test]
+    fn test_code_lenses_for_selected_kinds() {
+        let content = "fn main() {}\n#[test]\nfn it_works() {}\nfn helper() {}";
+        let titles = |kinds: &[FunctionType]| -> Vec<String> {
+            provide_code_lenses_for(content, &PositionEncodingKind::UTF16, kinds)
+                .into_iter()
+                .map(|lens| lens.command.unwrap().title)
+                .collect()
+        };
+
+        assert_eq!(titles(&[FunctionType::Test, FunctionType::Regular]), vec!["Run test", "Show references"]);
+        assert!(titles(&[]).is_empty(), "種類を選ばなければCodeLensはないべきです");
+        assert_eq!(
+            serde_json::from_str::<Vec<FunctionType>>(r#"["test", "main", "references"]"#).unwrap(),
+            vec![FunctionType::Test, FunctionType::Main, FunctionType::Regular]
+        );
+    }
 }
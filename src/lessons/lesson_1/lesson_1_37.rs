@@ -15,15 +15,28 @@
 // 3. 連動編集可能な範囲のリストを返します
 // 4. 該当する識別子がない場合は None を返します
 
-use lsp_types::{LinkedEditingRanges, Position, Range};
+use lsp_types::{LinkedEditingRanges, Position, PositionEncodingKind, Range};
+use crate::common::line_index::LineIndex;
 
 pub fn provide_linked_editing_ranges(content: &str, position: Position) -> Option<LinkedEditingRanges> {
+    provide_linked_editing_ranges_with_encoding(content, position, &PositionEncodingKind::UTF16)
+}
+
+// Position の列を encoding の単位で数える版（provide_linked_editing_ranges は UTF-16）
+pub fn provide_linked_editing_ranges_with_encoding(
+    content: &str,
+    position: Position,
+    encoding: &PositionEncodingKind,
+) -> Option<LinkedEditingRanges> {
     // ヒント：
     // 1. 指定位置の識別子を取得
     // 2. 同じ識別子の出現箇所をすべ検索
     // 3. LinkedEditingRanges を作成
-    let ident = get_identifier_at_position(content, position)?;
-    let ranges = find_all_occurrences(content, ident.as_str());
+    let line_index = LineIndex::new(content, encoding.clone());
+    // get_identifier_at_position は列を文字（char）単位で数える
+    let chars = LineIndex::new(content, PositionEncodingKind::UTF32);
+    let ident = get_identifier_at_position(content, chars.position(line_index.offset(position)?))?;
+    let ranges = find_all_occurrences(content, ident.as_str(), &line_index);
 
     Some(LinkedEditingRanges {
         ranges,
@@ -70,7 +83,8 @@ fn get_identifier_at_position(content: &str, position: Position) -> Option<Strin
 }
 
 // 識別子のすべての出現箇所を検索する
-fn find_all_occurrences(content: &str, identifier: &str) -> Vec<Range> {
+// 以下の start_pos / absolute_pos は行の先頭からのバイトオフセット
+fn find_all_occurrences(content: &str, identifier: &str, line_index: &LineIndex) -> Vec<Range> {
     let mut ranges = Vec::new();
     
     for (line_number, line) in content.lines().enumerate() {
@@ -83,20 +97,22 @@ fn find_all_occurrences(content: &str, identifier: &str) -> Vec<Range> {
             // 単語境界をチェック
             let is_word_boundary = {
                 let before_valid = absolute_pos == 0 || 
-                    !is_identifier_char(line.chars().nth(absolute_pos - 1).unwrap_or(' '));
+                    !is_identifier_char(line[..absolute_pos].chars().next_back().unwrap_or(' '));
                 let after_valid = absolute_pos + identifier.len() >= line.len() || 
-                    !is_identifier_char(line.chars().nth(absolute_pos + identifier.len()).unwrap_or(' '));
+                    !is_identifier_char(line[absolute_pos + identifier.len()..].chars().next().unwrap_or(' '));
                 before_valid && after_valid
             };
             
             if is_word_boundary {
+                let line_number = line_number as u32;
                 ranges.push(Range::new(
-                    Position::new(line_number as u32, absolute_pos as u32),
-                    Position::new(line_number as u32, (absolute_pos + identifier.len()) as u32),
+                    Position::new(line_number, line_index.character(line_number, absolute_pos)),
+                    Position::new(line_number, line_index.character(line_number, absolute_pos + identifier.len())),
                 ));
             }
             
-            start_pos = absolute_pos + 1;
+            // 次の文字から探す（1 バイト進めるだけだと日本語などの文字の途中になる）
+            start_pos = absolute_pos + identifier.chars().next().map_or(1, char::len_utf8);
         }
     }
     
@@ -119,7 +135,7 @@ fn is_identifier_start(c: char) -> bool {
 #[cfg(test)]
 mod tests {
     use super::provide_linked_editing_ranges;
-    use lsp_types::Position;
+    use lsp_types::{Position, Range};
 
     #[test]
     fn test_variable_linked_editing() {
@@ -215,4 +231,21 @@ mod tests {
         
         assert!(ranges.is_none(), "空のコンテンツでは None を返すべきです");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_non_ascii_identifier_linked_editing() {
+        let content = "let 名前 = 1; // 名前\nprintln!(\"{}\", 名前);";
+        let ranges = provide_linked_editing_ranges(content, Position::new(0, 5)).unwrap();
+
+        assert_eq!(ranges.word_pattern.as_deref(), Some("名前"));
+        assert_eq!(
+            ranges.ranges,
+            vec![
+                Range::new(Position::new(0, 4), Position::new(0, 6)),
+                Range::new(Position::new(0, 15), Position::new(0, 17)),
+                Range::new(Position::new(1, 15), Position::new(1, 17)),
+            ],
+            "日本語の識別子も UTF-16 の列で返すべきです"
+        );
+    }
+}
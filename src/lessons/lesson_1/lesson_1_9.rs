@@ -13,15 +13,18 @@
 // 6. Return an `Option<LspMessage>` where `LspMessage` is an enum you define below.
 //    - If any parsing step fails, return `None`.
 
+use serde::Deserialize;
 use serde_json::Value;
 use crate::lessons::lesson_1::lesson_1_2::parse_lsp_message;
 use crate::lessons::lesson_1::lesson_1_4::parse_json_content;
 use crate::lessons::lesson_1::lesson_1_6::is_request_and_get_id;
 use crate::lessons::lesson_1::lesson_1_7::get_lsp_method;
 use crate::lessons::lesson_1::lesson_1_8::get_lsp_params;
+use crate::lessons::lesson_1::lesson_1_11::{create_lsp_error_response, ErrorCode};
 
 // Define an enum to represent the parsed LSP message.
 // This will help you structure your output.
+// id は serde_json::Value のまま持つので、数値の 1 と文字列の "1" は別の id として区別される
 #[derive(Debug, PartialEq, Clone)]
 pub enum LspMessage {
     Request {
@@ -33,37 +36,136 @@ pub enum LspMessage {
         method: String,
         params: Option<Value>,
     },
+    // サーバーから送ったリクエスト（workspace/applyEdit など）に対するクライアントの応答
+    // result と error はどちらか片方だけが入る
+    Response {
+        id: Value,
+        result: Option<Value>,
+        error: Option<ResponseError>,
+    },
+}
+
+// 応答の "error" フィールド
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+pub struct ResponseError {
+    pub code: i32,
+    pub message: String,
+    pub data: Option<Value>,
+}
+
+impl ResponseError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ResponseError { code: code.code(), message: message.into(), data: None }
+    }
+
+    // クライアントへ返すエラー応答（Content-Length 付き）を作る
+    pub fn to_error_response(&self, id: Value) -> String {
+        create_lsp_error_response(id, self.code, self.message.clone(), self.data.clone())
+    }
+}
+
+// 受け取ったメッセージが JSON-RPC として正しくなかったときのエラー
+// id はわかる範囲で元のメッセージのものを入れる（わからなければ null）
+#[derive(Debug, PartialEq, Clone)]
+pub struct LspMessageError {
+    pub id: Value,
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl LspMessageError {
+    fn new(id: Value, code: ErrorCode, message: impl Into<String>) -> Self {
+        LspMessageError { id, code, message: message.into() }
+    }
+
+    // クライアントへ返すエラー応答（Content-Length 付き）を作る
+    pub fn to_error_response(&self) -> String {
+        create_lsp_error_response(self.id.clone(), self.code.code(), self.message.clone(), None)
+    }
 }
 
 pub fn parse_full_lsp_message(full_message: &str) -> Option<LspMessage> {
+    try_parse_full_lsp_message(full_message).ok()
+}
+
+// parse_full_lsp_message と同じだが、失敗した理由を JSON-RPC のエラーとして返す
+// - ヘッダーが壊れている / JSON として読めない → ParseError (-32700)
+// - jsonrpc が "2.0" でない、method が無い、id の型が不正など → InvalidRequest (-32600)
+pub fn try_parse_full_lsp_message(full_message: &str) -> Result<LspMessage, LspMessageError> {
     let json_content = parse_lsp_message(full_message)
-        .and_then(|(_, content_str)| parse_json_content(content_str))?;
+        .and_then(|(_, content_str)| parse_json_content(content_str))
+        .ok_or_else(|| LspMessageError::new(Value::Null, ErrorCode::ParseError, "Parse error: invalid JSON content."))?;
+
+    if !json_content.is_object() {
+        return Err(LspMessageError::new(Value::Null, ErrorCode::InvalidRequest, "Invalid request: message must be a JSON object."));
+    }
+
+    // エラー応答に載せる id（数値か文字列のときだけ）
+    let id = json_content.get("id").filter(|v| v.is_string() || v.is_number()).cloned();
+    let reply_id = id.clone().unwrap_or(Value::Null);
 
     // jsonrpcが"2.0"であることを確認
     let is_valid_lsp_jsonrpc = json_content.get("jsonrpc")
                                            .and_then(|v| v.as_str())
                                            .map_or(false, |s| s == "2.0");
     if !is_valid_lsp_jsonrpc {
-        return None;
+        return Err(LspMessageError::new(reply_id, ErrorCode::InvalidRequest, "Invalid request: jsonrpc must be \"2.0\"."));
     }
 
-    let method = get_lsp_method(&json_content)?; // メソッドはリクエスト/通知両方に必須
+    // id があるなら数値か文字列でなければならない（応答の場合だけ null も許される）
+    let has_invalid_id = json_content.get("id").is_some_and(|v| !(v.is_string() || v.is_number() || v.is_null()));
+    if has_invalid_id {
+        return Err(LspMessageError::new(Value::Null, ErrorCode::InvalidRequest, "Invalid request: id must be a number or a string."));
+    }
+
+    if json_content.get("method").is_none() {
+        return parse_response(&json_content, reply_id);
+    }
+
+    let method = get_lsp_method(&json_content) // メソッドはリクエスト/通知両方に必須
+        .ok_or_else(|| LspMessageError::new(reply_id.clone(), ErrorCode::InvalidRequest, "Invalid request: method must be a string."))?;
     let params = get_lsp_params(&json_content); // パラメータはOption<Value>なのでそのまま
 
     if let Some(id) = is_request_and_get_id(&json_content) {
-        Some(LspMessage::Request { id, method, params })
+        Ok(LspMessage::Request { id, method, params })
+    } else if json_content.get("id").is_some() {
+        // "id": null のリクエストは受け付けない
+        Err(LspMessageError::new(Value::Null, ErrorCode::InvalidRequest, "Invalid request: id must be a number or a string."))
     } else {
-        Some(LspMessage::Notification { method, params })
+        Ok(LspMessage::Notification { method, params })
     }
 }
 
+// method を持たないメッセージは応答として解釈する
+fn parse_response(json_content: &Value, reply_id: Value) -> Result<LspMessage, LspMessageError> {
+    let result = json_content.get("result").cloned();
+    let error = json_content.get("error");
+
+    let id = match json_content.get("id") {
+        Some(id) if result.is_some() != error.is_some() => id.clone(),
+        _ => return Err(LspMessageError::new(reply_id, ErrorCode::InvalidRequest, "Invalid request: missing method.")),
+    };
+
+    let error = error
+        .map(|error| serde_json::from_value::<ResponseError>(error.clone()))
+        .transpose()
+        .map_err(|err| LspMessageError::new(reply_id, ErrorCode::InvalidRequest, format!("Invalid response error object: {}", err)))?;
+
+    Ok(LspMessage::Response { id, result, error })
+}
 
 // --- Tests --- //
 
 #[cfg(test)]
 mod tests {
-    use super::{parse_full_lsp_message, LspMessage};
-    use serde_json::json;
+    use super::{parse_full_lsp_message, try_parse_full_lsp_message, LspMessage, ResponseError};
+    use crate::lessons::lesson_1::lesson_1_11::ErrorCode;
+    use serde_json::{json, Value};
+
+    fn frame(json_content: &Value) -> String {
+        let content_str = serde_json::to_string(json_content).unwrap();
+        format!("Content-Length: {}\r\n\r\n{}", content_str.len(), content_str)
+    }
 
     #[test]
     fn test_parse_valid_request_message() {
@@ -151,4 +253,92 @@ mod tests {
             })
         );
     }
+
+    #[test]
+    fn test_number_and_string_ids_are_distinct() {
+        let number_id = parse_full_lsp_message(&frame(&json!({"jsonrpc": "2.0", "id": 1, "method": "shutdown"})));
+        let string_id = parse_full_lsp_message(&frame(&json!({"jsonrpc": "2.0", "id": "1", "method": "shutdown"})));
+
+        assert!(matches!(&number_id, Some(LspMessage::Request { id, .. }) if *id == json!(1)));
+        assert!(matches!(&string_id, Some(LspMessage::Request { id, .. }) if *id == json!("1")));
+        assert_ne!(number_id, string_id, "1 and \"1\" must not be treated as the same id.");
+    }
+
+    #[test]
+    fn test_parse_success_response() {
+        let parsed = try_parse_full_lsp_message(&frame(&json!({"jsonrpc": "2.0", "id": 7, "result": {"applied": true}})));
+        assert_eq!(
+            parsed,
+            Ok(LspMessage::Response { id: json!(7), result: Some(json!({"applied": true})), error: None })
+        );
+    }
+
+    #[test]
+    fn test_parse_null_result_response() {
+        let parsed = try_parse_full_lsp_message(&frame(&json!({"jsonrpc": "2.0", "id": "abc", "result": null})));
+        assert_eq!(
+            parsed,
+            Ok(LspMessage::Response { id: json!("abc"), result: Some(Value::Null), error: None }),
+            "A null result is still a success response."
+        );
+    }
+
+    #[test]
+    fn test_parse_error_response() {
+        let parsed = try_parse_full_lsp_message(&frame(&json!({
+            "jsonrpc": "2.0",
+            "id": 2,
+            "error": {"code": -32601, "message": "Unhandled method"}
+        })));
+        assert_eq!(
+            parsed,
+            Ok(LspMessage::Response {
+                id: json!(2),
+                result: None,
+                error: Some(ResponseError { code: -32601, message: "Unhandled method".to_string(), data: None }),
+            })
+        );
+    }
+
+    #[test]
+    fn test_invalid_json_is_parse_error() {
+        let error = try_parse_full_lsp_message("Content-Length: 5\r\n\r\n{abc}").unwrap_err();
+        assert_eq!(error.code, ErrorCode::ParseError);
+        assert_eq!(error.id, Value::Null, "The id of an unparsable message is unknown.");
+
+        let response: Value = serde_json::from_str(error.to_error_response().split_once("\r\n\r\n").unwrap().1).unwrap();
+        assert_eq!(response["error"]["code"], -32700);
+        assert!(response["id"].is_null());
+    }
+
+    #[test]
+    fn test_missing_method_is_invalid_request() {
+        let error = try_parse_full_lsp_message(&frame(&json!({"jsonrpc": "2.0", "id": 3, "params": {}}))).unwrap_err();
+        assert_eq!(error.code, ErrorCode::InvalidRequest);
+        assert_eq!(error.id, json!(3), "The error should be reported against the request id.");
+
+        let response: Value = serde_json::from_str(error.to_error_response().split_once("\r\n\r\n").unwrap().1).unwrap();
+        assert_eq!(response["error"]["code"], -32600);
+        assert_eq!(response["id"], 3);
+    }
+
+    #[test]
+    fn test_invalid_id_type_is_invalid_request() {
+        let error = try_parse_full_lsp_message(&frame(&json!({"jsonrpc": "2.0", "id": {"a": 1}, "method": "shutdown"}))).unwrap_err();
+        assert_eq!(error.code, ErrorCode::InvalidRequest);
+
+        let error = try_parse_full_lsp_message(&frame(&json!({"jsonrpc": "2.0", "id": null, "method": "shutdown"}))).unwrap_err();
+        assert_eq!(error.code, ErrorCode::InvalidRequest, "Requests must not use a null id.");
+    }
+
+    #[test]
+    fn test_response_with_both_result_and_error_is_invalid() {
+        let error = try_parse_full_lsp_message(&frame(&json!({
+            "jsonrpc": "2.0",
+            "id": 4,
+            "result": 1,
+            "error": {"code": -32603, "message": "oops"}
+        }))).unwrap_err();
+        assert_eq!(error.code, ErrorCode::InvalidRequest);
+    }
 }
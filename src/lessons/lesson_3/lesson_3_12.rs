@@ -356,11 +356,35 @@ impl SymbolTable {
     }
 }
 
+// 名前の種類（エディタのホバーなどで使う）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Variable,
+    Parameter,
+    Function,
+    Struct,
+    Field,
+}
+
+// 名前の出現1つと、それが解決された先
+// 定義そのものの出現も記録する（そのときは span と definition_span が同じ）
+#[derive(Debug, Clone, PartialEq)]
+pub struct Resolution {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub span: Span,
+    pub symbol_type: Type,
+    pub definition_span: Span,
+}
+
 // 構造体対応型チェッカー
 #[derive(Debug)]
 pub struct StructTypeChecker {
     symbol_table: SymbolTable,
     diagnostics: Vec<Diagnostic>,
+    // スコープを出るとシンボルテーブルから消えてしまうので、チェックしながら解決の結果と式の型を残しておく
+    resolutions: Vec<Resolution>,
+    expression_types: Vec<(Span, Type)>,
 }
 
 impl StructTypeChecker {
@@ -368,6 +392,8 @@ impl StructTypeChecker {
         StructTypeChecker {
             symbol_table: SymbolTable::new(),
             diagnostics: Vec::new(),
+            resolutions: Vec::new(),
+            expression_types: Vec::new(),
         }
     }
 
@@ -404,11 +430,12 @@ impl StructTypeChecker {
                     }
                 }
 
-                if let Err(diagnostic) =
-                    self.symbol_table
-                        .define(name.clone(), inferred_type, span.clone())
-                {
-                    self.diagnostics.push(diagnostic);
+                // 型注釈があれば、その型の変数にする（rustc と同じ）
+                // 値の型が違っていたら上で E0002 を報告済みなので、後ろで変数を使うたびに同じ食い違いを報告し直さずに済む
+                let symbol_type = type_annotation.clone().unwrap_or(inferred_type);
+                match self.symbol_table.define(name.clone(), symbol_type.clone(), span.clone()) {
+                    Ok(()) => self.record_definition(name, SymbolKind::Variable, span, &symbol_type),
+                    Err(diagnostic) => self.diagnostics.push(diagnostic),
                 }
             }
             Stmt::Expression(expr) => {
@@ -484,22 +511,18 @@ impl StructTypeChecker {
                     return_type: Box::new(return_type.clone().unwrap_or(Type::Unknown)),
                 };
 
-                if let Err(diagnostic) =
-                    self.symbol_table
-                        .define(name.clone(), function_type, span.clone())
-                {
-                    self.diagnostics.push(diagnostic);
+                match self.symbol_table.define(name.clone(), function_type.clone(), span.clone()) {
+                    Ok(()) => self.record_definition(name, SymbolKind::Function, span, &function_type),
+                    Err(diagnostic) => self.diagnostics.push(diagnostic),
                 }
 
                 self.symbol_table.enter_scope();
 
                 for param in parameters {
                     let param_type = param.param_type.clone().unwrap_or(Type::Unknown);
-                    if let Err(diagnostic) =
-                        self.symbol_table
-                            .define(param.name.clone(), param_type, param.span.clone())
-                    {
-                        self.diagnostics.push(diagnostic);
+                    match self.symbol_table.define(param.name.clone(), param_type.clone(), param.span.clone()) {
+                        Ok(()) => self.record_definition(&param.name, SymbolKind::Parameter, &param.span, &param_type),
+                        Err(diagnostic) => self.diagnostics.push(diagnostic),
                     }
                 }
 
@@ -527,21 +550,66 @@ impl StructTypeChecker {
                     }
                 }
 
-                if let Err(diagnostic) = self.symbol_table.define(name.clone(), s, span.clone()) {
-                    self.diagnostics.push(diagnostic);
+                match self.symbol_table.define(name.clone(), s.clone(), span.clone()) {
+                    Ok(()) => {
+                        self.record_definition(name, SymbolKind::Struct, span, &s);
+                        for field in fields {
+                            self.record_definition(&field.name, SymbolKind::Field, &field.span, &field.field_type);
+                        }
+                    }
+                    Err(diagnostic) => self.diagnostics.push(diagnostic),
                 }
             }
         }
     }
 
+    // 式の型を推論し、推論できた型を式の span と一緒に残す版（推論そのものは infer_expression_type_inner）
     fn infer_expression_type(&mut self, expr: &Expr) -> Option<Type> {
+        let inferred = self.infer_expression_type_inner(expr);
+        if let Some(inferred) = &inferred {
+            self.expression_types.push((expr.span().clone(), inferred.clone()));
+        }
+        inferred
+    }
+
+    fn record_definition(&mut self, name: &str, kind: SymbolKind, span: &Span, symbol_type: &Type) {
+        self.resolutions.push(Resolution {
+            name: name.to_string(),
+            kind,
+            span: span.clone(),
+            symbol_type: symbol_type.clone(),
+            definition_span: span.clone(),
+        });
+    }
+
+    // span に出てきた名前が symbol に解決されたことを残す（名前の種類は定義のときに残したものを使う）
+    fn record_use(&mut self, span: &Span, symbol: &Symbol) {
+        let Some(kind) = self
+            .resolutions
+            .iter()
+            .find(|resolution| resolution.name == symbol.name && resolution.span == symbol.definition_span)
+            .map(|definition| definition.kind)
+        else {
+            return;
+        };
+        self.resolutions.push(Resolution {
+            name: symbol.name.clone(),
+            kind,
+            span: span.clone(),
+            symbol_type: symbol.symbol_type.clone(),
+            definition_span: symbol.definition_span.clone(),
+        });
+    }
+
+    fn infer_expression_type_inner(&mut self, expr: &Expr) -> Option<Type> {
         match expr {
             Expr::Number(_, _) => Some(Type::Integer),
             Expr::Boolean(_, _) => Some(Type::Boolean),
             Expr::String(_, _) => Some(Type::String),
             Expr::Identifier(name, span) => {
-                if let Some(symbol) = self.symbol_table.resolve(name) {
-                    Some(symbol.symbol_type.clone())
+                if let Some(symbol) = self.symbol_table.resolve(name).cloned() {
+                    self.record_use(span, &symbol);
+                    Some(symbol.symbol_type)
                 } else {
                     self.diagnostics.push(
                         Diagnostic::error(format!("Variable '{}' not defined", name), span.clone())
@@ -615,8 +683,9 @@ impl StructTypeChecker {
                 arguments,
                 span,
             } => {
-                let function_type = if let Some(symbol) = self.symbol_table.resolve(name) {
-                    Some(symbol.symbol_type.clone())
+                let function_type = if let Some(symbol) = self.symbol_table.resolve(name).cloned() {
+                    self.record_use(span, &symbol);
+                    Some(symbol.symbol_type)
                 } else {
                     self.diagnostics.push(
                         Diagnostic::error(format!("Function '{}' not defined", name), span.clone())
@@ -671,13 +740,14 @@ impl StructTypeChecker {
                 }
             }
             Expr::Assignment { name, value, span } => {
-                if self.symbol_table.resolve(name).is_none() {
+                let Some(symbol) = self.symbol_table.resolve(name).cloned() else {
                     self.diagnostics.push(
                         Diagnostic::error(format!("Variable '{}' not defined", name), span.clone())
                             .with_code("E0004".to_string()),
                     );
                     return None;
-                }
+                };
+                self.record_use(span, &symbol);
 
                 self.infer_expression_type(value)
             }
@@ -695,7 +765,16 @@ impl StructTypeChecker {
                 match self.infer_expression_type(object) {
                     Some(Type::Struct { name: _, fields }) => {
                         match fields.iter().find(|f| f.name == *field_name) {
-                            Some(field) => Some(field.field_type.clone()),
+                            Some(field) => {
+                                self.resolutions.push(Resolution {
+                                    name: field.name.clone(),
+                                    kind: SymbolKind::Field,
+                                    span: span.clone(),
+                                    symbol_type: field.field_type.clone(),
+                                    definition_span: field.span.clone(),
+                                });
+                                Some(field.field_type.clone())
+                            }
                             None => {
                                 self.diagnostics.push(Diagnostic::error(
                                     format!("no field: {}", field_name),
@@ -729,6 +808,7 @@ impl StructTypeChecker {
 
                 // Step 1: 構造体型が定義されているかチェック
                 let struct_symbol = self.symbol_table.resolve(struct_name)?.clone();
+                self.record_use(span, &struct_symbol);
 
                 if let Type::Struct { fields, .. } = &struct_symbol.symbol_type {
                     // Step 2: 提供されたフィールドが全て存在するかチェック
@@ -776,6 +856,16 @@ impl StructTypeChecker {
     pub fn get_symbol_table(&self) -> &SymbolTable {
         &self.symbol_table
     }
+
+    // チェックした名前の出現と解決先（出てきた順）
+    pub fn get_resolutions(&self) -> &Vec<Resolution> {
+        &self.resolutions
+    }
+
+    // 型を推論できた式の span と型（内側の式から順に）
+    pub fn get_expression_types(&self) -> &Vec<(Span, Type)> {
+        &self.expression_types
+    }
 }
 
 // 公開API
@@ -944,4 +1034,92 @@ mod tests {
         // 2つ目のエラー：存在しないフィールドへのアクセス
         assert!(diagnostics[1].message.contains("no field"));
     }
+
+    #[test]
+    fn test_annotated_variable_takes_the_annotated_type() {
+        // let flag: bool = 1;
+        // let copy: bool = flag;
+        let program = Program {
+            statements: vec![
+                Stmt::LetDeclaration {
+                    name: "flag".to_string(),
+                    value: Expr::Number(1, Span::single(Position::new(0, 17))),
+                    type_annotation: Some(Type::Boolean),
+                    span: Span::single(Position::new(0, 4)),
+                },
+                Stmt::LetDeclaration {
+                    name: "copy".to_string(),
+                    value: Expr::Identifier("flag".to_string(), Span::single(Position::new(1, 17))),
+                    type_annotation: Some(Type::Boolean),
+                    span: Span::single(Position::new(1, 4)),
+                },
+            ],
+        };
+
+        let mut checker = StructTypeChecker::new();
+        let diagnostics = checker.check_program(&program);
+
+        // 食い違いは1行目の1回だけ（flag は bool として読むので、2行目はエラーにならない）
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, Some("E0002".to_string()));
+        assert_eq!(diagnostics[0].span, Span::single(Position::new(0, 4)));
+        assert_eq!(checker.get_symbol_table().resolve("flag").map(|symbol| &symbol.symbol_type), Some(&Type::Boolean));
+    }
+
+    #[test]
+    fn test_resolutions_and_expression_types_are_recorded() {
+        // fn main() { let point = Point { x: 1 }; point.x; }
+        let x_field = Span::single(Position::new(0, 15));
+        let point_definition = Span::single(Position::new(1, 4));
+        let point_use = Span::single(Position::new(2, 0));
+        let field_use = Span::single(Position::new(2, 6));
+        let program = Program {
+            statements: vec![
+                Stmt::StructDeclaration {
+                    name: "Point".to_string(),
+                    fields: vec![Field { name: "x".to_string(), field_type: Type::Integer, span: x_field.clone() }],
+                    span: Span::single(Position::new(0, 7)),
+                },
+                Stmt::Block {
+                    statements: vec![
+                        Stmt::LetDeclaration {
+                            name: "point".to_string(),
+                            value: Expr::StructConstructor {
+                                struct_name: "Point".to_string(),
+                                field_values: vec![("x".to_string(), Expr::Number(1, Span::single(Position::new(1, 20))))],
+                                span: Span::single(Position::new(1, 12)),
+                            },
+                            type_annotation: None,
+                            span: point_definition.clone(),
+                        },
+                        Stmt::Expression(Expr::FieldAccess {
+                            object: Box::new(Expr::Identifier("point".to_string(), point_use.clone())),
+                            field_name: "x".to_string(),
+                            span: field_use.clone(),
+                        }),
+                    ],
+                    span: Span::new(Position::new(1, 0), Position::new(3, 0)),
+                },
+            ],
+        };
+
+        let mut checker = StructTypeChecker::new();
+        assert!(checker.check_program(&program).is_empty());
+
+        // ブロックを出た後でも、中の名前の解決先が残っている
+        let resolutions = checker.get_resolutions();
+        let point = resolutions.iter().find(|resolution| resolution.span == point_use).unwrap();
+        assert_eq!(point.kind, SymbolKind::Variable);
+        assert_eq!(point.definition_span, point_definition);
+        assert!(matches!(point.symbol_type.resolve(), Type::Struct { name, .. } if name == "Point"));
+
+        let field = resolutions.iter().find(|resolution| resolution.span == field_use).unwrap();
+        assert_eq!((field.kind, &field.symbol_type, &field.definition_span), (SymbolKind::Field, &Type::Integer, &x_field));
+
+        let constructor = resolutions.iter().find(|resolution| resolution.span == Span::single(Position::new(1, 12))).unwrap();
+        assert_eq!(constructor.kind, SymbolKind::Struct);
+
+        let literal = Span::single(Position::new(1, 20));
+        assert!(checker.get_expression_types().contains(&(literal, Type::Integer)));
+    }
 }
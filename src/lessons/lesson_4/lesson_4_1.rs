@@ -9,6 +9,7 @@ use super::common::{
     diagnostic::{Diagnostic, DiagnosticCategory},
     span::Span,
 };
+use std::collections::HashMap;
 
 // シンボル情報（使用状況追跡）
 #[derive(Debug, Clone)]
@@ -50,11 +51,6 @@ impl UnusedVariableChecker {
         // Phase 1: 変数定義を収集
         self.collect_definitions(program);
 
-        /// comment
-        /// rearea
-        use std::collections::HashMap as Map;
-        use std::collections::HashMap;
-
         // Phase 2: 変数使用を追跡
         self.track_usage(program);
 
@@ -178,6 +178,22 @@ impl VariableRenamer {
         }
     }
 
+    // リネームはせずに、衝突だけを調べる（エディタのリネームで、編集を作る前に確かめるのに使う）
+    // target_position に変数が無ければ None
+    pub fn find_rename_conflict(
+        &mut self,
+        program: &ScopedProgram,
+        target_position: Position,
+        new_name: &str,
+    ) -> Option<String> {
+        self.variables.clear();
+        self.current_scope = 0;
+        self.collect_variables(program);
+
+        let target_var = self.find_target_variable(&target_position)?;
+        self.check_rename_conflicts(target_var, new_name)
+    }
+
     // Phase 1: 変数定義と使用箇所の収集
     fn collect_variables(&mut self, program: &ScopedProgram) {
         for stmt in &program.statements {
@@ -274,7 +290,7 @@ impl VariableRenamer {
     // Phase 3: リネームの衝突チェック
     fn check_rename_conflicts(
         &self,
-        _target_var: &VariableDefinition,
+        target_var: &VariableDefinition,
         new_name: &str,
     ) -> Option<String> {
         // todo!("リネームの衝突チェックを実装してください")
@@ -288,7 +304,7 @@ impl VariableRenamer {
             if new_name == var_name {
                 if let Some(_conflict_def) = var_defs
                     .iter()
-                    .find(|def| def.scope_id == self.current_scope)
+                    .find(|def| def.scope_id == target_var.scope_id)
                 {
                     return Some(
                         "can not rename to existing variable name in the same scope".to_string(),
@@ -441,6 +457,33 @@ mod tests {
         assert!(result.edits.iter().all(|edit| edit.new_text == "renamed"));
     }
 
+    #[test]
+    fn test_conflicts_only_in_the_same_scope() {
+        let declaration = |name: &str, line: usize, scope_id: usize| ScopedStmt::LetDeclaration {
+            name: name.to_string(),
+            value: ScopedExpr::Number(0, Span::single(Position::new(line, 12))),
+            span: Span::new(Position::new(line, 4), Position::new(line, 4 + name.len())),
+            scope_id,
+        };
+        let program = ScopedProgram {
+            statements: vec![
+                declaration("outer", 0, 0),
+                ScopedStmt::Block {
+                    statements: vec![declaration("inner", 2, 1), declaration("other", 3, 1)],
+                    span: Span::new(Position::new(1, 0), Position::new(4, 1)),
+                    scope_id: 1,
+                },
+            ],
+        };
+
+        let mut renamer = VariableRenamer::new();
+        // 外側の変数と同じ名前にするのは（隠すだけなので）衝突ではない
+        assert_eq!(renamer.find_rename_conflict(&program, Position::new(2, 5), "outer"), None);
+        assert!(renamer.find_rename_conflict(&program, Position::new(2, 5), "other").is_some());
+        assert!(renamer.find_rename_conflict(&program, Position::new(0, 5), "inner").is_none());
+        assert_eq!(renamer.find_rename_conflict(&program, Position::new(9, 0), "other"), None, "no variable there");
+    }
+
     #[test]
     fn test_variable_not_found() {
         let program = ScopedProgram {
@@ -5,5 +5,6 @@
 
 pub mod common;
 pub mod lessons;
+pub mod server;
 
 pub mod rust_analyzer;
\ No newline at end of file
@@ -0,0 +1,977 @@
+// ドキュメントの意味解析（ホバーや定義へのジャンプなどで使う）
+// common::syntax のトークンから lesson_3_12 の AST を組み立てて StructTypeChecker にかけ、
+// 名前の出現ごとの解決先（Resolution）と、式の型を取り出す
+// - checks.rs と同じく完全な構文解析はしない。読めない式は型の分からない値（UNKNOWN）にして、中の名前の解決だけは続ける
+// - アイテム（fn / struct）は書いた順に関係なく使えるので、ブロックの先頭で先に宣言しておく
+// - 同じブロックで同じ名前を let し直す（シャドーイング）と、そこから後ろを1つ内側のブロックにする
+//   （lesson_3_12 のシンボルテーブルは同じスコープでの再定義をエラーにするので）
+// - 参照（&x）や *x は x と同じ型として扱う（lesson_3_12 の型には参照が無い）
+// チェッカーの診断はここでは使わない（アイテムを先に宣言した分の「定義済み」のエラーなどが混ざるため）
+
+use std::collections::{HashMap, HashSet};
+
+use lsp_types::{PositionEncodingKind, Range};
+
+use crate::common::line_index::LineIndex;
+use crate::common::syntax::{leading_keyword, split_statements, token_trees, tokenize, Token, TokenKind, TokenTree};
+use crate::lessons::lesson_3::lesson_3_12::{
+    BinaryOp, Expr, Field, Parameter, Position, Program, Resolution, Span, Stmt, StructTypeChecker, SymbolKind, Type,
+};
+
+// 型の分からない値として渡す名前（どこにも定義されないので、チェッカーは Unknown として扱う）
+const UNKNOWN: &str = "_";
+
+pub struct Analysis {
+    resolutions: Vec<Resolution>,
+    expression_types: Vec<(Span, Type)>,
+}
+
+impl Analysis {
+    pub fn new(text: &str, encoding: &PositionEncodingKind) -> Self {
+        let index = LineIndex::new(text, encoding.clone());
+        let tokens = tokenize(text);
+        let trees = token_trees(&tokens);
+
+        let mut lowering = Lowering { index: &index, structs: HashMap::new() };
+        lowering.collect_structs(&trees);
+        let program = Program { statements: lowering.lower_block(&trees, None) };
+
+        let mut checker = StructTypeChecker::new();
+        checker.check_program(&program);
+
+        // 先に宣言したアイテムは、定義が2回記録されることがある（同じ出現と定義の組は1つにする）
+        let mut seen = HashSet::new();
+        let resolutions: Vec<Resolution> = checker
+            .get_resolutions()
+            .iter()
+            .filter(|resolution| seen.insert((to_range(&resolution.span), to_range(&resolution.definition_span))))
+            .cloned()
+            .collect();
+        Analysis { resolutions, expression_types: checker.get_expression_types().clone() }
+    }
+
+    pub fn resolutions(&self) -> &[Resolution] {
+        &self.resolutions
+    }
+
+    // range にちょうど重なる名前の出現
+    pub fn resolution_at(&self, range: Range) -> Option<&Resolution> {
+        self.resolutions.iter().find(|resolution| to_range(&resolution.span) == range)
+    }
+
+    // range にちょうど重なる式の型
+    pub fn type_at(&self, range: Range) -> Option<&Type> {
+        self.expression_types.iter().find(|(span, _)| to_range(span) == range).map(|(_, expression_type)| expression_type)
+    }
+
+    // range の名前・式の型が構造体なら、その構造体の定義（関数なら戻り値の型を見る）
+    pub fn type_definition(&self, range: Range) -> Option<&Resolution> {
+        let ty = match self.resolution_at(range) {
+            Some(resolution) => &resolution.symbol_type,
+            None => self.type_at(range)?,
+        };
+        let name = match ty.resolve() {
+            Type::Struct { name, .. } => name,
+            Type::Function { return_type, .. } => match return_type.resolve() {
+                Type::Struct { name, .. } => name,
+                _ => return None,
+            },
+            _ => return None,
+        };
+        self.resolutions.iter().find(|resolution| {
+            resolution.kind == SymbolKind::Struct && resolution.name == *name && resolution.span == resolution.definition_span
+        })
+    }
+}
+
+// position にあるトークン（名前の直後にカーソルがあるときも、その名前にする）
+pub fn token_at<'a>(text: &'a str, index: &LineIndex, position: lsp_types::Position) -> Option<Token<'a>> {
+    let offset = index.offset(position)?;
+    let tokens = tokenize(text);
+    let touching: Vec<&Token> = tokens.iter().filter(|token| token.start <= offset && offset <= token.end()).collect();
+    touching
+        .iter()
+        .find(|token| token.kind != TokenKind::Punct)
+        .or(touching.first())
+        .map(|token| **token)
+}
+
+// lesson_3_12 の Span → LSP の Range（Span には LineIndex で変換した行・列がそのまま入っている）
+pub fn to_range(span: &Span) -> Range {
+    let position = |position: &Position| lsp_types::Position::new(position.line as u32, position.column as u32);
+    Range::new(position(&span.start), position(&span.end))
+}
+
+// Rust の書き方に近い型の表示
+// lesson_3_12 の整数型は1種類だけなので i32 と表示する
+pub fn display_type(ty: &Type) -> String {
+    match ty.resolve() {
+        Type::Integer => "i32".to_string(),
+        Type::Boolean => "bool".to_string(),
+        Type::String => "String".to_string(),
+        Type::Function { parameters, return_type } => {
+            let parameters: Vec<String> = parameters.iter().map(display_type).collect();
+            format!("fn({}){}", parameters.join(", "), display_return_type(return_type))
+        }
+        Type::Struct { name, .. } => name.clone(),
+        Type::Unknown | Type::Inferred(_) => "{unknown}".to_string(),
+    }
+}
+
+// " -> 型"（戻り値の型が分からない・書かれていないときは何も付けない）
+pub fn display_return_type(return_type: &Type) -> String {
+    match return_type.resolve() {
+        Type::Unknown => String::new(),
+        return_type => format!(" -> {}", display_type(return_type)),
+    }
+}
+
+struct Lowering<'i> {
+    index: &'i LineIndex,
+    // 構造体の名前 → フィールド（型注釈の構造体名を Type::Struct にするのに使う）
+    structs: HashMap<String, Vec<Field>>,
+}
+
+impl Lowering<'_> {
+    fn span(&self, start: usize, end: usize) -> Span {
+        let position = |offset: usize| {
+            let position = self.index.position(offset);
+            Position::new(position.line as usize, position.character as usize)
+        };
+        Span::new(position(start), position(end))
+    }
+
+    fn token_span(&self, token: &Token) -> Span {
+        self.span(token.start, token.end())
+    }
+
+    fn unknown(&self, at: usize) -> Expr {
+        Expr::Identifier(UNKNOWN.to_string(), self.span(at, at))
+    }
+
+    // ドキュメント中の構造体を集める
+    // フィールドの型が別の構造体のこともあるので、2回読んで1段目の入れ子まで型が分かるようにする
+    fn collect_structs(&mut self, trees: &[TokenTree]) {
+        for _ in 0..2 {
+            let mut structs = HashMap::new();
+            self.collect_structs_into(trees, &mut structs);
+            self.structs = structs;
+        }
+    }
+
+    fn collect_structs_into(&self, trees: &[TokenTree], structs: &mut HashMap<String, Vec<Field>>) {
+        for statement in split_statements(trees) {
+            if leading_keyword(statement) == Some("struct") {
+                if let Some((name, fields)) = self.lower_struct(statement) {
+                    structs.entry(name.text.to_string()).or_insert(fields);
+                }
+            }
+            for tree in statement {
+                if let TokenTree::Group { children, .. } = tree {
+                    self.collect_structs_into(children, structs);
+                }
+            }
+        }
+    }
+
+    // ブロックの中身（文の並び）
+    // self_type: impl の中なら、その型の名前（`self` / `Self` の型になる）
+    fn lower_block(&self, trees: &[TokenTree], self_type: Option<&str>) -> Vec<Stmt> {
+        let statements = split_statements(trees);
+
+        // アイテムを先に宣言しておく（関数の本体は空。本当の宣言のときに中身をチェックする）
+        let mut items = Vec::new();
+        for statement in &statements {
+            match leading_keyword(statement) {
+                Some("struct") => items.extend(self.lower_struct_declaration(statement)),
+                Some("fn") => items.extend(self.lower_function(statement, self_type, false)),
+                _ => {}
+            }
+        }
+
+        // segments: シャドーイングのたびに1つ内側のブロックになる文の並び
+        let mut segments: Vec<(Vec<Stmt>, HashSet<String>, Span)> = vec![(items, HashSet::new(), self.span(0, 0))];
+        for statement in statements {
+            let mut lowered = Vec::new();
+            self.lower_statement(statement, self_type, &mut lowered);
+            for stmt in lowered {
+                if let Stmt::LetDeclaration { name, span, .. } = &stmt {
+                    if segments.last().is_some_and(|(_, names, _)| names.contains(name)) {
+                        segments.push((Vec::new(), HashSet::new(), span.clone()));
+                    }
+                    segments.last_mut().unwrap().1.insert(name.clone());
+                }
+                segments.last_mut().unwrap().0.push(stmt);
+            }
+        }
+
+        let (mut block, _, _) = segments.pop().unwrap();
+        while let Some((mut outer, _, _)) = segments.pop() {
+            let span = block.first().map_or(self.span(0, 0), |stmt| stmt.span().clone());
+            outer.push(Stmt::Block { statements: block, span });
+            block = outer;
+        }
+        block
+    }
+
+    // 文1つ。式の中から取り出した文（UNKNOWN にした式の中身など）も含めて、チェックする順に lowered に足す
+    fn lower_statement(&self, statement: &[TokenTree], self_type: Option<&str>, lowered: &mut Vec<Stmt>) {
+        let statement = match statement {
+            [rest @ .., last] if last.leaf().is_some_and(|token| token.is_punct(";")) => rest,
+            _ => statement,
+        };
+        let Some(first) = statement.first() else {
+            return;
+        };
+        match leading_keyword(statement) {
+            // 先に宣言してある
+            Some("struct") => {}
+            Some("fn") => lowered.extend(self.lower_function(statement, self_type, true)),
+            Some("impl") => {
+                let self_type = impl_self_type(statement);
+                if let Some(body) = statement.last().and_then(|tree| tree.group("{")) {
+                    let span = self.span(first.first().start, first.end());
+                    lowered.push(Stmt::Block { statements: self.lower_block(body, self_type), span });
+                }
+            }
+            Some("let") | Some("const") | Some("static") => self.lower_let(statement, self_type, lowered),
+            Some("if") | Some("while") => {
+                let stmt = self.lower_conditional(statement, self_type, lowered);
+                lowered.extend(stmt);
+            }
+            Some("for") => self.lower_for(statement, self_type, lowered),
+            Some("return") | Some("break") => {
+                if statement.len() > 1 {
+                    let value = self.lower_expr(&statement[1..], self_type, lowered);
+                    lowered.push(Stmt::Expression(value));
+                }
+            }
+            Some("use") | Some("mod") | Some("enum") | Some("trait") | Some("type") | Some("extern") | Some("match")
+            | Some("loop") | Some("unsafe") => self.collect_blocks(statement, self_type, lowered),
+            _ => self.lower_expression_statement(statement, self_type, lowered),
+        }
+    }
+
+    // 分からない文の中のブロックだけを、それぞれのスコープとして読む
+    fn collect_blocks(&self, trees: &[TokenTree], self_type: Option<&str>, lowered: &mut Vec<Stmt>) {
+        for tree in trees {
+            if let TokenTree::Group { open, children, .. } = tree {
+                if open.text == "{" {
+                    let span = self.span(open.start, tree.end());
+                    lowered.push(Stmt::Block { statements: self.lower_block(children, self_type), span });
+                } else {
+                    self.collect_blocks(children, self_type, lowered);
+                }
+            }
+        }
+    }
+
+    // `struct Name { field: Type, ... }`（タプル構造体・ユニット構造体はフィールド無し）
+    fn lower_struct<'t, 'a>(&self, statement: &'t [TokenTree<'a>]) -> Option<(&'t Token<'a>, Vec<Field>)> {
+        let keyword = statement.iter().position(|tree| tree.leaf().is_some_and(|token| token.is_keyword("struct")))?;
+        let name = statement.get(keyword + 1)?.leaf().filter(|token| token.is_ident())?;
+        let Some(body) = statement.iter().find_map(|tree| tree.group("{")) else {
+            return Some((name, Vec::new()));
+        };
+
+        let fields = split_by(body, ",")
+            .into_iter()
+            .filter_map(|field| {
+                let colon = field.iter().position(|tree| tree.leaf().is_some_and(|token| token.is_punct(":")))?;
+                let name = field[..colon].last()?.leaf().filter(|token| token.is_ident())?;
+                Some(Field { name: name.text.to_string(), field_type: self.lower_type(&field[colon + 1..], None), span: self.token_span(name) })
+            })
+            .collect();
+        Some((name, fields))
+    }
+
+    fn lower_struct_declaration(&self, statement: &[TokenTree]) -> Option<Stmt> {
+        let (name, fields) = self.lower_struct(statement)?;
+        Some(Stmt::StructDeclaration { name: name.text.to_string(), fields, span: self.token_span(name) })
+    }
+
+    // `fn name(params) -> Type { body }`
+    // with_body が false なら、先に宣言しておくための本体の無い宣言
+    fn lower_function(&self, statement: &[TokenTree], self_type: Option<&str>, with_body: bool) -> Option<Stmt> {
+        let keyword = statement.iter().position(|tree| tree.leaf().is_some_and(|token| token.is_keyword("fn")))?;
+        let name = statement.get(keyword + 1)?.leaf().filter(|token| token.is_ident())?;
+        let rest = &statement[keyword + 2..];
+        let parameters_index = rest.iter().position(|tree| tree.group("(").is_some())?;
+        let body_index = rest.iter().position(|tree| tree.group("{").is_some());
+
+        let parameters = split_by(rest[parameters_index].group("(").unwrap_or_default(), ",")
+            .into_iter()
+            .filter_map(|parameter| self.lower_parameter(parameter, self_type))
+            .collect();
+
+        let signature_end = body_index.unwrap_or(rest.len());
+        let after_parameters = &rest[parameters_index + 1..signature_end];
+        let return_type = match after_parameters {
+            [arrow, return_type @ ..] if arrow.leaf().is_some_and(|token| token.is_punct("->")) => {
+                let end = return_type
+                    .iter()
+                    .position(|tree| tree.leaf().is_some_and(|token| token.is_keyword("where")))
+                    .unwrap_or(return_type.len());
+                Some(self.lower_type(&return_type[..end], self_type))
+            }
+            _ => None,
+        };
+
+        let body_statements = match (with_body, body_index) {
+            (true, Some(body_index)) => self.lower_block(rest[body_index].group("{").unwrap_or_default(), self_type),
+            _ => Vec::new(),
+        };
+        let body_span = body_index.map_or(self.token_span(name), |body_index| self.span(rest[body_index].first().start, rest[body_index].end()));
+
+        Some(Stmt::FunctionDeclaration {
+            name: name.text.to_string(),
+            parameters,
+            return_type,
+            body: Box::new(Stmt::Block { statements: body_statements, span: body_span }),
+            span: self.token_span(name),
+        })
+    }
+
+    // `name: Type` / `mut name: Type` / `self` / `&self` / `&mut self` / `mut self`
+    fn lower_parameter(&self, parameter: &[TokenTree], self_type: Option<&str>) -> Option<Parameter> {
+        let tokens: Vec<&Token> = parameter.iter().map_while(TokenTree::leaf).collect();
+        if let Some(receiver) = tokens.iter().find(|token| token.is_keyword("self")) {
+            if tokens.iter().all(|token| token.is_keyword("self") || token.is_keyword("mut") || token.is_punct("&") || token.kind == TokenKind::Lifetime) {
+                let param_type = self_type.map(|name| self.struct_type(name));
+                return Some(Parameter { name: "self".to_string(), param_type, span: self.token_span(receiver) });
+            }
+        }
+
+        let colon = parameter.iter().position(|tree| tree.leaf().is_some_and(|token| token.is_punct(":")))?;
+        let name = match &parameter[..colon] {
+            [name] | [_, name] => name.leaf().filter(|token| token.is_ident() && token.text != UNKNOWN)?,
+            _ => return None,
+        };
+        Some(Parameter {
+            name: name.text.to_string(),
+            param_type: Some(self.lower_type(&parameter[colon + 1..], self_type)),
+            span: self.token_span(name),
+        })
+    }
+
+    // 型注釈（& や mut、ライフタイムは読み飛ばす）
+    fn lower_type(&self, trees: &[TokenTree], self_type: Option<&str>) -> Type {
+        let mut tokens = trees.iter().map_while(TokenTree::leaf).skip_while(|token| {
+            token.is_punct("&") || token.is_keyword("mut") || token.is_keyword("dyn") || token.kind == TokenKind::Lifetime
+        });
+        let Some(name) = tokens.next() else {
+            return Type::Unknown;
+        };
+        // `Vec<i32>` などの型引数のある型は扱わない
+        if tokens.next().is_some() {
+            return Type::Unknown;
+        }
+        match name.text {
+            "i8" | "i16" | "i32" | "i64" | "i128" | "isize" | "u8" | "u16" | "u32" | "u64" | "u128" | "usize" => Type::Integer,
+            "bool" => Type::Boolean,
+            "String" | "str" => Type::String,
+            "Self" => self_type.map_or(Type::Unknown, |name| self.struct_type(name)),
+            name if self.structs.contains_key(name) => self.struct_type(name),
+            _ => Type::Unknown,
+        }
+    }
+
+    fn struct_type(&self, name: &str) -> Type {
+        match self.structs.get(name) {
+            Some(fields) => Type::Struct { name: name.to_string(), fields: fields.clone() },
+            None => Type::Unknown,
+        }
+    }
+
+    // `let name: Type = value` / `const NAME: Type = value`（パターンで束縛するものは値だけ読む）
+    fn lower_let(&self, statement: &[TokenTree], self_type: Option<&str>, lowered: &mut Vec<Stmt>) {
+        let keyword = statement
+            .iter()
+            .position(|tree| tree.leaf().is_some_and(|token| ["let", "const", "static"].iter().any(|keyword| token.is_keyword(keyword))))
+            .unwrap_or(0);
+        let rest = &statement[keyword + 1..];
+        let equals = rest.iter().position(|tree| tree.leaf().is_some_and(|token| token.is_punct("=")));
+        let (pattern, value) = match equals {
+            Some(equals) => (&rest[..equals], Some(&rest[equals + 1..])),
+            None => (rest, None),
+        };
+        let value = match value {
+            Some(value) if !value.is_empty() => self.lower_expr(value, self_type, lowered),
+            _ => self.unknown(statement.last().map_or(0, TokenTree::end)),
+        };
+
+        let colon = pattern.iter().position(|tree| tree.leaf().is_some_and(|token| token.is_punct(":")));
+        let (names, type_annotation) = match colon {
+            Some(colon) => (&pattern[..colon], Some(self.lower_type(&pattern[colon + 1..], self_type))),
+            None => (pattern, None),
+        };
+        let name = match names {
+            [name] => name.leaf(),
+            [mutable, name] if mutable.leaf().is_some_and(|token| token.is_keyword("mut")) => name.leaf(),
+            _ => None,
+        };
+        match name.filter(|name| name.is_ident() && name.text != UNKNOWN) {
+            // 注釈の型が分からないときは、値から推論した型をそのまま使う
+            Some(name) => lowered.push(Stmt::LetDeclaration {
+                name: name.text.to_string(),
+                value,
+                type_annotation: type_annotation.filter(|annotation| *annotation != Type::Unknown),
+                span: self.token_span(name),
+            }),
+            None => lowered.push(Stmt::Expression(value)),
+        }
+    }
+
+    // `if 条件 { ... } else ...` / `while 条件 { ... }`（`if let` / `while let` の条件は読まない）
+    fn lower_conditional(&self, statement: &[TokenTree], self_type: Option<&str>, lowered: &mut Vec<Stmt>) -> Option<Stmt> {
+        let keyword = statement.first()?.leaf()?;
+        let body_index = statement.iter().position(|tree| tree.group("{").is_some())?;
+        let condition_trees = &statement[1..body_index];
+        let condition = match condition_trees.first().and_then(TokenTree::leaf) {
+            Some(token) if token.is_keyword("let") => {
+                self.collect_blocks(condition_trees, self_type, lowered);
+                self.unknown(token.start)
+            }
+            _ if condition_trees.is_empty() => self.unknown(keyword.end()),
+            _ => self.lower_expr(condition_trees, self_type, lowered),
+        };
+        let block = |tree: &TokenTree| Stmt::Block {
+            statements: self.lower_block(tree.group("{").unwrap_or_default(), self_type),
+            span: self.span(tree.first().start, tree.end()),
+        };
+        let body = Box::new(block(&statement[body_index]));
+        let span = self.span(keyword.start, statement.last().map_or(keyword.end(), TokenTree::end));
+
+        if keyword.is_keyword("while") {
+            return Some(Stmt::WhileStatement { condition, body, span });
+        }
+        let else_branch = match &statement[body_index + 1..] {
+            [else_keyword, otherwise @ ..] if else_keyword.leaf().is_some_and(|token| token.is_keyword("else")) => match otherwise {
+                // 入力途中の `if x {} else`
+                [] => None,
+                [tree] if tree.group("{").is_some() => Some(Box::new(block(tree))),
+                _ => {
+                    // else if ...: 条件の中から取り出した文も else の中に入れる
+                    let mut nested = Vec::new();
+                    let stmt = self.lower_conditional(otherwise, self_type, &mut nested);
+                    nested.extend(stmt);
+                    Some(Box::new(Stmt::Block { statements: nested, span: self.span(else_keyword.first().start, else_keyword.end()) }))
+                }
+            },
+            _ => None,
+        };
+        Some(Stmt::IfStatement { condition, then_branch: body, else_branch, span })
+    }
+
+    // `for name in iter { body }` → { let name = UNKNOWN; body }
+    fn lower_for(&self, statement: &[TokenTree], self_type: Option<&str>, lowered: &mut Vec<Stmt>) {
+        let in_index = statement.iter().position(|tree| tree.leaf().is_some_and(|token| token.is_keyword("in")));
+        let body_index = statement.iter().position(|tree| tree.group("{").is_some());
+        let (Some(in_index), Some(body_index)) = (in_index, body_index) else {
+            self.collect_blocks(statement, self_type, lowered);
+            return;
+        };
+        if in_index + 1 < body_index {
+            let iterator = self.lower_expr(&statement[in_index + 1..body_index], self_type, lowered);
+            lowered.push(Stmt::Expression(iterator));
+        }
+
+        let mut statements = Vec::new();
+        if let [name] = &statement[1..in_index] {
+            if let Some(name) = name.leaf().filter(|token| token.is_ident() && token.text != UNKNOWN) {
+                statements.push(Stmt::LetDeclaration {
+                    name: name.text.to_string(),
+                    value: self.unknown(name.start),
+                    type_annotation: None,
+                    span: self.token_span(name),
+                });
+            }
+        }
+        let body = &statement[body_index];
+        statements.push(Stmt::Block {
+            statements: self.lower_block(body.group("{").unwrap_or_default(), self_type),
+            span: self.span(body.first().start, body.end()),
+        });
+        lowered.push(Stmt::Block { statements, span: self.span(statement[0].first().start, body.end()) });
+    }
+
+    // `name = value` / `name += value` / それ以外の式
+    fn lower_expression_statement(&self, statement: &[TokenTree], self_type: Option<&str>, lowered: &mut Vec<Stmt>) {
+        if let [name, rest @ ..] = statement {
+            let assigned_value = match rest {
+                [equals, value @ ..] if is_punct(equals, "=") && !value.first().is_some_and(|tree| is_punct(tree, "=")) => Some(value),
+                [operator, equals, value @ ..] if is_compound_assignment(operator, equals) => Some(value),
+                _ => None,
+            };
+            if let (Some(name), Some(value)) = (name.leaf().filter(|token| token.is_ident()), assigned_value) {
+                let value = self.lower_expr(value, self_type, lowered);
+                lowered.push(Stmt::Expression(Expr::Assignment {
+                    name: name.text.to_string(),
+                    value: Box::new(value),
+                    span: self.token_span(name),
+                }));
+                return;
+            }
+        }
+        let expr = self.lower_expr(statement, self_type, lowered);
+        lowered.push(Stmt::Expression(expr));
+    }
+
+    // 式
+    // 二項演算子のうち一番弱いもの（同じ強さなら一番右）で分け、無ければ単項の式として読む
+    fn lower_expr(&self, trees: &[TokenTree], self_type: Option<&str>, lowered: &mut Vec<Stmt>) -> Expr {
+        let Some(first) = trees.first() else {
+            return self.unknown(0);
+        };
+
+        if let Some((operator_index, operator_len, operator)) = find_binary_operator(trees) {
+            let left = &trees[..operator_index];
+            let right = &trees[operator_index + operator_len..];
+            let span = self.span(first.first().start, trees.last().map_or(first.end(), TokenTree::end));
+            let left = self.lower_expr(left, self_type, lowered);
+            let right = self.lower_expr(right, self_type, lowered);
+            return match operator {
+                Some(operator) => Expr::Binary { left: Box::new(left), operator, right: Box::new(right), span },
+                // lesson_3_12 に無い演算子（範囲 `..` など）は、両辺を読むだけにする
+                None => {
+                    lowered.push(Stmt::Expression(left));
+                    lowered.push(Stmt::Expression(right));
+                    self.unknown(span_start(trees))
+                }
+            };
+        }
+
+        let token = first.leaf();
+        match token {
+            // -x / !x / &x / *x は x と同じ型
+            Some(token) if ["-", "!", "&", "*"].contains(&token.text) && token.kind == TokenKind::Punct => {
+                let rest = match &trees[1..] {
+                    [mutable, rest @ ..] if mutable.leaf().is_some_and(|token| token.is_keyword("mut")) => rest,
+                    rest => rest,
+                };
+                self.lower_expr(rest, self_type, lowered)
+            }
+            // クロージャは引数を定義できないので読まない
+            Some(token) if token.is_punct("|") || token.is_keyword("move") => self.unknown(token.start),
+            Some(token) if ["if", "while", "match", "loop", "unsafe", "for"].iter().any(|keyword| token.is_keyword(keyword)) => {
+                self.lower_statement(trees, self_type, lowered);
+                self.unknown(token.start)
+            }
+            _ => self.lower_postfix(trees, self_type, lowered),
+        }
+    }
+
+    // 値1つと、その後ろの `.field` / `.method()` / `?` / `[index]` / `as Type`
+    fn lower_postfix(&self, trees: &[TokenTree], self_type: Option<&str>, lowered: &mut Vec<Stmt>) -> Expr {
+        let (mut expr, mut rest) = self.lower_atom(trees, self_type, lowered);
+
+        while let Some(tree) = rest.first() {
+            match rest {
+                [dot, field, TokenTree::Group { open, children, .. }, after @ ..] if is_punct(dot, ".") && open.text == "(" => {
+                    // メソッド呼び出しの結果の型は分からない
+                    let start = field.first().start;
+                    lowered.push(Stmt::Expression(expr));
+                    self.lower_arguments(children, self_type, lowered);
+                    expr = self.unknown(start);
+                    rest = after;
+                }
+                [dot, field, after @ ..] if is_punct(dot, ".") && field.leaf().is_some_and(|token| token.is_ident()) => {
+                    let field = field.leaf().unwrap();
+                    expr = Expr::FieldAccess { object: Box::new(expr), field_name: field.text.to_string(), span: self.token_span(field) };
+                    rest = after;
+                }
+                [TokenTree::Group { open, children, .. }, after @ ..] if open.text == "[" => {
+                    lowered.push(Stmt::Expression(expr));
+                    let index = self.lower_expr(children, self_type, lowered);
+                    lowered.push(Stmt::Expression(index));
+                    expr = self.unknown(open.start);
+                    rest = after;
+                }
+                _ => {
+                    // `?` / `.0` / `as Type` など。残りにある名前だけ読む
+                    lowered.push(Stmt::Expression(expr));
+                    self.collect_names(rest, self_type, lowered);
+                    return self.unknown(tree.first().start);
+                }
+            }
+        }
+        expr
+    }
+
+    // 値1つと、その後ろの残り
+    fn lower_atom<'t, 'a>(
+        &self,
+        trees: &'t [TokenTree<'a>],
+        self_type: Option<&str>,
+        lowered: &mut Vec<Stmt>,
+    ) -> (Expr, &'t [TokenTree<'a>]) {
+        let rest = &trees[1..];
+        let token = match &trees[0] {
+            TokenTree::Leaf(token) => token,
+            TokenTree::Group { open, children, .. } => {
+                let expr = match open.text {
+                    // (値) はそのまま、タプルは中身を読むだけ
+                    "(" if split_by(children, ",").len() == 1 && !children.is_empty() => self.lower_expr(children, self_type, lowered),
+                    "{" => {
+                        let span = self.span(open.start, trees[0].end());
+                        lowered.push(Stmt::Block { statements: self.lower_block(children, self_type), span });
+                        self.unknown(open.start)
+                    }
+                    _ => {
+                        self.lower_arguments(children, self_type, lowered);
+                        self.unknown(open.start)
+                    }
+                };
+                return (expr, rest);
+            }
+        };
+
+        let span = self.token_span(token);
+        match token.kind {
+            TokenKind::Number => match parse_integer(token.text) {
+                Some(value) => (Expr::Number(value, span), rest),
+                None => (self.unknown(token.start), rest),
+            },
+            TokenKind::Str => (Expr::String(token.text.to_string(), span), rest),
+            _ if token.is_keyword("true") || token.is_keyword("false") => (Expr::Boolean(token.text == "true", span), rest),
+            TokenKind::Ident if token.is_ident() || token.is_keyword("self") => match rest {
+                [TokenTree::Group { open, children, .. }, after @ ..] if open.text == "(" => {
+                    let arguments = split_by(children, ",")
+                        .into_iter()
+                        .map(|argument| self.lower_expr(argument, self_type, lowered))
+                        .collect();
+                    (Expr::FunctionCall { name: token.text.to_string(), arguments, span }, after)
+                }
+                [TokenTree::Group { open, children, .. }, after @ ..] if open.text == "{" && self.structs.contains_key(token.text) => {
+                    let field_values = self.lower_field_values(children, self_type, lowered);
+                    (Expr::StructConstructor { struct_name: token.text.to_string(), field_values, span }, after)
+                }
+                // マクロ呼び出し `name!(...)`
+                [bang, TokenTree::Group { children, .. }, after @ ..] if is_punct(bang, "!") => {
+                    self.lower_arguments(children, self_type, lowered);
+                    (self.unknown(token.start), after)
+                }
+                // パス `Type::item`: 先頭の名前だけ解決する
+                [separator, ..] if is_punct(separator, "::") => {
+                    lowered.push(Stmt::Expression(Expr::Identifier(token.text.to_string(), span)));
+                    let mut after = rest;
+                    while let [separator, segment, more @ ..] = after {
+                        if !is_punct(separator, "::") || segment.leaf().is_none() {
+                            break;
+                        }
+                        after = more;
+                    }
+                    if let [TokenTree::Group { open, children, .. }, more @ ..] = after {
+                        if open.text == "(" {
+                            self.lower_arguments(children, self_type, lowered);
+                            after = more;
+                        }
+                    }
+                    (self.unknown(token.start), after)
+                }
+                _ => (Expr::Identifier(token.text.to_string(), span), rest),
+            },
+            _ => (self.unknown(token.start), rest),
+        }
+    }
+
+    // `Name { field: value, shorthand, ..base }` の中身
+    fn lower_field_values(&self, children: &[TokenTree], self_type: Option<&str>, lowered: &mut Vec<Stmt>) -> Vec<(String, Expr)> {
+        let mut field_values = Vec::new();
+        for field in split_by(children, ",") {
+            match field {
+                [name] => {
+                    if let Some(name) = name.leaf().filter(|token| token.is_ident()) {
+                        field_values.push((name.text.to_string(), Expr::Identifier(name.text.to_string(), self.token_span(name))));
+                    }
+                }
+                [name, colon, value @ ..] if is_punct(colon, ":") && name.leaf().is_some_and(|token| token.is_ident()) => {
+                    let value = self.lower_expr(value, self_type, lowered);
+                    field_values.push((name.first().text.to_string(), value));
+                }
+                other => self.collect_names(other, self_type, lowered),
+            }
+        }
+        field_values
+    }
+
+    // 関数・マクロの引数や配列の要素（型は要らないので、式として読むだけ）
+    fn lower_arguments(&self, children: &[TokenTree], self_type: Option<&str>, lowered: &mut Vec<Stmt>) {
+        for argument in split_by(children, ",") {
+            for element in split_by(argument, ";") {
+                let expr = self.lower_expr(element, self_type, lowered);
+                lowered.push(Stmt::Expression(expr));
+            }
+        }
+    }
+
+    // 読めない式の残りの中の名前を、それぞれ解決だけする
+    fn collect_names(&self, trees: &[TokenTree], self_type: Option<&str>, lowered: &mut Vec<Stmt>) {
+        for tree in trees {
+            match tree {
+                TokenTree::Leaf(token) if token.is_ident() || token.is_keyword("self") => {
+                    lowered.push(Stmt::Expression(Expr::Identifier(token.text.to_string(), self.token_span(token))));
+                }
+                TokenTree::Leaf(_) => {}
+                TokenTree::Group { .. } => self.collect_blocks(std::slice::from_ref(tree), self_type, lowered),
+            }
+        }
+    }
+}
+
+fn is_punct(tree: &TokenTree, punct: &str) -> bool {
+    tree.leaf().is_some_and(|token| token.is_punct(punct))
+}
+
+// `+=` / `-=` など（トークンは1文字ずつに分かれている）
+fn is_compound_assignment(operator: &TokenTree, equals: &TokenTree) -> bool {
+    let (Some(operator), Some(equals)) = (operator.leaf(), equals.leaf()) else {
+        return false;
+    };
+    operator.kind == TokenKind::Punct
+        && ["+", "-", "*", "/", "%", "|", "&", "^"].contains(&operator.text)
+        && equals.is_punct("=")
+        && operator.end() == equals.start
+}
+
+fn span_start(trees: &[TokenTree]) -> usize {
+    trees.first().map_or(0, |tree| tree.first().start)
+}
+
+// trees を最上位の separator で分ける（空の要素は捨てる）
+fn split_by<'t, 'a>(trees: &'t [TokenTree<'a>], separator: &str) -> Vec<&'t [TokenTree<'a>]> {
+    trees.split(|tree| is_punct(tree, separator)).filter(|part| !part.is_empty()).collect()
+}
+
+// `impl Name { ... }` / `impl Trait for Name { ... }` の Name
+fn impl_self_type<'a>(statement: &[TokenTree<'a>]) -> Option<&'a str> {
+    let tokens: Vec<&Token<'a>> = statement.iter().filter_map(TokenTree::leaf).collect();
+    let start = tokens.iter().position(|token| token.is_keyword("for")).or_else(|| tokens.iter().position(|token| token.is_keyword("impl")))?;
+    // 型引数 `<T>` の中の名前は飛ばす
+    let mut depth = 0;
+    for token in &tokens[start + 1..] {
+        match token.text {
+            "<" => depth += 1,
+            ">" => depth -= 1,
+            _ if depth == 0 && token.is_ident() => return Some(token.text),
+            _ => {}
+        }
+    }
+    None
+}
+
+// 二項演算子の位置・トークンの数・lesson_3_12 の演算子（無いものは None）
+// 演算子の前に値が無ければ単項の演算子（-x など）なので対象にしない
+fn find_binary_operator(trees: &[TokenTree]) -> Option<(usize, usize, Option<BinaryOp>)> {
+    // (強さ, 位置, トークンの数, 演算子)
+    let mut weakest: Option<(u8, usize, usize, Option<BinaryOp>)> = None;
+    let mut previous_is_value = false;
+    let mut i = 0;
+
+    while i < trees.len() {
+        let Some(token) = trees[i].leaf().filter(|token| token.kind == TokenKind::Punct) else {
+            // `as` も値の後ろに来るが、型を読むだけなので二項演算子としては扱わない
+            previous_is_value = !trees[i].leaf().is_some_and(|token| token.is_keyword("as"));
+            i += 1;
+            continue;
+        };
+        let next = trees.get(i + 1).and_then(TokenTree::leaf).filter(|next| next.kind == TokenKind::Punct && next.start == token.end());
+        let pair = next.map(|next| format!("{}{}", token.text, next.text));
+        let (strength, len, operator) = match (pair.as_deref(), token.text) {
+            // lesson_3_12 には論理演算子が無い。比較として読むと `1 && 2` まで bool になってしまうので、両辺を読むだけにする
+            (Some("||") | Some("&&"), _) => (1, 2, None),
+            (Some("=="), _) => (2, 2, Some(BinaryOp::Equal)),
+            (Some("!="), _) => (2, 2, Some(BinaryOp::NotEqual)),
+            (Some("<="), _) => (2, 2, Some(BinaryOp::LessThan)),
+            (Some(">="), _) => (2, 2, Some(BinaryOp::GreaterThan)),
+            (Some(".."), _) => (0, 2, None),
+            (_, "<") => (2, 1, Some(BinaryOp::LessThan)),
+            (_, ">") => (2, 1, Some(BinaryOp::GreaterThan)),
+            (_, "+") => (3, 1, Some(BinaryOp::Add)),
+            (_, "-") => (3, 1, Some(BinaryOp::Subtract)),
+            (_, "*") => (4, 1, Some(BinaryOp::Multiply)),
+            (_, "/") | (_, "%") => (4, 1, Some(BinaryOp::Divide)),
+            _ => {
+                // `?` の後ろは値が続いているものとして扱う
+                previous_is_value = token.text == "?" || token.text == ".";
+                i += 1;
+                continue;
+            }
+        };
+        // `..=` は `..` と同じ
+        let len = if pair.as_deref() == Some("..") && trees.get(i + 2).is_some_and(|tree| is_punct(tree, "=")) { 3 } else { len };
+
+        if previous_is_value && weakest.as_ref().is_none_or(|(weakest, ..)| strength <= *weakest) {
+            weakest = Some((strength, i, len, operator));
+        }
+        previous_is_value = false;
+        i += len;
+    }
+    weakest.map(|(_, index, len, operator)| (index, len, operator))
+}
+
+// 整数リテラル（`1_000` / `42u8` / `0xff` など）。小数は扱わない
+fn parse_integer(text: &str) -> Option<i64> {
+    let digits: String = text.chars().filter(|&ch| ch != '_').collect();
+    let (radix, digits) = match digits.get(..2) {
+        Some("0x") => (16, &digits[2..]),
+        Some("0o") => (8, &digits[2..]),
+        Some("0b") => (2, &digits[2..]),
+        _ => (10, digits.as_str()),
+    };
+    let end = digits.find(['i', 'u']).unwrap_or(digits.len());
+    i64::from_str_radix(&digits[..end], radix).ok()
+}
+
+
+// --- Tests --- //
+
+#[cfg(test)]
+mod tests {
+    use super::{display_type, Analysis};
+    use crate::lessons::lesson_3::lesson_3_12::{SymbolKind, Type};
+    use lsp_types::{Position, PositionEncodingKind, Range};
+
+    fn analyze(text: &str) -> Analysis {
+        Analysis::new(text, &PositionEncodingKind::UTF16)
+    }
+
+    fn range(line: u32, start: u32, end: u32) -> Range {
+        Range::new(Position::new(line, start), Position::new(line, end))
+    }
+
+    #[test]
+    fn test_variables_resolve_to_their_let() {
+        let analysis = analyze("fn main() {\n    let x = 1 + 2;\n    let y = x > 0;\n}");
+
+        let x = analysis.resolution_at(range(2, 12, 13)).unwrap();
+        assert_eq!(x.kind, SymbolKind::Variable);
+        assert_eq!(super::to_range(&x.definition_span), range(1, 8, 9));
+        assert_eq!(display_type(&x.symbol_type), "i32");
+
+        let y = analysis.resolution_at(range(2, 8, 9)).unwrap();
+        assert_eq!(display_type(&y.symbol_type), "bool");
+    }
+
+    #[test]
+    fn test_items_can_be_used_before_their_definition() {
+        let text = "fn main() {\n    let total = add(1, 2);\n}\n\nfn add(a: i32, b: i32) -> i32 {\n    a + b\n}";
+        let analysis = analyze(text);
+
+        let call = analysis.resolution_at(range(1, 16, 19)).unwrap();
+        assert_eq!(call.kind, SymbolKind::Function);
+        assert_eq!(super::to_range(&call.definition_span), range(4, 3, 6));
+        assert_eq!(display_type(&call.symbol_type), "fn(i32, i32) -> i32");
+
+        let total = analysis.resolution_at(range(1, 8, 13)).unwrap();
+        assert_eq!(display_type(&total.symbol_type), "i32", "the return type flows into the let");
+
+        let a = analysis.resolution_at(range(5, 4, 5)).unwrap();
+        assert_eq!((a.kind, super::to_range(&a.definition_span)), (SymbolKind::Parameter, range(4, 7, 8)));
+
+        // 先に宣言した分の定義は重ねて残さない
+        let definitions = analysis.resolutions().iter().filter(|resolution| resolution.name == "add").count();
+        assert_eq!(definitions, 2, "the definition and the call");
+    }
+
+    #[test]
+    fn test_shadowing_and_scopes() {
+        let text = "fn main() {\n    let x = 1;\n    let x = x > 0;\n    {\n        let x = \"inner\";\n    }\n    x;\n}";
+        let analysis = analyze(text);
+
+        // 2つ目の let の値の x は1つ目の x
+        let shadowed = analysis.resolution_at(range(2, 12, 13)).unwrap();
+        assert_eq!(super::to_range(&shadowed.definition_span), range(1, 8, 9));
+        // ブロックの外の x は2つ目の x
+        let outer = analysis.resolution_at(range(6, 4, 5)).unwrap();
+        assert_eq!(super::to_range(&outer.definition_span), range(2, 8, 9));
+        assert_eq!(outer.symbol_type.resolve(), &Type::Boolean);
+    }
+
+    #[test]
+    fn test_structs_fields_and_methods() {
+        let text = "struct Point {\n    x: i32,\n    label: String,\n}\n\nimpl Point {\n    fn x(&self) -> i32 {\n        self.x\n    }\n}\n\nfn main() {\n    let p = Point { x: 1, label: \"a\".to_string() };\n    p.label;\n}";
+        let analysis = analyze(text);
+
+        let field = analysis.resolution_at(range(7, 13, 14)).unwrap();
+        assert_eq!((field.kind, super::to_range(&field.definition_span)), (SymbolKind::Field, range(1, 4, 5)));
+
+        let receiver = analysis.resolution_at(range(7, 8, 12)).unwrap();
+        assert_eq!(receiver.kind, SymbolKind::Parameter);
+        assert_eq!(display_type(&receiver.symbol_type), "Point");
+
+        let constructor = analysis.resolution_at(range(12, 12, 17)).unwrap();
+        assert_eq!(constructor.kind, SymbolKind::Struct);
+
+        let label = analysis.resolution_at(range(13, 6, 11)).unwrap();
+        assert_eq!(display_type(&label.symbol_type), "String");
+    }
+
+    #[test]
+    fn test_literal_types() {
+        let analysis = analyze("let a = 42;\nlet b = \"hi\";\nlet c = 0xff + 1_000;\nlet d = 1.5;");
+        assert_eq!(analysis.type_at(range(0, 8, 10)), Some(&Type::Integer));
+        assert_eq!(analysis.type_at(range(1, 8, 12)), Some(&Type::String));
+        assert_eq!(analysis.type_at(range(2, 8, 12)), Some(&Type::Integer));
+        assert_eq!(analysis.type_at(range(3, 8, 11)), None, "floats are not part of the lesson_3 types");
+    }
+
+    #[test]
+    fn test_unknown_syntax_still_resolves_names_inside() {
+        let text = "fn main() {\n    let v = vec![1];\n    let n = v.len() + helper(v[0]);\n    println!(\"{}\", n);\n}";
+        let analysis = analyze(text);
+
+        let v = analysis.resolution_at(range(2, 12, 13)).unwrap();
+        assert_eq!(display_type(&v.symbol_type), "{unknown}");
+        assert!(analysis.resolution_at(range(2, 29, 30)).is_some(), "v inside the index expression");
+        assert!(analysis.resolution_at(range(3, 19, 20)).is_some(), "n inside the macro arguments");
+        assert!(analysis.resolution_at(range(2, 22, 28)).is_none(), "helper is not defined");
+    }
+
+    #[test]
+    fn test_incomplete_headers_do_not_panic() {
+        // 入力途中のコード（エディタからは打ちかけの状態のまま届く）
+        let texts = [
+            "fn main() {\n    if x {} else\n}",
+            "fn main() {\n    if x {} else if\n}",
+            "fn main() {\n    if x {} else if y\n}",
+            "fn main() {\n    if\n}",
+            "fn main() {\n    if x\n}",
+            "fn main() {\n    while\n}",
+            "fn main() {\n    while x\n}",
+            "fn main() {\n    for\n}",
+            "fn main() {\n    for x in\n}",
+            "fn main() {\n    for in {}\n}",
+            "fn",
+            "fn main",
+            "fn main(",
+            "fn main() ->",
+            "fn main(x: i32) -> {",
+            "struct",
+            "impl",
+            "let",
+            "let x =",
+        ];
+        for text in texts {
+            let analysis = analyze(text);
+            assert!(analysis.resolutions().len() < 10, "{:?}", text);
+        }
+
+        // else の後ろが空でも、それより前はいつもどおり読む
+        let analysis = analyze("fn main() {\n    let x = 1;\n    if x > 0 {} else\n}");
+        assert!(analysis.resolution_at(range(2, 7, 8)).is_some());
+    }
+
+    #[test]
+    fn test_logical_operators_are_not_typed() {
+        let analysis = analyze("fn main() {
+    let flag = 1 > 0;
+    let both = flag && 1;
+}");
+
+        let both = analysis.resolution_at(range(2, 8, 12)).unwrap();
+        assert_eq!(display_type(&both.symbol_type), "{unknown}");
+        assert!(analysis.resolution_at(range(2, 15, 19)).is_some(), "flag on the left is still resolved");
+        assert_eq!(analysis.type_at(range(2, 23, 24)), Some(&Type::Integer), "the right side is still read");
+    }
+}
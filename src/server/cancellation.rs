@@ -0,0 +1,199 @@
+// リクエストのキャンセル ($/cancelRequest)
+// 処理中のリクエストの id ごとにキャンセルトークンを持っておき、
+// $/cancelRequest が来たらトークンを立てる。重い処理はトークンをときどき確認して途中で諦める。
+//
+// メッセージを読むスレッドが $/cancelRequest を見つけた時点でトークンを立てるので、
+// メインループが別のリクエストを処理している間に届いたキャンセルも取りこぼさない。
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use lsp_types::NumberOrString;
+use serde_json::Value;
+
+use crate::lessons::lesson_1::lesson_1_9::ResponseError;
+use crate::lessons::lesson_1::lesson_1_11::ErrorCode;
+
+// リクエストの id
+// 数値の 1 と文字列の "1" を区別するため、JSON としての表現をそのままキーにする
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct RequestId(String);
+
+impl From<&Value> for RequestId {
+    fn from(id: &Value) -> Self {
+        RequestId(id.to_string())
+    }
+}
+
+impl From<&NumberOrString> for RequestId {
+    fn from(id: &NumberOrString) -> Self {
+        match id {
+            NumberOrString::Number(number) => RequestId(number.to_string()),
+            NumberOrString::String(string) => RequestId(Value::String(string.clone()).to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    // ハンドラの中で `token.check()?` のように使う
+    pub fn check(&self) -> Result<(), ResponseError> {
+        if self.is_cancelled() {
+            Err(request_cancelled())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+pub fn request_cancelled() -> ResponseError {
+    ResponseError::new(ErrorCode::RequestCancelled, "Request cancelled.")
+}
+
+// 処理中（または処理待ち）のリクエストとそのキャンセルトークン
+// 読み取りスレッドとメインループで共有するので Arc<Mutex<..>> で持つ
+#[derive(Debug, Clone, Default)]
+pub struct InFlightRequests {
+    tokens: Arc<Mutex<HashMap<RequestId, CancellationToken>>>,
+    // キャンセル可能な進捗表示のトークン → その作業をしているリクエストのキャンセルトークン
+    // （window/workDoneProgress/cancel で止められるようにする）
+    progress: Arc<Mutex<HashMap<RequestId, CancellationToken>>>,
+}
+
+impl InFlightRequests {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // リクエストを登録してトークンを返す（登録済みなら同じトークンを返す）
+    pub fn start(&self, id: &Value) -> CancellationToken {
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.entry(RequestId::from(id)).or_default().clone()
+    }
+
+    // 応答を返し終わったら登録を消す
+    pub fn finish(&self, id: &Value) {
+        self.tokens.lock().unwrap().remove(&RequestId::from(id));
+    }
+
+    // 知らない id（もう応答済みのものなど）へのキャンセルは無視する
+    pub fn cancel(&self, id: impl Into<RequestId>) {
+        if let Some(token) = self.tokens.lock().unwrap().get(&id.into()) {
+            token.cancel();
+        }
+    }
+
+    // 進捗のトークンでキャンセルできるようにする
+    pub fn watch_progress(&self, progress_token: &NumberOrString, token: CancellationToken) {
+        self.progress.lock().unwrap().insert(RequestId::from(progress_token), token);
+    }
+
+    // 進捗の報告が終わったら登録を消す
+    pub fn finish_progress(&self, progress_token: &NumberOrString) {
+        self.progress.lock().unwrap().remove(&RequestId::from(progress_token));
+    }
+
+    // 終わった進捗や知らないトークンへのキャンセルは無視する
+    pub fn cancel_progress(&self, progress_token: &NumberOrString) {
+        if let Some(token) = self.progress.lock().unwrap().get(&RequestId::from(progress_token)) {
+            token.cancel();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+
+// --- Tests --- //
+
+#[cfg(test)]
+mod tests {
+    use super::{CancellationToken, InFlightRequests, RequestId};
+    use lsp_types::NumberOrString;
+    use serde_json::json;
+
+    #[test]
+    fn test_token_is_shared_between_clones() {
+        let token = CancellationToken::new();
+        let clone = token.clone();
+
+        assert!(!clone.is_cancelled());
+        assert!(token.check().is_ok());
+        token.cancel();
+        assert!(clone.is_cancelled(), "Clones should observe cancellation");
+        assert_eq!(clone.check().unwrap_err().code, -32800);
+    }
+
+    #[test]
+    fn test_request_ids_keep_number_and_string_distinct() {
+        assert_eq!(RequestId::from(&json!(1)), RequestId::from(&NumberOrString::Number(1)));
+        assert_eq!(RequestId::from(&json!("1")), RequestId::from(&NumberOrString::String("1".to_string())));
+        assert_ne!(RequestId::from(&json!(1)), RequestId::from(&json!("1")));
+    }
+
+    #[test]
+    fn test_cancel_in_flight_request() {
+        let in_flight = InFlightRequests::new();
+        let token = in_flight.start(&json!(1));
+        let other = in_flight.start(&json!("1"));
+
+        in_flight.cancel(&NumberOrString::Number(1));
+
+        assert!(token.is_cancelled());
+        assert!(!other.is_cancelled(), "Only the matching id should be cancelled");
+        assert!(in_flight.start(&json!(1)).is_cancelled(), "start should return the already registered token");
+    }
+
+    #[test]
+    fn test_finished_requests_are_forgotten() {
+        let in_flight = InFlightRequests::new();
+        let token = in_flight.start(&json!(2));
+        in_flight.finish(&json!(2));
+
+        in_flight.cancel(&NumberOrString::Number(2));
+
+        assert!(!token.is_cancelled(), "Cancelling a finished request should do nothing");
+        assert!(in_flight.is_empty());
+    }
+
+    #[test]
+    fn test_cancel_by_progress_token() {
+        let in_flight = InFlightRequests::new();
+        let token = in_flight.start(&json!(3));
+        let progress_token = NumberOrString::String("indexing".to_string());
+        in_flight.watch_progress(&progress_token, token.clone());
+
+        in_flight.cancel_progress(&NumberOrString::String("other".to_string()));
+        assert!(!token.is_cancelled(), "Only the matching progress token should cancel");
+
+        in_flight.cancel_progress(&progress_token);
+        assert!(token.is_cancelled(), "Cancelling the progress should cancel the request");
+
+        let next = in_flight.start(&json!(4));
+        in_flight.watch_progress(&progress_token, next.clone());
+        in_flight.finish_progress(&progress_token);
+        in_flight.cancel_progress(&progress_token);
+        assert!(!next.is_cancelled(), "Finished progress should be forgotten");
+    }
+}
@@ -0,0 +1,422 @@
+// 機能（capabilities）のネゴシエーション
+// - サーバー側: ルーターに登録されているハンドラから ServerCapabilities を組み立てる
+// - クライアント側: initialize の ClientCapabilities を読んで、応答の形や positionEncoding を決める
+
+use lsp_types::notification::{DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, Notification};
+use lsp_types::request::{
+    CallHierarchyPrepare, CodeActionRequest, CodeLensRequest, CodeLensResolve, Completion, DocumentDiagnosticRequest,
+    DocumentHighlightRequest, DocumentSymbolRequest, FoldingRangeRequest, Formatting, GotoDeclaration,
+    GotoDefinition, GotoTypeDefinition, HoverRequest, InlayHintRequest, LinkedEditingRange,
+    PrepareRenameRequest, References, Rename, Request, ResolveCompletionItem, SelectionRangeRequest,
+    SemanticTokensFullRequest, SignatureHelpRequest, WorkspaceDiagnosticRequest, WorkspaceSymbolRequest,
+};
+use lsp_types::{
+    CallHierarchyOptions, CallHierarchyServerCapability, ClientCapabilities, CodeActionProviderCapability, CodeLensOptions,
+    CompletionOptions, DeclarationCapability, DiagnosticOptions, DiagnosticServerCapabilities, FoldingRangeProviderCapability, GotoCapability,
+    HoverProviderCapability,
+    LinkedEditingRangeServerCapabilities, OneOf, PositionEncodingKind, ReferencesOptions, RenameOptions,
+    SelectionRangeProviderCapability, SemanticTokenType, SemanticTokensFullOptions, SemanticTokensLegend,
+    SemanticTokensOptions, SemanticTokensServerCapabilities, ServerCapabilities, SignatureHelpOptions,
+    TextDocumentSyncCapability, TextDocumentSyncKind, TextDocumentSyncOptions,
+    TypeDefinitionProviderCapability, WorkDoneProgressOptions, WorkspaceSymbolOptions,
+};
+
+use crate::server::router::Router;
+
+// クライアントとのネゴシエーションで決まった設定
+#[derive(Debug, PartialEq, Clone)]
+pub struct NegotiatedCapabilities {
+    // Position.character を何の単位で数えるか
+    pub position_encoding: PositionEncodingKind,
+    // textDocument/documentSymbol で DocumentSymbol の木を返してよいか（false なら SymbolInformation のリスト）
+    pub hierarchical_document_symbols: bool,
+    // 補完で ${1:name} のようなスニペットを使ってよいか
+    pub snippet_support: bool,
+    // window/workDoneProgress/create でサーバーから進捗表示を始めてよいか
+    pub work_done_progress: bool,
+    // クライアントが textDocument/diagnostic で診断を取りに来るか（true ならサーバーからは publishDiagnostics を送らない）
+    pub pull_diagnostics: bool,
+    // textDocument/diagnostic の応答に relatedDocuments を入れてよいか
+    pub related_document_diagnostics: bool,
+    // client/registerCapability で workspace/didChangeWatchedFiles を登録してよいか
+    pub watch_files: bool,
+    // workspace/diagnostic/refresh で、取りに来た診断を取り直してもらえるか
+    pub diagnostic_refresh: bool,
+    // workspace/configuration でクライアントの設定を取りに行けるか
+    pub configuration: bool,
+    // client/registerCapability で workspace/didChangeConfiguration を登録してよいか
+    pub watch_configuration: bool,
+    // textDocument/definition・declaration・typeDefinition に LocationLink（カーソル側の範囲付き）で答えてよいか
+    pub definition_links: bool,
+    pub declaration_links: bool,
+    pub type_definition_links: bool,
+    // rename の WorkspaceEdit を documentChanges にして、編集に ChangeAnnotation を付けてよいか
+    pub change_annotations: bool,
+    // textDocument/prepareRename を送ってくるか（false なら renameProvider に prepareProvider を載せない）
+    pub prepare_rename: bool,
+}
+
+impl Default for NegotiatedCapabilities {
+    // クライアントが何も言ってこなかった場合の値（LSP の仕様上のデフォルト）
+    fn default() -> Self {
+        NegotiatedCapabilities {
+            position_encoding: PositionEncodingKind::UTF16,
+            hierarchical_document_symbols: false,
+            snippet_support: false,
+            work_done_progress: false,
+            pull_diagnostics: false,
+            related_document_diagnostics: false,
+            watch_files: false,
+            diagnostic_refresh: false,
+            configuration: false,
+            watch_configuration: false,
+            definition_links: false,
+            declaration_links: false,
+            type_definition_links: false,
+            change_annotations: false,
+            prepare_rename: false,
+        }
+    }
+}
+
+// サーバーが扱える positionEncoding
+const SUPPORTED_POSITION_ENCODINGS: [PositionEncodingKind; 3] = [
+    PositionEncodingKind::UTF8,
+    PositionEncodingKind::UTF16,
+    PositionEncodingKind::UTF32,
+];
+
+pub fn negotiate(client: &ClientCapabilities) -> NegotiatedCapabilities {
+    let text_document = client.text_document.as_ref();
+
+    // クライアントは希望順に並べてくるので、サーバーが扱える最初のものを選ぶ
+    // 何も無ければ UTF-16（必ずサポートしなければならない）
+    let position_encoding = client
+        .general
+        .as_ref()
+        .and_then(|general| general.position_encodings.as_ref())
+        .and_then(|encodings| encodings.iter().find(|encoding| SUPPORTED_POSITION_ENCODINGS.contains(encoding)))
+        .cloned()
+        .unwrap_or(PositionEncodingKind::UTF16);
+
+    let hierarchical_document_symbols = text_document
+        .and_then(|text_document| text_document.document_symbol.as_ref())
+        .and_then(|document_symbol| document_symbol.hierarchical_document_symbol_support)
+        .unwrap_or(false);
+
+    let snippet_support = text_document
+        .and_then(|text_document| text_document.completion.as_ref())
+        .and_then(|completion| completion.completion_item.as_ref())
+        .and_then(|completion_item| completion_item.snippet_support)
+        .unwrap_or(false);
+
+    let work_done_progress = client
+        .window
+        .as_ref()
+        .and_then(|window| window.work_done_progress)
+        .unwrap_or(false);
+
+    let diagnostic = text_document.and_then(|text_document| text_document.diagnostic.as_ref());
+    let pull_diagnostics = diagnostic.is_some();
+    let related_document_diagnostics = diagnostic
+        .and_then(|diagnostic| diagnostic.related_document_support)
+        .unwrap_or(false);
+
+    let workspace = client.workspace.as_ref();
+    let watch_files = workspace
+        .and_then(|workspace| workspace.did_change_watched_files.as_ref())
+        .and_then(|watched_files| watched_files.dynamic_registration)
+        .unwrap_or(false);
+
+    let diagnostic_refresh = workspace
+        .and_then(|workspace| workspace.diagnostic.as_ref())
+        .and_then(|diagnostic| diagnostic.refresh_support)
+        .unwrap_or(false);
+
+    let configuration = workspace.and_then(|workspace| workspace.configuration).unwrap_or(false);
+    let watch_configuration = workspace
+        .and_then(|workspace| workspace.did_change_configuration.as_ref())
+        .and_then(|configuration| configuration.dynamic_registration)
+        .unwrap_or(false);
+
+    let link_support = |goto: Option<&GotoCapability>| goto.and_then(|goto| goto.link_support).unwrap_or(false);
+    let definition_links = link_support(text_document.and_then(|text_document| text_document.definition.as_ref()));
+    let declaration_links = link_support(text_document.and_then(|text_document| text_document.declaration.as_ref()));
+    let type_definition_links = link_support(text_document.and_then(|text_document| text_document.type_definition.as_ref()));
+
+    // 注釈は TextDocumentEdit の中にしか書けないので、documentChanges にも対応していないと使えない
+    let workspace_edit = workspace.and_then(|workspace| workspace.workspace_edit.as_ref());
+    let change_annotations = workspace_edit.and_then(|workspace_edit| workspace_edit.document_changes).unwrap_or(false)
+        && workspace_edit.is_some_and(|workspace_edit| workspace_edit.change_annotation_support.is_some());
+
+    let prepare_rename = text_document
+        .and_then(|text_document| text_document.rename.as_ref())
+        .and_then(|rename| rename.prepare_support)
+        .unwrap_or(false);
+
+    NegotiatedCapabilities {
+        position_encoding,
+        hierarchical_document_symbols,
+        snippet_support,
+        work_done_progress,
+        pull_diagnostics,
+        related_document_diagnostics,
+        watch_files,
+        diagnostic_refresh,
+        configuration,
+        watch_configuration,
+        definition_links,
+        declaration_links,
+        type_definition_links,
+        change_annotations,
+        prepare_rename,
+    }
+}
+
+// ルーターに登録されているハンドラを見て、対応している機能だけを載せた ServerCapabilities を作る
+pub fn server_capabilities<S, T>(router: &Router<S, T>) -> ServerCapabilities {
+    let has_request = |method: &str| router.has_request(method);
+
+    let text_document_sync = router.has_notification(DidOpenTextDocument::METHOD).then(|| {
+        TextDocumentSyncCapability::Options(TextDocumentSyncOptions {
+            open_close: Some(router.has_notification(DidCloseTextDocument::METHOD)),
+            change: router.has_notification(DidChangeTextDocument::METHOD).then_some(TextDocumentSyncKind::INCREMENTAL),
+            ..Default::default()
+        })
+    });
+
+    ServerCapabilities {
+        text_document_sync,
+        hover_provider: has_request(HoverRequest::METHOD).then_some(HoverProviderCapability::Simple(true)),
+        completion_provider: has_request(Completion::METHOD).then(|| CompletionOptions {
+            resolve_provider: Some(has_request(ResolveCompletionItem::METHOD)),
+            ..Default::default()
+        }),
+        signature_help_provider: has_request(SignatureHelpRequest::METHOD).then(|| SignatureHelpOptions {
+            trigger_characters: Some(vec!["(".to_string(), "[".to_string(), ",".to_string()]),
+            retrigger_characters: None,
+            work_done_progress_options: Default::default(),
+        }),
+        definition_provider: enabled(router, GotoDefinition::METHOD),
+        declaration_provider: has_request(GotoDeclaration::METHOD).then_some(DeclarationCapability::Simple(true)),
+        type_definition_provider: has_request(GotoTypeDefinition::METHOD)
+            .then_some(TypeDefinitionProviderCapability::Simple(true)),
+        document_highlight_provider: enabled(router, DocumentHighlightRequest::METHOD),
+        document_symbol_provider: enabled(router, DocumentSymbolRequest::METHOD),
+        // 全ドキュメントを調べるものは、進捗を報告できることを伝える
+        references_provider: has_request(References::METHOD).then(|| {
+            OneOf::Right(ReferencesOptions { work_done_progress_options: reports_progress() })
+        }),
+        workspace_symbol_provider: has_request(WorkspaceSymbolRequest::METHOD).then(|| {
+            OneOf::Right(WorkspaceSymbolOptions {
+                work_done_progress_options: reports_progress(),
+                resolve_provider: None,
+            })
+        }),
+        code_action_provider: has_request(CodeActionRequest::METHOD).then_some(CodeActionProviderCapability::Simple(true)),
+        code_lens_provider: has_request(CodeLensRequest::METHOD).then(|| CodeLensOptions {
+            resolve_provider: Some(has_request(CodeLensResolve::METHOD)),
+        }),
+        document_formatting_provider: enabled(router, Formatting::METHOD),
+        rename_provider: has_request(Rename::METHOD).then(|| {
+            if has_request(PrepareRenameRequest::METHOD) {
+                OneOf::Right(RenameOptions {
+                    prepare_provider: Some(true),
+                    work_done_progress_options: Default::default(),
+                })
+            } else {
+                OneOf::Left(true)
+            }
+        }),
+        folding_range_provider: has_request(FoldingRangeRequest::METHOD).then_some(FoldingRangeProviderCapability::Simple(true)),
+        selection_range_provider: has_request(SelectionRangeRequest::METHOD)
+            .then_some(SelectionRangeProviderCapability::Simple(true)),
+        call_hierarchy_provider: has_request(CallHierarchyPrepare::METHOD).then(|| {
+            CallHierarchyServerCapability::Options(CallHierarchyOptions {
+                work_done_progress_options: reports_progress(),
+            })
+        }),
+        linked_editing_range_provider: has_request(LinkedEditingRange::METHOD)
+            .then_some(LinkedEditingRangeServerCapabilities::Simple(true)),
+        inlay_hint_provider: enabled(router, InlayHintRequest::METHOD),
+        // 診断は別のファイルの中身にも左右される（mod / use）ので、interFileDependencies を付ける
+        diagnostic_provider: has_request(DocumentDiagnosticRequest::METHOD).then(|| {
+            DiagnosticServerCapabilities::Options(DiagnosticOptions {
+                identifier: Some("toy-lang-server".to_string()),
+                inter_file_dependencies: true,
+                workspace_diagnostics: has_request(WorkspaceDiagnosticRequest::METHOD),
+                work_done_progress_options: reports_progress(),
+            })
+        }),
+        semantic_tokens_provider: has_request(SemanticTokensFullRequest::METHOD).then(|| {
+            SemanticTokensServerCapabilities::SemanticTokensOptions(SemanticTokensOptions {
+                legend: semantic_tokens_legend(),
+                full: Some(SemanticTokensFullOptions::Bool(true)),
+                ..Default::default()
+            })
+        }),
+        ..Default::default()
+    }
+}
+
+// `OneOf<bool, XxxOptions>` 型の項目用: 登録されていれば Left(true)
+fn enabled<S, T, O>(router: &Router<S, T>, method: &str) -> Option<OneOf<bool, O>> {
+    router.has_request(method).then_some(OneOf::Left(true))
+}
+
+fn reports_progress() -> WorkDoneProgressOptions {
+    WorkDoneProgressOptions {
+        work_done_progress: Some(true),
+    }
+}
+
+// クライアントに合わせて ServerCapabilities を調整する
+pub fn adapt_to_client(mut capabilities: ServerCapabilities, negotiated: &NegotiatedCapabilities) -> ServerCapabilities {
+    capabilities.position_encoding = Some(negotiated.position_encoding.clone());
+    // prepareSupport の無いクライアントに RenameOptions を返すのは仕様違反なので、真偽値に戻す
+    if !negotiated.prepare_rename && matches!(capabilities.rename_provider, Some(OneOf::Right(_))) {
+        capabilities.rename_provider = Some(OneOf::Left(true));
+    }
+    capabilities
+}
+
+// lesson_1_32 の token_type のインデックスと同じ順番にする
+pub fn semantic_tokens_legend() -> SemanticTokensLegend {
+    SemanticTokensLegend {
+        token_types: vec![
+            SemanticTokenType::KEYWORD,  // 0
+            SemanticTokenType::FUNCTION, // 1
+            SemanticTokenType::VARIABLE, // 2
+            SemanticTokenType::STRING,   // 3
+            SemanticTokenType::NUMBER,   // 4
+            SemanticTokenType::TYPE,     // 5
+        ],
+        token_modifiers: vec![],
+    }
+}
+
+
+// --- Tests --- //
+
+#[cfg(test)]
+mod tests {
+    use super::{adapt_to_client, negotiate, server_capabilities, NegotiatedCapabilities};
+    use crate::server::router::Router;
+    use lsp_types::notification::DidOpenTextDocument;
+    use lsp_types::request::{HoverRequest, PrepareRenameRequest, Rename, SemanticTokensFullRequest};
+    use lsp_types::{ClientCapabilities, OneOf, PositionEncodingKind, SemanticTokensServerCapabilities};
+    use serde_json::json;
+
+    fn client_capabilities(value: serde_json::Value) -> ClientCapabilities {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_capabilities_follow_registered_handlers() {
+        let mut router: Router<()> = Router::new();
+        router
+            .on_request::<HoverRequest, _>(|_, _| Ok(None))
+            .on_request::<SemanticTokensFullRequest, _>(|_, _| Ok(None))
+            .on_notification::<DidOpenTextDocument, _>(|_, _| Vec::new());
+
+        let capabilities = server_capabilities(&router);
+
+        assert!(capabilities.hover_provider.is_some(), "hover is registered");
+        assert!(capabilities.text_document_sync.is_some(), "didOpen is registered");
+        assert!(capabilities.completion_provider.is_none(), "completion is not registered");
+        assert!(capabilities.document_formatting_provider.is_none(), "formatting is not registered");
+        assert!(capabilities.rename_provider.is_none(), "rename is not registered");
+
+        let Some(SemanticTokensServerCapabilities::SemanticTokensOptions(options)) = capabilities.semantic_tokens_provider else {
+            panic!("semantic tokens should be advertised with a legend");
+        };
+        assert_eq!(options.legend.token_types.len(), 6);
+    }
+
+    #[test]
+    fn test_rename_advertises_prepare_provider_only_when_registered() {
+        let mut router: Router<()> = Router::new();
+        router.on_request::<Rename, _>(|_, _| Ok(None));
+        assert_eq!(server_capabilities(&router).rename_provider, Some(OneOf::Left(true)));
+
+        router.on_request::<PrepareRenameRequest, _>(|_, _| Ok(None));
+        let Some(OneOf::Right(options)) = server_capabilities(&router).rename_provider else {
+            panic!("rename should be advertised with options");
+        };
+        assert_eq!(options.prepare_provider, Some(true));
+
+        // prepareSupport を持たないクライアントには真偽値で伝える
+        let capabilities = adapt_to_client(server_capabilities(&router), &NegotiatedCapabilities::default());
+        assert_eq!(capabilities.rename_provider, Some(OneOf::Left(true)));
+
+        let negotiated = negotiate(&client_capabilities(json!({
+            "textDocument": {"rename": {"prepareSupport": true}}
+        })));
+        assert!(negotiated.prepare_rename);
+        let capabilities = adapt_to_client(server_capabilities(&router), &negotiated);
+        assert!(matches!(capabilities.rename_provider, Some(OneOf::Right(_))));
+    }
+
+    #[test]
+    fn test_negotiate_defaults_for_empty_client() {
+        let negotiated = negotiate(&ClientCapabilities::default());
+        assert_eq!(negotiated, NegotiatedCapabilities::default());
+        assert_eq!(negotiated.position_encoding, PositionEncodingKind::UTF16);
+    }
+
+    #[test]
+    fn test_negotiate_position_encoding_in_client_order() {
+        let negotiated = negotiate(&client_capabilities(json!({
+            "general": {"positionEncodings": ["utf-32", "utf-8", "utf-16"]}
+        })));
+        assert_eq!(negotiated.position_encoding, PositionEncodingKind::UTF32, "the first supported encoding should win");
+
+        let negotiated = negotiate(&client_capabilities(json!({
+            "general": {"positionEncodings": ["made-up", "utf-8"]}
+        })));
+        assert_eq!(negotiated.position_encoding, PositionEncodingKind::UTF8, "unknown encodings should be skipped");
+
+        let capabilities = adapt_to_client(Default::default(), &negotiated);
+        assert_eq!(capabilities.position_encoding, Some(PositionEncodingKind::UTF8));
+    }
+
+    #[test]
+    fn test_negotiate_document_symbols_and_snippets() {
+        let negotiated = negotiate(&client_capabilities(json!({
+            "textDocument": {
+                "documentSymbol": {"hierarchicalDocumentSymbolSupport": true},
+                "completion": {"completionItem": {"snippetSupport": true}},
+                "diagnostic": {"relatedDocumentSupport": true},
+                "definition": {"linkSupport": true},
+                "typeDefinition": {"linkSupport": false}
+            },
+            "window": {"workDoneProgress": true},
+            "workspace": {
+                "didChangeWatchedFiles": {"dynamicRegistration": true},
+                "diagnostic": {"refreshSupport": true},
+                "configuration": true,
+                "didChangeConfiguration": {"dynamicRegistration": true},
+                "workspaceEdit": {"documentChanges": true, "changeAnnotationSupport": {"groupsOnLabel": true}}
+            }
+        })));
+
+        assert!(negotiated.hierarchical_document_symbols);
+        assert!(negotiated.snippet_support);
+        assert!(negotiated.work_done_progress);
+        assert!(negotiated.pull_diagnostics);
+        assert!(negotiated.related_document_diagnostics);
+        assert!(negotiated.watch_files);
+        assert!(negotiated.diagnostic_refresh);
+        assert!(negotiated.configuration);
+        assert!(negotiated.watch_configuration);
+        assert!(negotiated.definition_links);
+        assert!(!negotiated.declaration_links, "declaration did not say anything");
+        assert!(!negotiated.type_definition_links);
+        assert!(negotiated.change_annotations);
+
+        let without_document_changes = negotiate(&client_capabilities(json!({
+            "workspace": {"workspaceEdit": {"changeAnnotationSupport": {}}}
+        })));
+        assert!(!without_document_changes.change_annotations);
+    }
+}
@@ -0,0 +1,481 @@
+// ドキュメントに対して動かすチェック
+// - lesson_1_13: TODO コメント（探す言葉は設定の diagnostics.todoKeywords）
+// - lesson_4_1: 未使用の変数 / lesson_4_2: 未使用のインポート / lesson_4_4: 到達できないコード
+// lesson_4 のチェッカーは組み立て済みの AST を受け取るので、common::syntax のトークンから簡単な AST を組み立てて渡す。
+// 完全な構文解析はしないので、分からないところは「使われている」「到達できる」側に倒して、誤った警告を出さないようにする
+
+use lsp_types::{Diagnostic, DiagnosticSeverity, DiagnosticTag, NumberOrString, PositionEncodingKind, Range, Url};
+
+use crate::common::line_index::LineIndex;
+use crate::common::syntax::{split_statements, token_trees, tokenize, unbalanced_delimiter, Token, TokenKind, TokenTree};
+use crate::lessons::lesson_1::lesson_1_13::generate_diagnostics_with_keywords;
+use crate::lessons::lesson_4::common::ast::{Expr, Program, Stmt};
+use crate::lessons::lesson_4::common::diagnostic as lesson_4;
+use crate::lessons::lesson_4::common::span::{Position, Span};
+use crate::lessons::lesson_4::lesson_4_1::check_unused_variables;
+use crate::lessons::lesson_4::lesson_4_2::{check_unused_imports, Import, ProgramWithImports};
+use crate::lessons::lesson_4::lesson_4_4::{check_unreachable_code, FlowExpr, FlowProgram, FlowStmt};
+use crate::server::settings::DiagnosticsSettings;
+
+// ドキュメントの診断をすべて集める（位置の順に並べる）
+pub fn check_document(uri: &Url, text: &str, encoding: &PositionEncodingKind, settings: &DiagnosticsSettings) -> Vec<Diagnostic> {
+    let index = LineIndex::new(text, encoding.clone());
+    let tokens = tokenize(text);
+
+    let mut diagnostics = generate_diagnostics_with_keywords(uri.clone(), text, &settings.todo_keywords);
+
+    let program = lower_program(&tokens, &index);
+    let variables = Program { statements: program.statements.clone() };
+    diagnostics.extend(check_unused_variables(&variables).into_iter().map(|diagnostic| to_lsp(diagnostic, "unused_variables")));
+    diagnostics.extend(check_unused_imports(&program).into_iter().map(|mut diagnostic| {
+        if program.imports.iter().any(|import| import.span == diagnostic.span && may_be_trait(&import.imported_name)) {
+            diagnostic.severity = lesson_4::DiagnosticSeverity::Hint;
+        }
+        to_lsp(diagnostic, "unused_imports")
+    }));
+
+    let mut flow_programs = Vec::new();
+    let statements = lower_statements(&token_trees(&tokens), &index, &mut flow_programs);
+    flow_programs.push(FlowProgram { statements });
+    for program in &flow_programs {
+        diagnostics.extend(check_unreachable_code(program).into_iter().map(|diagnostic| to_lsp(diagnostic, "unreachable_code")));
+    }
+
+    // チェッカーは HashMap の順で返すので、結果が毎回同じになるように並べる
+    diagnostics.sort_by(|a, b| (a.range.start, a.range.end, &a.message).cmp(&(b.range.start, b.range.end, &b.message)));
+    diagnostics
+}
+
+// ドキュメントを解析しきれない理由と、その範囲
+// 今のところ見ているのは括弧の対応だけ（対応が取れていないと、それより後ろのチェックが当てにならない）
+pub fn syntax_problem(text: &str, encoding: &PositionEncodingKind) -> Option<(Range, String)> {
+    let token = unbalanced_delimiter(&token_trees(&tokenize(text)))?;
+    let message = match token.text {
+        "(" | "[" | "{" => format!("unclosed '{}'", token.text),
+        _ => format!("unexpected '{}'", token.text),
+    };
+    Some((LineIndex::new(text, encoding.clone()).range(token.range()), message))
+}
+
+// `mod name;` と `use crate::name` / `use super::name` / `use self::name` で参照しているモジュールの名前
+// 別のファイルの中身に依存していることを表すので、関連ドキュメント（relatedDocuments）を探すのに使う
+pub fn module_dependencies(text: &str) -> Vec<String> {
+    let tokens = tokenize(text);
+    let mut names = Vec::new();
+    for (i, token) in tokens.iter().enumerate() {
+        let name = match (token.text, tokens.get(i + 1), tokens.get(i + 2), tokens.get(i + 3)) {
+            ("mod", Some(name), Some(semicolon), _) if token.kind == TokenKind::Ident && semicolon.is_punct(";") => name,
+            ("use", Some(root), Some(separator), Some(name))
+                if token.kind == TokenKind::Ident
+                    && ["crate", "super", "self"].contains(&root.text)
+                    && separator.is_punct("::") =>
+            {
+                name
+            }
+            _ => continue,
+        };
+        if name.is_ident() && !names.iter().any(|known| known == name.text) {
+            names.push(name.text.to_string());
+        }
+    }
+    names
+}
+
+// lesson_4 の Diagnostic → LSP の Diagnostic
+// lesson_4 の Span には LineIndex で変換した Position の行・列がそのまま入っている
+fn to_lsp(diagnostic: lesson_4::Diagnostic, code: &str) -> Diagnostic {
+    let severity = match diagnostic.severity {
+        lesson_4::DiagnosticSeverity::Error => DiagnosticSeverity::ERROR,
+        lesson_4::DiagnosticSeverity::Warning => DiagnosticSeverity::WARNING,
+        lesson_4::DiagnosticSeverity::Information => DiagnosticSeverity::INFORMATION,
+        lesson_4::DiagnosticSeverity::Hint => DiagnosticSeverity::HINT,
+    };
+    let position = |position: &Position| lsp_types::Position::new(position.line as u32, position.column as u32);
+
+    Diagnostic {
+        range: Range::new(position(&diagnostic.span.start), position(&diagnostic.span.end)),
+        severity: Some(severity),
+        code: Some(NumberOrString::String(diagnostic.code.unwrap_or_else(|| code.to_string()))),
+        source: Some("toy-lang-server".to_string()),
+        message: diagnostic.message,
+        // 未使用・到達できないコードは、エディタで薄く表示してもらう
+        tags: Some(vec![DiagnosticTag::UNNECESSARY]),
+        ..Default::default()
+    }
+}
+
+fn span(index: &LineIndex, start: usize, end: usize) -> Span {
+    let position = |offset: usize| {
+        let position = index.position(offset);
+        Position::new(position.line as usize, position.character as usize)
+    };
+    Span::new(position(start), position(end))
+}
+
+// lesson_4_1 / lesson_4_2 用の AST
+// - `let name = ...` → LetDeclaration（パターンで束縛するもの、`_` で始まる名前は対象にしない）
+// - `use path::Name;` → Import
+// - それ以外の識別子と、文字列の中の "{name}" → Expression(Identifier)（使われている扱い）
+fn lower_program(tokens: &[Token], index: &LineIndex) -> ProgramWithImports {
+    let mut imports = Vec::new();
+    let mut statements = Vec::new();
+    let mut i = 0;
+
+    while i < tokens.len() {
+        let token = &tokens[i];
+        if token.is_keyword("use") {
+            // `pub use` は外に向けて公開しているものなので、このファイルで使っていなくてもよい
+            let public = is_public(&tokens[..i]);
+            let mut found = Vec::new();
+            i = lower_use(tokens, i + 1, index, &mut found);
+            if !public {
+                imports.extend(found);
+            }
+            continue;
+        }
+        if token.is_keyword("let") {
+            if let Some((statement, next)) = lower_let(tokens, i + 1, index) {
+                statements.push(statement);
+                i = next;
+                continue;
+            }
+        }
+
+        match token.kind {
+            TokenKind::Ident if token.is_ident() => statements.push(identifier(token, index)),
+            TokenKind::Str => {
+                for (name, offset) in format_arguments(token.text) {
+                    let start = token.start + offset;
+                    statements.push(Stmt::Expression(Expr::Identifier(name.to_string(), span(index, start, start + name.len()))));
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+
+    ProgramWithImports { imports, statements }
+}
+
+// UpperCamelCase の名前は、メソッドを使うためだけに取り込んだトレイトかもしれない
+// 名前が出てこなくても使われているかどうかは型を調べないと分からないので、警告ではなくヒントにとどめる
+fn may_be_trait(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_uppercase()) && !name.contains('_')
+}
+
+// tokens の最後が `pub` / `pub(...)` か
+fn is_public(tokens: &[Token]) -> bool {
+    match tokens {
+        [.., last] if last.is_keyword("pub") => true,
+        [.., _, _, close] if close.is_punct(")") => {
+            let open = tokens.iter().rposition(|token| token.is_punct("("));
+            open.is_some_and(|open| open > 0 && tokens[open - 1].is_keyword("pub"))
+        }
+        _ => false,
+    }
+}
+
+fn identifier(token: &Token, index: &LineIndex) -> Stmt {
+    Stmt::Expression(Expr::Identifier(token.text.to_string(), span(index, token.start, token.end())))
+}
+
+// `let` の後ろ（tokens[start..]）
+// 名前1つを束縛するなら LetDeclaration と、続きを読む位置（名前の次）を返す
+// 型注釈や値の中の識別子は、呼び出し側でいつもどおり「使われている」ものとして読む
+fn lower_let(tokens: &[Token], start: usize, index: &LineIndex) -> Option<(Stmt, usize)> {
+    let mut i = start;
+    if tokens.get(i)?.is_keyword("mut") {
+        i += 1;
+    }
+    let name = tokens.get(i)?;
+    let after_name = tokens.get(i + 1);
+    if !name.is_ident() || name.text.starts_with('_') || !after_name.is_some_and(|token| token.is_punct(":") || token.is_punct("=")) {
+        return None;
+    }
+
+    // 型注釈（`: [u8; 4]` のように ";" を含むこともある）を飛ばして "=" を探し、値は ";" まで
+    let mut value = None;
+    let mut after_equals = false;
+    let mut depth = 0;
+    for token in &tokens[i + 1..] {
+        if token.kind == TokenKind::Punct {
+            match token.text {
+                "(" | "[" | "{" => depth += 1,
+                ")" | "]" | "}" if depth == 0 => break,
+                ")" | "]" | "}" => depth -= 1,
+                ";" if depth == 0 => break,
+                "=" if depth == 0 => after_equals = true,
+                _ => {}
+            }
+        } else if after_equals && is_atom(token) {
+            value = Some(atom(token, index));
+            break;
+        }
+    }
+
+    // `let x;` や値にリテラルも識別子も無いもの（`let x = [];` など）は対象にしない
+    let statement = Stmt::LetDeclaration {
+        name: name.text.to_string(),
+        value: value?,
+        span: span(index, name.start, name.end()),
+    };
+    Some((statement, i + 1))
+}
+
+fn is_atom(token: &Token) -> bool {
+    token.is_ident() || token.is_keyword("true") || token.is_keyword("false") || matches!(token.kind, TokenKind::Number | TokenKind::Str | TokenKind::Char)
+}
+
+fn atom(token: &Token, index: &LineIndex) -> Expr {
+    let span = span(index, token.start, token.end());
+    match token.kind {
+        TokenKind::Number => Expr::Number(token.text.parse().unwrap_or_default(), span),
+        TokenKind::Str | TokenKind::Char => Expr::String(token.text.to_string(), span),
+        _ if token.is_keyword("true") || token.is_keyword("false") => Expr::Boolean(token.text == "true", span),
+        _ => Expr::Identifier(token.text.to_string(), span),
+    }
+}
+
+// `use` の後ろ（tokens[start..]）を ";" まで読んで、取り込む名前を imports に足す
+// `use a::{b, c::d as e};` のような入れ子にも対応する。`*` と `self`、`as _` は名前を取り込まないので対象にしない
+fn lower_use(tokens: &[Token], start: usize, index: &LineIndex, imports: &mut Vec<Import>) -> usize {
+    // path: いま読んでいるパス、groups: "{" を開いたときのパスの長さ
+    let mut path: Vec<&str> = Vec::new();
+    let mut groups: Vec<usize> = Vec::new();
+    let mut i = start;
+
+    while let Some(token) = tokens.get(i) {
+        i += 1;
+        match token.text {
+            ";" if token.kind == TokenKind::Punct => break,
+            "}" if token.kind == TokenKind::Punct && groups.is_empty() => break,
+            "{" if token.kind == TokenKind::Punct => groups.push(path.len()),
+            "}" if token.kind == TokenKind::Punct => {
+                path.truncate(groups.pop().unwrap_or(0));
+            }
+            "," if token.kind == TokenKind::Punct => path.truncate(groups.last().copied().unwrap_or(0)),
+            _ if token.kind == TokenKind::Ident => {
+                let next = tokens.get(i);
+                if next.is_some_and(|next| next.is_punct("::")) {
+                    path.push(token.text);
+                    i += 1;
+                    continue;
+                }
+                // パスの最後の名前（`as` があれば別名）が取り込まれる
+                let (name, alias_end) = match (next, tokens.get(i + 1)) {
+                    (Some(as_keyword), Some(alias)) if as_keyword.is_keyword("as") => (alias, i + 2),
+                    _ => (token, i),
+                };
+                if name.is_ident() && name.text != "_" {
+                    imports.push(Import {
+                        module_name: path.join("::"),
+                        imported_name: name.text.to_string(),
+                        span: span(index, name.start, name.end()),
+                    });
+                }
+                i = alias_end;
+            }
+            _ => {}
+        }
+    }
+    i
+}
+
+// 書式文字列の中で名前で参照している引数（"{name}" / "{name:?}"）と、トークンの先頭からのバイトオフセット
+fn format_arguments(literal: &str) -> Vec<(&str, usize)> {
+    let mut arguments = Vec::new();
+    let mut offset = 0;
+    while let Some(open) = literal[offset..].find('{') {
+        let start = offset + open + 1;
+        // "{{" はエスケープされた "{"
+        if literal[start..].starts_with('{') {
+            offset = start + 1;
+            continue;
+        }
+        let len = literal[start..].find(|c: char| !c.is_alphanumeric() && c != '_').unwrap_or(literal.len() - start);
+        let name = &literal[start..start + len];
+        if name.starts_with(|c: char| c.is_alphabetic() || c == '_') && literal[start + len..].starts_with(['}', ':']) {
+            arguments.push((name, start));
+        }
+        offset = start;
+    }
+    arguments
+}
+
+// lesson_4_4 用の AST
+// 文の並び（ブロックの中身）を FlowStmt にする。return と if / else、ただのブロックだけを読み分け、
+// それ以外の文（関数やループ、クロージャなど）の中のブロックは、別の並びとして programs に集める
+fn lower_statements(trees: &[TokenTree], index: &LineIndex, programs: &mut Vec<FlowProgram>) -> Vec<FlowStmt> {
+    split_statements(trees)
+        .into_iter()
+        .map(|statement| lower_statement(statement, index, programs))
+        .collect()
+}
+
+fn lower_statement(statement: &[TokenTree], index: &LineIndex, programs: &mut Vec<FlowProgram>) -> FlowStmt {
+    let first = statement[0].first();
+    let end = statement.last().map_or(first.end(), TokenTree::end);
+    let statement_span = span(index, first.start, end);
+
+    if let [TokenTree::Group { open, children, .. }] = statement {
+        if open.text == "{" {
+            return FlowStmt::Block { statements: lower_statements(children, index, programs), span: statement_span };
+        }
+    }
+    if first.is_keyword("if") {
+        if let Some(if_statement) = lower_if(statement, index, programs) {
+            return if_statement;
+        }
+    }
+
+    collect_blocks(statement, index, programs);
+    if first.is_keyword("return") {
+        return FlowStmt::Return { value: None, span: statement_span };
+    }
+    FlowStmt::Expression(FlowExpr::Identifier(first.text.to_string(), statement_span))
+}
+
+// `if 条件 { ... } else if ... { ... } else { ... }`
+fn lower_if(statement: &[TokenTree], index: &LineIndex, programs: &mut Vec<FlowProgram>) -> Option<FlowStmt> {
+    let first = statement[0].first();
+    let then_index = statement.iter().position(|tree| tree.group("{").is_some())?;
+    collect_blocks(&statement[1..then_index], index, programs);
+
+    let block = |tree: &TokenTree, programs: &mut Vec<FlowProgram>| {
+        let statements = lower_statements(tree.group("{").unwrap_or_default(), index, programs);
+        FlowStmt::Block { statements, span: span(index, tree.first().start, tree.end()) }
+    };
+    let then_branch = block(&statement[then_index], programs);
+
+    let rest = &statement[then_index + 1..];
+    let else_branch = match rest {
+        [else_keyword, otherwise @ ..] if else_keyword.leaf().is_some_and(|token| token.is_keyword("else")) => {
+            match otherwise {
+                [tree] if tree.group("{").is_some() => Some(block(tree, programs)),
+                [tree, ..] if tree.leaf().is_some_and(|token| token.is_keyword("if")) => lower_if(otherwise, index, programs),
+                _ => {
+                    collect_blocks(otherwise, index, programs);
+                    None
+                }
+            }
+        }
+        _ => {
+            collect_blocks(rest, index, programs);
+            None
+        }
+    };
+
+    let condition = statement.get(1).map_or(first, TokenTree::first);
+    Some(FlowStmt::IfStatement {
+        condition: FlowExpr::Identifier(condition.text.to_string(), span(index, condition.start, condition.end())),
+        then_branch: Box::new(then_branch),
+        else_branch: else_branch.map(Box::new),
+        span: span(index, first.start, statement.last().map_or(first.end(), TokenTree::end)),
+    })
+}
+
+// 文の中にあるブロック（関数の本体、ループ、クロージャなど）を、それぞれ別の並びとして programs に足す
+fn collect_blocks(trees: &[TokenTree], index: &LineIndex, programs: &mut Vec<FlowProgram>) {
+    for tree in trees {
+        if let TokenTree::Group { open, children, .. } = tree {
+            if open.text == "{" {
+                let statements = lower_statements(children, index, programs);
+                programs.push(FlowProgram { statements });
+            } else {
+                collect_blocks(children, index, programs);
+            }
+        }
+    }
+}
+
+
+// --- Tests --- //
+
+#[cfg(test)]
+mod tests {
+    use super::{check_document, module_dependencies, syntax_problem};
+    use crate::server::settings::DiagnosticsSettings;
+    use lsp_types::{Diagnostic, DiagnosticSeverity, NumberOrString, Position, PositionEncodingKind, Range, Url};
+    use std::str::FromStr;
+
+    fn check(text: &str) -> Vec<Diagnostic> {
+        check_document(&Url::from_str("file:///test.rs").unwrap(), text, &PositionEncodingKind::UTF16, &DiagnosticsSettings::default())
+    }
+
+    fn codes(diagnostics: &[Diagnostic]) -> Vec<(u32, String)> {
+        diagnostics
+            .iter()
+            .map(|diagnostic| match &diagnostic.code {
+                Some(NumberOrString::String(code)) => (diagnostic.range.start.line, code.clone()),
+                _ => (diagnostic.range.start.line, diagnostic.message.clone()),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_unused_variables_and_imports() {
+        let text = "use std::collections::{HashMap, HashSet as Set};\nuse std::io::Write;\npub use crate::a::Exported;\n\nfn main() {\n    let unused = 1;\n    let mut used = HashMap::new();\n    let _ignored = 2;\n    let (a, b) = (1, 2);\n    println!(\"{used:?}\");\n}";
+        let diagnostics = check(text);
+
+        assert_eq!(
+            codes(&diagnostics),
+            vec![
+                (0, "unused_import".to_string()),
+                (1, "unused_import".to_string()),
+                (5, "unused_variables".to_string())
+            ],
+            "Set, Write and unused should be reported; re-exports, `_` names and patterns should not"
+        );
+        assert!(diagnostics[0].message.contains("Set"));
+        assert!(diagnostics[1].message.contains("Write"));
+        // Write は write! などのためだけに取り込んだトレイトかもしれない
+        assert_eq!(diagnostics[1].severity, Some(DiagnosticSeverity::HINT));
+        assert_eq!(diagnostics[2].severity, Some(DiagnosticSeverity::WARNING));
+        assert_eq!(diagnostics[2].range.start, Position::new(5, 8), "the variable name should be highlighted");
+
+        let text = "use std::fmt::{self, format};
+
+fn main() {
+    fmt::x();
+}";
+        let diagnostics = check(text);
+        assert_eq!(codes(&diagnostics), vec![(0, "unused_import".to_string())]);
+        assert_eq!(diagnostics[0].severity, Some(DiagnosticSeverity::WARNING), "lower-case names are not traits");
+    }
+
+    #[test]
+    fn test_unreachable_code() {
+        let text = "fn f(x: bool) -> i32 {\n    if x {\n        return 1;\n    } else {\n        return 2;\n    }\n    let y = 3;\n    y\n}\nfn g(x: bool) {\n    if x { return; }\n    for i in 0..3 { return; }\n    h(|| { return; });\n    done();\n}";
+        let diagnostics = check(text);
+
+        assert_eq!(
+            codes(&diagnostics),
+            vec![(6, "unreachable_code".to_string()), (7, "unreachable_code".to_string())],
+            "only code after a return on every branch is unreachable"
+        );
+    }
+
+    #[test]
+    fn test_todo_comments_are_included() {
+        let diagnostics = check("// TODO: later\nfn main() {}");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].message, "Found a TODO item.");
+    }
+
+    #[test]
+    fn test_module_dependencies() {
+        let text = "mod parser;\nuse crate::lexer::Token;\nuse super::ast;\nuse std::fmt;\nmod inline { }";
+        assert_eq!(module_dependencies(text), vec!["parser", "lexer", "ast"]);
+    }
+
+    #[test]
+    fn test_syntax_problem() {
+        let encoding = PositionEncodingKind::UTF16;
+        assert_eq!(syntax_problem("fn main() {\n    f(1;\n}", &encoding).map(|(_, message)| message), Some("unclosed '{'".to_string()));
+        assert_eq!(
+            syntax_problem("fn main() {}\n}", &encoding),
+            Some((Range::new(Position::new(1, 0), Position::new(1, 1)), "unexpected '}'".to_string()))
+        );
+        assert_eq!(syntax_problem("fn main() { f([1]); }", &encoding), None);
+    }
+}
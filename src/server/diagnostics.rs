@@ -0,0 +1,219 @@
+// 診断をクライアントに届ける
+// プッシュ型（textDocument/publishDiagnostics）: 入力のたびに診断を計算すると重いので、最後の変更から delay だけ待ってから計算する（デバウンス）
+// - 待っている間に次の変更が来たら、待ち時間をやり直す
+// - 送る診断には、計算したときのドキュメントの version を付ける
+// - 計算し終わったときにドキュメントが新しくなっていたら、その結果は古いので送らない
+// プル型（textDocument/diagnostic, workspace/diagnostic）: クライアントが好きなときに取りに来る
+// - resultId は診断の中身から作る。クライアントが前回受け取った resultId と同じなら、中身を送らずに Unchanged を返す
+// - 関連ドキュメント: `mod name;` や `use crate::name::...` で参照している、開いているドキュメント
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+use lsp_types::{
+    Diagnostic, DocumentDiagnosticReportKind, FullDocumentDiagnosticReport, UnchangedDocumentDiagnosticReport, Url,
+    WorkspaceDocumentDiagnosticReport, WorkspaceFullDocumentDiagnosticReport, WorkspaceUnchangedDocumentDiagnosticReport,
+};
+
+use crate::common::document_store::DocumentStore;
+use crate::server::checks::module_dependencies;
+
+// 最後の変更から診断を計算するまで待つ時間のデフォルト
+pub const DEFAULT_DIAGNOSTICS_DELAY: Duration = Duration::from_millis(200);
+
+#[derive(Debug)]
+pub struct DiagnosticsScheduler {
+    delay: Duration,
+    // 診断を計算する時刻（ドキュメントごと）
+    pending: HashMap<Url, Instant>,
+}
+
+impl DiagnosticsScheduler {
+    pub fn new() -> Self {
+        Self::with_delay(DEFAULT_DIAGNOSTICS_DELAY)
+    }
+
+    pub fn with_delay(delay: Duration) -> Self {
+        DiagnosticsScheduler { delay, pending: HashMap::new() }
+    }
+
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    // uri が now に変更された。まだ待っていれば待ち時間をやり直す
+    pub fn schedule(&mut self, uri: Url, now: Instant) {
+        self.pending.insert(uri, now + self.delay);
+    }
+
+    // uri の診断はもう要らない（閉じられた、すぐに送った など）
+    pub fn cancel(&mut self, uri: &Url) {
+        self.pending.remove(uri);
+    }
+
+    // 一番近い計算の時刻（待っているものが無ければ None）
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().min().copied()
+    }
+
+    // now の時点で計算する時刻になったドキュメントを取り出す
+    pub fn take_due(&mut self, now: Instant) -> Vec<Url> {
+        let due: Vec<Url> = self
+            .pending
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(uri, _)| uri.clone())
+            .collect();
+        for uri in &due {
+            self.pending.remove(uri);
+        }
+        due
+    }
+}
+
+impl Default for DiagnosticsScheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// 診断の中身から作る resultId（同じ中身なら同じ値になる）
+pub fn result_id(diagnostics: &[Diagnostic]) -> String {
+    let mut hasher = DefaultHasher::new();
+    serde_json::to_string(diagnostics).unwrap_or_default().hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+// クライアントが previous_result_id を持っていて中身が変わっていなければ Unchanged、そうでなければ Full
+pub fn document_report(diagnostics: Vec<Diagnostic>, previous_result_id: Option<&str>) -> DocumentDiagnosticReportKind {
+    let result_id = result_id(&diagnostics);
+    if previous_result_id == Some(result_id.as_str()) {
+        DocumentDiagnosticReportKind::Unchanged(UnchangedDocumentDiagnosticReport { result_id })
+    } else {
+        DocumentDiagnosticReportKind::Full(FullDocumentDiagnosticReport { result_id: Some(result_id), items: diagnostics })
+    }
+}
+
+// workspace/diagnostic の1ドキュメント分（どの version の診断かも付ける）
+// 開いていないファイル（ディスクの中身）の診断は version を None にする
+pub fn workspace_report(uri: Url, version: Option<i32>, report: DocumentDiagnosticReportKind) -> WorkspaceDocumentDiagnosticReport {
+    let version = version.map(i64::from);
+    match report {
+        DocumentDiagnosticReportKind::Full(full_document_diagnostic_report) => {
+            WorkspaceDocumentDiagnosticReport::Full(WorkspaceFullDocumentDiagnosticReport { uri, version, full_document_diagnostic_report })
+        }
+        DocumentDiagnosticReportKind::Unchanged(unchanged_document_diagnostic_report) => {
+            WorkspaceDocumentDiagnosticReport::Unchanged(WorkspaceUnchangedDocumentDiagnosticReport {
+                uri,
+                version,
+                unchanged_document_diagnostic_report,
+            })
+        }
+    }
+}
+
+// uri が参照しているモジュールのファイルのうち、開いているもの
+// `mod parser;` なら .../parser.rs か .../parser/mod.rs
+pub fn related_documents(store: &DocumentStore, uri: &Url) -> Vec<Url> {
+    let Some(text) = store.text(uri) else {
+        return Vec::new();
+    };
+    let modules = module_dependencies(text);
+
+    let mut related: Vec<Url> = store
+        .iter()
+        .map(|(other, _)| other)
+        .filter(|other| *other != uri)
+        .filter(|other| module_name(other).is_some_and(|module| modules.iter().any(|name| name == module)))
+        .cloned()
+        .collect();
+    related.sort();
+    related
+}
+
+// ファイルが表すモジュールの名前（.../parser.rs も .../parser/mod.rs も parser）
+pub fn module_name(uri: &Url) -> Option<&str> {
+    let mut segments = uri.path().rsplit('/');
+    match segments.next()?.strip_suffix(".rs")? {
+        "mod" => segments.next(),
+        stem => Some(stem),
+    }
+}
+
+
+// --- Tests --- //
+
+#[cfg(test)]
+mod tests {
+    use super::{document_report, related_documents, DiagnosticsScheduler};
+    use crate::common::document_store::DocumentStore;
+    use lsp_types::{Diagnostic, DocumentDiagnosticReportKind, Position, Range, Url};
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+
+    fn uri(path: &str) -> Url {
+        Url::from_str(&format!("file:///{}", path)).unwrap()
+    }
+
+    #[test]
+    fn test_edits_are_debounced() {
+        let mut scheduler = DiagnosticsScheduler::with_delay(Duration::from_millis(100));
+        let start = Instant::now();
+
+        scheduler.schedule(uri("a.rs"), start);
+        // 待っている間にもう一度変更された
+        scheduler.schedule(uri("a.rs"), start + Duration::from_millis(60));
+
+        assert!(scheduler.take_due(start + Duration::from_millis(100)).is_empty(), "the second edit should restart the wait");
+        assert_eq!(scheduler.next_deadline(), Some(start + Duration::from_millis(160)));
+        assert_eq!(scheduler.take_due(start + Duration::from_millis(160)), vec![uri("a.rs")]);
+        assert!(scheduler.next_deadline().is_none(), "each edit should be diagnosed once");
+    }
+
+    #[test]
+    fn test_documents_are_scheduled_separately() {
+        let mut scheduler = DiagnosticsScheduler::with_delay(Duration::from_millis(100));
+        let start = Instant::now();
+
+        scheduler.schedule(uri("a.rs"), start);
+        scheduler.schedule(uri("b.rs"), start + Duration::from_millis(50));
+        scheduler.schedule(uri("c.rs"), start);
+        scheduler.cancel(&uri("c.rs"));
+
+        assert_eq!(scheduler.take_due(start + Duration::from_millis(100)), vec![uri("a.rs")]);
+        assert_eq!(scheduler.take_due(start + Duration::from_millis(150)), vec![uri("b.rs")]);
+        assert!(scheduler.take_due(start + Duration::from_secs(1)).is_empty(), "cancelled documents should not be diagnosed");
+    }
+
+    #[test]
+    fn test_unchanged_diagnostics_keep_their_result_id() {
+        let diagnostics = vec![Diagnostic::new_simple(Range::new(Position::new(0, 0), Position::new(0, 1)), "x".to_string())];
+
+        let DocumentDiagnosticReportKind::Full(first) = document_report(diagnostics.clone(), None) else {
+            panic!("the first report should be full");
+        };
+        let result_id = first.result_id.expect("a full report should have a result id");
+
+        let second = document_report(diagnostics, Some(&result_id));
+        assert!(matches!(second, DocumentDiagnosticReportKind::Unchanged(ref report) if report.result_id == result_id));
+
+        let DocumentDiagnosticReportKind::Full(changed) = document_report(Vec::new(), Some(&result_id)) else {
+            panic!("changed diagnostics should be sent in full");
+        };
+        assert_ne!(changed.result_id, Some(result_id), "different diagnostics need a different result id");
+    }
+
+    #[test]
+    fn test_related_documents_are_open_module_files() {
+        let mut store = DocumentStore::new();
+        store.open(uri("src/main.rs"), "rust", 1, "mod parser;\nmod lexer;\nuse crate::ast::Node;");
+        store.open(uri("src/parser.rs"), "rust", 1, "");
+        store.open(uri("src/ast/mod.rs"), "rust", 1, "");
+        store.open(uri("src/other.rs"), "rust", 1, "");
+
+        assert_eq!(related_documents(&store, &uri("src/main.rs")), vec![uri("src/ast/mod.rs"), uri("src/parser.rs")]);
+        assert!(related_documents(&store, &uri("src/other.rs")).is_empty());
+    }
+}
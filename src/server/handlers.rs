@@ -0,0 +1,889 @@
+// リクエスト / 通知ハンドラ
+// lesson_1 で作った各機能を lsp_types の型に合わせてルーターへ登録する
+
+use std::collections::HashMap;
+use std::time::Instant;
+
+use lsp_types::notification::{
+    Cancel, DidChangeConfiguration, DidChangeTextDocument, DidChangeWatchedFiles, DidCloseTextDocument, DidOpenTextDocument, Exit, Initialized,
+    Notification, SetTrace, WorkDoneProgressCancel,
+};
+use lsp_types::request::{
+    CallHierarchyIncomingCalls, CallHierarchyPrepare, CodeActionRequest, CodeLensRequest, Completion,
+    DocumentDiagnosticRequest, DocumentHighlightRequest, DocumentSymbolRequest, FoldingRangeRequest, Formatting, GotoDeclaration, GotoDefinition,
+    GotoTypeDefinition, HoverRequest, Initialize, InlayHintRequest, LinkedEditingRange, PrepareRenameRequest, References, RegisterCapability, Rename,
+    ResolveCompletionItem, SelectionRangeRequest, SemanticTokensFullRequest, Shutdown, SignatureHelpRequest, WorkspaceConfiguration,
+    WorkspaceDiagnosticRefresh, WorkspaceDiagnosticRequest, WorkspaceSymbolRequest,
+};
+use lsp_types::{
+    CallHierarchyItem, CodeActionOrCommand, CompletionItem, ConfigurationItem, ConfigurationParams, CompletionItemKind, CompletionResponse, CompletionTextEdit, Diagnostic,
+    DidChangeWatchedFilesRegistrationOptions, DocumentDiagnosticReport, DocumentDiagnosticReportKind, DocumentDiagnosticReportResult, DocumentSymbol, DocumentSymbolResponse, FileSystemWatcher, GlobPattern, GotoDefinitionResponse, InitializeResult,
+    InsertReplaceEdit, InsertTextFormat, Location, MessageType, PartialResultParams, Position, PositionEncodingKind, Range,
+    Registration, RegistrationParams, RelatedFullDocumentDiagnosticReport, RelatedUnchangedDocumentDiagnosticReport, SemanticTokensResult, ServerCapabilities,
+    ServerInfo, SymbolInformation, SymbolKind, TextDocumentPositionParams, TextEdit, TraceValue, Url, WorkDoneProgressParams, WorkspaceDiagnosticReport,
+    WorkspaceDiagnosticReportPartialResult, WorkspaceDiagnosticReportResult, WorkspaceSymbolResponse,
+};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::lessons::lesson_1::lesson_1_9::ResponseError;
+use crate::lessons::lesson_1::lesson_1_12::LifecycleState;
+use crate::lessons::lesson_1::lesson_1_14::{
+    create_publish_diagnostics_notification, create_publish_diagnostics_notification_with_version,
+};
+use crate::lessons::lesson_1::lesson_1_16::open_document;
+use crate::lessons::lesson_1::lesson_1_17::apply_did_change_with_encoding;
+use crate::lessons::lesson_1::lesson_1_18::handle_did_close_notification;
+use crate::common::document_store::{Document, DocumentStore};
+use crate::lessons::lesson_1::lesson_1_19::get_hover_info_with_encoding;
+use crate::lessons::lesson_1::lesson_1_23::get_code_actions;
+use crate::lessons::lesson_1::lesson_1_26::get_document_highlights_with_encoding;
+use crate::lessons::lesson_1::lesson_1_27::get_inlay_hints_with_encoding;
+use crate::lessons::lesson_1::lesson_1_28::{get_ranked_completion_items_with_encoding, resolve_completion_item};
+use crate::lessons::lesson_1::lesson_1_29::get_signature_help_with_encoding;
+use crate::lessons::lesson_1::lesson_1_30::{symbol_matches, workspace_symbol_in_document};
+use crate::lessons::lesson_1::lesson_1_31::call_hierarchy_incoming_calls_in_document;
+use crate::lessons::lesson_1::lesson_1_32::provide_semantic_tokens_with_encoding;
+use crate::lessons::lesson_1::lesson_1_33::format_document_with_indent;
+use crate::lessons::lesson_1::lesson_1_34::provide_folding_ranges_with_encoding;
+use crate::lessons::lesson_1::lesson_1_35::provide_selection_ranges_with_encoding;
+use crate::lessons::lesson_1::lesson_1_36::provide_code_lenses_for;
+use crate::lessons::lesson_1::lesson_1_37::provide_linked_editing_ranges_with_encoding;
+use crate::server::cancellation::{request_cancelled, CancellationToken, InFlightRequests};
+use crate::server::capabilities::{adapt_to_client, negotiate, NegotiatedCapabilities};
+use crate::server::checks::{check_document, syntax_problem};
+use crate::server::diagnostics::{document_report, related_documents, workspace_report, DiagnosticsScheduler};
+use crate::server::hover::semantic_hover;
+use crate::server::logging::{log_message, show_message};
+use crate::server::navigation::{goto, Target};
+use crate::server::outgoing::{Outgoing, Sink};
+use crate::server::progress::{send_partial_result, ProgressTokens, WorkDoneProgress};
+use crate::server::references::{definition_at, references_in_document};
+use crate::server::rename::{prepare_rename, rename};
+use crate::server::router::Router;
+use crate::server::settings::{DiagnosticsSettings, Settings, SECTION};
+use crate::server::symbols::document_symbols;
+use crate::server::workspace::{workspace_roots, WorkspaceIndex};
+
+// メインループの外（ワーカー）で動かす、時間のかかる処理
+// outgoing から $/progress などを送りながら動き、最後にメインループで状態へ反映する処理を返す
+pub type Task = Box<dyn FnOnce(&mut Outgoing<()>) -> Update + Send>;
+pub type Update = Box<dyn FnOnce(&mut ServerState) -> Vec<String> + Send>;
+
+// ハンドラが読み書きするサーバーの状態
+#[derive(Default)]
+pub struct ServerState {
+    pub document_store: DocumentStore,
+    pub lifecycle: LifecycleState,
+    // 登録されているハンドラから作った ServerCapabilities（クライアントに合わせる前のもの）
+    pub capabilities: ServerCapabilities,
+    // initialize でクライアントと決めた設定
+    pub negotiated: NegotiatedCapabilities,
+    // 処理中のリクエストの一覧
+    pub in_flight: InFlightRequests,
+    // サーバーからクライアントへ送るリクエスト / 通知
+    pub outgoing: Outgoing<ServerState>,
+    // サーバーが作った進捗表示のトークン
+    pub progress_tokens: ProgressTokens,
+    // 編集の後、診断を送るまで待っているドキュメント
+    pub diagnostics: DiagnosticsScheduler,
+    // ディスク上のワークスペースのファイルとシンボル（開いていないファイルも含む）
+    pub workspace: WorkspaceIndex,
+    // $/logTrace をどこまで詳しく送るか（initialize の trace と $/setTrace で決まる）
+    pub trace: TraceValue,
+    // クライアントの設定（workspace/configuration で取りに行き、didChangeConfiguration で読み直す）
+    pub settings: Settings,
+    // ハンドラが積んだ、メインループの外で動かす処理（ハンドラが戻った後にメインループがワーカーへ渡す）
+    pub tasks: Vec<Task>,
+}
+
+impl ServerState {
+    // クライアントと決めた positionEncoding
+    pub fn encoding(&self) -> &PositionEncodingKind {
+        &self.negotiated.position_encoding
+    }
+
+    // 読み取り専用のリクエストに渡すスナップショット（cancellation はそのリクエストのキャンセルトークン）
+    // スナップショットから送るメッセージは、送るたびに sink へ渡す
+    pub fn snapshot(&self, cancellation: CancellationToken, sink: Sink) -> ServerSnapshot {
+        ServerSnapshot {
+            document_store: self.document_store.snapshot(),
+            workspace: self.workspace.clone(),
+            negotiated: self.negotiated.clone(),
+            settings: self.settings.clone(),
+            in_flight: self.in_flight.clone(),
+            cancellation,
+            outgoing: self.outgoing.fork(sink),
+            progress_tokens: self.progress_tokens.clone(),
+        }
+    }
+}
+
+// 読み取り専用のハンドラが受け取る、リクエストが届いた時点のサーバーの状態
+// それより前に届いた通知（didChange など）を当てた後の状態をコピーしたもので、ワーカースレッドへ持っていける
+// （ドキュメントの中身は Arc で共有しているので、コピーしても文字列は複製されない）
+pub struct ServerSnapshot {
+    pub document_store: DocumentStore,
+    pub workspace: WorkspaceIndex,
+    pub negotiated: NegotiatedCapabilities,
+    pub settings: Settings,
+    // 処理中のリクエストの一覧（メインループと共有）と、このリクエストのキャンセルトークン
+    pub in_flight: InFlightRequests,
+    pub cancellation: CancellationToken,
+    // $/progress などは、応答を待たずに送った時点でメインループへ渡る
+    pub outgoing: Outgoing<()>,
+    pub progress_tokens: ProgressTokens,
+}
+
+impl ServerSnapshot {
+    pub fn encoding(&self) -> &PositionEncodingKind {
+        &self.negotiated.position_encoding
+    }
+}
+
+pub fn register_handlers(router: &mut Router<ServerState, ServerSnapshot>) {
+    // ライフサイクル
+    // 状態遷移はメインループが LifecycleState で済ませてからハンドラを呼ぶので、ここでは応答を返すだけ
+    router
+        .on_request::<Initialize, _>(|state, params| {
+            state.negotiated = negotiate(&params.capabilities);
+            state.trace = params.trace.unwrap_or_default();
+            state.workspace = WorkspaceIndex::new(workspace_roots(&params), state.negotiated.position_encoding.clone());
+            Ok(initialize_result(adapt_to_client(state.capabilities.clone(), &state.negotiated)))
+        })
+        .on_request::<Shutdown, _>(|_, ()| Ok(()))
+        // ワークスペースのファイルを読み込むのは、クライアントへ進捗表示などを送れるようになる initialized の後
+        // 設定も同じく initialized の後に取りに行く。届くまではデフォルトの設定で動く
+        .on_notification::<Initialized, _>(|state, _params| {
+            index_workspace(state);
+            request_settings(state);
+            register_capabilities(state);
+            Vec::new()
+        })
+        .on_notification::<Exit, _>(|_, ()| Vec::new())
+        .on_notification::<Cancel, _>(|state, params| {
+            state.in_flight.cancel(&params.id);
+            Vec::new()
+        })
+        .on_notification::<WorkDoneProgressCancel, _>(|state, params| {
+            state.in_flight.cancel_progress(&params.token);
+            Vec::new()
+        })
+        .on_notification::<SetTrace, _>(|state, params| {
+            state.trace = params.value;
+            Vec::new()
+        })
+        // workspace/configuration に対応しているクライアントは、通知の settings に中身を入れてこないことが多いので取りに行き直す
+        .on_notification::<DidChangeConfiguration, _>(|state, params| {
+            if state.negotiated.configuration {
+                request_settings(state);
+            } else {
+                apply_settings(state, &params.settings[SECTION]);
+            }
+            Vec::new()
+        });
+
+    // ドキュメントの同期
+    // lesson_1_16〜18 のハンドラは serde_json::Value を受け取るので、型付きの params を Value に戻して渡す
+    // didChange は差分（Incremental）で届くので、version を見て順番どおりに当てる
+    // 診断は開いたときはすぐに送り、変更のときは入力が落ち着くまで待ってから送る（DiagnosticsScheduler）
+    // クライアントが診断を取りに来る（textDocument/diagnostic）場合は、サーバーからは送らない
+    router
+        .on_notification::<DidOpenTextDocument, _>(|state, params| {
+            let Some(uri) = open_document(&to_value(&params), &mut state.document_store) else {
+                log_message(&mut state.outgoing, MessageType::WARNING, format!("Could not open {}.", params.text_document.uri));
+                return Vec::new();
+            };
+            report_syntax_problem(state, &uri);
+            state.diagnostics.cancel(&uri);
+            if state.negotiated.pull_diagnostics {
+                return Vec::new();
+            }
+            publish_diagnostics(state, vec![uri]);
+            Vec::new()
+        })
+        .on_notification::<DidChangeTextDocument, _>(|state, params| {
+            let encoding = state.encoding().clone();
+            match apply_did_change_with_encoding(&to_value(&params), &mut state.document_store, &encoding) {
+                Ok(uri) => {
+                    if !state.negotiated.pull_diagnostics {
+                        state.diagnostics.schedule(uri, Instant::now());
+                    }
+                    Vec::new()
+                }
+                Err(err) => {
+                    // 重複・順番違いの通知は当てずに捨て、クライアントのログに残す
+                    log_message(&mut state.outgoing, MessageType::WARNING, err.to_string());
+                    Vec::new()
+                }
+            }
+        })
+        .on_notification::<DidCloseTextDocument, _>(|state, params| {
+            handle_did_close_notification(&to_value(&params), &mut state.document_store);
+            // 閉じたドキュメントの診断がエディタに残らないように、空の診断を送る
+            let uri = params.text_document.uri;
+            state.diagnostics.cancel(&uri);
+            if state.negotiated.pull_diagnostics {
+                return Vec::new();
+            }
+            vec![create_publish_diagnostics_notification(uri, Vec::new())]
+        });
+
+    // ワークスペースのファイル
+    // 開いていないファイルはディスクの中身を索引しておき（server::workspace）、変更はクライアントのファイル監視で受け取る
+    // 作成・変更されたファイルは、index_workspace と同じくメインループの外で読んでから取り込む
+    router.on_notification::<DidChangeWatchedFiles, _>(|state, params| {
+        let reads = state.workspace.apply_file_events(&params.changes);
+        if !reads.is_empty() {
+            state.tasks.push(Box::new(move |_| {
+                let read = reads.read();
+                Box::new(move |state: &mut ServerState| {
+                    state.workspace.merge(read);
+                    Vec::new()
+                })
+            }));
+        }
+        Vec::new()
+    });
+
+    // プル型の診断
+    // lesson_1_13 の TODO と lesson_4 のチェッカーの結果（server::checks）を、resultId を付けて返す
+    router
+        .on_read_request::<DocumentDiagnosticRequest, _>(|snapshot, params| {
+            let report = document_diagnostic_report(snapshot, &params.text_document.uri, params.previous_result_id.as_deref());
+            Ok(DocumentDiagnosticReportResult::Report(report))
+        })
+        .on_read_request::<WorkspaceDiagnosticRequest, _>(|snapshot, params| {
+            let previous_result_ids: HashMap<Url, String> =
+                params.previous_result_ids.into_iter().map(|previous| (previous.uri, previous.value)).collect();
+            let encoding = snapshot.encoding().clone();
+            let settings = snapshot.settings.diagnostics.clone();
+            let open = snapshot.document_store.snapshot();
+            let items = scan_documents_with_partial(
+                snapshot,
+                "Checking the workspace",
+                params.work_done_progress_params,
+                params.partial_result_params,
+                |uri, document| {
+                    let report = document_report(
+                        check_document(uri, document.text(), &encoding, &settings),
+                        previous_result_ids.get(uri).map(String::as_str),
+                    );
+                    let version = open.contains_key(uri).then(|| document.version());
+                    vec![workspace_report(uri.clone(), version, report)]
+                },
+                |items| WorkspaceDiagnosticReportPartialResult { items },
+            )?;
+            Ok(WorkspaceDiagnosticReportResult::Report(WorkspaceDiagnosticReport { items }))
+        });
+
+    // 言語機能
+    // Position の列は initialize で決めた positionEncoding の単位なので、lesson_1 の *_with_encoding 版を使う
+    // どれも状態を書き換えないので、スナップショットに対して処理する（メインループを待たせない）
+    router
+        .on_read_request::<HoverRequest, _>(|snapshot, params| {
+            let position = params.text_document_position_params;
+            let store = &snapshot.document_store;
+            // 名前とリテラルは意味解析で、それ以外（キーワード）は lesson_1_19 で
+            let semantic = store
+                .text(&position.text_document.uri)
+                .and_then(|text| semantic_hover(text, position.position, snapshot.encoding()));
+            Ok(semantic.or_else(|| get_hover_info_with_encoding(&position.text_document.uri, position.position, store, snapshot.encoding())))
+        })
+        .on_read_request::<GotoDefinition, _>(|snapshot, params| {
+            let links = snapshot.negotiated.definition_links;
+            Ok(goto_in_document(snapshot, params.text_document_position_params, Target::Definition, links))
+        })
+        .on_read_request::<GotoDeclaration, _>(|snapshot, params| {
+            let links = snapshot.negotiated.declaration_links;
+            Ok(goto_in_document(snapshot, params.text_document_position_params, Target::Declaration, links))
+        })
+        .on_read_request::<GotoTypeDefinition, _>(|snapshot, params| {
+            let links = snapshot.negotiated.type_definition_links;
+            Ok(goto_in_document(snapshot, params.text_document_position_params, Target::TypeDefinition, links))
+        })
+        .on_read_request::<References, _>(|snapshot, params| {
+            let position = params.text_document_position;
+            let include_declaration = params.context.include_declaration;
+            let encoding = snapshot.encoding().clone();
+            // 定義は別のファイルにあることもあるので、開いていないファイルも含めて探す
+            let documents = snapshot.workspace.documents(&snapshot.document_store);
+            let Some(definition) = definition_at(&documents, &position.text_document.uri, position.position, &encoding) else {
+                return Ok(Some(Vec::new()));
+            };
+            let mut locations = scan_documents(
+                snapshot,
+                "Finding references",
+                params.work_done_progress_params,
+                params.partial_result_params,
+                |uri, document| references_in_document(uri, document.text(), &definition, &encoding, include_declaration),
+            )?;
+            // ドキュメントを調べる順は決まっていないので、ファイル・位置の順に並べる
+            locations.sort_by(|a, b| (a.uri.as_str(), a.range.start).cmp(&(b.uri.as_str(), b.range.start)));
+            Ok(Some(locations))
+        })
+        .on_read_request::<DocumentSymbolRequest, _>(|snapshot, params| {
+            let uri = params.text_document.uri;
+            let symbols = snapshot
+                .document_store
+                .text(&uri)
+                .map(|text| document_symbols(text, snapshot.encoding()))
+                .unwrap_or_default();
+            // 木構造に対応していないクライアントには、フラットな SymbolInformation のリストで返す（親の名前は containerName に）
+            if snapshot.negotiated.hierarchical_document_symbols {
+                Ok(Some(DocumentSymbolResponse::Nested(symbols)))
+            } else {
+                Ok(Some(DocumentSymbolResponse::Flat(flatten_document_symbols(&uri, symbols, None))))
+            }
+        })
+        .on_read_request::<CodeActionRequest, _>(|_, params| {
+            let actions = get_code_actions(params.text_document.uri, params.range, params.context.diagnostics);
+            Ok(Some(actions.into_iter().map(CodeActionOrCommand::CodeAction).collect()))
+        })
+        .on_read_request::<Formatting, _>(|snapshot, params| {
+            // インデントの幅は設定が優先。設定が無ければエディタのタブ幅に合わせる
+            let indent_width = snapshot.settings.formatting.indent_width.unwrap_or(params.options.tab_size);
+            Ok(format_whole_document(&snapshot.document_store, &params.text_document.uri, indent_width as usize))
+        })
+        .on_read_request::<PrepareRenameRequest, _>(|snapshot, params| {
+            let documents = snapshot.workspace.documents(&snapshot.document_store);
+            prepare_rename(&documents, &params.text_document.uri, params.position, snapshot.encoding()).map(Some)
+        })
+        .on_read_request::<Rename, _>(|snapshot, params| {
+            let position = params.text_document_position;
+            // 開いていないファイルの参照も書き換える
+            let documents = snapshot.workspace.documents(&snapshot.document_store);
+            let renaming = rename(&documents, &position.text_document.uri, position.position, &params.new_name, snapshot.encoding())?;
+            Ok(Some(renaming.into_workspace_edit(
+                &position.text_document.uri,
+                &snapshot.document_store,
+                snapshot.negotiated.change_annotations,
+            )))
+        })
+        .on_read_request::<DocumentHighlightRequest, _>(|snapshot, params| {
+            let position = params.text_document_position_params;
+            Ok(Some(get_document_highlights_with_encoding(
+                &position.text_document.uri,
+                position.position,
+                &snapshot.document_store,
+                snapshot.encoding(),
+            )))
+        })
+        .on_read_request::<InlayHintRequest, _>(|snapshot, params| {
+            if !snapshot.settings.inlay_hints.enabled {
+                return Ok(Some(Vec::new()));
+            }
+            Ok(Some(get_inlay_hints_with_encoding(&params.text_document.uri, params.range, &snapshot.document_store, snapshot.encoding())))
+        })
+        .on_read_request::<Completion, _>(|snapshot, params| {
+            let position = params.text_document_position;
+            let items = get_ranked_completion_items_with_encoding(
+                &position.text_document.uri,
+                position.position,
+                &snapshot.document_store,
+                snapshot.encoding(),
+            );
+            if snapshot.negotiated.snippet_support {
+                Ok(Some(CompletionResponse::Array(items.into_iter().map(with_keyword_snippet).collect())))
+            } else {
+                Ok(Some(CompletionResponse::Array(items)))
+            }
+        })
+        .on_read_request::<ResolveCompletionItem, _>(|_, item| Ok(resolve_completion_item(item)))
+        .on_read_request::<SignatureHelpRequest, _>(|snapshot, params| {
+            let position = params.text_document_position_params;
+            Ok(get_signature_help_with_encoding(
+                &position.text_document.uri,
+                position.position,
+                &snapshot.document_store,
+                snapshot.encoding(),
+            ))
+        })
+        .on_read_request::<WorkspaceSymbolRequest, _>(|snapshot, params| {
+            let query = params.query;
+            let encoding = snapshot.encoding().clone();
+            let workspace = snapshot.workspace.clone();
+            let open = snapshot.document_store.snapshot();
+            let symbols = scan_documents(
+                snapshot,
+                "Searching workspace symbols",
+                params.work_done_progress_params,
+                params.partial_result_params,
+                |uri, document| match workspace.symbols(uri) {
+                    // 開いていないファイルは、索引したときに作ったシンボルから探す
+                    Some(symbols) if !open.contains_key(uri) => {
+                        symbols.iter().filter(|symbol| symbol_matches(&query, &symbol.name)).cloned().collect()
+                    }
+                    _ => workspace_symbol_in_document(&query, uri, document.text(), &encoding),
+                },
+            )?;
+            Ok(Some(WorkspaceSymbolResponse::Flat(symbols)))
+        })
+        .on_read_request::<CallHierarchyPrepare, _>(|snapshot, params| {
+            let position = params.text_document_position_params;
+            Ok(prepare_call_hierarchy(snapshot, &position.text_document.uri, position.position))
+        })
+        .on_read_request::<CallHierarchyIncomingCalls, _>(|snapshot, params| {
+            let name = params.item.name;
+            let encoding = snapshot.encoding().clone();
+            let calls = scan_documents(
+                snapshot,
+                "Finding incoming calls",
+                params.work_done_progress_params,
+                params.partial_result_params,
+                |uri, document| call_hierarchy_incoming_calls_in_document(&name, uri, document.text(), &encoding),
+            )?;
+            Ok(Some(calls))
+        })
+        .on_read_request::<SemanticTokensFullRequest, _>(|snapshot, params| {
+            Ok(snapshot
+                .document_store
+                .text(&params.text_document.uri)
+                .map(|content| SemanticTokensResult::Tokens(provide_semantic_tokens_with_encoding(content, snapshot.encoding()))))
+        })
+        .on_read_request::<FoldingRangeRequest, _>(|snapshot, params| {
+            Ok(snapshot
+                .document_store
+                .text(&params.text_document.uri)
+                .map(|content| provide_folding_ranges_with_encoding(content, snapshot.encoding())))
+        })
+        .on_read_request::<SelectionRangeRequest, _>(|snapshot, params| {
+            Ok(snapshot
+                .document_store
+                .text(&params.text_document.uri)
+                .map(|content| provide_selection_ranges_with_encoding(content, &params.positions, snapshot.encoding())))
+        })
+        .on_read_request::<CodeLensRequest, _>(|snapshot, params| {
+            Ok(snapshot
+                .document_store
+                .text(&params.text_document.uri)
+                .map(|content| provide_code_lenses_for(content, snapshot.encoding(), &snapshot.settings.code_lens.kinds)))
+        })
+        .on_read_request::<LinkedEditingRange, _>(|snapshot, params| {
+            let position = params.text_document_position_params;
+            Ok(snapshot
+                .document_store
+                .text(&position.text_document.uri)
+                .and_then(|content| provide_linked_editing_ranges_with_encoding(content, position.position, snapshot.encoding())))
+        });
+}
+
+fn to_value<T: Serialize>(params: &T) -> serde_json::Value {
+    serde_json::to_value(params).unwrap_or_default()
+}
+
+// ワークスペースの全ドキュメント（開いていないファイルはディスクの中身）を1つずつ調べる
+// - 1つ調べるごとに進捗を報告し、キャンセル（$/cancelRequest や進捗表示のキャンセル）されていたら途中で諦める
+// - partialResultToken が付いていれば、見つかった結果をその都度 $/progress で送り、戻り値には含めない
+fn scan_documents<T>(
+    snapshot: &mut ServerSnapshot,
+    title: &str,
+    work_done: WorkDoneProgressParams,
+    partial_result: PartialResultParams,
+    scan: impl FnMut(&Url, &Document) -> Vec<T>,
+) -> Result<Vec<T>, ResponseError>
+where
+    T: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    scan_documents_with_partial(snapshot, title, work_done, partial_result, scan, |found| found)
+}
+
+// scan_documents と同じ。部分的な結果は partial で包んでから送る（workspace/diagnostic の { items } など）
+fn scan_documents_with_partial<T, P>(
+    snapshot: &mut ServerSnapshot,
+    title: &str,
+    work_done: WorkDoneProgressParams,
+    partial_result: PartialResultParams,
+    mut scan: impl FnMut(&Url, &Document) -> Vec<T>,
+    partial: impl Fn(Vec<T>) -> P,
+) -> Result<Vec<T>, ResponseError>
+where
+    P: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    let mut progress = begin_progress(snapshot, title, work_done);
+    // 調べている間に届いた変更が混ざらないように、始めた時点のスナップショットを調べる
+    let documents = snapshot.workspace.documents(&snapshot.document_store);
+    let total = documents.len();
+    let mut results = Vec::new();
+    let mut cancelled = false;
+
+    for (done, (uri, document)) in documents.iter().enumerate() {
+        if snapshot.cancellation.is_cancelled() {
+            cancelled = true;
+            break;
+        }
+
+        let found = scan(uri, document);
+        match &partial_result.partial_result_token {
+            Some(token) if !found.is_empty() => send_partial_result(&mut snapshot.outgoing, token, partial(found)),
+            Some(_) => {}
+            None => results.extend(found),
+        }
+        if let Some(progress) = progress.as_mut() {
+            progress.report(&mut snapshot.outgoing, done + 1, total, None);
+        }
+    }
+
+    // キャンセルされた場合も、クライアントの進捗表示を閉じるために end は送る
+    if let Some(progress) = progress {
+        snapshot.in_flight.finish_progress(progress.token());
+        progress.end(&mut snapshot.outgoing, None);
+    }
+
+    if cancelled {
+        Err(request_cancelled())
+    } else {
+        Ok(results)
+    }
+}
+
+// initialize で受け取ったワークスペースの .rs ファイルを読み込んで索引を作る
+// ディスクを読むのは時間がかかるので、メインループの外で読み、読み終えたらメインループで索引に取り込む
+// （それまでに届いたリクエストは、開いているドキュメントだけを見る）
+// クライアントが対応していれば、サーバーから進捗表示を出す（途中で止めることはできない）
+fn index_workspace(state: &mut ServerState) {
+    if state.workspace.roots().is_empty() {
+        return;
+    }
+
+    let mut workspace = state.workspace.clone();
+    let progress_tokens = state.progress_tokens.clone();
+    let work_done_progress = state.negotiated.work_done_progress;
+    state.tasks.push(Box::new(move |outgoing| {
+        let paths = workspace.discover();
+        let mut progress = work_done_progress.then(|| {
+            let token = progress_tokens.create(outgoing);
+            WorkDoneProgress::begin(outgoing, token, "Indexing the workspace", false)
+        });
+        for (done, path) in paths.iter().enumerate() {
+            if !workspace.load_file(path) {
+                log_message(outgoing, MessageType::WARNING, format!("Could not index {}: it is not readable UTF-8 text.", path.display()));
+            }
+            if let Some(progress) = progress.as_mut() {
+                progress.report(outgoing, done + 1, paths.len(), None);
+            }
+        }
+        if let Some(progress) = progress {
+            progress.end(outgoing, Some(format!("Indexed {} files", workspace.len())));
+        }
+        Box::new(move |state: &mut ServerState| {
+            state.workspace.merge(workspace);
+            Vec::new()
+        })
+    }));
+}
+
+// クライアントが対応していれば、client/registerCapability でまとめて登録する
+// - workspace/didChangeWatchedFiles: .rs ファイルの作成・変更・削除を知らせてもらう
+//   登録できなかった場合も、開いているドキュメントはエディタの中身を使うので、索引が古くなるのは開いていないファイルだけ
+// - workspace/didChangeConfiguration: 設定が変わったことを知らせてもらう（登録しないと送ってこないクライアントもある）
+fn register_capabilities(state: &mut ServerState) {
+    let mut registrations = Vec::new();
+
+    if state.negotiated.watch_files && !state.workspace.roots().is_empty() {
+        let options = DidChangeWatchedFilesRegistrationOptions {
+            watchers: vec![FileSystemWatcher { glob_pattern: GlobPattern::String("**/*.rs".to_string()), kind: None }],
+        };
+        registrations.push(Registration {
+            id: "toy-lang-server/watched-files".to_string(),
+            method: DidChangeWatchedFiles::METHOD.to_string(),
+            register_options: serde_json::to_value(options).ok(),
+        });
+    }
+    if state.negotiated.watch_configuration {
+        registrations.push(Registration {
+            id: "toy-lang-server/configuration".to_string(),
+            method: DidChangeConfiguration::METHOD.to_string(),
+            register_options: None,
+        });
+    }
+
+    if !registrations.is_empty() {
+        state.outgoing.send_request::<RegisterCapability, _>(RegistrationParams { registrations }, |_, _| {});
+    }
+}
+
+// workspace/configuration でクライアントの設定（"toyLangServer" セクション）を取りに行く
+fn request_settings(state: &mut ServerState) {
+    if !state.negotiated.configuration {
+        return;
+    }
+
+    let params = ConfigurationParams {
+        items: vec![ConfigurationItem { scope_uri: None, section: Some(SECTION.to_string()) }],
+    };
+    state.outgoing.send_request::<WorkspaceConfiguration, _>(params, |state, result| match result {
+        Ok(values) => apply_settings(state, values.first().unwrap_or(&serde_json::Value::Null)),
+        Err(err) => log_message(&mut state.outgoing, MessageType::WARNING, format!("Could not read the settings: {}", err.message)),
+    });
+}
+
+// 設定を読んで反映する
+// - 無視した設定（書き間違い・範囲外の値）は、ユーザーに知らせる
+// - 診断の設定が変わったら、開いているドキュメントの診断を計算し直す
+//   （取りに来てもらう場合は、クライアントが対応していれば workspace/diagnostic/refresh で取り直してもらう）
+fn apply_settings(state: &mut ServerState, value: &serde_json::Value) {
+    let (settings, warnings) = Settings::from_value(value);
+    if !warnings.is_empty() {
+        show_message(&mut state.outgoing, MessageType::WARNING, warnings.join("\n"));
+    }
+
+    let diagnostics_changed = settings.diagnostics != state.settings.diagnostics;
+    state.settings = settings;
+    if !diagnostics_changed {
+        return;
+    }
+    if !state.negotiated.pull_diagnostics {
+        let now = Instant::now();
+        let open: Vec<Url> = state.document_store.iter().map(|(uri, _)| uri.clone()).collect();
+        for uri in open {
+            state.diagnostics.schedule(uri, now);
+        }
+    } else if state.negotiated.diagnostic_refresh {
+        state.outgoing.send_request::<WorkspaceDiagnosticRefresh, _>((), |_, _| {});
+    }
+}
+
+// 進捗表示を始める
+// クライアントがリクエストに workDoneToken を付けてきたらそれを使い、
+// 無ければクライアントが対応している場合だけサーバーでトークンを作る
+fn begin_progress(snapshot: &mut ServerSnapshot, title: &str, params: WorkDoneProgressParams) -> Option<WorkDoneProgress> {
+    let token = match params.work_done_token {
+        Some(token) => token,
+        None if snapshot.negotiated.work_done_progress => snapshot.progress_tokens.create(&mut snapshot.outgoing),
+        None => return None,
+    };
+    // 進捗表示のキャンセルボタンで、いま処理しているリクエストを止められるようにする
+    snapshot.in_flight.watch_progress(&token, snapshot.cancellation.clone());
+    Some(WorkDoneProgress::begin(&mut snapshot.outgoing, token, title, true))
+}
+
+// 開いたドキュメントを解析しきれなければ（括弧の対応が取れていないなど）、診断が足りないかもしれないことをユーザーに知らせる
+fn report_syntax_problem(state: &mut ServerState, uri: &Url) {
+    let Some((range, problem)) = state.document_store.text(uri).and_then(|text| syntax_problem(text, state.encoding())) else {
+        return;
+    };
+    let name = uri.path_segments().and_then(|mut segments| segments.next_back()).unwrap_or(uri.as_str());
+    show_message(
+        &mut state.outgoing,
+        MessageType::WARNING,
+        format!("Could not parse {}: {} at line {}. Diagnostics may be incomplete.", name, problem, range.start.line + 1),
+    );
+}
+
+// 待ち時間が過ぎたドキュメントの診断を送る
+pub fn publish_due_diagnostics(state: &mut ServerState, now: Instant) {
+    let due = state.diagnostics.take_due(now);
+    publish_diagnostics(state, due);
+}
+
+// uris の診断を送る
+// 診断はメインループの外でいまのスナップショットから計算し、publishDiagnostics はメインループで送る
+// その間に編集されたり閉じられたりしたドキュメントの（古くなった）診断は送らない
+fn publish_diagnostics(state: &mut ServerState, uris: Vec<Url>) {
+    if uris.is_empty() {
+        return;
+    }
+
+    let snapshot = state.document_store.snapshot();
+    let encoding = state.encoding().clone();
+    let settings = state.settings.diagnostics.clone();
+    state.tasks.push(Box::new(move |_| {
+        let computed: Vec<(Url, i32, Vec<Diagnostic>)> = uris
+            .into_iter()
+            .filter_map(|uri| {
+                let (version, diagnostics) = compute_diagnostics(&snapshot, &uri, &encoding, &settings)?;
+                Some((uri, version, diagnostics))
+            })
+            .collect();
+        Box::new(move |state: &mut ServerState| {
+            computed
+                .into_iter()
+                .filter_map(|(uri, version, diagnostics)| publish_if_current(&state.document_store, uri, version, diagnostics))
+                .collect()
+        })
+    }));
+}
+
+// スナップショットの uri の診断を、計算に使ったドキュメントの version と一緒に返す
+fn compute_diagnostics(
+    snapshot: &DocumentStore,
+    uri: &Url,
+    encoding: &PositionEncodingKind,
+    settings: &DiagnosticsSettings,
+) -> Option<(i32, Vec<Diagnostic>)> {
+    let document = snapshot.get(uri)?;
+    Some((document.version(), check_document(uri, document.text(), encoding, settings)))
+}
+
+// 計算に使ったドキュメントがまだ最新（閉じられていない・新しい version が来ていない）なら publishDiagnostics を作る
+fn publish_if_current(store: &DocumentStore, uri: Url, version: i32, diagnostics: Vec<Diagnostic>) -> Option<String> {
+    let current = store.get(&uri)?.version();
+    (current == version).then(|| create_publish_diagnostics_notification_with_version(uri, diagnostics, Some(version)))
+}
+
+// textDocument/diagnostic の応答
+// 開いていないドキュメントは診断が無いものとして返す
+// クライアントが対応していれば、参照しているモジュールのファイルの診断も relatedDocuments に入れる
+fn document_diagnostic_report(snapshot: &ServerSnapshot, uri: &Url, previous_result_id: Option<&str>) -> DocumentDiagnosticReport {
+    let store = &snapshot.document_store;
+    let check = |uri: &Url| {
+        store.text(uri).map(|text| check_document(uri, text, snapshot.encoding(), &snapshot.settings.diagnostics)).unwrap_or_default()
+    };
+
+    let related_documents = snapshot
+        .negotiated
+        .related_document_diagnostics
+        .then(|| {
+            related_documents(store, uri)
+                .into_iter()
+                .map(|related| {
+                    let report = document_report(check(&related), None);
+                    (related, report)
+                })
+                .collect::<HashMap<Url, DocumentDiagnosticReportKind>>()
+        })
+        .filter(|related| !related.is_empty());
+
+    match document_report(check(uri), previous_result_id) {
+        DocumentDiagnosticReportKind::Full(full_document_diagnostic_report) => {
+            DocumentDiagnosticReport::Full(RelatedFullDocumentDiagnosticReport { related_documents, full_document_diagnostic_report })
+        }
+        DocumentDiagnosticReportKind::Unchanged(unchanged_document_diagnostic_report) => {
+            DocumentDiagnosticReport::Unchanged(RelatedUnchangedDocumentDiagnosticReport {
+                related_documents,
+                unchanged_document_diagnostic_report,
+            })
+        }
+    }
+}
+
+fn initialize_result(capabilities: ServerCapabilities) -> InitializeResult {
+    InitializeResult {
+        capabilities,
+        server_info: Some(ServerInfo {
+            name: "toy-lang-server".to_string(),
+            version: Some(env!("CARGO_PKG_VERSION").to_string()),
+        }),
+    }
+}
+
+// DocumentSymbol の木を、親の名前を container_name に入れたフラットなリストにする
+#[allow(deprecated)] // SymbolInformation::deprecated は非推奨だが、構造体を作るには指定が必要
+fn flatten_document_symbols(uri: &Url, symbols: Vec<DocumentSymbol>, container_name: Option<&str>) -> Vec<SymbolInformation> {
+    let mut flattened = Vec::new();
+    for symbol in symbols {
+        flattened.push(SymbolInformation {
+            name: symbol.name.clone(),
+            kind: symbol.kind,
+            tags: symbol.tags.clone(),
+            deprecated: None,
+            location: Location::new(uri.clone(), symbol.range),
+            container_name: container_name.map(|name| name.to_string()),
+        });
+        if let Some(children) = symbol.children {
+            flattened.extend(flatten_document_symbols(uri, children, Some(&symbol.name)));
+        }
+    }
+    flattened
+}
+
+// スニペットに対応しているクライアント向けに、キーワードの補完を雛形付きにする
+// textEdit があるとクライアントは insertText を使わないので、textEdit の newText も雛形にする
+fn with_keyword_snippet(item: CompletionItem) -> CompletionItem {
+    let snippet = match (item.kind, item.label.as_str()) {
+        (Some(CompletionItemKind::KEYWORD), "fn") => "fn ${1:name}($2) {\n\t$0\n}",
+        (Some(CompletionItemKind::KEYWORD), "struct") => "struct ${1:Name} {\n\t$0\n}",
+        (Some(CompletionItemKind::KEYWORD), "impl") => "impl ${1:Type} {\n\t$0\n}",
+        (Some(CompletionItemKind::KEYWORD), "if") => "if ${1:condition} {\n\t$0\n}",
+        (Some(CompletionItemKind::KEYWORD), "for") => "for ${1:item} in ${2:iter} {\n\t$0\n}",
+        (Some(CompletionItemKind::KEYWORD), "loop") => "loop {\n\t$0\n}",
+        (Some(CompletionItemKind::KEYWORD), "let") => "let ${1:name} = $0;",
+        _ => return item,
+    };
+
+    let text_edit = item.text_edit.map(|text_edit| match text_edit {
+        CompletionTextEdit::Edit(edit) => CompletionTextEdit::Edit(TextEdit { new_text: snippet.to_string(), ..edit }),
+        CompletionTextEdit::InsertAndReplace(edit) => {
+            CompletionTextEdit::InsertAndReplace(InsertReplaceEdit { new_text: snippet.to_string(), ..edit })
+        }
+    });
+    CompletionItem {
+        insert_text: Some(snippet.to_string()),
+        insert_text_format: Some(InsertTextFormat::SNIPPET),
+        text_edit,
+        ..item
+    }
+}
+
+// lesson_1_33 のフォーマッタの結果を、ドキュメント全体を置き換える1つの TextEdit にする
+fn format_whole_document(store: &DocumentStore, uri: &Url, indent_width: usize) -> Option<Vec<TextEdit>> {
+    let content = store.text(uri)?;
+    let formatted = format_document_with_indent(content, indent_width);
+    if formatted == content {
+        return Some(Vec::new());
+    }
+
+    let last_line = content.lines().count() as u32;
+    Some(vec![TextEdit::new(
+        Range::new(Position::new(0, 0), Position::new(last_line + 1, 0)),
+        formatted,
+    )])
+}
+
+// 定義 / 宣言 / 型定義へのジャンプ（開いていないドキュメントでは None）
+fn goto_in_document(
+    snapshot: &ServerSnapshot,
+    position: TextDocumentPositionParams,
+    target: Target,
+    links: bool,
+) -> Option<GotoDefinitionResponse> {
+    let uri = &position.text_document.uri;
+    let text = snapshot.document_store.text(uri)?;
+    goto(uri, text, position.position, snapshot.encoding(), target, links)
+}
+
+// カーソル位置の関数名を workspace_symbol で探して CallHierarchyItem にする
+fn prepare_call_hierarchy(snapshot: &ServerSnapshot, uri: &Url, position: Position) -> Option<Vec<CallHierarchyItem>> {
+    let document = snapshot.document_store.get(uri)?;
+    let line = document.text().lines().nth(position.line as usize)?;
+    let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+
+    // カーソルの前後の識別子の文字を辿る（バイトオフセットで扱う）
+    let cursor = document.line_index(snapshot.encoding()).byte_column(position.line, position.character)?;
+    let start = line[..cursor]
+        .char_indices()
+        .rev()
+        .take_while(|&(_, c)| is_ident(c))
+        .last()
+        .map_or(cursor, |(i, _)| i);
+    let end = line[cursor..].find(|c: char| !is_ident(c)).map_or(line.len(), |i| cursor + i);
+    if start >= end {
+        return None;
+    }
+    let name = &line[start..end];
+
+    let items: Vec<CallHierarchyItem> = snapshot
+        .workspace
+        .documents(&snapshot.document_store)
+        .iter()
+        .flat_map(|(uri, document)| workspace_symbol_in_document(name, uri, document.text(), snapshot.encoding()))
+        .filter(|symbol| symbol.kind == SymbolKind::FUNCTION && symbol.name == name)
+        .map(|symbol| CallHierarchyItem {
+            name: symbol.name,
+            kind: symbol.kind,
+            tags: None,
+            detail: None,
+            uri: symbol.location.uri,
+            range: symbol.location.range,
+            selection_range: symbol.location.range,
+            data: None,
+        })
+        .collect();
+
+    if items.is_empty() {
+        None
+    } else {
+        Some(items)
+    }
+}
@@ -0,0 +1,148 @@
+// 意味解析（analysis.rs）を使ったホバー
+// - 名前: 種類と型、定義した場所、定義の上の `///` コメント
+// - リテラル: 型と値
+// どちらでもないとき（キーワードなど）は None を返し、lesson_1_19 のホバーに任せる
+
+use lsp_types::{Hover, HoverContents, MarkupContent, MarkupKind, Position, PositionEncodingKind};
+
+use crate::common::line_index::LineIndex;
+use crate::common::syntax::TokenKind;
+use crate::lessons::lesson_3::lesson_3_12::{Resolution, SymbolKind, Type};
+use crate::server::analysis::{display_return_type, display_type, token_at, Analysis};
+
+pub fn semantic_hover(text: &str, position: Position, encoding: &PositionEncodingKind) -> Option<Hover> {
+    let index = LineIndex::new(text, encoding.clone());
+    let token = token_at(text, &index, position)?;
+    let range = index.range(token.range());
+    let analysis = Analysis::new(text, encoding);
+
+    let value = match token.kind {
+        TokenKind::Ident if token.is_ident() || token.is_keyword("self") => {
+            let resolution = analysis.resolution_at(range)?;
+            let mut sections = vec![
+                format!("```rust\n{}\n```", signature(resolution)),
+                format!("{} defined at line {}", kind_name(resolution.kind), resolution.definition_span.start.line + 1),
+            ];
+            sections.extend(doc_comment(text, resolution.definition_span.start.line));
+            sections.join("\n\n---\n\n")
+        }
+        TokenKind::Number | TokenKind::Str | TokenKind::Ident => {
+            let literal_type = analysis.type_at(range)?;
+            format!("```rust\n{}\n```\n\n---\n\nvalue: `{}`", display_type(literal_type), token.text)
+        }
+        _ => return None,
+    };
+
+    Some(Hover { contents: HoverContents::Markup(MarkupContent { kind: MarkupKind::Markdown, value }), range: Some(range) })
+}
+
+fn kind_name(kind: SymbolKind) -> &'static str {
+    match kind {
+        SymbolKind::Variable => "variable",
+        SymbolKind::Parameter => "parameter",
+        SymbolKind::Function => "function",
+        SymbolKind::Struct => "struct",
+        SymbolKind::Field => "field",
+    }
+}
+
+// ホバーの先頭に出す、Rust で書いたときの形
+fn signature(resolution: &Resolution) -> String {
+    let name = &resolution.name;
+    match (resolution.kind, resolution.symbol_type.resolve()) {
+        (SymbolKind::Variable, symbol_type) => format!("let {}: {}", name, display_type(symbol_type)),
+        (SymbolKind::Function, Type::Function { parameters, return_type }) => {
+            let parameters: Vec<String> = parameters.iter().map(display_type).collect();
+            format!("fn {}({}){}", name, parameters.join(", "), display_return_type(return_type))
+        }
+        (SymbolKind::Struct, Type::Struct { fields, .. }) if !fields.is_empty() => {
+            let fields: Vec<String> = fields.iter().map(|field| format!("{}: {}", field.name, display_type(&field.field_type))).collect();
+            format!("struct {} {{ {} }}", name, fields.join(", "))
+        }
+        (SymbolKind::Struct, _) => format!("struct {}", name),
+        (_, symbol_type) => format!("{}: {}", name, display_type(symbol_type)),
+    }
+}
+
+// line（0 始まり）の定義の直前に並んでいる `///` コメント（属性 `#[...]` の行は飛ばす）
+fn doc_comment(text: &str, line: usize) -> Option<String> {
+    let lines: Vec<&str> = text.lines().take(line).collect();
+    let mut docs = Vec::new();
+    for line in lines.iter().rev().map(|line| line.trim()) {
+        if let Some(doc) = line.strip_prefix("///") {
+            docs.push(doc.strip_prefix(' ').unwrap_or(doc));
+        } else if !line.starts_with("#[") {
+            break;
+        }
+    }
+    if docs.is_empty() {
+        return None;
+    }
+    docs.reverse();
+    Some(docs.join("\n"))
+}
+
+
+// --- Tests --- //
+
+#[cfg(test)]
+mod tests {
+    use super::semantic_hover;
+    use lsp_types::{HoverContents, Position, PositionEncodingKind, Range};
+
+    fn hover(text: &str, line: u32, character: u32) -> Option<(String, Range)> {
+        let hover = semantic_hover(text, Position::new(line, character), &PositionEncodingKind::UTF16)?;
+        match hover.contents {
+            HoverContents::Markup(content) => Some((content.value, hover.range.unwrap())),
+            other => panic!("expected Markdown, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_hover_shows_type_definition_and_docs() {
+        let text = "/// Adds two numbers.\n/// Overflow wraps.\n#[inline]\nfn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n\nfn main() {\n    let sum = add(1, 2);\n}";
+
+        let (value, range) = hover(text, 8, 16).unwrap();
+        assert_eq!(
+            value,
+            "```rust\nfn add(i32, i32) -> i32\n```\n\n---\n\nfunction defined at line 4\n\n---\n\nAdds two numbers.\nOverflow wraps."
+        );
+        assert_eq!(range, Range::new(Position::new(8, 14), Position::new(8, 17)));
+
+        let (value, _) = hover(text, 8, 8).unwrap();
+        assert_eq!(value, "```rust\nlet sum: i32\n```\n\n---\n\nvariable defined at line 9");
+
+        let (value, _) = hover(text, 4, 4).unwrap();
+        assert!(value.starts_with("```rust\na: i32\n```\n\n---\n\nparameter defined at line 4"), "{}", value);
+    }
+
+    #[test]
+    fn test_hover_on_structs_and_fields() {
+        let text = "struct Point {\n    /// Horizontal position.\n    x: i32,\n}\n\nfn main() {\n    let p = Point { x: 1 };\n    p.x;\n}";
+
+        let (value, _) = hover(text, 0, 8).unwrap();
+        assert!(value.starts_with("```rust\nstruct Point { x: i32 }\n```"), "{}", value);
+
+        let (value, _) = hover(text, 7, 6).unwrap();
+        assert_eq!(value, "```rust\nx: i32\n```\n\n---\n\nfield defined at line 3\n\n---\n\nHorizontal position.");
+    }
+
+    #[test]
+    fn test_hover_on_literals() {
+        let text = "fn main() {\n    let answer = 42;\n    let ok = true;\n    let name = \"toy\";\n}";
+
+        let (value, range) = hover(text, 1, 18).unwrap();
+        assert_eq!(value, "```rust\ni32\n```\n\n---\n\nvalue: `42`");
+        assert_eq!(range, Range::new(Position::new(1, 17), Position::new(1, 19)));
+
+        assert!(hover(text, 2, 15).unwrap().0.starts_with("```rust\nbool\n```"));
+        assert!(hover(text, 3, 17).unwrap().0.ends_with("value: `\"toy\"`"));
+    }
+
+    #[test]
+    fn test_no_semantic_hover_on_keywords_or_unknown_names() {
+        let text = "fn main() {\n    let x = undefined;\n}";
+        assert!(hover(text, 1, 5).is_none(), "keywords are left to lesson_1_19");
+        assert!(hover(text, 1, 14).is_none());
+    }
+}
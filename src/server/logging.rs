@@ -0,0 +1,75 @@
+// プロトコルでのログ
+// ハンドラが黙って None や空の結果を返したときに、何が起きたのかをクライアント側で追えるようにする
+// - $/logTrace: サーバーが何をしたか（リクエストごとの処理時間など）。initialize の trace と $/setTrace で決めた詳しさで送る
+// - window/logMessage: クライアントの出力に残すメッセージ（無視した通知など）
+// - window/showMessage: ユーザーに見せるメッセージ（ドキュメントを解析できなかったなど）
+
+use std::time::Duration;
+
+use lsp_types::notification::{LogMessage, LogTrace, ShowMessage};
+use lsp_types::{LogMessageParams, LogTraceParams, MessageType, ShowMessageParams, TraceValue};
+
+use crate::server::outgoing::Outgoing;
+
+// trace が off でなければ $/logTrace を送る
+// verbose は trace が verbose のときだけ作る（params や結果を文字列にするのは重いので）
+pub fn log_trace<S: 'static>(outgoing: &mut Outgoing<S>, trace: TraceValue, message: impl Into<String>, verbose: impl FnOnce() -> String) {
+    let verbose = match trace {
+        TraceValue::Off => return,
+        TraceValue::Messages => None,
+        TraceValue::Verbose => Some(verbose()),
+    };
+    outgoing.send_notification::<LogTrace>(LogTraceParams { message: message.into(), verbose });
+}
+
+pub fn log_message<S: 'static>(outgoing: &mut Outgoing<S>, typ: MessageType, message: impl Into<String>) {
+    outgoing.send_notification::<LogMessage>(LogMessageParams { typ, message: message.into() });
+}
+
+pub fn show_message<S: 'static>(outgoing: &mut Outgoing<S>, typ: MessageType, message: impl Into<String>) {
+    outgoing.send_notification::<ShowMessage>(ShowMessageParams { typ, message: message.into() });
+}
+
+// "0.42ms" のような処理時間
+pub fn format_elapsed(elapsed: Duration) -> String {
+    format!("{:.2}ms", elapsed.as_secs_f64() * 1000.0)
+}
+
+
+// --- Tests --- //
+
+#[cfg(test)]
+mod tests {
+    use super::{format_elapsed, log_trace};
+    use crate::server::outgoing::Outgoing;
+    use lsp_types::TraceValue;
+    use serde_json::Value;
+    use std::time::Duration;
+
+    fn sent_params(outgoing: &mut Outgoing<()>) -> Vec<Value> {
+        outgoing
+            .take_messages()
+            .iter()
+            .map(|message| serde_json::from_str::<Value>(message.split_once("\r\n\r\n").unwrap().1).unwrap()["params"].clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_log_trace_follows_the_trace_value() {
+        let mut outgoing = Outgoing::new();
+
+        log_trace(&mut outgoing, TraceValue::Off, "off", || unreachable!("verbose is not built when tracing is off"));
+        assert!(sent_params(&mut outgoing).is_empty());
+
+        log_trace(&mut outgoing, TraceValue::Messages, "messages", || unreachable!("verbose is only built for 'verbose'"));
+        assert_eq!(sent_params(&mut outgoing), vec![serde_json::json!({"message": "messages"})]);
+
+        log_trace(&mut outgoing, TraceValue::Verbose, "verbose", || "details".to_string());
+        assert_eq!(sent_params(&mut outgoing), vec![serde_json::json!({"message": "verbose", "verbose": "details"})]);
+    }
+
+    #[test]
+    fn test_format_elapsed() {
+        assert_eq!(format_elapsed(Duration::from_micros(1500)), "1.50ms");
+    }
+}
@@ -0,0 +1,1457 @@
+// サーバーのメインループ
+// 読み取りスレッド: メッセージを読み出し → try_parse_full_lsp_message で解析 → チャネルでメインループへ送る
+// メインループ: ルーターでハンドラに振り分け → 応答を書き戻す
+// ワーカースレッド: 読み取り専用のリクエストをスナップショットに対して処理 → 処理中の $/progress と応答を、送るたびにチャネルでメインループへ送る
+//                   ワークスペースの読み込みのような時間のかかる処理も動かし、結果はメインループで状態に反映する
+//
+// 状態を書き換える通知（didChange など）は、メインループで届いた順に処理する。
+// 読み取り専用のリクエストは、届いた時点のスナップショットをワーカーに渡したらすぐ次のメッセージへ進むので、
+// 応答は処理が終わった順に（届いた順と入れ替わることもある）書き出す。
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use lsp_types::notification::{Cancel, Notification, WorkDoneProgressCancel};
+use lsp_types::{CancelParams, MessageType, TraceValue, WorkDoneProgressCancelParams};
+
+use crate::lessons::lesson_1::lesson_1_9::{try_parse_full_lsp_message, LspMessage, LspMessageError};
+use crate::lessons::lesson_1::lesson_1_11::ErrorCode;
+use crate::server::cancellation::{request_cancelled, CancellationToken, InFlightRequests};
+use crate::server::capabilities::server_capabilities;
+use crate::server::handlers::{publish_due_diagnostics, register_handlers, ServerSnapshot, ServerState, Update};
+use crate::server::logging::{format_elapsed, log_message, log_trace};
+use crate::server::outgoing::{Forwarded, Outgoing, Sink};
+use crate::server::router::Router;
+use crate::server::transport::{write_message, MessageReadError, MessageReader};
+use crate::server::workers::WorkerPool;
+
+pub struct Server {
+    router: Router<ServerState, ServerSnapshot>,
+    state: ServerState,
+    // 読み取り専用のリクエストと、ハンドラが積んだ処理（ServerState::tasks）を動かすワーカー
+    // None ならメインループでその場で動かす
+    workers: Option<WorkerPool>,
+    // ワーカーが送るものの送り先
+    sink: WorkerSink,
+    // その場で動かしたときに sink へ送られたもの
+    inline: Receiver<FromWorker>,
+}
+
+// ワーカーからメインループへ渡すもの
+pub enum FromWorker {
+    // クライアントへ送るメッセージ（処理中の $/progress や、処理し終えたリクエストの応答）
+    Message(Forwarded),
+    // メインループの外で動かした処理の結果（ワークスペースの索引など）。メインループで状態に反映する
+    Update(Update),
+}
+
+type WorkerSink = Arc<dyn Fn(FromWorker) + Send + Sync>;
+
+impl Server {
+    // 読み取り専用のリクエストも、handle_message の中でその場で処理するサーバー（テストやトレースの再生で使う）
+    pub fn new() -> Self {
+        let mut router = Router::new();
+        register_handlers(&mut router);
+        let state = ServerState {
+            capabilities: server_capabilities(&router),
+            ..Default::default()
+        };
+        let (sink, inline) = inline_sink();
+        Server { router, state, workers: None, sink, inline }
+    }
+
+    // 読み取り専用のリクエストと、ハンドラが積んだ処理を pool で動かすサーバー
+    // ワーカーが送るもの（応答や処理中の $/progress）は handle_message では返さず、送るたびに sink に渡す
+    // （メインループで handle_worker に渡して書き出す）
+    pub fn with_workers(pool: WorkerPool, sink: impl Fn(FromWorker) + Send + Sync + 'static) -> Self {
+        Server { workers: Some(pool), sink: Arc::new(sink), ..Self::new() }
+    }
+
+    // ワーカーに渡したものがすべて終わるまで待つ（それ以降はその場で動かす）
+    pub fn wait_for_workers(&mut self) {
+        self.workers.take();
+        (self.sink, self.inline) = inline_sink();
+    }
+
+    // ワーカーが送ったものを受け取り、クライアントへ書き出すメッセージを返す
+    // - サーバーからのリクエストなら、ここから先はメインループで応答を待つ
+    // - メインループの外で動かした処理の結果は、ここで状態に反映する
+    pub fn handle_worker(&mut self, output: FromWorker) -> Vec<String> {
+        match output {
+            FromWorker::Message(forwarded) => vec![self.state.outgoing.forward(forwarded)],
+            FromWorker::Update(update) => {
+                let messages = update(&mut self.state);
+                let mut outgoing = self.state.outgoing.take_messages();
+                outgoing.extend(messages);
+                outgoing
+            }
+        }
+    }
+
+    // `exit` 通知を受け取っていれば、プロセスの終了コード
+    pub fn exit_code(&self) -> Option<i32> {
+        self.state.lifecycle.exit_code()
+    }
+
+    // 処理中のリクエストの一覧（読み取りスレッドと共有する）
+    pub fn in_flight(&self) -> InFlightRequests {
+        self.state.in_flight.clone()
+    }
+
+    // 1つのメッセージを処理して、クライアントへ送るメッセージ（フレーム済みの文字列）を返す
+    // ハンドラがサーバーから送るリクエストや通知を積んでいれば、それも一緒に返す
+    // （$/progress の部分的な結果は応答より先に届かないと捨てられるので、応答より前に並べる）
+    pub fn handle_message(&mut self, message: LspMessage) -> Vec<String> {
+        let responses = self.dispatch(message);
+        let mut messages = self.state.outgoing.take_messages();
+        messages.extend(responses);
+        messages.extend(self.spawn_tasks());
+        messages
+    }
+
+    // メッセージが来なくても何かする必要がある、一番近い時刻
+    // - 応答を待っているサーバー → クライアントのリクエストのタイムアウト
+    // - 編集が落ち着いたドキュメントの診断
+    pub fn next_deadline(&self) -> Option<Instant> {
+        [self.state.outgoing.next_deadline(), self.state.diagnostics.next_deadline()]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    // now の時点で時刻が来たものを処理して、クライアントへ送るメッセージを返す
+    // タイムアウトしたリクエストは諦め、待ち時間が過ぎたドキュメントの診断をワーカーで計算して送る
+    pub fn handle_timers(&mut self, now: Instant) -> Vec<String> {
+        for callback in self.state.outgoing.expire(now) {
+            callback(&mut self.state);
+        }
+        publish_due_diagnostics(&mut self.state, now);
+        let mut messages = self.state.outgoing.take_messages();
+        messages.extend(self.spawn_tasks());
+        messages
+    }
+
+    fn dispatch(&mut self, message: LspMessage) -> Vec<String> {
+        // まずライフサイクルの状態を進め、今の状態で受け付けられないメッセージはここで止める
+        match self.state.lifecycle.on_message(&message) {
+            Ok(true) => {}
+            Ok(false) => return Vec::new(),
+            Err(err) => {
+                return match message {
+                    LspMessage::Request { id, .. } => {
+                        self.state.in_flight.finish(&id);
+                        vec![err.to_error_response(id)]
+                    }
+                    _ => Vec::new(),
+                };
+            }
+        }
+
+        match message {
+            LspMessage::Request { id, method, params } => self.handle_request(id, &method, params),
+            // 通知には応答を返せないので、未知の通知や不正な params は読み捨てて、クライアントのログに残す
+            LspMessage::Notification { method, params } => self.handle_notification(&method, params),
+            // サーバーから送ったリクエストへの応答。知らない id のものは読み捨てる
+            LspMessage::Response { id, result, error } => {
+                if let Some(callback) = self.state.outgoing.complete(&id, result, error) {
+                    callback(&mut self.state);
+                }
+                Vec::new()
+            }
+        }
+    }
+
+    fn handle_request(&mut self, id: serde_json::Value, method: &str, params: Option<serde_json::Value>) -> Vec<String> {
+        let request = StartedRequest::start(&self.state.in_flight, self.state.trace, id.clone(), method, &params);
+
+        if !self.router.is_read_request(method) {
+            let response = (!request.token.is_cancelled()).then(|| self.router.handle_request(&mut self.state, id, method, params));
+            let in_flight = self.state.in_flight.clone();
+            return vec![request.finish(response, &in_flight, &mut self.state.outgoing, self.state.trace)];
+        }
+
+        // 読み取り専用のリクエストは、いまの状態のスナップショットに対して処理する
+        // 処理中に送るメッセージ（$/progress など）も応答も、送った時点で sink へ渡す
+        let job = self.router.read_request(id, method, params).expect("checked by is_read_request");
+        let mut snapshot = self.state.snapshot(request.token.clone(), messages_to(&self.sink));
+        let sink = self.sink.clone();
+        let trace = self.state.trace;
+        self.execute(move || {
+            let response = (!request.token.is_cancelled()).then(|| job.run(&mut snapshot));
+            let response = request.finish(response, &snapshot.in_flight, &mut snapshot.outgoing, trace);
+            sink(FromWorker::Message(Forwarded::message(response)));
+        })
+    }
+
+    // ハンドラが積んだ処理をワーカーで動かす
+    fn spawn_tasks(&mut self) -> Vec<String> {
+        let mut messages = Vec::new();
+        for task in std::mem::take(&mut self.state.tasks) {
+            let mut outgoing = self.state.outgoing.fork(messages_to(&self.sink));
+            let sink = self.sink.clone();
+            messages.extend(self.execute(move || sink(FromWorker::Update(task(&mut outgoing)))));
+        }
+        messages
+    }
+
+    // job をワーカーで動かす
+    // ワーカーが無ければその場で動かし、sink へ送られたものを順に処理して、書き出すメッセージを返す
+    fn execute(&mut self, job: impl FnOnce() + Send + 'static) -> Vec<String> {
+        if let Some(pool) = &self.workers {
+            pool.execute(job);
+            return Vec::new();
+        }
+        job();
+        let outputs: Vec<FromWorker> = self.inline.try_iter().collect();
+        outputs.into_iter().flat_map(|output| self.handle_worker(output)).collect()
+    }
+
+    fn handle_notification(&mut self, method: &str, params: Option<serde_json::Value>) -> Vec<String> {
+        let started = Instant::now();
+        let traced_params = verbose_params(self.state.trace, &params);
+
+        match self.router.handle_notification(&mut self.state, method, params) {
+            Ok(messages) => {
+                let message = format!("Handled notification '{}' in {}.", method, format_elapsed(started.elapsed()));
+                log_trace(&mut self.state.outgoing, self.state.trace, message, || {
+                    format!("Params: {}", traced_params.unwrap_or_default())
+                });
+                messages
+            }
+            // "$/" で始まる通知は、対応していなければ無視してよい（$/setTrace 以外の拡張など）
+            Err(err) if method.starts_with("$/") && err.code == ErrorCode::MethodNotFound.code() => Vec::new(),
+            Err(err) => {
+                log_message(&mut self.state.outgoing, MessageType::WARNING, format!("Ignored notification '{}': {}", method, err.message));
+                Vec::new()
+            }
+        }
+    }
+}
+
+// 処理を始めたリクエスト
+// メインループでもワーカーでも、キャンセルの確認・処理時間の $/logTrace は同じように扱う
+struct StartedRequest {
+    id: serde_json::Value,
+    method: String,
+    started: Instant,
+    traced_params: Option<serde_json::Value>,
+    token: CancellationToken,
+}
+
+impl StartedRequest {
+    fn start(in_flight: &InFlightRequests, trace: TraceValue, id: serde_json::Value, method: &str, params: &Option<serde_json::Value>) -> Self {
+        StartedRequest {
+            token: in_flight.start(&id),
+            id,
+            method: method.to_string(),
+            started: Instant::now(),
+            traced_params: verbose_params(trace, params),
+        }
+    }
+
+    // ハンドラの応答（処理を始める前にキャンセルされていたら None）から、クライアントへ返す応答を作る
+    // 登録を消して、処理にかかった時間を $/logTrace で送る
+    fn finish<S: 'static>(self, response: Option<String>, in_flight: &InFlightRequests, outgoing: &mut Outgoing<S>, trace: TraceValue) -> String {
+        let StartedRequest { id, method, started, traced_params, token } = self;
+
+        // 処理中にキャンセルされた場合、計算済みの結果はもう要らない（古いかもしれない）ので捨てる
+        let response = match response {
+            Some(response) if !token.is_cancelled() => response,
+            _ => request_cancelled().to_error_response(id.clone()),
+        };
+        in_flight.finish(&id);
+
+        // 応答の本文（Content-Length の後ろ）から、成功したか失敗したかを読む
+        let content: serde_json::Value = response
+            .split_once("\r\n\r\n")
+            .and_then(|(_, content)| serde_json::from_str(content).ok())
+            .unwrap_or_default();
+        let elapsed = format_elapsed(started.elapsed());
+        let message = match content["error"]["message"].as_str() {
+            Some(error) => format!("Request '{} - ({})' failed in {}: {}", method, id, elapsed, error),
+            None => format!("Handled request '{} - ({})' in {}.", method, id, elapsed),
+        };
+        log_trace(outgoing, trace, message, || {
+            let outcome = content.get("error").or_else(|| content.get("result")).cloned().unwrap_or_default();
+            format!("Params: {}\n\nResult: {}", traced_params.unwrap_or_default(), outcome)
+        });
+        response
+    }
+}
+
+// trace が verbose のときだけ、$/logTrace に載せるために params を取っておく
+fn verbose_params(trace: TraceValue, params: &Option<serde_json::Value>) -> Option<serde_json::Value> {
+    (trace == TraceValue::Verbose).then(|| params.clone().unwrap_or_default())
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// その場で動かすときの sink（送られたものは受け取り側に貯まる）
+fn inline_sink() -> (WorkerSink, Receiver<FromWorker>) {
+    let (sender, receiver) = mpsc::channel();
+    let sink: WorkerSink = Arc::new(move |output| {
+        let _ = sender.send(output);
+    });
+    (sink, receiver)
+}
+
+// クライアントへ送るメッセージだけを sink へ渡す、fork() した Outgoing 用の送り先
+fn messages_to(sink: &WorkerSink) -> Sink {
+    let sink = sink.clone();
+    Arc::new(move |forwarded| sink(FromWorker::Message(forwarded)))
+}
+
+// 読み取りスレッドとワーカーからメインループへ送るもの
+enum Incoming {
+    Message(LspMessage),
+    Malformed(LspMessageError),
+    // 入力が終わった（ワーカーも送り手なので、チャネルが閉じるのを待つだけでは分からない）
+    EndOfInput,
+    // ワーカーが送ったもの
+    Worker(FromWorker),
+}
+
+// 別スレッドでメッセージを読み続けて sender へ送る
+// リクエストはこの時点で InFlightRequests に登録し、$/cancelRequest と window/workDoneProgress/cancel はこの時点でトークンを立てる
+// （メインループが重いリクエストを処理している最中でもキャンセルが届くようにするため）
+fn spawn_reader<R>(reader: R, in_flight: InFlightRequests, sender: Sender<Result<Incoming, MessageReadError>>)
+where
+    R: BufRead + Send + 'static,
+{
+    thread::spawn(move || {
+        for message in MessageReader::new(reader) {
+            let incoming = message.map(|message| match try_parse_full_lsp_message(&message) {
+                Ok(message) => {
+                    watch_cancellation(&message, &in_flight);
+                    Incoming::Message(message)
+                }
+                Err(err) => Incoming::Malformed(err),
+            });
+
+            let is_read_error = incoming.is_err();
+            // メインループが終わっていたら（exit 済み）読むのをやめる
+            if sender.send(incoming).is_err() || is_read_error {
+                return;
+            }
+        }
+        let _ = sender.send(Ok(Incoming::EndOfInput));
+    });
+}
+
+fn watch_cancellation(message: &LspMessage, in_flight: &InFlightRequests) {
+    match message {
+        LspMessage::Request { id, .. } => {
+            in_flight.start(id);
+        }
+        LspMessage::Notification { method, params } if method == Cancel::METHOD => {
+            let params = params.clone().and_then(|params| serde_json::from_value::<CancelParams>(params).ok());
+            if let Some(params) = params {
+                in_flight.cancel(&params.id);
+            }
+        }
+        // 進捗表示のキャンセルボタンが押された
+        LspMessage::Notification { method, params } if method == WorkDoneProgressCancel::METHOD => {
+            let params = params
+                .clone()
+                .and_then(|params| serde_json::from_value::<WorkDoneProgressCancelParams>(params).ok());
+            if let Some(params) = params {
+                in_flight.cancel_progress(&params.token);
+            }
+        }
+        _ => {}
+    }
+}
+
+// reader からメッセージを読み続け、応答を writer に書き出す
+// `exit` 通知を受け取るか、入力が終わったらループを抜けて、プロセスの終了コードを返す
+// shutdown → exit の順で終わった場合だけ 0、それ以外（exit 無しで入力が終わった場合も含む）は 1
+pub fn run<R, W>(reader: R, mut writer: W) -> Result<i32, MessageReadError>
+where
+    R: BufRead + Send + 'static,
+    W: Write,
+{
+    let (sender, receiver) = mpsc::channel();
+    let from_workers = sender.clone();
+    let mut server = Server::with_workers(WorkerPool::with_available_parallelism(), move |output| {
+        // メインループが終わっていたら（exit 済み）もう書き出せないので捨てる
+        let _ = from_workers.send(Ok(Incoming::Worker(output)));
+    });
+    spawn_reader(reader, server.in_flight(), sender);
+
+    loop {
+        // リクエストのタイムアウトや診断の送信を待っている間は、その時刻までしか待たない
+        let incoming = match server.next_deadline() {
+            Some(deadline) => match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(incoming) => incoming,
+                Err(RecvTimeoutError::Timeout) => {
+                    for outgoing in server.handle_timers(Instant::now()) {
+                        write_message(&mut writer, &outgoing)?;
+                    }
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            },
+            None => match receiver.recv() {
+                Ok(incoming) => incoming,
+                Err(_) => break,
+            },
+        };
+
+        let message = match incoming? {
+            Incoming::Message(message) => message,
+            // 壊れたメッセージには JSON-RPC のエラー応答を返して次へ進む
+            Incoming::Malformed(err) => {
+                write_message(&mut writer, &err.to_error_response())?;
+                continue;
+            }
+            Incoming::Worker(output) => {
+                for outgoing in server.handle_worker(output) {
+                    write_message(&mut writer, &outgoing)?;
+                }
+                continue;
+            }
+            // 入力が終わっても、受け取ったリクエストには最後まで答える（ファイルからメッセージを流し込んだ場合など）
+            Incoming::EndOfInput => {
+                server.wait_for_workers();
+                for incoming in receiver.try_iter() {
+                    if let Ok(Incoming::Worker(output)) = incoming {
+                        for outgoing in server.handle_worker(output) {
+                            write_message(&mut writer, &outgoing)?;
+                        }
+                    }
+                }
+                break;
+            }
+        };
+
+        for outgoing in server.handle_message(message) {
+            write_message(&mut writer, &outgoing)?;
+        }
+
+        if server.exit_code().is_some() {
+            break;
+        }
+    }
+
+    writer.flush()?;
+    Ok(server.exit_code().unwrap_or(1))
+}
+
+// stdin / stdout を使ってサーバーを起動する
+pub fn run_stdio() -> Result<i32, MessageReadError> {
+    let stdout = io::stdout();
+    run(BufReader::new(io::stdin()), stdout.lock())
+}
+
+// --- Tests --- //
+
+#[cfg(test)]
+mod tests {
+    use super::{run, Server};
+    use crate::lessons::lesson_1::lesson_1_9::LspMessage;
+    use crate::server::transport::MessageReader;
+    use crate::server::workers::WorkerPool;
+    use lsp_types::notification::ShowMessage;
+    use lsp_types::request::{HoverRequest, ShowMessageRequest};
+    use lsp_types::{Hover, HoverContents, MarkedString, MessageType, NumberOrString, ShowMessageParams, ShowMessageRequestParams, Url};
+    use std::sync::{mpsc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use serde_json::{json, Value};
+    use std::io::{self, BufReader, Cursor, Read, Write};
+
+    fn frame(content: Value) -> String {
+        let content = content.to_string();
+        format!("Content-Length: {}\r\n\r\n{}", content.len(), content)
+    }
+
+    // クライアントからのメッセージ列を流し込み、サーバーが書き出したメッセージの本文を返す
+    fn run_session(messages: Vec<Value>) -> Vec<Value> {
+        run_session_with_exit_code(messages).0
+    }
+
+    fn run_session_with_exit_code(messages: Vec<Value>) -> (Vec<Value>, i32) {
+        let input: String = messages.into_iter().map(frame).collect();
+        let mut output = Vec::new();
+        let exit_code = run(Cursor::new(input), &mut output).expect("server loop should finish cleanly");
+
+        let outputs = MessageReader::new(Cursor::new(output))
+            .map(|message| {
+                let message = message.unwrap();
+                let (_, content) = message.split_once("\r\n\r\n").unwrap();
+                serde_json::from_str(content).unwrap()
+            })
+            .collect();
+        (outputs, exit_code)
+    }
+
+    fn initialize() -> Value {
+        json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {"capabilities": {}}})
+    }
+
+    fn did_open(uri: &str, text: &str) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": {"textDocument": {"uri": uri, "languageId": "rust", "version": 1, "text": text}}
+        })
+    }
+
+    #[test]
+    fn test_initialize_advertises_capabilities() {
+        let outputs = run_session(vec![initialize()]);
+
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0]["id"], 1);
+        assert_eq!(outputs[0]["result"]["capabilities"]["hoverProvider"], true);
+        assert_eq!(outputs[0]["result"]["capabilities"]["textDocumentSync"]["change"], 2, "the server should ask for incremental changes");
+        assert_eq!(outputs[0]["result"]["serverInfo"]["name"], "toy-lang-server");
+    }
+
+    #[test]
+    fn test_initialize_adapts_to_client_capabilities() {
+        let outputs = run_session(vec![json!({
+            "jsonrpc": "2.0", "id": 1, "method": "initialize",
+            "params": {"capabilities": {"general": {"positionEncodings": ["utf-8", "utf-16"]}}}
+        })]);
+
+        let capabilities = &outputs[0]["result"]["capabilities"];
+        assert_eq!(capabilities["positionEncoding"], "utf-8");
+        assert!(capabilities["semanticTokensProvider"]["legend"]["tokenTypes"].is_array());
+        assert_eq!(capabilities["declarationProvider"], true);
+        assert_eq!(capabilities["codeLensProvider"]["resolveProvider"], false, "unregistered features should not be advertised");
+    }
+
+    #[test]
+    fn test_document_symbols_follow_client_support() {
+        let document_symbol = json!({
+            "jsonrpc": "2.0", "id": 2, "method": "textDocument/documentSymbol",
+            "params": {"textDocument": {"uri": "file:///test.rs"}}
+        });
+
+        let text = "struct Point {\n    x: i32,\n}\n\nfn main() {}";
+
+        let flat = run_session(vec![initialize(), did_open("file:///test.rs", text), document_symbol.clone()]);
+        let flat = flat.iter().find(|output| output["id"] == 2).unwrap();
+        assert_eq!(flat["result"][0]["name"], "Point");
+        assert_eq!(flat["result"][0]["location"]["uri"], "file:///test.rs", "flat SymbolInformation has a location");
+        assert_eq!(flat["result"][1]["name"], "x");
+        assert_eq!(flat["result"][1]["containerName"], "Point", "the parent becomes the container name");
+        assert_eq!(flat["result"][2]["name"], "main");
+
+        let nested = run_session(vec![
+            json!({
+                "jsonrpc": "2.0", "id": 1, "method": "initialize",
+                "params": {"capabilities": {"textDocument": {"documentSymbol": {"hierarchicalDocumentSymbolSupport": true}}}}
+            }),
+            did_open("file:///test.rs", text),
+            document_symbol,
+        ]);
+        let nested = nested.iter().find(|output| output["id"] == 2).unwrap();
+        assert_eq!(nested["result"][0]["name"], "Point");
+        assert!(nested["result"][0]["selectionRange"].is_object(), "DocumentSymbol has a selectionRange");
+        assert_eq!(nested["result"][0]["children"][0]["name"], "x");
+        assert_eq!(nested["result"][1]["name"], "main");
+    }
+
+    #[test]
+    fn test_completion_snippets_follow_client_support() {
+        let completion = json!({
+            "jsonrpc": "2.0", "id": 2, "method": "textDocument/completion",
+            "params": {"textDocument": {"uri": "file:///test.rs"}, "position": {"line": 0, "character": 1}}
+        });
+
+        let plain = run_session(vec![initialize(), did_open("file:///test.rs", "f"), completion.clone()]);
+        let plain = plain.iter().find(|output| output["id"] == 2).unwrap();
+        let item = plain["result"].as_array().unwrap().iter().find(|item| item["label"] == "fn").unwrap();
+        assert!(item.get("insertTextFormat").is_none(), "snippets should not be sent without client support");
+        assert_eq!(item["textEdit"]["newText"], "fn");
+        assert_eq!(item["textEdit"]["range"]["start"]["character"], 0, "the edit replaces the typed word");
+
+        let snippet = run_session(vec![
+            json!({
+                "jsonrpc": "2.0", "id": 1, "method": "initialize",
+                "params": {"capabilities": {"textDocument": {"completion": {"completionItem": {"snippetSupport": true}}}}}
+            }),
+            did_open("file:///test.rs", "f"),
+            completion,
+        ]);
+        let snippet = snippet.iter().find(|output| output["id"] == 2).unwrap();
+        let item = snippet["result"].as_array().unwrap().iter().find(|item| item["label"] == "fn").unwrap();
+        assert_eq!(item["insertTextFormat"], 2);
+        assert!(item["insertText"].as_str().unwrap().contains("${1:name}"));
+        assert_eq!(item["textEdit"]["newText"], item["insertText"], "the client inserts the textEdit, not insertText");
+    }
+
+    #[test]
+    fn test_completion_items_are_resolved_lazily() {
+        let completion = json!({
+            "jsonrpc": "2.0", "id": 2, "method": "textDocument/completion",
+            "params": {"textDocument": {"uri": "file:///test.rs"}, "position": {"line": 0, "character": 2}}
+        });
+        let outputs = run_session(vec![initialize(), did_open("file:///test.rs", "lt"), completion]);
+        let initialized = outputs.iter().find(|output| output["id"] == 1).unwrap();
+        assert_eq!(initialized["result"]["capabilities"]["completionProvider"]["resolveProvider"], true);
+
+        let listed = outputs.iter().find(|output| output["id"] == 2).unwrap();
+        let items = listed["result"].as_array().unwrap();
+        let item = items.iter().find(|item| item["label"] == "let").unwrap();
+        assert_eq!(item["sortText"], "0000", "'let' ranks first for 'lt'");
+        assert!(item.get("documentation").is_none());
+
+        let resolve = json!({"jsonrpc": "2.0", "id": 3, "method": "completionItem/resolve", "params": item});
+        let outputs = run_session(vec![initialize(), resolve]);
+        let resolved = outputs.iter().find(|output| output["id"] == 3).unwrap();
+        assert_eq!(resolved["result"]["label"], "let");
+        assert_eq!(resolved["result"]["detail"], "keyword");
+        assert_eq!(resolved["result"]["documentation"]["kind"], "markdown");
+        assert_eq!(resolved["result"]["textEdit"], item["textEdit"], "resolve keeps what the list sent");
+    }
+
+    #[test]
+    fn test_did_open_publishes_diagnostics() {
+        let outputs = run_session(vec![
+            initialize(),
+            json!({"jsonrpc": "2.0", "method": "initialized", "params": {}}),
+            did_open("file:///test.rs", "fn main() {\n// TODO: fix\n}"),
+        ]);
+
+        assert_eq!(outputs.len(), 2, "initialized should not produce output");
+        assert_eq!(outputs[1]["method"], "textDocument/publishDiagnostics");
+        assert_eq!(outputs[1]["params"]["uri"], "file:///test.rs");
+        assert_eq!(outputs[1]["params"]["diagnostics"][0]["message"], "Found a TODO item.");
+    }
+
+    #[test]
+    fn test_hover_request_is_dispatched() {
+        let outputs = run_session(vec![
+            initialize(),
+            did_open("file:///test.rs", "fn main() {}"),
+            json!({
+                "jsonrpc": "2.0", "id": 2, "method": "textDocument/hover",
+                "params": {"textDocument": {"uri": "file:///test.rs"}, "position": {"line": 0, "character": 1}}
+            }),
+        ]);
+
+        let hover = outputs.iter().find(|output| output["id"] == 2).expect("hover response");
+        assert_eq!(hover["result"]["contents"]["value"], "Keyword: Function definition");
+    }
+
+    #[test]
+    fn test_hover_on_names_uses_the_type_checker() {
+        let outputs = run_session(vec![
+            initialize(),
+            did_open("file:///test.rs", "fn main() {\n    let x = 1 > 0;\n    x;\n}"),
+            json!({
+                "jsonrpc": "2.0", "id": 2, "method": "textDocument/hover",
+                "params": {"textDocument": {"uri": "file:///test.rs"}, "position": {"line": 2, "character": 4}}
+            }),
+        ]);
+
+        let hover = outputs.iter().find(|output| output["id"] == 2).expect("hover response");
+        assert_eq!(hover["result"]["contents"]["value"], "```rust\nlet x: bool\n```\n\n---\n\nvariable defined at line 2");
+        assert_eq!(hover["result"]["range"]["start"], json!({"line": 2, "character": 4}));
+    }
+
+    #[test]
+    fn test_positions_use_negotiated_encoding() {
+        let outputs = run_session(vec![
+            json!({
+                "jsonrpc": "2.0", "id": 1, "method": "initialize",
+                "params": {"capabilities": {"general": {"positionEncodings": ["utf-8"]}}}
+            }),
+            json!({"jsonrpc": "2.0", "method": "initialized", "params": {}}),
+            did_open("file:///test.rs", "let s = \"あ\"; let my_variable = s;"),
+            json!({
+                "jsonrpc": "2.0", "id": 2, "method": "textDocument/references",
+                "params": {"textDocument": {"uri": "file:///test.rs"}, "position": {"line": 0, "character": 33},
+                    "context": {"includeDeclaration": false}}
+            }),
+        ]);
+
+        let references = outputs.iter().find(|output| output["id"] == 2).expect("references response");
+        // "let s = \"あ\"; let my_variable = " は 33 バイト（UTF-16 なら 31）
+        assert_eq!(references["result"][0]["range"]["start"]["character"], 33);
+        assert_eq!(references["result"][0]["range"]["end"]["character"], 34);
+    }
+
+    #[test]
+    fn test_formatting_request_returns_whole_document_edit() {
+        let outputs = run_session(vec![
+            initialize(),
+            did_open("file:///test.rs", "fn main() {\nlet x = 1;\n}"),
+            json!({
+                "jsonrpc": "2.0", "id": 2, "method": "textDocument/formatting",
+                "params": {"textDocument": {"uri": "file:///test.rs"}, "options": {"tabSize": 4, "insertSpaces": true}}
+            }),
+        ]);
+
+        let formatting = outputs.iter().find(|output| output["id"] == 2).expect("formatting response");
+        assert_eq!(formatting["result"][0]["newText"], "fn main() {\n    let x = 1;\n}");
+    }
+
+    #[test]
+    fn test_unknown_request_gets_method_not_found() {
+        let outputs = run_session(vec![
+            initialize(),
+            json!({"jsonrpc": "2.0", "id": 2, "method": "textDocument/unknown", "params": {}}),
+        ]);
+
+        assert_eq!(outputs[1]["id"], 2);
+        assert_eq!(outputs[1]["error"]["code"], -32601);
+    }
+
+    #[test]
+    fn test_invalid_params_gets_error_response() {
+        let outputs = run_session(vec![
+            initialize(),
+            json!({"jsonrpc": "2.0", "id": 2, "method": "textDocument/hover", "params": {"position": 1}}),
+        ]);
+
+        assert_eq!(outputs[1]["id"], 2);
+        assert_eq!(outputs[1]["error"]["code"], -32602);
+    }
+
+    #[test]
+    fn test_exit_stops_the_loop() {
+        let (outputs, exit_code) = run_session_with_exit_code(vec![
+            initialize(),
+            json!({"jsonrpc": "2.0", "id": 2, "method": "shutdown"}),
+            json!({"jsonrpc": "2.0", "method": "exit"}),
+            json!({"jsonrpc": "2.0", "id": 3, "method": "textDocument/hover", "params": {}}),
+        ]);
+
+        assert_eq!(outputs.len(), 2, "messages after exit should not be processed");
+        assert_eq!(outputs[1]["id"], 2);
+        assert!(outputs[1]["result"].is_null());
+        assert_eq!(exit_code, 0, "exit after shutdown should exit with code 0");
+    }
+
+    #[test]
+    fn test_exit_without_shutdown_exits_with_error() {
+        let (_, exit_code) = run_session_with_exit_code(vec![initialize(), json!({"jsonrpc": "2.0", "method": "exit"})]);
+        assert_eq!(exit_code, 1);
+
+        let (_, exit_code) = run_session_with_exit_code(vec![initialize()]);
+        assert_eq!(exit_code, 1, "end of input without exit should also be an error");
+    }
+
+    #[test]
+    fn test_requests_before_initialize_are_rejected() {
+        let outputs = run_session(vec![
+            did_open("file:///test.rs", "// TODO"),
+            json!({"jsonrpc": "2.0", "id": 1, "method": "textDocument/hover", "params": {}}),
+        ]);
+
+        assert_eq!(outputs.len(), 1, "notifications before initialize should be dropped");
+        assert_eq!(outputs[0]["id"], 1);
+        assert_eq!(outputs[0]["error"]["code"], -32002);
+    }
+
+    #[test]
+    fn test_requests_after_shutdown_are_rejected() {
+        let outputs = run_session(vec![
+            initialize(),
+            json!({"jsonrpc": "2.0", "id": 2, "method": "shutdown"}),
+            did_open("file:///test.rs", "// TODO"),
+            json!({"jsonrpc": "2.0", "id": 3, "method": "textDocument/hover", "params": {}}),
+        ]);
+
+        assert_eq!(outputs.len(), 3, "notifications after shutdown should be dropped");
+        assert_eq!(outputs[2]["id"], 3);
+        assert_eq!(outputs[2]["error"]["code"], -32600);
+    }
+
+    #[test]
+    fn test_malformed_messages_get_json_rpc_errors() {
+        let broken = "{\"jsonrpc\": \"2.0\", \"id\": 1,";
+        let input = format!(
+            "Content-Length: {}\r\n\r\n{}{}",
+            broken.len(),
+            broken,
+            frame(json!({"jsonrpc": "2.0", "id": "a", "params": {}}))
+        );
+        let mut output = Vec::new();
+        run(Cursor::new(input), &mut output).unwrap();
+
+        let outputs: Vec<Value> = MessageReader::new(Cursor::new(output))
+            .map(|message| serde_json::from_str(message.unwrap().split_once("\r\n\r\n").unwrap().1).unwrap())
+            .collect();
+        assert_eq!(outputs[0]["error"]["code"], -32700);
+        assert!(outputs[0]["id"].is_null());
+        assert_eq!(outputs[1]["error"]["code"], -32600);
+        assert_eq!(outputs[1]["id"], "a");
+    }
+
+    #[test]
+    fn test_client_responses_are_not_answered() {
+        let outputs = run_session(vec![
+            initialize(),
+            json!({"jsonrpc": "2.0", "id": 99, "result": {"applied": true}}),
+        ]);
+
+        assert_eq!(outputs.len(), 1, "A response from the client must not be replied to.");
+    }
+
+    fn request(id: Value, method: &str, params: Value) -> LspMessage {
+        LspMessage::Request { id, method: method.to_string(), params: Some(params) }
+    }
+
+    fn initialized_server() -> Server {
+        let mut server = Server::new();
+        server.handle_message(request(json!(1), "initialize", json!({"capabilities": {}})));
+        server
+    }
+
+    fn content(message: &str) -> Value {
+        serde_json::from_str(message.split_once("\r\n\r\n").unwrap().1).unwrap()
+    }
+
+    #[test]
+    fn test_request_cancelled_before_processing() {
+        let mut server = initialized_server();
+        // 読み取りスレッドがリクエストとキャンセルを先に読んだ状況
+        let in_flight = server.in_flight();
+        in_flight.start(&json!(5));
+        in_flight.cancel(&NumberOrString::Number(5));
+
+        let outputs = server.handle_message(request(json!(5), "workspace/symbol", json!({"query": "main"})));
+
+        let response = content(&outputs[0]);
+        assert_eq!(response["id"], 5);
+        assert_eq!(response["error"]["code"], -32800);
+        assert!(in_flight.is_empty(), "Answered requests should no longer be tracked");
+    }
+
+    #[test]
+    fn test_request_cancelled_while_processing_drops_result() {
+        let mut server = initialized_server();
+        // 処理中にキャンセルが届いた状況を再現するため、自分でキャンセルしてから結果を返すハンドラに差し替える
+        server.router.on_request::<HoverRequest, _>(|state, _| {
+            state.in_flight.cancel(&NumberOrString::String("h".to_string()));
+            Ok(None)
+        });
+
+        let outputs = server.handle_message(request(
+            json!("h"),
+            "textDocument/hover",
+            json!({"textDocument": {"uri": "file:///a.rs"}, "position": {"line": 0, "character": 0}}),
+        ));
+
+        let response = content(&outputs[0]);
+        assert_eq!(response["error"]["code"], -32800, "A cancelled request must not return its stale result");
+        assert!(response.get("result").is_none());
+    }
+
+    #[test]
+    fn test_cancel_request_notification_cancels_tracked_token() {
+        let mut server = initialized_server();
+        let token = server.in_flight().start(&json!(9));
+
+        let outputs = server.handle_message(LspMessage::Notification {
+            method: "$/cancelRequest".to_string(),
+            params: Some(json!({"id": 9})),
+        });
+
+        assert!(outputs.is_empty(), "$/cancelRequest has no response");
+        assert!(token.is_cancelled());
+    }
+
+    #[test]
+    fn test_uncancelled_requests_are_answered_normally() {
+        let mut server = initialized_server();
+        server.handle_message(LspMessage::Notification {
+            method: "textDocument/didOpen".to_string(),
+            params: Some(json!({"textDocument": {"uri": "file:///a.rs", "languageId": "rust", "version": 1, "text": "fn main() {}"}})),
+        });
+
+        let outputs = server.handle_message(request(json!(6), "workspace/symbol", json!({"query": "main"})));
+
+        let response = content(&outputs[0]);
+        assert_eq!(response["result"][0]["name"], "main");
+        assert!(server.in_flight().is_empty());
+    }
+
+    fn did_open_text(server: &mut Server, uri: &str, text: &str) {
+        server.handle_message(LspMessage::Notification {
+            method: "textDocument/didOpen".to_string(),
+            params: Some(json!({"textDocument": {"uri": uri, "languageId": "rust", "version": 1, "text": text}})),
+        });
+    }
+
+    fn did_change_text(server: &mut Server, uri: &str, version: i32, range: Value, text: &str) -> Vec<Value> {
+        let outputs = server.handle_message(LspMessage::Notification {
+            method: "textDocument/didChange".to_string(),
+            params: Some(json!({
+                "textDocument": {"uri": uri, "version": version},
+                "contentChanges": [{"range": range, "text": text}]
+            })),
+        });
+        outputs.iter().map(|output| content(output)).collect()
+    }
+
+    fn line_range(line: u32, start: u32, end: u32) -> Value {
+        json!({"start": {"line": line, "character": start}, "end": {"line": line, "character": end}})
+    }
+
+    #[test]
+    fn test_incremental_changes_are_diagnosed_after_debounce() {
+        let mut server = initialized_server();
+        let outputs = server.handle_message(LspMessage::Notification {
+            method: "textDocument/didOpen".to_string(),
+            params: Some(json!({"textDocument": {"uri": "file:///test.rs", "languageId": "rust", "version": 1, "text": "fn main() {\n// TODO: fix\n}"}})),
+        });
+        assert_eq!(content(&outputs[0])["params"]["version"], 1, "diagnostics on open should be sent right away");
+
+        assert!(did_change_text(&mut server, "file:///test.rs", 2, line_range(1, 3, 7), "DONE").is_empty(), "diagnostics should wait for typing to settle");
+        assert!(did_change_text(&mut server, "file:///test.rs", 3, line_range(1, 0, 0), "// FIXME\n").is_empty());
+        // 同じ version の通知がもう一度届いても当てない
+        let duplicate = did_change_text(&mut server, "file:///test.rs", 3, line_range(0, 0, 0), "// TODO: again\n");
+        assert_eq!(duplicate[0]["method"], "window/logMessage");
+        assert_eq!(duplicate[0]["params"]["type"], 2);
+
+        let deadline = server.next_deadline().expect("diagnostics should be scheduled");
+        assert!(server.handle_timers(Instant::now()).is_empty(), "not yet");
+        let outputs = server.handle_timers(deadline);
+
+        assert_eq!(outputs.len(), 1, "the two edits should be diagnosed once");
+        let publish = content(&outputs[0]);
+        assert_eq!(publish["method"], "textDocument/publishDiagnostics");
+        assert_eq!(publish["params"]["version"], 3);
+        assert_eq!(publish["params"]["diagnostics"], json!([]), "the TODO was replaced and the duplicate was not applied");
+        assert!(server.next_deadline().is_none());
+    }
+
+    #[test]
+    fn test_pull_diagnostics_with_result_ids_and_related_documents() {
+        let mut server = Server::new();
+        let outputs = server.handle_message(request(
+            json!(1),
+            "initialize",
+            json!({"capabilities": {"textDocument": {"diagnostic": {"relatedDocumentSupport": true}}}}),
+        ));
+        assert_eq!(content(&outputs[0])["result"]["capabilities"]["diagnosticProvider"]["interFileDependencies"], true);
+
+        did_open_text(&mut server, "file:///src/main.rs", "mod parser;\n// TODO: parse\nfn main() {}");
+        let outputs = server.handle_message(LspMessage::Notification {
+            method: "textDocument/didOpen".to_string(),
+            params: Some(json!({"textDocument": {"uri": "file:///src/parser.rs", "languageId": "rust", "version": 1, "text": "fn parse() {\n    let unused = 1;\n}"}})),
+        });
+        assert!(outputs.is_empty(), "the client pulls diagnostics, so nothing should be pushed");
+
+        let outputs = server.handle_message(request(json!(2), "textDocument/diagnostic", json!({"textDocument": {"uri": "file:///src/main.rs"}})));
+        let report = content(&outputs[0])["result"].clone();
+        assert_eq!(report["kind"], "full");
+        assert_eq!(report["items"][0]["message"], "Found a TODO item.");
+        assert_eq!(report["relatedDocuments"]["file:///src/parser.rs"]["items"][0]["code"], "unused_variables");
+        let result_id = report["resultId"].clone();
+
+        let outputs = server.handle_message(request(
+            json!(3),
+            "textDocument/diagnostic",
+            json!({"textDocument": {"uri": "file:///src/main.rs"}, "previousResultId": result_id}),
+        ));
+        let report = content(&outputs[0])["result"].clone();
+        assert_eq!(report["kind"], "unchanged", "nothing changed since the last pull");
+        assert_eq!(report["resultId"], result_id);
+
+        let outputs = server.handle_message(request(
+            json!(4),
+            "workspace/diagnostic",
+            json!({"previousResultIds": [{"uri": "file:///src/main.rs", "value": result_id}]}),
+        ));
+        let mut items = content(&outputs[0])["result"]["items"].as_array().unwrap().clone();
+        items.sort_by_key(|item| item["uri"].as_str().unwrap().to_string());
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0]["kind"], "unchanged");
+        assert_eq!(items[1]["kind"], "full");
+        assert_eq!(items[1]["version"], 1);
+        assert_eq!(items[1]["items"][0]["range"]["start"], json!({"line": 1, "character": 8}));
+    }
+
+    #[test]
+    fn test_close_clears_diagnostics() {
+        let mut server = initialized_server();
+        did_open_text(&mut server, "file:///test.rs", "// TODO: fix");
+        did_change_text(&mut server, "file:///test.rs", 2, line_range(0, 0, 0), "// TODO: more\n");
+
+        let outputs = server.handle_message(LspMessage::Notification {
+            method: "textDocument/didClose".to_string(),
+            params: Some(json!({"textDocument": {"uri": "file:///test.rs"}})),
+        });
+
+        let publish = content(&outputs[0]);
+        assert_eq!(publish["method"], "textDocument/publishDiagnostics");
+        assert_eq!(publish["params"]["diagnostics"], json!([]));
+        assert!(publish["params"].get("version").is_none());
+        assert!(server.next_deadline().is_none(), "a closed document should not be diagnosed later");
+    }
+
+    // テストからメッセージを流し込み続けられる入力・書き出されたものをその場で読める出力
+    // （サーバーが動いている間に、届いたメッセージを見てから次のメッセージを送るため）
+    struct ChannelReader {
+        receiver: mpsc::Receiver<Vec<u8>>,
+        buffer: Cursor<Vec<u8>>,
+    }
+
+    impl ChannelReader {
+        fn new(receiver: mpsc::Receiver<Vec<u8>>) -> BufReader<Self> {
+            BufReader::new(ChannelReader { receiver, buffer: Cursor::new(Vec::new()) })
+        }
+    }
+
+    impl Read for ChannelReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            loop {
+                let read = self.buffer.read(buf)?;
+                if read > 0 || buf.is_empty() {
+                    return Ok(read);
+                }
+                // 送り手がいなくなったら入力の終わり
+                match self.receiver.recv() {
+                    Ok(bytes) => self.buffer = Cursor::new(bytes),
+                    Err(_) => return Ok(0),
+                }
+            }
+        }
+    }
+
+    struct ChannelWriter(mpsc::Sender<Vec<u8>>);
+
+    impl Write for ChannelWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let _ = self.0.send(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_progress_is_streamed_and_cancel_stops_the_scan() {
+        let (input, reader) = mpsc::channel();
+        let (writer, written) = mpsc::channel();
+        let server = thread::spawn(move || run(ChannelReader::new(reader), ChannelWriter(writer)));
+        let send = |message: Value| input.send(frame(message).into_bytes()).unwrap();
+        let mut outputs = MessageReader::new(ChannelReader::new(written)).map(|message| content(&message.unwrap()));
+
+        // 診断を取りに来るクライアントなので、開いただけでは診断しない（時間がかかるのは workspace/diagnostic だけ）
+        send(json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {"capabilities": {"textDocument": {"diagnostic": {}}}}}));
+        assert_eq!(outputs.next().unwrap()["id"], 1);
+        let text: String = (0..100).map(|i| format!("fn f{}() {{\n    let unused = {};\n}}\n", i, i)).collect();
+        for i in 0..200 {
+            send(did_open(&format!("file:///src/m{}.rs", i), &text));
+        }
+        send(json!({
+            "jsonrpc": "2.0", "id": 2, "method": "workspace/diagnostic",
+            "params": {"previousResultIds": [], "workDoneToken": "work", "partialResultToken": "partial"}
+        }));
+
+        // 調べている途中の部分的な結果と report が、応答を待たずに届く
+        let mut partial_results = 0;
+        let mut percentage = loop {
+            let output = outputs.next().expect("a progress report");
+            assert_ne!(output["id"], 2, "a report should be written before the response");
+            partial_results += (output["params"]["token"] == "partial") as usize;
+            if output["params"]["value"]["kind"] == "report" {
+                break output["params"]["value"]["percentage"].as_u64().unwrap();
+            }
+        };
+        assert!(partial_results > 0, "partial results are streamed while scanning");
+
+        // 進捗表示のキャンセルボタンで、残りのドキュメントは調べずに止まる
+        send(json!({"jsonrpc": "2.0", "method": "window/workDoneProgress/cancel", "params": {"token": "work"}}));
+        let mut kinds = Vec::new();
+        let response = loop {
+            let output = outputs.next().expect("the response");
+            if output["id"] == 2 {
+                break output;
+            }
+            if output["params"]["token"] == "work" {
+                percentage = output["params"]["value"]["percentage"].as_u64().unwrap_or(percentage);
+                kinds.push(output["params"]["value"]["kind"].clone());
+            }
+        };
+        assert_eq!(response["error"]["code"], -32800);
+        assert_eq!(kinds.last(), Some(&json!("end")), "the progress is closed even when cancelled");
+        assert!(percentage < 100, "the scan should stop partway through, stopped at {}%", percentage);
+
+        send(json!({"jsonrpc": "2.0", "id": 3, "method": "shutdown"}));
+        send(json!({"jsonrpc": "2.0", "method": "exit"}));
+        assert_eq!(server.join().unwrap().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_server_creates_progress_token_when_client_supports_it() {
+        let mut server = Server::new();
+        server.handle_message(request(json!(1), "initialize", json!({"capabilities": {"window": {"workDoneProgress": true}}})));
+        did_open_text(&mut server, "file:///a.rs", "fn helper() {}\nfn main() {\n    helper();\n}");
+
+        let outputs = server.handle_message(request(
+            json!(2),
+            "callHierarchy/incomingCalls",
+            json!({"item": {"name": "helper", "kind": 12, "uri": "file:///a.rs",
+                "range": {"start": {"line": 0, "character": 0}, "end": {"line": 0, "character": 14}},
+                "selectionRange": {"start": {"line": 0, "character": 3}, "end": {"line": 0, "character": 9}}}}),
+        ));
+        let outputs: Vec<Value> = outputs.iter().map(|output| content(output)).collect();
+
+        assert_eq!(outputs[0]["method"], "window/workDoneProgress/create");
+        let token = &outputs[0]["params"]["token"];
+        assert_eq!(outputs[1]["method"], "$/progress");
+        assert_eq!(&outputs[1]["params"]["token"], token);
+        assert_eq!(outputs[1]["params"]["value"]["kind"], "begin");
+        assert_eq!(outputs[1]["params"]["value"]["cancellable"], true);
+        let response = outputs.last().unwrap();
+        assert_eq!(response["result"][0]["from"]["name"], "main");
+        assert!(server.next_deadline().is_some(), "the main loop waits for the response to the create request");
+    }
+
+    #[test]
+    fn test_no_progress_without_token_or_client_support() {
+        let mut server = initialized_server();
+        did_open_text(&mut server, "file:///a.rs", "fn main() {}");
+
+        let outputs = server.handle_message(request(json!(8), "workspace/symbol", json!({"query": "main"})));
+
+        assert_eq!(outputs.len(), 1, "only the response should be sent");
+    }
+
+    #[test]
+    fn test_work_done_progress_cancel_cancels_request() {
+        let mut server = initialized_server();
+        let token = server.in_flight().start(&json!(10));
+        server.in_flight().watch_progress(&NumberOrString::String("work".to_string()), token.clone());
+
+        let outputs = server.handle_message(LspMessage::Notification {
+            method: "window/workDoneProgress/cancel".to_string(),
+            params: Some(json!({"token": "work"})),
+        });
+
+        assert!(outputs.is_empty());
+        assert!(token.is_cancelled(), "cancelling the progress should cancel the request doing the work");
+    }
+
+    // hover を受け取ったらクライアントに質問し、答えを window/showMessage で返すハンドラに差し替える
+    fn server_asking_client() -> Server {
+        let mut server = initialized_server();
+        server.router.on_request::<HoverRequest, _>(|state, _| {
+            let params = ShowMessageRequestParams { typ: MessageType::INFO, message: "Continue?".to_string(), actions: None };
+            state.outgoing.send_request::<ShowMessageRequest, _>(params, |state, result| {
+                let message = match result {
+                    Ok(Some(item)) => format!("chosen: {}", item.title),
+                    Ok(None) => "dismissed".to_string(),
+                    Err(err) => format!("failed: {}", err.code),
+                };
+                state.outgoing.send_notification::<ShowMessage>(ShowMessageParams { typ: MessageType::INFO, message });
+            });
+            Ok(None)
+        });
+        server
+    }
+
+    fn hover_request(id: i64) -> LspMessage {
+        request(json!(id), "textDocument/hover", json!({"textDocument": {"uri": "file:///a.rs"}, "position": {"line": 0, "character": 0}}))
+    }
+
+    #[test]
+    fn test_server_request_response_is_correlated() {
+        let mut server = server_asking_client();
+
+        let outputs = server.handle_message(hover_request(10));
+        assert_eq!(outputs.len(), 2, "the server request and the hover response");
+        let server_request = content(&outputs[0]);
+        assert_eq!(server_request["method"], "window/showMessageRequest");
+
+        let outputs = server.handle_message(LspMessage::Response {
+            id: server_request["id"].clone(),
+            result: Some(json!({"title": "Yes"})),
+            error: None,
+        });
+
+        assert_eq!(content(&outputs[0])["method"], "window/showMessage");
+        assert_eq!(content(&outputs[0])["params"]["message"], "chosen: Yes");
+        assert!(server.next_deadline().is_none(), "nothing should be pending after the response");
+    }
+
+    #[test]
+    fn test_server_request_times_out() {
+        let mut server = server_asking_client();
+        server.handle_message(hover_request(11));
+        let deadline = server.next_deadline().expect("the request should be waiting for a response");
+
+        assert!(server.handle_timers(Instant::now()).is_empty(), "not timed out yet");
+        let outputs = server.handle_timers(deadline + Duration::from_millis(1));
+
+        let methods: Vec<Value> = outputs.iter().map(|output| content(output)["method"].clone()).collect();
+        assert_eq!(methods, vec![json!("$/cancelRequest"), json!("window/showMessage")]);
+        assert_eq!(content(&outputs[1])["params"]["message"], "failed: -32800");
+        assert!(server.next_deadline().is_none());
+    }
+
+    #[test]
+    fn test_requests_are_traced_at_the_requested_level() {
+        let mut server = Server::new();
+        server.handle_message(request(json!(1), "initialize", json!({"capabilities": {}, "trace": "messages"})));
+        did_open_text(&mut server, "file:///a.rs", "fn main() {}");
+
+        let hover = json!({"textDocument": {"uri": "file:///a.rs"}, "position": {"line": 0, "character": 1}});
+        let traces = |outputs: Vec<String>| -> Vec<Value> {
+            outputs.iter().map(|output| content(output)).filter(|output| output["method"] == "$/logTrace").map(|output| output["params"].clone()).collect()
+        };
+
+        let logged = traces(server.handle_message(request(json!(2), "textDocument/hover", hover.clone())));
+        assert_eq!(logged.len(), 1);
+        let message = logged[0]["message"].as_str().unwrap();
+        assert!(message.starts_with("Handled request 'textDocument/hover - (2)' in ") && message.ends_with("ms."), "{}", message);
+        assert!(logged[0].get("verbose").is_none(), "'messages' does not include the details");
+
+        server.handle_message(LspMessage::Notification { method: "$/setTrace".to_string(), params: Some(json!({"value": "verbose"})) });
+        let logged = traces(server.handle_message(request(json!(3), "textDocument/hover", json!({"textDocument": {"uri": "file:///a.rs"}}))));
+        assert!(logged[0]["message"].as_str().unwrap().starts_with("Request 'textDocument/hover - (3)' failed in "));
+        assert!(logged[0]["verbose"].as_str().unwrap().starts_with("Params: {\"textDocument\""), "verbose traces include the params");
+
+        server.handle_message(LspMessage::Notification { method: "$/setTrace".to_string(), params: Some(json!({"value": "off"})) });
+        assert!(traces(server.handle_message(request(json!(4), "textDocument/hover", hover))).is_empty());
+    }
+
+    #[test]
+    fn test_problems_are_reported_to_the_user() {
+        let mut server = initialized_server();
+        let outputs: Vec<Value> = server
+            .handle_message(LspMessage::Notification {
+                method: "textDocument/didOpen".to_string(),
+                params: Some(json!({"textDocument": {"uri": "file:///src/broken.rs", "languageId": "rust", "version": 1, "text": "fn main() {\n    f(1;\n"}})),
+            })
+            .iter()
+            .map(|output| content(output))
+            .collect();
+        assert_eq!(outputs[0]["method"], "window/showMessage");
+        assert_eq!(outputs[0]["params"]["message"], "Could not parse broken.rs: unclosed '{' at line 1. Diagnostics may be incomplete.");
+
+        let outputs = server.handle_message(LspMessage::Notification {
+            method: "textDocument/didClose".to_string(),
+            params: Some(json!({"textDocument": {}})),
+        });
+        let log = content(&outputs[0]);
+        assert_eq!(log["method"], "window/logMessage", "a notification with invalid params should not be dropped silently");
+        assert!(log["params"]["message"].as_str().unwrap().starts_with("Ignored notification 'textDocument/didClose': "));
+
+        let outputs = server.handle_message(LspMessage::Notification { method: "$/unknownExtension".to_string(), params: None });
+        assert!(outputs.is_empty(), "unsupported '$/' notifications may be ignored");
+    }
+
+    #[test]
+    fn test_workspace_files_on_disk_are_indexed_and_watched() {
+        let root = std::env::temp_dir().join(format!("toy-lang-server-main-loop-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("shapes.rs"), "struct Circle {}\nfn circle_area() {}\n").unwrap();
+        let root_uri = Url::from_file_path(&root).unwrap();
+        let shapes_uri = Url::from_file_path(root.join("shapes.rs")).unwrap();
+
+        let (sender, from_workers) = mpsc::channel();
+        let mut server = Server::with_workers(WorkerPool::new(1), move |output| sender.send(output).unwrap());
+        server.handle_message(request(
+            json!(1),
+            "initialize",
+            json!({
+                "rootUri": root_uri,
+                "capabilities": {"window": {"workDoneProgress": true}, "workspace": {"didChangeWatchedFiles": {"dynamicRegistration": true}}}
+            }),
+        ));
+        let outputs: Vec<Value> = server
+            .handle_message(LspMessage::Notification { method: "initialized".to_string(), params: Some(json!({})) })
+            .iter()
+            .map(|output| content(output))
+            .collect();
+        let registration = outputs.iter().find(|output| output["method"] == "client/registerCapability").expect("the watcher should be registered");
+        assert_eq!(registration["params"]["registrations"][0]["method"], "workspace/didChangeWatchedFiles");
+        assert!(outputs.iter().all(|output| output["method"] != "$/progress"), "the files are read off the main loop");
+
+        // ワーカーが送った進捗表示を書き出し、読み終えた索引をメインループで取り込む
+        server.wait_for_workers();
+        let outputs: Vec<Value> = from_workers
+            .try_iter()
+            .flat_map(|output| server.handle_worker(output))
+            .map(|output| content(&output))
+            .collect();
+        assert_eq!(outputs[0]["method"], "window/workDoneProgress/create");
+        let end = outputs.iter().find(|output| output["params"]["value"]["kind"] == "end").expect("the indexing progress should end");
+        assert_eq!(end["params"]["value"]["message"], "Indexed 1 files");
+
+        let search = |server: &mut Server, id: i64, query: &str| {
+            let outputs = server.handle_message(request(json!(id), "workspace/symbol", json!({"query": query})));
+            let response = outputs.iter().map(|output| content(output)).find(|output| output["id"] == id).unwrap();
+            response["result"].as_array().unwrap().iter().map(|symbol| symbol["name"].as_str().unwrap().to_string()).collect::<Vec<_>>()
+        };
+        assert_eq!(search(&mut server, 2, "circle"), vec!["Circle", "circle_area"], "files that are not open should be searched");
+
+        std::fs::write(root.join("shapes.rs"), "struct Square {}\n").unwrap();
+        server.handle_message(LspMessage::Notification {
+            method: "workspace/didChangeWatchedFiles".to_string(),
+            params: Some(json!({"changes": [{"uri": shapes_uri, "type": 2}]})),
+        });
+        assert_eq!(search(&mut server, 3, "r"), vec!["Square"]);
+
+        did_open_text(&mut server, shapes_uri.as_str(), "struct Triangle {}");
+        assert_eq!(search(&mut server, 4, "r"), vec!["Triangle"], "the open document takes priority over the file on disk");
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    fn outputs_of(server: &mut Server, message: LspMessage) -> Vec<Value> {
+        server.handle_message(message).iter().map(|output| content(output)).collect()
+    }
+
+    #[test]
+    fn test_settings_are_pulled_from_the_client_and_refreshed() {
+        let mut server = Server::new();
+        server.handle_message(request(
+            json!(1),
+            "initialize",
+            json!({"capabilities": {"workspace": {"configuration": true, "didChangeConfiguration": {"dynamicRegistration": true}}}}),
+        ));
+        let outputs = outputs_of(&mut server, LspMessage::Notification { method: "initialized".to_string(), params: Some(json!({})) });
+        assert_eq!(outputs[0]["method"], "workspace/configuration");
+        assert_eq!(outputs[0]["params"], json!({"items": [{"section": "toyLangServer"}]}));
+        assert_eq!(outputs[1]["params"]["registrations"][0]["method"], "workspace/didChangeConfiguration");
+
+        did_open_text(&mut server, "file:///a.rs", "fn main() {\nlet x = 1;\n// FIXME\n}");
+        let inlay_hints = request(json!(2), "textDocument/inlayHint", json!({"textDocument": {"uri": "file:///a.rs"}, "range": {"start": {"line": 0, "character": 0}, "end": {"line": 3, "character": 0}}}));
+        let hints = outputs_of(&mut server, inlay_hints.clone());
+        assert_ne!(hints[0]["result"], json!([]), "inlay hints are on until the settings arrive");
+
+        let settings = json!([{"diagnostics": {"todoKeywords": ["FIXME"]}, "formatting": {"indentWidth": 2}, "inlayHints": {"enabled": false}}]);
+        let outputs = outputs_of(&mut server, LspMessage::Response { id: outputs[0]["id"].clone(), result: Some(settings), error: None });
+        assert!(outputs.is_empty(), "valid settings are applied silently");
+
+        let deadline = server.next_deadline().expect("open documents should be diagnosed again");
+        let publish = content(&server.handle_timers(deadline)[0]);
+        let messages: Vec<&Value> = publish["params"]["diagnostics"].as_array().unwrap().iter().map(|diagnostic| &diagnostic["message"]).collect();
+        assert!(messages.contains(&&json!("Found a FIXME item.")), "{:?}", messages);
+        assert_eq!(outputs_of(&mut server, inlay_hints)[0]["result"], json!([]));
+
+        let formatting = |server: &mut Server, id: i64| {
+            let params = json!({"textDocument": {"uri": "file:///a.rs"}, "options": {"tabSize": 8, "insertSpaces": true}});
+            outputs_of(server, request(json!(id), "textDocument/formatting", params))[0]["result"][0]["newText"].clone()
+        };
+        assert_eq!(formatting(&mut server, 3), "fn main() {\n  let x = 1;\n  // FIXME\n}", "indentWidth wins over the tab size");
+
+        // 変更の通知を受けたら取りに行き直す。範囲外の値は無視してユーザーに知らせる
+        let outputs = outputs_of(&mut server, LspMessage::Notification { method: "workspace/didChangeConfiguration".to_string(), params: Some(json!({"settings": null})) });
+        assert_eq!(outputs[0]["method"], "workspace/configuration");
+        let settings = json!([{"formatting": {"indentWidth": 40}}]);
+        let outputs = outputs_of(&mut server, LspMessage::Response { id: outputs[0]["id"].clone(), result: Some(settings), error: None });
+        assert_eq!(outputs[0]["method"], "window/showMessage");
+        assert_eq!(outputs[0]["params"]["type"], 2);
+        assert_eq!(outputs[0]["params"]["message"], "Ignored setting 'toyLangServer.formatting.indentWidth': expected a width from 1 to 16, got 40.");
+        assert_eq!(formatting(&mut server, 4), "fn main() {\n        let x = 1;\n        // FIXME\n}", "without indentWidth the tab size is used");
+    }
+
+    #[test]
+    fn test_settings_pushed_by_the_client_are_applied() {
+        let mut server = initialized_server();
+        did_open_text(&mut server, "file:///a.rs", "fn main() {}\nfn helper() {}");
+        server.handle_message(LspMessage::Notification {
+            method: "workspace/didChangeConfiguration".to_string(),
+            params: Some(json!({"settings": {"toyLangServer": {"codeLens": {"kinds": ["main"]}}}})),
+        });
+
+        let outputs = outputs_of(&mut server, request(json!(2), "textDocument/codeLens", json!({"textDocument": {"uri": "file:///a.rs"}})));
+        let titles: Vec<&Value> = outputs[0]["result"].as_array().unwrap().iter().map(|lens| &lens["command"]["title"]).collect();
+        assert_eq!(titles, vec!["Run function"], "only the configured kinds get a lens");
+    }
+
+    #[test]
+    fn test_stale_diagnostics_are_not_published() {
+        let (sender, from_workers) = mpsc::channel();
+        let mut server = Server::with_workers(WorkerPool::new(1), move |output| sender.send(output).unwrap());
+        server.handle_message(request(json!(1), "initialize", json!({"capabilities": {}})));
+        did_open_text(&mut server, "file:///a.rs", "fn main() {\n// TODO: fix\n}");
+        let computed = from_workers.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // ワーカーが診断を計算している間に編集が届いたら、計算し終えた version 1 の診断はもう古い
+        did_change_text(&mut server, "file:///a.rs", 2, line_range(1, 3, 7), "NOTE");
+        assert!(server.handle_worker(computed).is_empty(), "diagnostics of an edited document should not be published");
+
+        let deadline = server.next_deadline().expect("the edit should be diagnosed later");
+        assert!(server.handle_timers(deadline).is_empty(), "the diagnostics are computed by a worker");
+        let published = server.handle_worker(from_workers.recv_timeout(Duration::from_secs(5)).unwrap());
+        let publish = content(&published[0]);
+        assert_eq!(publish["params"]["version"], 2);
+        assert_eq!(publish["params"]["diagnostics"], json!([]));
+    }
+
+    #[test]
+    fn test_read_requests_run_on_workers_and_may_answer_out_of_order() {
+        let (sender, completed) = mpsc::channel();
+        let mut server = Server::with_workers(WorkerPool::new(2), move |messages| sender.send(messages).unwrap());
+        server.handle_message(request(json!(1), "initialize", json!({"capabilities": {}})));
+        did_open_text(&mut server, "file:///a.rs", "fn before() {}");
+        let published = server.handle_worker(completed.recv_timeout(Duration::from_secs(5)).unwrap());
+        assert_eq!(content(&published[0])["method"], "textDocument/publishDiagnostics", "diagnostics are computed by a worker too");
+
+        // 止めておける hover に差し替え、スナップショットで見えたドキュメントの中身を返す
+        let (release, released) = mpsc::channel::<()>();
+        let released = Mutex::new(released);
+        server.router.on_read_request::<HoverRequest, _>(move |snapshot, params| {
+            released.lock().unwrap().recv().unwrap();
+            let text = snapshot.document_store.text(&params.text_document_position_params.text_document.uri).unwrap_or_default();
+            Ok(Some(Hover { contents: HoverContents::Scalar(MarkedString::String(text.to_string())), range: None }))
+        });
+        assert!(server.handle_message(hover_request(2)).is_empty(), "the hover is answered by a worker");
+        assert!(did_change_text(&mut server, "file:///a.rs", 2, line_range(0, 3, 9), "after").is_empty(), "edits are not blocked by the hover");
+        let symbols = request(json!(3), "textDocument/documentSymbol", json!({"textDocument": {"uri": "file:///a.rs"}}));
+        assert!(server.handle_message(symbols).is_empty());
+
+        let symbols = server.handle_worker(completed.recv_timeout(Duration::from_secs(5)).unwrap());
+        let symbols = content(&symbols[0]);
+        assert_eq!(symbols["id"], 3, "the later request finishes first");
+        assert_eq!(symbols["result"][0]["name"], "after", "requests see the edits received before them");
+
+        release.send(()).unwrap();
+        let hover = server.handle_worker(completed.recv_timeout(Duration::from_secs(5)).unwrap());
+        let hover = content(&hover[0]);
+        assert_eq!(hover["id"], 2);
+        assert_eq!(hover["result"]["contents"], "fn before() {}", "the hover works on the snapshot taken when it arrived");
+        assert!(server.in_flight().is_empty());
+    }
+
+    #[test]
+    fn test_panicking_read_request_gets_an_error_and_workers_keep_answering() {
+        let (sender, completed) = mpsc::channel();
+        let mut server = Server::with_workers(WorkerPool::new(1), move |messages| sender.send(messages).unwrap());
+        server.handle_message(request(json!(1), "initialize", json!({"capabilities": {}})));
+        server.router.on_read_request::<HoverRequest, _>(|_, params| {
+            if params.text_document_position_params.position.line == 0 {
+                panic!("hover failed");
+            }
+            Ok(None)
+        });
+
+        server.handle_message(hover_request(2));
+        let failed = server.handle_worker(completed.recv_timeout(Duration::from_secs(5)).unwrap());
+        let failed = content(&failed[0]);
+        assert_eq!(failed["id"], 2);
+        assert_eq!(failed["error"]["code"], -32603);
+        assert!(failed["error"]["message"].as_str().unwrap().contains("hover failed"));
+
+        // 1本しかないワーカーが、続くリクエストにも答える
+        let position = json!({"textDocument": {"uri": "file:///a.rs"}, "position": {"line": 1, "character": 0}});
+        server.handle_message(request(json!(3), "textDocument/hover", position));
+        let answered = server.handle_worker(completed.recv_timeout(Duration::from_secs(5)).unwrap());
+        let answered = content(&answered[0]);
+        assert_eq!((answered["id"].clone(), answered["result"].clone()), (json!(3), Value::Null));
+        assert!(server.in_flight().is_empty());
+    }
+}
@@ -0,0 +1,24 @@
+// サーバーランタイム
+// lesson_1 で作った各機能を、実際にエディタから起動できるLSPサーバーとして動かすための部品
+
+pub mod analysis;
+pub mod cancellation;
+pub mod capabilities;
+pub mod checks;
+pub mod diagnostics;
+pub mod handlers;
+pub mod hover;
+pub mod logging;
+pub mod main_loop;
+pub mod navigation;
+pub mod outgoing;
+pub mod progress;
+pub mod references;
+pub mod rename;
+pub mod replay;
+pub mod router;
+pub mod settings;
+pub mod symbols;
+pub mod transport;
+pub mod workers;
+pub mod workspace;
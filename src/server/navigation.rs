@@ -0,0 +1,127 @@
+// 意味解析（analysis.rs）を使った定義へのジャンプ
+// - textDocument/definition: 名前をスコープに沿って解決し、その名前を定義した場所へ
+// - textDocument/declaration: この言語では let / fn / struct が宣言と定義を兼ねるので、定義と同じ場所へ
+// - textDocument/typeDefinition: 名前・式の型が構造体なら、その struct の定義へ（関数は戻り値の型）
+// クライアントが linkSupport を持っていれば、ジャンプ元の範囲つきの LocationLink で返す
+
+use lsp_types::{GotoDefinitionResponse, Location, LocationLink, Position, PositionEncodingKind, Url};
+
+use crate::common::line_index::LineIndex;
+use crate::common::syntax::TokenKind;
+use crate::server::analysis::{to_range, token_at, Analysis};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Definition,
+    Declaration,
+    TypeDefinition,
+}
+
+pub fn goto(
+    uri: &Url,
+    text: &str,
+    position: Position,
+    encoding: &PositionEncodingKind,
+    target: Target,
+    links: bool,
+) -> Option<GotoDefinitionResponse> {
+    let index = LineIndex::new(text, encoding.clone());
+    let token = token_at(text, &index, position)?;
+    if token.kind == TokenKind::Punct {
+        return None;
+    }
+    let origin = index.range(token.range());
+    let analysis = Analysis::new(text, encoding);
+
+    let resolution = match target {
+        Target::Definition | Target::Declaration => analysis.resolution_at(origin)?,
+        Target::TypeDefinition => analysis.type_definition(origin)?,
+    };
+    // 名前だけを選ぶ（lesson_3_12 の Span は名前のトークンなので、target_range も同じ範囲になる）
+    let target_range = to_range(&resolution.definition_span);
+
+    if links {
+        Some(GotoDefinitionResponse::Link(vec![LocationLink {
+            origin_selection_range: Some(origin),
+            target_uri: uri.clone(),
+            target_range,
+            target_selection_range: target_range,
+        }]))
+    } else {
+        Some(GotoDefinitionResponse::Scalar(Location::new(uri.clone(), target_range)))
+    }
+}
+
+
+// --- Tests --- //
+
+#[cfg(test)]
+mod tests {
+    use super::{goto, Target};
+    use lsp_types::{GotoDefinitionResponse, Location, Position, PositionEncodingKind, Range, Url};
+
+    fn uri() -> Url {
+        Url::parse("file:///main.rs").unwrap()
+    }
+
+    fn range(line: u32, start: u32, end: u32) -> Range {
+        Range::new(Position::new(line, start), Position::new(line, end))
+    }
+
+    fn location(text: &str, line: u32, character: u32, target: Target) -> Option<Range> {
+        match goto(&uri(), text, Position::new(line, character), &PositionEncodingKind::UTF16, target, false)? {
+            GotoDefinitionResponse::Scalar(location) => Some(location.range),
+            other => panic!("expected a single location, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_definition_follows_shadowing() {
+        // lesson_3_5: 後の let が前の x を隠す。ブロックの中の x は外へ出ると見えなくなる
+        let text = "fn main() {\n    let x = 1;\n    let x = x + 1;\n    {\n        let x = true;\n        x;\n    }\n    x;\n}";
+
+        assert_eq!(location(text, 2, 12, Target::Definition), Some(range(1, 8, 9)));
+        assert_eq!(location(text, 5, 8, Target::Definition), Some(range(4, 12, 13)));
+        assert_eq!(location(text, 7, 4, Target::Definition), Some(range(2, 8, 9)));
+    }
+
+    #[test]
+    fn test_definition_stays_inside_the_function_scope() {
+        // lesson_3_8: 同じ名前の引数でも、関数ごとに別のもの
+        let text = "fn double(n: i32) -> i32 {\n    n + n\n}\n\nfn square(n: i32) -> i32 {\n    n * n\n}\n\nfn main() {\n    square(double(2));\n}";
+
+        assert_eq!(location(text, 1, 4, Target::Definition), Some(range(0, 10, 11)));
+        assert_eq!(location(text, 5, 8, Target::Definition), Some(range(4, 10, 11)));
+        assert_eq!(location(text, 9, 12, Target::Definition), Some(range(0, 3, 9)));
+        assert_eq!(location(text, 9, 12, Target::Declaration), location(text, 9, 12, Target::Definition));
+        assert_eq!(location(text, 8, 1, Target::Definition), None, "keywords and punctuation have no definition");
+    }
+
+    #[test]
+    fn test_type_definition_jumps_to_the_struct() {
+        let text = "struct Point {\n    x: i32,\n}\n\nfn origin() -> Point {\n    Point { x: 0 }\n}\n\nfn main() {\n    let p = origin();\n    p.x;\n}";
+
+        assert_eq!(location(text, 10, 4, Target::TypeDefinition), Some(range(0, 7, 12)));
+        assert_eq!(location(text, 9, 13, Target::TypeDefinition), Some(range(0, 7, 12)), "a function's return type");
+        assert_eq!(location(text, 10, 6, Target::TypeDefinition), None, "x is an i32");
+    }
+
+    #[test]
+    fn test_links_carry_the_origin_range() {
+        let text = "fn main() {\n    let total = 1;\n    total;\n}";
+        let position = Position::new(2, 6);
+
+        let link = goto(&uri(), text, position, &PositionEncodingKind::UTF16, Target::Definition, true);
+        match link {
+            Some(GotoDefinitionResponse::Link(links)) => {
+                assert_eq!(links.len(), 1);
+                assert_eq!(links[0].origin_selection_range, Some(range(2, 4, 9)));
+                assert_eq!((links[0].target_uri.clone(), links[0].target_selection_range), (uri(), range(1, 8, 13)));
+            }
+            other => panic!("expected links, got {:?}", other),
+        }
+
+        let scalar = goto(&uri(), text, position, &PositionEncodingKind::UTF16, Target::Definition, false);
+        assert_eq!(scalar, Some(GotoDefinitionResponse::Scalar(Location::new(uri(), range(1, 8, 13)))));
+    }
+}
@@ -0,0 +1,388 @@
+// サーバー → クライアント方向のメッセージ
+// workspace/applyEdit や client/registerCapability のように、サーバーからクライアントへリクエストを送る場合は
+// - id を振る
+// - 応答が来たときに呼ぶコールバックを id ごとに覚えておく
+// - 応答（LspMessage::Response）の id からコールバックを探して呼ぶ
+// - いつまでも応答が無いものはタイムアウトさせる
+// ハンドラはここに送りたいメッセージを積み、メインループがまとめて書き出す。
+//
+// ワーカースレッドで動く読み取り専用のハンドラには fork() したものを渡す。
+// fork() したものは積まずに、送るたびにメッセージを sink へ渡す（$/progress を処理の途中で届けるため）。
+// id はメインループのものと同じ番号の並びから振るので重ならない。応答はメインループに届くので、
+// fork() したものから送ったリクエストは、メッセージを書き出す前に forward() でメインループの Outgoing に引き取る。
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use lsp_types::notification::{Cancel, Notification};
+use lsp_types::request::Request;
+use lsp_types::{CancelParams, NumberOrString};
+use serde_json::{json, Value};
+
+use crate::lessons::lesson_1::lesson_1_9::ResponseError;
+use crate::lessons::lesson_1::lesson_1_11::ErrorCode;
+use crate::server::cancellation::RequestId;
+
+// 応答を待つ時間のデフォルト
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+type ResponseCallback<S> = Box<dyn FnOnce(&mut S, Result<Value, ResponseError>) + Send>;
+
+// 応答が来たら呼ぶ処理（状態 S を受け取る）
+pub type PendingCallback<S> = Box<dyn FnOnce(&mut S)>;
+
+struct PendingRequest<S> {
+    id: NumberOrString,
+    method: &'static str,
+    deadline: Instant,
+    callback: ResponseCallback<S>,
+}
+
+// fork() したものが送ったメッセージ（Content-Length 付き）
+// リクエストなら、応答を待つためのコールバックも一緒に持っている
+pub struct Forwarded<S = ()> {
+    message: String,
+    pending: Option<PendingRequest<S>>,
+}
+
+impl Forwarded {
+    // 応答を待たずに書き出すだけのメッセージ（ワーカーが返すリクエストの応答など）
+    pub fn message(message: String) -> Self {
+        Forwarded { message, pending: None }
+    }
+}
+
+// fork() したものが、メッセージを送るたびに呼ぶ
+pub type Sink = Arc<dyn Fn(Forwarded) + Send + Sync>;
+
+pub struct Outgoing<S> {
+    // 最後に振った id（fork() したものと共有する）
+    last_id: Arc<AtomicI32>,
+    timeout: Duration,
+    pending: HashMap<RequestId, PendingRequest<S>>,
+    // まだ書き出していないメッセージ（Content-Length 付き）
+    queue: Vec<String>,
+    // fork() したものは、積まずにここへ渡す
+    sink: Option<Arc<dyn Fn(Forwarded<S>) + Send + Sync>>,
+}
+
+impl<S: 'static> Outgoing<S> {
+    pub fn new() -> Self {
+        Self::with_timeout(DEFAULT_REQUEST_TIMEOUT)
+    }
+
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Outgoing {
+            last_id: Arc::new(AtomicI32::new(0)),
+            timeout,
+            pending: HashMap::new(),
+            queue: Vec::new(),
+            sink: None,
+        }
+    }
+
+    // リクエスト R をクライアントへ送る
+    // 応答（またはエラー、タイムアウト）が来たら callback が呼ばれる。戻り値は振った id
+    pub fn send_request<R, F>(&mut self, params: R::Params, callback: F) -> NumberOrString
+    where
+        R: Request,
+        F: FnOnce(&mut S, Result<R::Result, ResponseError>) + Send + 'static,
+    {
+        let id = NumberOrString::Number(self.last_id.fetch_add(1, Ordering::SeqCst) + 1);
+
+        let pending = PendingRequest {
+            id: id.clone(),
+            method: R::METHOD,
+            deadline: Instant::now() + self.timeout,
+            callback: Box::new(move |state, result| {
+                let result = result.and_then(|value| {
+                    serde_json::from_value::<R::Result>(value).map_err(|err| {
+                        ResponseError::new(ErrorCode::InvalidParams, format!("Invalid response to {}: {}", R::METHOD, err))
+                    })
+                });
+                callback(state, result)
+            }),
+        };
+        let message = frame(&json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": R::METHOD,
+            "params": params,
+        }));
+        self.send(Forwarded { message, pending: Some(pending) });
+        id
+    }
+
+    // 通知 N をクライアントへ送る
+    pub fn send_notification<N: Notification>(&mut self, params: N::Params) {
+        let message = frame(&json!({
+            "jsonrpc": "2.0",
+            "method": N::METHOD,
+            "params": params,
+        }));
+        self.send(Forwarded { message, pending: None });
+    }
+
+    fn send(&mut self, forwarded: Forwarded<S>) {
+        if let Some(sink) = &self.sink {
+            return sink(forwarded);
+        }
+        if let Some(pending) = forwarded.pending {
+            self.pending.insert(RequestId::from(&pending.id), pending);
+        }
+        self.queue.push(forwarded.message);
+    }
+
+    // 書き出し待ちのメッセージを取り出す
+    pub fn take_messages(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.queue)
+    }
+
+    // クライアントからの応答を、送ったリクエストと突き合わせる
+    // 知らない id（タイムアウト済みなど）の応答なら None
+    pub fn complete(&mut self, id: &Value, result: Option<Value>, error: Option<ResponseError>) -> Option<PendingCallback<S>> {
+        let pending = self.pending.remove(&RequestId::from(id))?;
+        let result = match error {
+            Some(error) => Err(error),
+            None => Ok(result.unwrap_or(Value::Null)),
+        };
+        Some(Box::new(move |state| (pending.callback)(state, result)))
+    }
+
+    // 一番近いタイムアウトの時刻（待っているリクエストが無ければ None）
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|pending| pending.deadline).min()
+    }
+
+    // now の時点でタイムアウトしたリクエストを諦める
+    // クライアントには $/cancelRequest を送り、コールバックにはエラーを渡す
+    pub fn expire(&mut self, now: Instant) -> Vec<PendingCallback<S>> {
+        let expired: Vec<RequestId> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.deadline <= now)
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        let mut callbacks: Vec<PendingCallback<S>> = Vec::new();
+        for id in expired {
+            let Some(pending) = self.pending.remove(&id) else {
+                continue;
+            };
+            self.send_notification::<Cancel>(CancelParams { id: pending.id.clone() });
+            let error = ResponseError::new(
+                ErrorCode::RequestCancelled,
+                format!("Request {} timed out after {:?}.", pending.method, self.timeout),
+            );
+            callbacks.push(Box::new(move |state| (pending.callback)(state, Err(error))));
+        }
+        callbacks
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
+
+    // 別のスレッドで使う、送ったものをすぐ sink へ渡す Outgoing を作る（id の並びとタイムアウトは共有する）
+    // 状態はメインループにしか無いので、コールバックは () を受け取る
+    pub fn fork(&self, sink: Sink) -> Outgoing<()> {
+        Outgoing {
+            last_id: self.last_id.clone(),
+            timeout: self.timeout,
+            pending: HashMap::new(),
+            queue: Vec::new(),
+            sink: Some(sink),
+        }
+    }
+
+    // fork() したものが送ったメッセージを受け取り、書き出すメッセージを返す
+    // リクエストなら、応答（とタイムアウト）をこちらで待つ
+    pub fn forward(&mut self, forwarded: Forwarded) -> String {
+        if let Some(PendingRequest { id, method, deadline, callback }) = forwarded.pending {
+            self.pending.insert(
+                RequestId::from(&id),
+                PendingRequest { id, method, deadline, callback: Box::new(move |_, result| callback(&mut (), result)) },
+            );
+        }
+        forwarded.message
+    }
+}
+
+impl<S: 'static> Default for Outgoing<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn frame(content: &Value) -> String {
+    let content = content.to_string();
+    format!("Content-Length: {}\r\n\r\n{}", content.len(), content)
+}
+
+
+// --- Tests --- //
+
+#[cfg(test)]
+mod tests {
+    use super::Outgoing;
+    use crate::lessons::lesson_1::lesson_1_9::ResponseError;
+    use crate::lessons::lesson_1::lesson_1_11::ErrorCode;
+    use lsp_types::notification::ShowMessage;
+    use lsp_types::request::{ApplyWorkspaceEdit, ShowMessageRequest};
+    use lsp_types::{
+        ApplyWorkspaceEditParams, MessageType, NumberOrString, ShowMessageParams, ShowMessageRequestParams, WorkspaceEdit,
+    };
+    use serde_json::{json, Value};
+    use std::sync::{mpsc, Arc};
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct State {
+        applied: Vec<Result<bool, i32>>,
+    }
+
+    fn content(message: &str) -> Value {
+        serde_json::from_str(message.split_once("\r\n\r\n").unwrap().1).unwrap()
+    }
+
+    fn apply_edit(outgoing: &mut Outgoing<State>) -> NumberOrString {
+        let params = ApplyWorkspaceEditParams { label: Some("rename".to_string()), edit: WorkspaceEdit::default() };
+        outgoing.send_request::<ApplyWorkspaceEdit, _>(params, |state, result| {
+            state.applied.push(result.map(|response| response.applied).map_err(|err| err.code));
+        })
+    }
+
+    #[test]
+    fn test_send_request_assigns_increasing_ids() {
+        let mut outgoing = Outgoing::new();
+        let first = apply_edit(&mut outgoing);
+        let second = apply_edit(&mut outgoing);
+
+        assert_ne!(first, second);
+        let messages = outgoing.take_messages();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(content(&messages[0])["method"], "workspace/applyEdit");
+        assert_eq!(content(&messages[0])["id"], 1);
+        assert_eq!(content(&messages[1])["id"], 2);
+        assert!(outgoing.take_messages().is_empty(), "messages should be taken only once");
+        assert_eq!(outgoing.pending_count(), 2);
+    }
+
+    #[test]
+    fn test_response_is_matched_to_callback() {
+        let mut outgoing = Outgoing::new();
+        let mut state = State::default();
+        apply_edit(&mut outgoing);
+        apply_edit(&mut outgoing);
+
+        // 2番目のリクエストへの応答が先に来ても、正しいコールバックが呼ばれる
+        let callback = outgoing.complete(&json!(2), Some(json!({"applied": false})), None).expect("id 2 is pending");
+        callback(&mut state);
+        let callback = outgoing.complete(&json!(1), Some(json!({"applied": true})), None).expect("id 1 is pending");
+        callback(&mut state);
+
+        assert_eq!(state.applied, vec![Ok(false), Ok(true)]);
+        assert_eq!(outgoing.pending_count(), 0);
+    }
+
+    #[test]
+    fn test_error_response_is_passed_to_callback() {
+        let mut outgoing = Outgoing::new();
+        let mut state = State::default();
+        apply_edit(&mut outgoing);
+
+        let error = ResponseError::new(ErrorCode::RequestFailed, "edit rejected");
+        outgoing.complete(&json!(1), None, Some(error)).unwrap()(&mut state);
+
+        assert_eq!(state.applied, vec![Err(-32803)]);
+    }
+
+    #[test]
+    fn test_unknown_or_duplicate_response_is_ignored() {
+        let mut outgoing: Outgoing<State> = Outgoing::new();
+        apply_edit(&mut outgoing);
+
+        assert!(outgoing.complete(&json!(42), Some(json!({"applied": true})), None).is_none());
+        assert!(outgoing.complete(&json!("1"), Some(json!({"applied": true})), None).is_none(), "\"1\" is not the id 1");
+        assert!(outgoing.complete(&json!(1), Some(json!({"applied": true})), None).is_some());
+        assert!(outgoing.complete(&json!(1), Some(json!({"applied": true})), None).is_none(), "a response is handled only once");
+    }
+
+    #[test]
+    fn test_malformed_result_becomes_error() {
+        let mut outgoing = Outgoing::new();
+        let mut state = State::default();
+        apply_edit(&mut outgoing);
+
+        outgoing.complete(&json!(1), Some(json!("yes")), None).unwrap()(&mut state);
+
+        assert_eq!(state.applied, vec![Err(-32602)]);
+    }
+
+    #[test]
+    fn test_unanswered_requests_time_out() {
+        let mut outgoing = Outgoing::with_timeout(Duration::from_secs(5));
+        let mut state = State::default();
+        apply_edit(&mut outgoing);
+        outgoing.take_messages();
+
+        let deadline = outgoing.next_deadline().expect("a request is pending");
+        assert!(outgoing.expire(deadline - Duration::from_secs(1)).is_empty(), "not timed out yet");
+
+        let callbacks = outgoing.expire(deadline);
+        assert_eq!(callbacks.len(), 1);
+        for callback in callbacks {
+            callback(&mut state);
+        }
+
+        assert_eq!(state.applied, vec![Err(-32800)]);
+        assert!(outgoing.next_deadline().is_none());
+        let cancel = outgoing.take_messages();
+        assert_eq!(content(&cancel[0])["method"], "$/cancelRequest", "the client should be told to drop the request");
+        assert_eq!(content(&cancel[0])["params"]["id"], 1);
+        assert!(outgoing.complete(&json!(1), Some(json!({"applied": true})), None).is_none(), "late responses are ignored");
+    }
+
+    #[test]
+    fn test_show_message_request_and_notification() {
+        let mut outgoing: Outgoing<Option<String>> = Outgoing::new();
+        let mut chosen = None;
+        outgoing.send_request::<ShowMessageRequest, _>(
+            ShowMessageRequestParams { typ: MessageType::INFO, message: "Apply?".to_string(), actions: None },
+            |chosen, result| *chosen = result.ok().flatten().map(|item| item.title),
+        );
+        outgoing.send_notification::<ShowMessage>(ShowMessageParams { typ: MessageType::INFO, message: "hi".to_string() });
+
+        let messages = outgoing.take_messages();
+        assert_eq!(content(&messages[1])["method"], "window/showMessage");
+        assert!(content(&messages[1]).get("id").is_none(), "notifications have no id");
+
+        outgoing.complete(&json!(1), Some(json!({"title": "Yes"})), None).unwrap()(&mut chosen);
+        assert_eq!(chosen, Some("Yes".to_string()));
+    }
+
+    #[test]
+    fn test_forked_outgoing_shares_the_ids() {
+        let mut outgoing: Outgoing<State> = Outgoing::new();
+        apply_edit(&mut outgoing);
+
+        let (sink, forwarded) = mpsc::channel();
+        let mut forked = outgoing.fork(Arc::new(move |message| sink.send(message).unwrap()));
+        let (sender, applied) = mpsc::channel();
+        let params = ApplyWorkspaceEditParams { label: None, edit: WorkspaceEdit::default() };
+        let id = forked.send_request::<ApplyWorkspaceEdit, _>(params, move |_, result| sender.send(result.map(|response| response.applied)).unwrap());
+        assert_eq!(id, NumberOrString::Number(2), "ids must not collide with the main loop's requests");
+        assert_eq!(apply_edit(&mut outgoing), NumberOrString::Number(3));
+        assert!(forked.take_messages().is_empty(), "forked messages go to the sink as soon as they are sent");
+        assert_eq!(outgoing.take_messages().len(), 2);
+
+        // 応答はメインループに届くので、fork したもので送ったリクエストも引き取って待つ
+        let message = outgoing.forward(forwarded.try_recv().unwrap());
+        assert_eq!(content(&message)["id"], 2);
+        assert_eq!(outgoing.pending_count(), 3);
+        outgoing.complete(&json!(2), Some(json!({"applied": true})), None).expect("id 2 is forwarded")(&mut State::default());
+        assert_eq!(applied.try_recv().unwrap().ok(), Some(true));
+    }
+}
@@ -0,0 +1,199 @@
+// 作業の進捗 ($/progress)
+// 時間のかかるリクエストでは、クライアントに進み具合を知らせる
+// - workDoneToken: リクエストに付いていればそれを使う。無ければサーバーが window/workDoneProgress/create で作る
+// - begin → report（何回でも）→ end の順に $/progress を送る
+// - partialResultToken: 結果を分けて $/progress で送る。その場合、最後の応答には結果を入れない
+
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Arc;
+
+use lsp_types::notification::{Notification, Progress};
+use lsp_types::request::WorkDoneProgressCreate;
+use lsp_types::{
+    NumberOrString, ProgressParams, ProgressParamsValue, ProgressToken, WorkDoneProgressBegin,
+    WorkDoneProgressCreateParams, WorkDoneProgressEnd, WorkDoneProgressReport,
+};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::server::outgoing::Outgoing;
+
+// サーバーが作る進捗トークン
+// ワーカースレッドでも作るので、clone したもの同士で番号の並びを共有する
+#[derive(Debug, Clone, Default)]
+pub struct ProgressTokens {
+    last: Arc<AtomicI32>,
+}
+
+impl ProgressTokens {
+    // 新しいトークンを作り、クライアントに window/workDoneProgress/create で知らせる
+    // クライアントはメッセージを順番に処理するので、応答を待たずに $/progress を送り始めてよい
+    pub fn create<S: 'static>(&self, outgoing: &mut Outgoing<S>) -> ProgressToken {
+        let next = self.last.fetch_add(1, Ordering::SeqCst) + 1;
+        let token = NumberOrString::String(format!("toy-lang-server/progress/{}", next));
+        // 作れなかった（エラー応答）場合も、$/progress はクライアントに無視されるだけなので何もしない
+        outgoing.send_request::<WorkDoneProgressCreate, _>(WorkDoneProgressCreateParams { token: token.clone() }, |_, _| {});
+        token
+    }
+}
+
+// begin を送った進捗表示
+// 最後に end を送る必要があるので、使い終わったら必ず end() を呼ぶ
+#[derive(Debug)]
+pub struct WorkDoneProgress {
+    token: ProgressToken,
+    percentage: Option<u32>,
+}
+
+impl WorkDoneProgress {
+    pub fn begin<S: 'static>(outgoing: &mut Outgoing<S>, token: ProgressToken, title: impl Into<String>, cancellable: bool) -> Self {
+        let begin = WorkDoneProgressBegin {
+            title: title.into(),
+            cancellable: Some(cancellable),
+            message: None,
+            percentage: Some(0),
+        };
+        send_progress(outgoing, &token, lsp_types::WorkDoneProgress::Begin(begin));
+        WorkDoneProgress { token, percentage: Some(0) }
+    }
+
+    pub fn token(&self) -> &ProgressToken {
+        &self.token
+    }
+
+    // done / total の割合を送る（割合が変わらないときは送らない）
+    pub fn report<S: 'static>(&mut self, outgoing: &mut Outgoing<S>, done: usize, total: usize, message: Option<String>) {
+        let percentage = percentage(done, total);
+        if self.percentage == Some(percentage) && message.is_none() {
+            return;
+        }
+        self.percentage = Some(percentage);
+
+        let report = WorkDoneProgressReport {
+            cancellable: None,
+            message,
+            percentage: Some(percentage),
+        };
+        send_progress(outgoing, &self.token, lsp_types::WorkDoneProgress::Report(report));
+    }
+
+    pub fn end<S: 'static>(self, outgoing: &mut Outgoing<S>, message: Option<String>) {
+        send_progress(outgoing, &self.token, lsp_types::WorkDoneProgress::End(WorkDoneProgressEnd { message }));
+    }
+}
+
+// 0〜100 の割合（total が 0 なら終わっているものとして 100）
+pub fn percentage(done: usize, total: usize) -> u32 {
+    if total == 0 {
+        return 100;
+    }
+    (done.min(total) * 100 / total) as u32
+}
+
+fn send_progress<S: 'static>(outgoing: &mut Outgoing<S>, token: &ProgressToken, progress: lsp_types::WorkDoneProgress) {
+    outgoing.send_notification::<Progress>(ProgressParams {
+        token: token.clone(),
+        value: ProgressParamsValue::WorkDone(progress),
+    });
+}
+
+// 部分的な結果を送る $/progress
+// lsp_types の Progress は workDone の値しか持てないので、結果の型 T ごとに通知の型を用意する
+pub struct PartialResult<T>(PhantomData<T>);
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct PartialResultParams<T> {
+    pub token: ProgressToken,
+    pub value: T,
+}
+
+impl<T> Notification for PartialResult<T>
+where
+    T: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    type Params = PartialResultParams<T>;
+    const METHOD: &'static str = Progress::METHOD;
+}
+
+// partialResultToken に結果の一部を送る
+pub fn send_partial_result<S, T>(outgoing: &mut Outgoing<S>, token: &ProgressToken, value: T)
+where
+    S: 'static,
+    T: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    outgoing.send_notification::<PartialResult<T>>(PartialResultParams { token: token.clone(), value });
+}
+
+
+// --- Tests --- //
+
+#[cfg(test)]
+mod tests {
+    use super::{percentage, send_partial_result, ProgressTokens, WorkDoneProgress};
+    use crate::server::outgoing::Outgoing;
+    use lsp_types::NumberOrString;
+    use serde_json::{json, Value};
+
+    fn contents(outgoing: &mut Outgoing<()>) -> Vec<Value> {
+        outgoing
+            .take_messages()
+            .iter()
+            .map(|message| serde_json::from_str(message.split_once("\r\n\r\n").unwrap().1).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_begin_report_end() {
+        let mut outgoing = Outgoing::new();
+        let token = NumberOrString::String("client-token".to_string());
+
+        let mut progress = WorkDoneProgress::begin(&mut outgoing, token, "Searching", true);
+        progress.report(&mut outgoing, 1, 4, None);
+        progress.report(&mut outgoing, 1, 4, None); // 割合が変わらないので送らない
+        progress.report(&mut outgoing, 2, 4, Some("src/main.rs".to_string()));
+        progress.end(&mut outgoing, None);
+
+        let messages = contents(&mut outgoing);
+        let values: Vec<Value> = messages.iter().map(|message| message["params"]["value"].clone()).collect();
+        assert!(messages.iter().all(|message| message["method"] == "$/progress"));
+        assert!(messages.iter().all(|message| message["params"]["token"] == "client-token"));
+        assert_eq!(values.len(), 4, "the repeated report should be skipped");
+        assert_eq!(values[0], json!({"kind": "begin", "title": "Searching", "cancellable": true, "percentage": 0}));
+        assert_eq!(values[1], json!({"kind": "report", "percentage": 25}));
+        assert_eq!(values[2], json!({"kind": "report", "message": "src/main.rs", "percentage": 50}));
+        assert_eq!(values[3], json!({"kind": "end"}));
+    }
+
+    #[test]
+    fn test_server_created_tokens() {
+        let mut outgoing = Outgoing::new();
+        let tokens = ProgressTokens::default();
+
+        let first = tokens.create(&mut outgoing);
+        let second = tokens.create(&mut outgoing);
+
+        assert_ne!(first, second, "each progress needs its own token");
+        let messages = contents(&mut outgoing);
+        assert_eq!(messages[0]["method"], "window/workDoneProgress/create");
+        assert_eq!(messages[0]["params"]["token"], json!(first));
+        assert_eq!(outgoing.pending_count(), 2, "the create requests wait for the client's response");
+    }
+
+    #[test]
+    fn test_partial_result() {
+        let mut outgoing = Outgoing::new();
+        send_partial_result(&mut outgoing, &NumberOrString::Number(7), vec!["a", "b"].into_iter().map(String::from).collect::<Vec<String>>());
+
+        let messages = contents(&mut outgoing);
+        assert_eq!(messages[0], json!({"jsonrpc": "2.0", "method": "$/progress", "params": {"token": 7, "value": ["a", "b"]}}));
+    }
+
+    #[test]
+    fn test_percentage() {
+        assert_eq!(percentage(0, 3), 0);
+        assert_eq!(percentage(1, 3), 33);
+        assert_eq!(percentage(3, 3), 100);
+        assert_eq!(percentage(0, 0), 100, "nothing to do means done");
+    }
+}
@@ -0,0 +1,181 @@
+// 意味解析（analysis.rs）を使った参照の検索
+// - 名前をスコープに沿って解決し、同じ定義に解決された出現だけを集める
+//   （文字列やコメントの中、たまたま同じ名前の別の変数は含めない）
+// - 関数と構造体は別のファイルからも使える。`mod name;` / `use crate::name::...` で定義のあるモジュールを参照している
+//   ドキュメントでは、そのドキュメントの中で解決できない同じ名前を、その定義への参照とみなす
+// - includeDeclaration が false なら、定義している名前そのものは含めない
+
+use lsp_types::{Location, Position, PositionEncodingKind, Range, Url};
+
+use crate::common::document_store::DocumentStore;
+use crate::common::line_index::LineIndex;
+use crate::common::syntax::{tokenize, TokenKind};
+use crate::lessons::lesson_3::lesson_3_12::{Resolution, SymbolKind};
+use crate::server::analysis::{to_range, token_at, Analysis};
+use crate::server::checks::module_dependencies;
+use crate::server::diagnostics::{module_name, related_documents};
+
+// 参照を探す定義（定義のあるドキュメントと、定義している名前の範囲）
+#[derive(Debug, Clone, PartialEq)]
+pub struct Definition {
+    pub uri: Url,
+    pub name: String,
+    pub kind: SymbolKind,
+    pub range: Range,
+}
+
+// uri の position にある名前の定義
+// そのドキュメントの中で解決できなければ、参照しているモジュールのファイルの関数・構造体から探す
+pub fn definition_at(documents: &DocumentStore, uri: &Url, position: Position, encoding: &PositionEncodingKind) -> Option<Definition> {
+    let text = documents.text(uri)?;
+    let index = LineIndex::new(text, encoding.clone());
+    let token = token_at(text, &index, position)?;
+    if token.kind != TokenKind::Ident {
+        return None;
+    }
+
+    let range = index.range(token.range());
+    if let Some(resolution) = Analysis::new(text, encoding).resolution_at(range) {
+        return Some(definition(uri, resolution));
+    }
+    related_documents(documents, uri).into_iter().find_map(|module| {
+        let analysis = Analysis::new(documents.text(&module)?, encoding);
+        let item = analysis.resolutions().iter().find(|resolution| {
+            is_item(resolution.kind) && resolution.name == token.text && resolution.span == resolution.definition_span
+        })?;
+        Some(definition(&module, item))
+    })
+}
+
+// uri のドキュメント（中身は text）の中の、definition への参照（位置の順）
+pub fn references_in_document(
+    uri: &Url,
+    text: &str,
+    definition: &Definition,
+    encoding: &PositionEncodingKind,
+    include_declaration: bool,
+) -> Vec<Location> {
+    let analysis = Analysis::new(text, encoding);
+    let mut ranges: Vec<Range> = if *uri == definition.uri {
+        analysis
+            .resolutions()
+            .iter()
+            .filter(|resolution| resolution.name == definition.name && to_range(&resolution.definition_span) == definition.range)
+            .map(|resolution| to_range(&resolution.span))
+            .filter(|range| include_declaration || *range != definition.range)
+            .collect()
+    } else if is_visible_from(definition, text) {
+        unresolved_uses(text, &analysis, &definition.name, encoding)
+    } else {
+        Vec::new()
+    };
+    ranges.sort_by_key(|range| range.start);
+    ranges.into_iter().map(|range| Location::new(uri.clone(), range)).collect()
+}
+
+fn definition(uri: &Url, resolution: &Resolution) -> Definition {
+    Definition {
+        uri: uri.clone(),
+        name: resolution.name.clone(),
+        kind: resolution.kind,
+        range: to_range(&resolution.definition_span),
+    }
+}
+
+// 別のファイルから使えるもの
+fn is_item(kind: SymbolKind) -> bool {
+    matches!(kind, SymbolKind::Function | SymbolKind::Struct)
+}
+
+// text が definition のあるモジュールを参照しているか
+fn is_visible_from(definition: &Definition, text: &str) -> bool {
+    is_item(definition.kind)
+        && module_name(&definition.uri).is_some_and(|module| module_dependencies(text).iter().any(|name| name == module))
+}
+
+// text の中で解決できない name の出現（`.name` はフィールド・メソッドなので除く）
+fn unresolved_uses(text: &str, analysis: &Analysis, name: &str, encoding: &PositionEncodingKind) -> Vec<Range> {
+    let index = LineIndex::new(text, encoding.clone());
+    let tokens = tokenize(text);
+    tokens
+        .iter()
+        .enumerate()
+        .filter(|(i, token)| token.is_ident() && token.text == name && !(*i > 0 && tokens[i - 1].is_punct(".")))
+        .map(|(_, token)| index.range(token.range()))
+        .filter(|range| analysis.resolution_at(*range).is_none())
+        .collect()
+}
+
+
+// --- Tests --- //
+
+#[cfg(test)]
+mod tests {
+    use super::{definition_at, references_in_document};
+    use crate::common::document_store::DocumentStore;
+    use lsp_types::{Position, PositionEncodingKind, Range, Url};
+
+    fn url(path: &str) -> Url {
+        Url::parse(&format!("file:///src/{}", path)).unwrap()
+    }
+
+    fn store(files: &[(&str, &str)]) -> DocumentStore {
+        let mut store = DocumentStore::new();
+        for (path, text) in files {
+            store.open(url(path), "rust", 1, text);
+        }
+        store
+    }
+
+    fn range(line: u32, start: u32, end: u32) -> Range {
+        Range::new(Position::new(line, start), Position::new(line, end))
+    }
+
+    // すべてのドキュメントから集めた参照（ファイル名と範囲）
+    fn references(store: &DocumentStore, path: &str, line: u32, character: u32, include_declaration: bool) -> Vec<(String, Range)> {
+        let encoding = PositionEncodingKind::UTF16;
+        let Some(definition) = definition_at(store, &url(path), Position::new(line, character), &encoding) else {
+            return Vec::new();
+        };
+        let mut found: Vec<(String, Range)> = store
+            .iter()
+            .flat_map(|(uri, document)| references_in_document(uri, document.text(), &definition, &encoding, include_declaration))
+            .map(|location| (location.uri.path().trim_start_matches("/src/").to_string(), location.range))
+            .collect();
+        found.sort_by_key(|(path, range)| (path.clone(), range.start));
+        found
+    }
+
+    #[test]
+    fn test_only_uses_of_the_same_binding() {
+        let text = "fn main() {\n    let count = 1;\n    // count\n    let label = \"count\";\n    let total = count + 1;\n    {\n        let count = true;\n        count;\n    }\n}";
+        let store = store(&[("main.rs", text)]);
+
+        assert_eq!(
+            references(&store, "main.rs", 4, 17, true),
+            vec![("main.rs".to_string(), range(1, 8, 13)), ("main.rs".to_string(), range(4, 16, 21))]
+        );
+        assert_eq!(references(&store, "main.rs", 1, 8, false), vec![("main.rs".to_string(), range(4, 16, 21))]);
+        assert_eq!(references(&store, "main.rs", 7, 8, false), vec![("main.rs".to_string(), range(7, 8, 13))], "the inner count");
+    }
+
+    #[test]
+    fn test_items_are_found_in_modules_that_use_them() {
+        let math = "fn add(a: i32, b: i32) -> i32 {\n    a + b\n}";
+        let main = "mod math;\nuse crate::math::add;\n\nfn main() {\n    add(1, add(2, 3));\n}";
+        // math を参照していないので、この add は別のもの
+        let other = "fn main() {\n    add(1, 2);\n}";
+        let store = store(&[("math.rs", math), ("main.rs", main), ("other.rs", other)]);
+
+        let expected = vec![
+            ("main.rs".to_string(), range(1, 17, 20)),
+            ("main.rs".to_string(), range(4, 4, 7)),
+            ("main.rs".to_string(), range(4, 11, 14)),
+            ("math.rs".to_string(), range(0, 3, 6)),
+        ];
+        assert_eq!(references(&store, "math.rs", 0, 4, true), expected);
+        assert_eq!(references(&store, "main.rs", 4, 5, true), expected, "from a use in another file");
+        assert_eq!(references(&store, "main.rs", 4, 5, false).len(), 3);
+        assert!(references(&store, "other.rs", 1, 5, true).is_empty());
+    }
+}
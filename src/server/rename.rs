@@ -0,0 +1,440 @@
+// 意味解析（analysis.rs）と参照の検索（references.rs）を使ったリネーム
+// - textDocument/prepareRename: カーソルの名前の範囲と今の名前を返す
+//   キーワード・リテラル・組み込みの型・定義の分からない名前はリネームできないので、理由を付けて断る
+// - textDocument/rename: 新しい名前が識別子として正しいか（キーワードでないか）を確かめ、
+//   書き換えるファイルごとに lesson_4_5 の規則（同じスコープに同じ名前があれば衝突）で調べてから、すべてのファイルの参照を書き換える
+//   （ほかのファイルでは、`use` で取り込んだ名前も同じスコープの名前として調べる）
+// - クライアントが対応していれば、編集に ChangeAnnotation を付ける（ほかのファイルの編集は確かめてから当ててもらう）
+
+use std::collections::HashMap;
+
+use lsp_types::{
+    AnnotatedTextEdit, ChangeAnnotation, DocumentChanges, OneOf, OptionalVersionedTextDocumentIdentifier, Position,
+    PositionEncodingKind, PrepareRenameResponse, Range, TextDocumentEdit, TextEdit, Url, WorkspaceEdit,
+};
+
+use crate::common::document_store::DocumentStore;
+use crate::common::line_index::LineIndex;
+use crate::common::syntax::{is_keyword, token_trees, tokenize, Token, TokenKind, TokenTree};
+use crate::lessons::lesson_1::lesson_1_9::ResponseError;
+use crate::lessons::lesson_1::lesson_1_11::ErrorCode;
+use crate::lessons::lesson_4::common::span::{Position as ScopedPosition, Span};
+use crate::lessons::lesson_4::lesson_4_5::{ScopedExpr, ScopedProgram, ScopedStmt, VariableRenamer};
+use crate::server::analysis::token_at;
+use crate::server::references::{definition_at, references_in_document, Definition};
+
+const BUILTIN_TYPES: [&str; 18] = [
+    "i8", "i16", "i32", "i64", "i128", "isize", "u8", "u16", "u32", "u64", "u128", "usize", "f32", "f64", "bool", "char", "str",
+    "String",
+];
+
+// リネームの中身（ファイルごとの編集は URI の順、ファイルの中は位置の順）
+#[derive(Debug, Clone, PartialEq)]
+pub struct Renaming {
+    pub from: String,
+    pub to: String,
+    pub edits: Vec<(Url, Vec<TextEdit>)>,
+}
+
+pub fn prepare_rename(
+    documents: &DocumentStore,
+    uri: &Url,
+    position: Position,
+    encoding: &PositionEncodingKind,
+) -> Result<PrepareRenameResponse, ResponseError> {
+    let (range, placeholder, _) = renamable_at(documents, uri, position, encoding)?;
+    Ok(PrepareRenameResponse::RangeWithPlaceholder { range, placeholder })
+}
+
+pub fn rename(
+    documents: &DocumentStore,
+    uri: &Url,
+    position: Position,
+    new_name: &str,
+    encoding: &PositionEncodingKind,
+) -> Result<Renaming, ResponseError> {
+    let (_, from, definition) = renamable_at(documents, uri, position, encoding)?;
+    if is_keyword(new_name) {
+        return Err(ResponseError::new(ErrorCode::InvalidParams, format!("`{}` is a keyword.", new_name)));
+    }
+    if !is_identifier(new_name) {
+        return Err(ResponseError::new(ErrorCode::InvalidParams, format!("`{}` is not a valid identifier.", new_name)));
+    }
+
+    let mut edits: Vec<(Url, Vec<TextEdit>)> = documents
+        .iter()
+        .map(|(uri, document)| {
+            let edits = references_in_document(uri, document.text(), &definition, encoding, true)
+                .into_iter()
+                .map(|location| TextEdit::new(location.range, new_name.to_string()))
+                .collect::<Vec<_>>();
+            (uri.clone(), edits)
+        })
+        .filter(|(_, edits)| !edits.is_empty())
+        .collect();
+    edits.sort_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()));
+
+    // 定義のあるファイルでは定義の名前を、ほかのファイルでは書き換える名前（`use` で取り込んだ名前と使っている場所）を調べる
+    for (uri, file_edits) in &edits {
+        let Some(text) = documents.text(uri) else {
+            continue;
+        };
+        let targets: Vec<Position> = if *uri == definition.uri {
+            vec![definition.range.start]
+        } else {
+            file_edits.iter().map(|edit| edit.range.start).collect()
+        };
+        if let Some(conflict) = find_conflict(text, &targets, new_name, encoding) {
+            let place = if *uri == definition.uri { String::new() } else { format!(" in {}", file_name(uri)) };
+            return Err(ResponseError::new(
+                ErrorCode::RequestFailed,
+                format!("Cannot rename `{}` to `{}`: {}{}", from, new_name, conflict, place),
+            ));
+        }
+    }
+    Ok(Renaming { from, to: new_name.to_string(), edits })
+}
+
+impl Renaming {
+    // origin はリネームを始めたドキュメント、open は開いているドキュメント（編集にはそのバージョンを付ける）
+    // annotate が false なら、changes に URI ごとの編集を並べるだけにする
+    pub fn into_workspace_edit(self, origin: &Url, open: &DocumentStore, annotate: bool) -> WorkspaceEdit {
+        if !annotate {
+            return WorkspaceEdit { changes: Some(self.edits.into_iter().collect()), ..Default::default() };
+        }
+
+        const RENAME: &str = "rename";
+        const OTHER_FILES: &str = "rename-other-files";
+        let label = format!("Rename `{}` to `{}`", self.from, self.to);
+        let mut annotations = HashMap::new();
+        let mut changes = Vec::new();
+        for (uri, edits) in self.edits {
+            let annotation = if uri == *origin {
+                annotations.entry(RENAME.to_string()).or_insert_with(|| ChangeAnnotation {
+                    label: label.clone(),
+                    needs_confirmation: None,
+                    description: None,
+                });
+                RENAME
+            } else {
+                annotations.entry(OTHER_FILES.to_string()).or_insert_with(|| ChangeAnnotation {
+                    label: label.clone(),
+                    needs_confirmation: Some(true),
+                    description: Some(format!("Updates uses of `{}` in other files", self.from)),
+                });
+                OTHER_FILES
+            };
+            let version = open.get(&uri).map(|document| document.version());
+            changes.push(TextDocumentEdit {
+                text_document: OptionalVersionedTextDocumentIdentifier { uri, version },
+                edits: edits
+                    .into_iter()
+                    .map(|text_edit| OneOf::Right(AnnotatedTextEdit { text_edit, annotation_id: annotation.to_string() }))
+                    .collect(),
+            });
+        }
+        WorkspaceEdit {
+            document_changes: Some(DocumentChanges::Edits(changes)),
+            change_annotations: Some(annotations),
+            ..Default::default()
+        }
+    }
+}
+
+// position にあるリネームできる名前（範囲、今の名前、定義）
+fn renamable_at(
+    documents: &DocumentStore,
+    uri: &Url,
+    position: Position,
+    encoding: &PositionEncodingKind,
+) -> Result<(Range, String, Definition), ResponseError> {
+    let refuse = |message: String| ResponseError::new(ErrorCode::RequestFailed, message);
+    let text = documents.text(uri).ok_or_else(|| refuse(format!("{} is not open.", uri)))?;
+    let index = LineIndex::new(text, encoding.clone());
+    let token = token_at(text, &index, position).ok_or_else(|| refuse("No symbol to rename here.".to_string()))?;
+
+    match token.kind {
+        TokenKind::Number | TokenKind::Str | TokenKind::Char => return Err(refuse("Cannot rename a literal.".to_string())),
+        TokenKind::Ident if token.text == "true" || token.text == "false" => return Err(refuse("Cannot rename a literal.".to_string())),
+        TokenKind::Ident if is_keyword(token.text) => return Err(refuse(format!("Cannot rename keyword `{}`.", token.text))),
+        _ => {}
+    }
+    let Some(definition) = definition_at(documents, uri, position, encoding) else {
+        if BUILTIN_TYPES.contains(&token.text) {
+            return Err(refuse(format!("Cannot rename built-in type `{}`.", token.text)));
+        }
+        return Err(refuse("No symbol to rename here.".to_string()));
+    };
+    Ok((index.range(token.range()), token.text.to_string(), definition))
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    name != "_"
+        && chars.next().is_some_and(|first| first.is_alphabetic() || first == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_')
+}
+
+fn file_name(uri: &Url) -> &str {
+    uri.path_segments().and_then(|mut segments| segments.next_back()).unwrap_or(uri.as_str())
+}
+
+// ドキュメント（中身は text）の targets の位置にある名前を new_name にしたときの衝突
+fn find_conflict(text: &str, targets: &[Position], new_name: &str, encoding: &PositionEncodingKind) -> Option<String> {
+    let index = LineIndex::new(text, encoding.clone());
+    let tokens = tokenize(text);
+    let mut lowering = ScopedLowering { index: &index, scopes: 0 };
+    let program = ScopedProgram { statements: lowering.lower(&token_trees(&tokens), 0) };
+
+    targets.iter().find_map(|start| {
+        let target = ScopedPosition::new(start.line as usize, start.character as usize);
+        VariableRenamer::new().find_rename_conflict(&program, target, new_name)
+    })
+}
+
+// lesson_4_5 用の AST（衝突を調べるためだけに使う）
+// - let・fn・struct・引数・for のループ変数・構造体のフィールド・use で取り込む名前 → LetDeclaration（その名前が見えるスコープに置く）
+// - { } → Block（新しいスコープ）。fn の引数と for のループ変数は、続く { } のスコープに入れる
+// - それ以外の識別子 → Identifier（`.` の後ろのフィールド・メソッドは除く）
+struct ScopedLowering<'i> {
+    index: &'i LineIndex,
+    // これまでに作ったスコープの数（0 はファイルの一番外側）
+    scopes: usize,
+}
+
+impl ScopedLowering<'_> {
+    fn lower(&mut self, trees: &[TokenTree], scope_id: usize) -> Vec<ScopedStmt> {
+        let mut statements = Vec::new();
+        // 次の { } のスコープで定義される名前と、次の { } が struct の本体か
+        let mut pending: Vec<Token> = Vec::new();
+        let mut struct_body = false;
+        // `use ...;` の終わり（そこまでは読み飛ばす）
+        let mut resume = 0;
+
+        for (i, tree) in trees.iter().enumerate() {
+            if i < resume {
+                continue;
+            }
+            let before = |n: usize| i.checked_sub(n).and_then(|i| trees[i].leaf());
+            let after_keyword = |keyword: &str| before(1).is_some_and(|token| token.is_keyword(keyword));
+            match tree {
+                TokenTree::Leaf(token) if token.is_keyword("use") => {
+                    let end = trees[i..].iter().position(|tree| tree.leaf().is_some_and(|token| token.is_punct(";"))).map_or(trees.len(), |end| i + end);
+                    statements.extend(imported_names(&trees[i + 1..end]).into_iter().map(|token| self.declaration(token, scope_id)));
+                    resume = end + 1;
+                }
+                TokenTree::Leaf(token) if token.is_ident() => {
+                    if before(1).is_some_and(|token| token.is_punct(".")) {
+                        continue;
+                    }
+                    let binds = after_keyword("let")
+                        || (after_keyword("mut") && before(2).is_some_and(|token| token.is_keyword("let")));
+                    if binds || after_keyword("fn") || after_keyword("struct") {
+                        statements.push(self.declaration(token, scope_id));
+                        struct_body = after_keyword("struct");
+                    } else if after_keyword("for") {
+                        pending.push(*token);
+                    } else {
+                        statements.push(ScopedStmt::Expression(self.identifier(token, scope_id)));
+                    }
+                }
+                TokenTree::Group { open, children, .. } if open.text == "{" => {
+                    self.scopes += 1;
+                    let block_scope = self.scopes;
+                    let mut block: Vec<ScopedStmt> = pending.drain(..).map(|token| self.declaration(&token, block_scope)).collect();
+                    if std::mem::take(&mut struct_body) {
+                        block.extend(named_before_colon(children).map(|token| self.declaration(token, block_scope)));
+                    } else {
+                        block.extend(self.lower(children, block_scope));
+                    }
+                    statements.push(ScopedStmt::Block { statements: block, span: self.span(open, tree.end()), scope_id: block_scope });
+                }
+                // `fn name(...)` の引数
+                TokenTree::Group { open, children, .. }
+                    if open.text == "(" && before(1).is_some_and(Token::is_ident) && before(2).is_some_and(|token| token.is_keyword("fn")) =>
+                {
+                    pending.extend(named_before_colon(children).copied());
+                }
+                TokenTree::Group { children, .. } => statements.extend(self.lower(children, scope_id)),
+                TokenTree::Leaf(_) => {}
+            }
+        }
+        statements
+    }
+
+    // 初期化式の中の識別子は後ろの文として並べるので、value には名前の位置のダミーの値を入れる
+    fn declaration(&self, token: &Token, scope_id: usize) -> ScopedStmt {
+        let span = self.span(token, token.end());
+        ScopedStmt::LetDeclaration { name: token.text.to_string(), value: ScopedExpr::Number(0, span.clone()), span, scope_id }
+    }
+
+    fn identifier(&self, token: &Token, scope_id: usize) -> ScopedExpr {
+        ScopedExpr::Identifier { name: token.text.to_string(), span: self.span(token, token.end()), scope_id }
+    }
+
+    fn span(&self, start: &Token, end: usize) -> Span {
+        let position = |offset: usize| {
+            let position = self.index.position(offset);
+            ScopedPosition::new(position.line as usize, position.character as usize)
+        };
+        Span::new(position(start.start), position(end))
+    }
+}
+
+// `use` で取り込む名前（`a::b` の b、`a::{b, c as d}` の b と d）
+fn imported_names<'t, 'a>(trees: &'t [TokenTree<'a>]) -> Vec<&'t Token<'a>> {
+    let mut names = Vec::new();
+    for (i, tree) in trees.iter().enumerate() {
+        match tree {
+            TokenTree::Leaf(token) if token.is_ident() => {
+                // パスの最後の名前（`as` の前の名前は取り込まれない）
+                let last = match trees.get(i + 1) {
+                    None => true,
+                    Some(next) => next.leaf().is_some_and(|next| next.is_punct(",")),
+                };
+                if last {
+                    names.push(token);
+                }
+            }
+            TokenTree::Group { children, .. } => names.extend(imported_names(children)),
+            TokenTree::Leaf(_) => {}
+        }
+    }
+    names
+}
+
+// `name: ...` の形の名前（引数と、構造体のフィールド）
+fn named_before_colon<'t, 'a>(trees: &'t [TokenTree<'a>]) -> impl Iterator<Item = &'t Token<'a>> {
+    trees.iter().enumerate().filter_map(|(i, tree)| {
+        let token = tree.leaf()?;
+        let colon = trees.get(i + 1).and_then(TokenTree::leaf).is_some_and(|next| next.is_punct(":"));
+        (token.is_ident() && colon).then_some(token)
+    })
+}
+
+
+// --- Tests --- //
+
+#[cfg(test)]
+mod tests {
+    use super::{prepare_rename, rename, Renaming};
+    use crate::common::document_store::DocumentStore;
+    use lsp_types::{
+        DocumentChanges, OneOf, Position, PositionEncodingKind, PrepareRenameResponse, Range, TextEdit, Url,
+    };
+
+    fn url(path: &str) -> Url {
+        Url::parse(&format!("file:///src/{}", path)).unwrap()
+    }
+
+    fn store(files: &[(&str, &str)]) -> DocumentStore {
+        let mut store = DocumentStore::new();
+        for (path, text) in files {
+            store.open(url(path), "rust", 1, text);
+        }
+        store
+    }
+
+    fn range(line: u32, start: u32, end: u32) -> Range {
+        Range::new(Position::new(line, start), Position::new(line, end))
+    }
+
+    fn prepare(store: &DocumentStore, line: u32, character: u32) -> Result<PrepareRenameResponse, String> {
+        prepare_rename(store, &url("main.rs"), Position::new(line, character), &PositionEncodingKind::UTF16)
+            .map_err(|error| error.message)
+    }
+
+    fn rename_in_main(store: &DocumentStore, line: u32, character: u32, new_name: &str) -> Result<Renaming, String> {
+        rename(store, &url("main.rs"), Position::new(line, character), new_name, &PositionEncodingKind::UTF16)
+            .map_err(|error| error.message)
+    }
+
+    #[test]
+    fn test_prepare_rename_returns_the_name_or_refuses() {
+        let store = store(&[("main.rs", "fn main() {\n    let count: i32 = 1;\n    let label = \"count\";\n    count;\n}")]);
+
+        assert_eq!(
+            prepare(&store, 3, 6),
+            Ok(PrepareRenameResponse::RangeWithPlaceholder { range: range(3, 4, 9), placeholder: "count".to_string() })
+        );
+        assert_eq!(prepare(&store, 1, 5), Err("Cannot rename keyword `let`.".to_string()));
+        assert_eq!(prepare(&store, 1, 21), Err("Cannot rename a literal.".to_string()));
+        assert_eq!(prepare(&store, 2, 17), Err("Cannot rename a literal.".to_string()));
+        assert_eq!(prepare(&store, 1, 16), Err("Cannot rename built-in type `i32`.".to_string()));
+    }
+
+    #[test]
+    fn test_rename_validates_the_new_name() {
+        let store = store(&[("main.rs", "fn main() {\n    let count = 1;\n    let total = 2;\n    {\n        let inner = count;\n    }\n}")]);
+
+        assert_eq!(rename_in_main(&store, 1, 8, "fn").unwrap_err(), "`fn` is a keyword.");
+        assert_eq!(rename_in_main(&store, 1, 8, "1st").unwrap_err(), "`1st` is not a valid identifier.");
+        assert_eq!(
+            rename_in_main(&store, 1, 8, "total").unwrap_err(),
+            "Cannot rename `count` to `total`: can not rename to existing variable name in the same scope"
+        );
+        // 内側のスコープの名前にするのは衝突ではない（lesson_4_5 の規則）
+        assert!(rename_in_main(&store, 1, 8, "inner").is_ok());
+        assert!(rename_in_main(&store, 4, 12, "total").is_ok(), "shadowing an outer name is allowed");
+    }
+
+    #[test]
+    fn test_rename_edits_every_file_that_uses_an_item() {
+        let math = "fn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n\nfn sub(a: i32, b: i32) -> i32 {\n    a - b\n}";
+        let main = "mod math;\nuse crate::math::add;\n\nfn main() {\n    add(1, 2);\n}";
+        let store = store(&[("math.rs", math), ("main.rs", main)]);
+
+        let renaming = rename_in_main(&store, 4, 5, "sum").unwrap();
+        let edit = |line, start, end| TextEdit::new(range(line, start, end), "sum".to_string());
+        assert_eq!(
+            renaming.edits,
+            vec![(url("main.rs"), vec![edit(1, 17, 20), edit(4, 4, 7)]), (url("math.rs"), vec![edit(0, 3, 6)])]
+        );
+
+        // 衝突は書き換えるファイルごとに調べる
+        assert!(rename_in_main(&store, 4, 5, "sub").unwrap_err().starts_with("Cannot rename `add` to `sub`"));
+        assert_eq!(
+            rename_in_main(&store, 4, 5, "main").unwrap_err(),
+            "Cannot rename `add` to `main`: can not rename to existing variable name in the same scope in main.rs",
+            "`use crate::math::main;` would clash with `fn main`"
+        );
+    }
+
+    #[test]
+    fn test_names_imported_by_use_can_conflict() {
+        let math = "fn add(a: i32, b: i32) -> i32 {\n    a + b\n}";
+        let imports = store(&[("math.rs", math), ("main.rs", "mod math;\nuse crate::math::add;\nuse crate::shapes::area;")]);
+        assert!(rename_in_main(&imports, 1, 18, "area").unwrap_err().ends_with("in main.rs"));
+
+        let aliased = store(&[("math.rs", math), ("main.rs", "mod math;\nuse crate::math::{add as plus};\n\nfn main() {}")]);
+        assert!(rename_in_main(&aliased, 1, 19, "main").is_ok(), "the name before `as` is not imported");
+    }
+
+    #[test]
+    fn test_workspace_edit_with_change_annotations() {
+        let main = "mod math;\nuse crate::math::add;";
+        let store = store(&[("math.rs", "fn add() {}"), ("main.rs", main)]);
+        let renaming = rename_in_main(&store, 1, 18, "sum").unwrap();
+
+        let plain = renaming.clone().into_workspace_edit(&url("main.rs"), &store, false);
+        assert_eq!(plain.changes.unwrap().len(), 2);
+
+        let annotated = renaming.into_workspace_edit(&url("main.rs"), &DocumentStore::new(), true);
+        let Some(DocumentChanges::Edits(changes)) = annotated.document_changes else {
+            panic!("expected document changes");
+        };
+        let annotation_ids: Vec<&str> = changes
+            .iter()
+            .map(|change| match &change.edits[0] {
+                OneOf::Right(edit) => edit.annotation_id.as_str(),
+                OneOf::Left(_) => panic!("expected an annotated edit"),
+            })
+            .collect();
+        assert_eq!(annotation_ids, vec!["rename", "rename-other-files"]);
+        assert_eq!(changes[0].text_document.version, None, "the files are not open");
+
+        let annotations = annotated.change_annotations.unwrap();
+        assert_eq!(annotations["rename"].label, "Rename `add` to `sum`");
+        assert_eq!(annotations["rename-other-files"].needs_confirmation, Some(true));
+    }
+}
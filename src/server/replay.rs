@@ -0,0 +1,396 @@
+// LSP のセッションを記録したトレースを再生して、サーバーの出力と比べる
+// lesson_1 のテストは関数を1つ呼んで戻り値を見るだけなので、initialize → didOpen → hover → shutdown のような
+// セッション全体の流れはここで確かめる
+//
+// トレースは JSONL で、1行に1つ次のどれかを書く
+// - {"send": メッセージ}    クライアント → サーバーのメッセージ
+// - {"expect": メッセージ}  サーバー → クライアントのメッセージ。直前の send（または timers）で出たものを順番どおりにすべて書く
+// - "timers"                一番近いタイマー（診断の待ち時間、サーバーからのリクエストのタイムアウト）の時刻まで進める
+// - {"comment": "..."}      説明（再生では何もしない）
+//
+// 文字列には次のプレースホルダーを書ける
+// - "${root}"     ワークスペースのルートの URI（文字列の一部にも書ける）。出力の中のルートの URI も "${root}" に戻してから比べる
+// - "${id:名前}"  expect では実際の値（サーバーが振ったリクエストの id など）を名前に結びつけ、
+//                 send ではその値に置き換える（クライアントからの応答の id に使う）
+// - "${any}"      expect でどんな値にも一致する（resultId のように中身を気にしない値に使う）
+//
+// 出力が違えば、トレースとの差分を返す。UPDATE_TRACES=1 を付けて実行するとトレースを実際の出力で書き換える
+// （send だけを書いたトレースを用意して書き換えれば、新しいセッションを記録できる）
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use lsp_types::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::lessons::lesson_1::lesson_1_9::try_parse_full_lsp_message;
+use crate::server::main_loop::Server;
+
+const ROOT_PLACEHOLDER: &str = "${root}";
+const ANY_PLACEHOLDER: &str = "${any}";
+
+// トレースを書き換えるかどうかを決める環境変数
+pub const UPDATE_TRACES: &str = "UPDATE_TRACES";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TraceEntry {
+    Comment(String),
+    Send(Value),
+    Expect(Value),
+    Timers,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Trace {
+    pub entries: Vec<TraceEntry>,
+}
+
+// トレースを読めなかった・再生できなかった理由
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceError {
+    // JSONL の行が TraceEntry として読めない（行番号は 1 から）
+    InvalidLine { line: usize, message: String },
+    // send に結びついていない "${id:名前}" がある
+    UnboundId { entry: usize, name: String },
+    // exit の後にまだ send がある
+    AfterExit { entry: usize },
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceError::InvalidLine { line, message } => write!(f, "Line {} is not a trace entry: {}", line, message),
+            TraceError::UnboundId { entry, name } => {
+                write!(f, "Entry {} uses ${{id:{}}} before an expected message binds it.", entry + 1, name)
+            }
+            TraceError::AfterExit { entry } => write!(f, "Entry {} is sent after the server has exited.", entry + 1),
+        }
+    }
+}
+
+impl Trace {
+    // 空行は読み飛ばす
+    pub fn parse(text: &str) -> Result<Trace, TraceError> {
+        let entries = text
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(index, line)| {
+                serde_json::from_str(line).map_err(|err| TraceError::InvalidLine { line: index + 1, message: err.to_string() })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Trace { entries })
+    }
+
+    pub fn to_jsonl(&self) -> String {
+        self.entries.iter().map(|entry| serde_json::to_string(entry).unwrap() + "\n").collect()
+    }
+}
+
+// 再生の結果
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayOutcome {
+    // 実際の出力で expect を書き直したトレース（一致した expect は書いたまま残す）
+    pub actual: Trace,
+    // すべての expect が一致したか
+    pub matched: bool,
+}
+
+// サーバーを同じプロセスの中で動かしてトレースを再生する
+pub struct Replay {
+    root: String,
+}
+
+impl Replay {
+    // root は "${root}" に入れるワークスペースのルート
+    pub fn new(root: &Url) -> Self {
+        Replay { root: root.as_str().trim_end_matches('/').to_string() }
+    }
+
+    pub fn run(&self, trace: &Trace) -> Result<ReplayOutcome, TraceError> {
+        let mut server = Server::new();
+        let mut ids = HashMap::new();
+        let mut actual = Vec::new();
+        let mut matched = true;
+        let mut entries = trace.entries.iter().enumerate().peekable();
+
+        while let Some((index, entry)) = entries.next() {
+            let outputs = match entry {
+                TraceEntry::Comment(_) => {
+                    actual.push(entry.clone());
+                    continue;
+                }
+                // send / timers より前にある expect は、何の出力とも比べられない
+                TraceEntry::Expect(_) => {
+                    matched = false;
+                    continue;
+                }
+                TraceEntry::Send(message) => {
+                    if server.exit_code().is_some() {
+                        return Err(TraceError::AfterExit { entry: index });
+                    }
+                    let message = self.substitute(message, &ids).map_err(|name| TraceError::UnboundId { entry: index, name })?;
+                    send(&mut server, &message)
+                }
+                TraceEntry::Timers => match server.next_deadline() {
+                    Some(deadline) => server.handle_timers(deadline),
+                    None => Vec::new(),
+                },
+            };
+            actual.push(entry.clone());
+
+            // この入力の後に続く expect と、実際の出力を順番に比べる
+            let mut expected = Vec::new();
+            while let Some((_, TraceEntry::Expect(message))) = entries.peek() {
+                expected.push(message);
+                entries.next();
+            }
+            matched &= expected.len() == outputs.len();
+
+            for (position, output) in outputs.iter().enumerate() {
+                let output = self.normalize(&content(output));
+                match expected.get(position) {
+                    Some(pattern) if matches_binding(pattern, &output, &mut ids) => actual.push(TraceEntry::Expect((*pattern).clone())),
+                    _ => {
+                        matched = false;
+                        actual.push(TraceEntry::Expect(output));
+                    }
+                }
+            }
+        }
+
+        Ok(ReplayOutcome { actual: Trace { entries: actual }, matched })
+    }
+
+    // send のプレースホルダーを置き換える（結びついていない id があれば、その名前を返す）
+    fn substitute(&self, value: &Value, ids: &HashMap<String, Value>) -> Result<Value, String> {
+        Ok(match value {
+            Value::String(text) => match id_placeholder(text) {
+                Some(name) => ids.get(name).cloned().ok_or_else(|| name.to_string())?,
+                None => Value::String(text.replace(ROOT_PLACEHOLDER, &self.root)),
+            },
+            Value::Array(items) => Value::Array(items.iter().map(|item| self.substitute(item, ids)).collect::<Result<_, _>>()?),
+            Value::Object(fields) => Value::Object(
+                fields
+                    .iter()
+                    .map(|(key, field)| Ok((key.clone(), self.substitute(field, ids)?)))
+                    .collect::<Result<_, String>>()?,
+            ),
+            _ => value.clone(),
+        })
+    }
+
+    // 出力の中のルートの URI を "${root}" に戻す
+    fn normalize(&self, value: &Value) -> Value {
+        match value {
+            Value::String(text) => Value::String(text.replace(&self.root, ROOT_PLACEHOLDER)),
+            Value::Array(items) => Value::Array(items.iter().map(|item| self.normalize(item)).collect()),
+            Value::Object(fields) => Value::Object(fields.iter().map(|(key, field)| (key.clone(), self.normalize(field))).collect()),
+            _ => value.clone(),
+        }
+    }
+}
+
+// path のトレースを再生する
+// 一致しなければ差分をエラーにする。UPDATE_TRACES が設定されていれば、代わりにトレースを書き換える
+pub fn check_trace_file(path: &Path, root: &Url) -> Result<(), String> {
+    let text = fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    let expected = Trace::parse(&text).map_err(|err| format!("{}: {}", path.display(), err))?;
+    let outcome = Replay::new(root).run(&expected).map_err(|err| format!("{}: {}", path.display(), err))?;
+    if outcome.matched {
+        return Ok(());
+    }
+
+    if std::env::var_os(UPDATE_TRACES).is_some() {
+        return fs::write(path, outcome.actual.to_jsonl()).map_err(|err| format!("{}: {}", path.display(), err));
+    }
+    Err(format!(
+        "{} does not match the server output (rerun with {}=1 to update it):\n{}",
+        path.display(),
+        UPDATE_TRACES,
+        diff_lines(&expected.to_jsonl(), &outcome.actual.to_jsonl())
+    ))
+}
+
+// 行ごとの差分（"-" はトレースにだけある行、"+" は実際の出力にだけある行）
+pub fn diff_lines(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+
+    // common[i][j] = expected[i..] と actual[j..] の最長共通部分列の長さ
+    let mut common = vec![vec![0; actual.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            common[i][j] = if expected[i] == actual[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    let mut diff = String::new();
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+            diff += &format!("  {}\n", expected[i]);
+            i += 1;
+            j += 1;
+        } else if i < expected.len() && (j == actual.len() || common[i + 1][j] >= common[i][j + 1]) {
+            diff += &format!("- {}\n", expected[i]);
+            i += 1;
+        } else {
+            diff += &format!("+ {}\n", actual[j]);
+            j += 1;
+        }
+    }
+    diff
+}
+
+// メッセージをフレームにして、stdio から読んだときと同じように処理する
+// 読めないメッセージには、メインループと同じくエラー応答を返す
+fn send(server: &mut Server, message: &Value) -> Vec<String> {
+    let content = message.to_string();
+    let framed = format!("Content-Length: {}\r\n\r\n{}", content.len(), content);
+    match try_parse_full_lsp_message(&framed) {
+        Ok(message) => server.handle_message(message),
+        Err(err) => vec![err.to_error_response()],
+    }
+}
+
+fn content(message: &str) -> Value {
+    message
+        .split_once("\r\n\r\n")
+        .and_then(|(_, content)| serde_json::from_str(content).ok())
+        .unwrap_or(Value::Null)
+}
+
+fn id_placeholder(text: &str) -> Option<&str> {
+    text.strip_prefix("${id:").and_then(|rest| rest.strip_suffix('}'))
+}
+
+// pattern が actual に一致すれば、"${id:名前}" を ids に結びつける（一致しなければ ids は変えない）
+fn matches_binding(pattern: &Value, actual: &Value, ids: &mut HashMap<String, Value>) -> bool {
+    let mut bound = ids.clone();
+    let matched = matches(pattern, actual, &mut bound);
+    if matched {
+        *ids = bound;
+    }
+    matched
+}
+
+fn matches(pattern: &Value, actual: &Value, ids: &mut HashMap<String, Value>) -> bool {
+    match (pattern, actual) {
+        (Value::String(text), _) if text == ANY_PLACEHOLDER => true,
+        (Value::String(text), _) if id_placeholder(text).is_some() => {
+            let name = id_placeholder(text).unwrap();
+            ids.entry(name.to_string()).or_insert_with(|| actual.clone()) == actual
+        }
+        (Value::Array(patterns), Value::Array(items)) => {
+            patterns.len() == items.len() && patterns.iter().zip(items).all(|(pattern, item)| matches(pattern, item, ids))
+        }
+        (Value::Object(patterns), Value::Object(fields)) => {
+            patterns.len() == fields.len()
+                && patterns
+                    .iter()
+                    .all(|(key, pattern)| fields.get(key).is_some_and(|field| matches(pattern, field, ids)))
+        }
+        _ => pattern == actual,
+    }
+}
+
+
+// --- Tests --- //
+
+#[cfg(test)]
+mod tests {
+    use super::{check_trace_file, Replay, Trace, TraceEntry};
+    use lsp_types::Url;
+    use serde_json::json;
+    use std::path::Path;
+
+    fn root() -> Url {
+        Url::parse("file:///workspace").unwrap()
+    }
+
+    fn check(name: &str) {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/server/traces").join(name);
+        if let Err(diff) = check_trace_file(&path, &root()) {
+            panic!("{}", diff);
+        }
+    }
+
+    #[test]
+    fn test_trace_hover_session() {
+        check("hover_session.jsonl");
+    }
+
+    #[test]
+    fn test_trace_debounced_diagnostics() {
+        check("debounced_diagnostics.jsonl");
+    }
+
+    #[test]
+    fn test_trace_server_requests() {
+        check("server_requests.jsonl");
+    }
+
+    #[test]
+    fn test_placeholders() {
+        let trace = Trace::parse(
+            &[
+                json!({"send": {"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {"rootUri": "${root}", "capabilities": {"window": {"workDoneProgress": true}}}}}),
+                json!({"expect": {"jsonrpc": "2.0", "id": 1, "result": "${any}"}}),
+                json!({"send": {"jsonrpc": "2.0", "method": "textDocument/didOpen", "params": {"textDocument": {"uri": "${root}/a.rs", "languageId": "rust", "version": 1, "text": "// TODO"}}}}),
+                json!({"expect": {"jsonrpc": "2.0", "method": "textDocument/publishDiagnostics", "params": {"uri": "${root}/a.rs", "version": 1, "diagnostics": "${any}"}}}),
+                json!({"send": {"jsonrpc": "2.0", "id": 2, "method": "workspace/symbol", "params": {"query": "x"}}}),
+                json!({"expect": {"jsonrpc": "2.0", "id": "${id:create}", "method": "window/workDoneProgress/create", "params": "${any}"}}),
+                json!({"expect": "${any}"}),
+                json!({"expect": "${any}"}),
+                json!({"expect": "${any}"}),
+                json!({"expect": {"jsonrpc": "2.0", "id": 2, "result": []}}),
+                json!({"send": {"jsonrpc": "2.0", "id": "${id:create}", "result": null}}),
+            ]
+            .iter()
+            .map(|entry| entry.to_string() + "\n")
+            .collect::<String>(),
+        )
+        .unwrap();
+
+        let outcome = Replay::new(&root()).run(&trace).unwrap();
+        assert!(outcome.matched, "{}", outcome.actual.to_jsonl());
+        assert_eq!(outcome.actual, trace, "matching expectations are kept as written");
+
+        let mut unbound = trace.clone();
+        unbound.entries.insert(1, TraceEntry::Send(json!({"jsonrpc": "2.0", "id": "${id:missing}", "result": null})));
+        assert!(Replay::new(&root()).run(&unbound).is_err(), "an id must be bound before it is sent");
+    }
+
+    #[test]
+    fn test_mismatches_are_replaced_with_the_actual_output() {
+        let trace = Trace {
+            entries: vec![
+                TraceEntry::Send(json!({"jsonrpc": "2.0", "id": 1, "method": "shutdown"})),
+                TraceEntry::Expect(json!({"jsonrpc": "2.0", "id": 1, "result": "wrong"})),
+                TraceEntry::Expect(json!({"jsonrpc": "2.0", "method": "extra"})),
+            ],
+        };
+
+        let outcome = Replay::new(&root()).run(&trace).unwrap();
+        assert!(!outcome.matched);
+        assert_eq!(
+            outcome.actual.entries[1..],
+            [TraceEntry::Expect(json!({"jsonrpc": "2.0", "id": 1, "error": {"code": -32002, "message": "Server has not been initialized.", "data": null}}))],
+            "the expectations should become the actual output"
+        );
+
+        let diff = super::diff_lines(&trace.to_jsonl(), &outcome.actual.to_jsonl());
+        assert_eq!(diff.lines().filter(|line| line.starts_with("- ")).count(), 2);
+        assert_eq!(diff.lines().filter(|line| line.starts_with("+ ")).count(), 1);
+        assert!(diff.starts_with("  {\"send\""), "the unchanged send is shown as context");
+    }
+}
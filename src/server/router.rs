@@ -0,0 +1,361 @@
+// メソッドのルーター
+// lsp_types::request::Request / lsp_types::notification::Notification の型ごとにハンドラを登録しておき、
+// 受け取ったメッセージの method 名から呼び出す。
+// params の JSON → 型付き構造体、結果の型付き構造体 → JSON の変換はルーターが受け持つので、
+// ハンドラは lsp_types の型だけを見ればよい。
+//
+// ハンドラには2種類ある
+// - on_request / on_notification: サーバーの状態 S を書き換えられる。メインループで届いた順に処理する
+// - on_read_request: 状態のスナップショット T を読むだけ。別スレッドで動かせるので、重いリクエストの間も didChange を待たせない
+
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+use lsp_types::notification::Notification;
+use lsp_types::request::Request;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+use crate::lessons::lesson_1::lesson_1_9::ResponseError;
+use crate::lessons::lesson_1::lesson_1_10::create_lsp_response;
+use crate::lessons::lesson_1::lesson_1_11::ErrorCode;
+
+type RequestHandler<S> = Box<dyn Fn(&mut S, Value) -> Result<Value, ResponseError>>;
+type ReadRequestHandler<T> = Arc<dyn Fn(&mut T, Value) -> Result<Value, ResponseError> + Send + Sync>;
+type NotificationHandler<S> = Box<dyn Fn(&mut S, Value) -> Result<Vec<String>, ResponseError>>;
+
+// S はハンドラが読み書きするサーバーの状態、T は読み取り専用のハンドラが受け取る S のスナップショット
+pub struct Router<S, T = ()> {
+    request_handlers: HashMap<&'static str, RequestHandler<S>>,
+    read_request_handlers: HashMap<&'static str, ReadRequestHandler<T>>,
+    notification_handlers: HashMap<&'static str, NotificationHandler<S>>,
+}
+
+impl<S, T> Router<S, T> {
+    pub fn new() -> Self {
+        Router {
+            request_handlers: HashMap::new(),
+            read_request_handlers: HashMap::new(),
+            notification_handlers: HashMap::new(),
+        }
+    }
+
+    // リクエスト R のハンドラを登録する
+    // ハンドラがエラーを返した場合は、そのままエラー応答になる
+    pub fn on_request<R, F>(&mut self, handler: F) -> &mut Self
+    where
+        R: Request,
+        F: Fn(&mut S, R::Params) -> Result<R::Result, ResponseError> + 'static,
+    {
+        // 同じメソッドのハンドラは1つだけ（読み取り専用として登録されていても置き換える）
+        self.read_request_handlers.remove(R::METHOD);
+        self.request_handlers.insert(
+            R::METHOD,
+            Box::new(move |state, params| {
+                let params = deserialize_params::<R::Params>(params)?;
+                serialize_result(handler(state, params)?)
+            }),
+        );
+        self
+    }
+
+    // 状態を書き換えないリクエスト R のハンドラを登録する
+    // ハンドラはスナップショット T を受け取り、別スレッドから呼ばれることがある
+    pub fn on_read_request<R, F>(&mut self, handler: F) -> &mut Self
+    where
+        R: Request,
+        F: Fn(&mut T, R::Params) -> Result<R::Result, ResponseError> + Send + Sync + 'static,
+    {
+        self.request_handlers.remove(R::METHOD);
+        self.read_request_handlers.insert(
+            R::METHOD,
+            Arc::new(move |snapshot, params| {
+                let params = deserialize_params::<R::Params>(params)?;
+                serialize_result(handler(snapshot, params)?)
+            }),
+        );
+        self
+    }
+
+    // 通知 N のハンドラを登録する
+    // 通知には応答を返せないので、ハンドラはクライアントへ送るメッセージ（publishDiagnostics など）を返す
+    pub fn on_notification<N, F>(&mut self, handler: F) -> &mut Self
+    where
+        N: Notification,
+        F: Fn(&mut S, N::Params) -> Vec<String> + 'static,
+    {
+        self.notification_handlers.insert(
+            N::METHOD,
+            Box::new(move |state, params| {
+                let params = deserialize_params::<N::Params>(params)?;
+                Ok(handler(state, params))
+            }),
+        );
+        self
+    }
+
+    pub fn has_request(&self, method: &str) -> bool {
+        self.request_handlers.contains_key(method) || self.read_request_handlers.contains_key(method)
+    }
+
+    pub fn is_read_request(&self, method: &str) -> bool {
+        self.read_request_handlers.contains_key(method)
+    }
+
+    pub fn has_notification(&self, method: &str) -> bool {
+        self.notification_handlers.contains_key(method)
+    }
+
+    // リクエストを処理して応答（Content-Length 付き）を返す
+    // - 登録されていないメソッド → MethodNotFound (-32601)
+    // - params の形が違う → InvalidParams (-32602)
+    pub fn handle_request(&self, state: &mut S, id: Value, method: &str, params: Option<Value>) -> String {
+        let Some(handler) = self.request_handlers.get(method) else {
+            return ResponseError::new(ErrorCode::MethodNotFound, format!("Method '{}' not found.", method)).to_error_response(id);
+        };
+
+        match handler(state, params.unwrap_or(Value::Null)) {
+            Ok(result) => create_lsp_response(id, result),
+            Err(err) => err.to_error_response(id),
+        }
+    }
+
+    // 通知を処理して、クライアントへ送るメッセージを返す
+    // 通知には応答を返せないので、未登録のメソッドや不正な params はエラーとして呼び出し側に返すだけにする
+    pub fn handle_notification(&self, state: &mut S, method: &str, params: Option<Value>) -> Result<Vec<String>, ResponseError> {
+        match self.notification_handlers.get(method) {
+            Some(handler) => handler(state, params.unwrap_or(Value::Null)),
+            None => Err(ResponseError::new(ErrorCode::MethodNotFound, format!("Notification '{}' not found.", method))),
+        }
+    }
+
+    // 読み取り専用のリクエストなら、スナップショットに対して処理する仕事を返す（それ以外は None）
+    // 仕事はハンドラと params を持っていくので、ルーターから離れて別スレッドで動かせる
+    pub fn read_request(&self, id: Value, method: &str, params: Option<Value>) -> Option<ReadRequest<T>> {
+        let handler = self.read_request_handlers.get(method)?.clone();
+        Some(ReadRequest { handler, id, params: params.unwrap_or(Value::Null) })
+    }
+}
+
+impl<S, T> Default for Router<S, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// スナップショットに対して処理するのを待っている、読み取り専用のリクエスト
+pub struct ReadRequest<T> {
+    handler: ReadRequestHandler<T>,
+    id: Value,
+    params: Value,
+}
+
+impl<T> ReadRequest<T> {
+    // 処理して応答（Content-Length 付き）を返す
+    // ハンドラが panic したら InternalError (-32603) で答える（応答が無いと、クライアントはずっと待ち続ける）
+    pub fn run(self, snapshot: &mut T) -> String {
+        let (handler, params) = (self.handler, self.params);
+        match panic::catch_unwind(AssertUnwindSafe(|| handler(snapshot, params))) {
+            Ok(Ok(result)) => create_lsp_response(self.id, result),
+            Ok(Err(err)) => err.to_error_response(self.id),
+            Err(payload) => {
+                let reason = payload
+                    .downcast_ref::<&str>()
+                    .map(|reason| reason.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                ResponseError::new(ErrorCode::InternalError, format!("Request handler panicked: {}", reason)).to_error_response(self.id)
+            }
+        }
+    }
+}
+
+fn serialize_result<R: serde::Serialize>(result: R) -> Result<Value, ResponseError> {
+    serde_json::to_value(result)
+        .map_err(|err| ResponseError::new(ErrorCode::InternalError, format!("Failed to serialize result: {}", err)))
+}
+
+// params を型付きの構造体に変換する
+// 失敗した場合は、どのフィールドが悪かったのか（例: "textDocument.version"）をエラーに含める
+// ※ #[serde(flatten)] されたフィールドの中では serde がパスを追えないので、その場合はパス無しになる
+fn deserialize_params<P: DeserializeOwned>(params: Value) -> Result<P, ResponseError> {
+    serde_path_to_error::deserialize(params).map_err(|err| {
+        let path = err.path().to_string();
+        let mut error = ResponseError::new(ErrorCode::InvalidParams, format!("Invalid params: {}", err));
+        if path != "." {
+            error.data = Some(json!({ "path": path }));
+        }
+        error
+    })
+}
+
+
+// --- Tests --- //
+
+#[cfg(test)]
+mod tests {
+    use super::Router;
+    use crate::lessons::lesson_1::lesson_1_9::ResponseError;
+    use crate::lessons::lesson_1::lesson_1_11::ErrorCode;
+    use lsp_types::notification::{DidCloseTextDocument, Exit};
+    use lsp_types::request::{GotoDefinition, HoverRequest, ResolveCompletionItem, Shutdown};
+    use lsp_types::{Hover, HoverContents, MarkedString};
+    use serde_json::{json, Value};
+
+    #[derive(Default)]
+    struct State {
+        hover_count: usize,
+        closed: Vec<String>,
+    }
+
+    fn content(message: &str) -> Value {
+        serde_json::from_str(message.split_once("\r\n\r\n").unwrap().1).unwrap()
+    }
+
+    fn create_router() -> Router<State> {
+        let mut router: Router<State> = Router::new();
+        router
+            .on_request::<HoverRequest, _>(|state, params| {
+                state.hover_count += 1;
+                let position = params.text_document_position_params.position;
+                Ok(Some(Hover {
+                    contents: HoverContents::Scalar(MarkedString::String(format!("{}:{}", position.line, position.character))),
+                    range: None,
+                }))
+            })
+            .on_request::<Shutdown, _>(|_, ()| Ok(()))
+            .on_request::<ResolveCompletionItem, _>(|_, item| Ok(item))
+            .on_request::<GotoDefinition, _>(|_, _| Err(ResponseError::new(ErrorCode::RequestFailed, "no definition")))
+            .on_notification::<DidCloseTextDocument, _>(|state, params| {
+                state.closed.push(params.text_document.uri.to_string());
+                Vec::new()
+            })
+            .on_notification::<Exit, _>(|_, ()| Vec::new());
+        router
+    }
+
+    fn hover_params() -> Value {
+        json!({"textDocument": {"uri": "file:///a.rs"}, "position": {"line": 1, "character": 2}})
+    }
+
+    #[test]
+    fn test_request_is_dispatched_with_typed_params() {
+        let router = create_router();
+        let mut state = State::default();
+
+        let response = content(&router.handle_request(&mut state, json!(1), "textDocument/hover", Some(hover_params())));
+
+        assert_eq!(state.hover_count, 1, "The hover handler should be called once.");
+        assert_eq!(response["id"], 1);
+        assert_eq!(response["result"]["contents"], "1:2");
+    }
+
+    #[test]
+    fn test_request_without_params_uses_unit() {
+        let router = create_router();
+        let response = content(&router.handle_request(&mut State::default(), json!("s"), "shutdown", None));
+
+        assert_eq!(response["id"], "s");
+        assert!(response["result"].is_null());
+        assert!(response.get("error").is_none());
+    }
+
+    #[test]
+    fn test_unknown_request_is_method_not_found() {
+        let router = create_router();
+        let response = content(&router.handle_request(&mut State::default(), json!(2), "textDocument/unknown", None));
+
+        assert_eq!(response["id"], 2);
+        assert_eq!(response["error"]["code"], -32601);
+    }
+
+    #[test]
+    fn test_bad_params_is_invalid_params_with_path() {
+        let router = create_router();
+        let params = json!({"label": "main", "kind": "function"});
+
+        let response = content(&router.handle_request(&mut State::default(), json!(3), "completionItem/resolve", Some(params)));
+
+        assert_eq!(response["error"]["code"], -32602);
+        assert_eq!(response["error"]["data"]["path"], "kind", "The error should point at the bad field.");
+        assert!(response["error"]["message"].as_str().unwrap().contains("kind"));
+    }
+
+    #[test]
+    fn test_bad_params_inside_flattened_fields() {
+        let router = create_router();
+        let mut params = hover_params();
+        params["position"]["line"] = json!("one");
+
+        let response = content(&router.handle_request(&mut State::default(), json!(3), "textDocument/hover", Some(params)));
+
+        assert_eq!(response["error"]["code"], -32602);
+        assert!(response["error"]["message"].as_str().unwrap().starts_with("Invalid params:"));
+    }
+
+    #[test]
+    fn test_handler_error_becomes_error_response() {
+        let router = create_router();
+        let response = content(&router.handle_request(&mut State::default(), json!(4), "textDocument/definition", Some(hover_params())));
+
+        assert_eq!(response["error"]["code"], -32803);
+        assert_eq!(response["error"]["message"], "no definition");
+    }
+
+    #[test]
+    fn test_notification_is_dispatched() {
+        let router = create_router();
+        let mut state = State::default();
+
+        let outgoing = router.handle_notification(&mut state, "textDocument/didClose", Some(json!({"textDocument": {"uri": "file:///a.rs"}})));
+
+        assert_eq!(outgoing, Ok(Vec::new()));
+        assert_eq!(state.closed, vec!["file:///a.rs".to_string()]);
+    }
+
+    #[test]
+    fn test_notification_errors_are_returned_to_caller() {
+        let router = create_router();
+        let mut state = State::default();
+
+        let unknown = router.handle_notification(&mut state, "$/unknown", None).unwrap_err();
+        assert_eq!(unknown.code, -32601);
+
+        let invalid = router.handle_notification(&mut state, "textDocument/didClose", Some(json!({}))).unwrap_err();
+        assert_eq!(invalid.code, -32602);
+        assert!(state.closed.is_empty());
+    }
+
+    #[test]
+    fn test_read_requests_run_on_a_snapshot() {
+        let mut router: Router<State, Vec<String>> = Router::new();
+        router.on_read_request::<HoverRequest, _>(|log, params| {
+            log.push(params.text_document_position_params.text_document.uri.to_string());
+            Ok(None)
+        });
+        assert!(router.has_request("textDocument/hover"));
+        assert!(router.read_request(json!(1), "shutdown", None).is_none(), "only read requests become jobs");
+
+        let job = router.read_request(json!(1), "textDocument/hover", Some(hover_params())).unwrap();
+        let mut snapshot = Vec::new();
+        let response = std::thread::spawn(move || {
+            let response = job.run(&mut snapshot);
+            (response, snapshot)
+        })
+        .join()
+        .unwrap();
+        assert_eq!(content(&response.0)["id"], 1);
+        assert_eq!(response.1, vec!["file:///a.rs".to_string()]);
+
+        // 同じメソッドを書き換えるハンドラとして登録し直すと、読み取り専用ではなくなる
+        router.on_request::<HoverRequest, _>(|state, _| {
+            state.hover_count += 1;
+            Ok(None)
+        });
+        assert!(router.read_request(json!(2), "textDocument/hover", Some(hover_params())).is_none());
+        let mut state = State::default();
+        router.handle_request(&mut state, json!(2), "textDocument/hover", Some(hover_params()));
+        assert_eq!(state.hover_count, 1);
+    }
+}
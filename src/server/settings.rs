@@ -0,0 +1,219 @@
+// サーバーの設定
+// クライアントの設定の "toyLangServer" セクションを読んで、各機能のオプションにする
+// - 起動時（initialized の後）に workspace/configuration で取りに行き、workspace/didChangeConfiguration で読み直す
+// - セクションごとに読み、読めなかった・値がおかしいセクションはデフォルトのまま警告を返す
+//   （1つの書き間違いで他の設定まで効かなくならないように）
+//
+// 例:
+// {"toyLangServer": {
+//     "diagnostics": {"todoKeywords": ["TODO", "FIXME"]},
+//     "formatting": {"indentWidth": 2},
+//     "inlayHints": {"enabled": false},
+//     "codeLens": {"kinds": ["test", "main"]}
+// }}
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::lessons::lesson_1::lesson_1_36::FunctionType;
+
+// クライアントの設定のうち、このサーバーが読むセクション
+pub const SECTION: &str = "toyLangServer";
+
+// indentWidth に指定できる範囲
+const INDENT_WIDTHS: std::ops::RangeInclusive<u32> = 1..=16;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Settings {
+    pub diagnostics: DiagnosticsSettings,
+    pub formatting: FormattingSettings,
+    pub inlay_hints: InlayHintsSettings,
+    pub code_lens: CodeLensSettings,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase", deny_unknown_fields)]
+pub struct DiagnosticsSettings {
+    // この言葉を含む行を lesson_1_13 の診断で報告する（大文字・小文字は区別しない）
+    pub todo_keywords: Vec<String>,
+}
+
+impl Default for DiagnosticsSettings {
+    fn default() -> Self {
+        DiagnosticsSettings { todo_keywords: vec!["TODO".to_string()] }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase", deny_unknown_fields)]
+pub struct FormattingSettings {
+    // 1つのインデントレベルのスペースの数。None ならリクエストの options.tabSize を使う
+    pub indent_width: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase", deny_unknown_fields)]
+pub struct InlayHintsSettings {
+    pub enabled: bool,
+}
+
+impl Default for InlayHintsSettings {
+    fn default() -> Self {
+        InlayHintsSettings { enabled: true }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase", deny_unknown_fields)]
+pub struct CodeLensSettings {
+    // CodeLens を付ける関数の種類（"test" / "main" / "references"）
+    pub kinds: Vec<FunctionType>,
+}
+
+impl Default for CodeLensSettings {
+    fn default() -> Self {
+        CodeLensSettings { kinds: vec![FunctionType::Test, FunctionType::Main, FunctionType::Regular] }
+    }
+}
+
+impl Settings {
+    // "toyLangServer" セクションの中身から設定を作る
+    // 戻り値の2つ目は、無視した設定についての警告（ユーザーに見せる）
+    pub fn from_value(value: &Value) -> (Settings, Vec<String>) {
+        let mut settings = Settings::default();
+        let mut warnings = Vec::new();
+
+        let sections = match value {
+            // 設定が無い（クライアントが null を返した）ならデフォルトのまま
+            Value::Null => return (settings, warnings),
+            Value::Object(sections) => sections,
+            _ => {
+                warnings.push(format!("Ignored setting '{}': expected an object.", SECTION));
+                return (settings, warnings);
+            }
+        };
+
+        for (name, value) in sections {
+            let result = match name.as_str() {
+                "diagnostics" => read_section(name, value, validate_diagnostics).map(|section| settings.diagnostics = section),
+                "formatting" => read_section(name, value, validate_formatting).map(|section| settings.formatting = section),
+                "inlayHints" => read_section(name, value, |_| Ok(())).map(|section| settings.inlay_hints = section),
+                "codeLens" => read_section(name, value, |_| Ok(())).map(|section| settings.code_lens = section),
+                _ => Err(format!("Ignored setting '{}.{}': unknown setting.", SECTION, name)),
+            };
+            if let Err(warning) = result {
+                warnings.push(warning);
+            }
+        }
+        (settings, warnings)
+    }
+}
+
+// セクション name を読んで確かめる
+// 失敗した場合は、どの設定が悪かったのか（例: "toyLangServer.formatting.indentWidth"）を警告に含める
+fn read_section<T: DeserializeOwned>(
+    name: &str,
+    value: &Value,
+    validate: impl FnOnce(&T) -> Result<(), (&'static str, String)>,
+) -> Result<T, String> {
+    let ignored = |path: &str, message: &dyn std::fmt::Display| {
+        let path = if path.is_empty() || path == "." { String::new() } else { format!(".{}", path) };
+        format!("Ignored setting '{}.{}{}': {}", SECTION, name, path, message)
+    };
+
+    let section: T = serde_path_to_error::deserialize(value).map_err(|err| ignored(&err.path().to_string(), err.inner()))?;
+    validate(&section).map_err(|(field, message)| ignored(field, &message))?;
+    Ok(section)
+}
+
+fn validate_diagnostics(section: &DiagnosticsSettings) -> Result<(), (&'static str, String)> {
+    if section.todo_keywords.iter().any(|keyword| keyword.trim().is_empty()) {
+        return Err(("todoKeywords", "keywords must not be empty.".to_string()));
+    }
+    Ok(())
+}
+
+fn validate_formatting(section: &FormattingSettings) -> Result<(), (&'static str, String)> {
+    match section.indent_width {
+        Some(width) if !INDENT_WIDTHS.contains(&width) => Err((
+            "indentWidth",
+            format!("expected a width from {} to {}, got {}.", INDENT_WIDTHS.start(), INDENT_WIDTHS.end(), width),
+        )),
+        _ => Ok(()),
+    }
+}
+
+
+// --- Tests --- //
+
+#[cfg(test)]
+mod tests {
+    use super::Settings;
+    use crate::lessons::lesson_1::lesson_1_36::FunctionType;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn test_missing_settings_use_defaults() {
+        let (settings, warnings) = Settings::from_value(&Value::Null);
+        assert_eq!(settings, Settings::default());
+        assert!(warnings.is_empty());
+
+        assert_eq!(settings.diagnostics.todo_keywords, vec!["TODO"]);
+        assert_eq!(settings.formatting.indent_width, None);
+        assert!(settings.inlay_hints.enabled);
+        assert_eq!(settings.code_lens.kinds.len(), 3);
+    }
+
+    #[test]
+    fn test_reads_every_section() {
+        let (settings, warnings) = Settings::from_value(&json!({
+            "diagnostics": {"todoKeywords": ["FIXME", "XXX"]},
+            "formatting": {"indentWidth": 2},
+            "inlayHints": {"enabled": false},
+            "codeLens": {"kinds": ["test"]}
+        }));
+
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert_eq!(settings.diagnostics.todo_keywords, vec!["FIXME", "XXX"]);
+        assert_eq!(settings.formatting.indent_width, Some(2));
+        assert!(!settings.inlay_hints.enabled);
+        assert_eq!(settings.code_lens.kinds, vec![FunctionType::Test]);
+    }
+
+    #[test]
+    fn test_invalid_sections_fall_back_with_warnings() {
+        let (settings, warnings) = Settings::from_value(&json!({
+            "diagnostics": {"todoKeywords": ["FIXME", ""]},
+            "formatting": {"indentWidth": 0},
+            "inlayHints": {"enabled": "no"},
+            "codeLens": {"kinds": ["test", "debug"]},
+            "hover": {}
+        }));
+
+        // 読めたセクションが無いので、すべてデフォルトのまま
+        assert_eq!(settings, Settings::default());
+        assert_eq!(warnings.len(), 5, "{:?}", warnings);
+        assert_eq!(warnings[0], "Ignored setting 'toyLangServer.codeLens.kinds[1]': unknown variant `debug`, expected one of `test`, `main`, `references`");
+        assert_eq!(warnings[1], "Ignored setting 'toyLangServer.diagnostics.todoKeywords': keywords must not be empty.");
+        assert_eq!(warnings[2], "Ignored setting 'toyLangServer.formatting.indentWidth': expected a width from 1 to 16, got 0.");
+        assert!(warnings[3].starts_with("Ignored setting 'toyLangServer.hover': unknown setting."));
+        assert!(warnings[4].starts_with("Ignored setting 'toyLangServer.inlayHints.enabled': invalid type"), "{}", warnings[4]);
+
+        let (_, warnings) = Settings::from_value(&json!(["not", "an", "object"]));
+        assert_eq!(warnings, vec!["Ignored setting 'toyLangServer': expected an object."]);
+    }
+
+    #[test]
+    fn test_one_bad_section_keeps_the_others() {
+        let (settings, warnings) = Settings::from_value(&json!({
+            "formatting": {"indentWidth": 2, "useTabs": true},
+            "inlayHints": {"enabled": false}
+        }));
+
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("unknown field `useTabs`"), "{}", warnings[0]);
+        assert_eq!(settings.formatting.indent_width, None, "the whole section is ignored");
+        assert!(!settings.inlay_hints.enabled, "other sections still apply");
+    }
+}
@@ -0,0 +1,311 @@
+// common::syntax の木から作るドキュメントシンボル
+// - fn / struct / enum / trait / impl / mod / const / static / type を、入れ子のまま DocumentSymbol にする
+//   - 構造体のフィールドと enum のバリアントは、その下に置く
+//   - impl / trait の中の fn はメソッドとして、その下に置く
+//   - 関数の本体（if やループのブロックの中も）で定義した fn は、その関数の下に置く
+// - range は属性や pub も含めたアイテム全体、selectionRange は名前（impl は型の部分）
+// - let で作る変数はシンボルにしない
+
+use lsp_types::{DocumentSymbol, PositionEncodingKind, SymbolKind};
+
+use crate::common::line_index::LineIndex;
+use crate::common::syntax::{split_statements, token_trees, tokenize, Token, TokenKind, TokenTree};
+
+// fn の前に付けられる修飾（`extern "C"` の文字列も飛ばす）
+const QUALIFIERS: [&str; 4] = ["unsafe", "async", "const", "extern"];
+
+pub fn document_symbols(text: &str, encoding: &PositionEncodingKind) -> Vec<DocumentSymbol> {
+    let index = LineIndex::new(text, encoding.clone());
+    let tokens = tokenize(text);
+    let collector = SymbolCollector { text, index: &index };
+    collector.items(&token_trees(&tokens), false)
+}
+
+struct SymbolCollector<'a> {
+    text: &'a str,
+    index: &'a LineIndex,
+}
+
+impl SymbolCollector<'_> {
+    // trees の中のアイテム（in_impl なら fn をメソッドにする）
+    // アイテムでない文（let や式）の中の { } も探す
+    fn items(&self, trees: &[TokenTree], in_impl: bool) -> Vec<DocumentSymbol> {
+        let mut symbols = Vec::new();
+        for statement in split_statements(trees) {
+            match self.item(statement, in_impl) {
+                Some(symbol) => symbols.push(symbol),
+                None => {
+                    for tree in statement {
+                        if let TokenTree::Group { children, .. } = tree {
+                            symbols.extend(self.items(children, false));
+                        }
+                    }
+                }
+            }
+        }
+        symbols
+    }
+
+    fn item(&self, statement: &[TokenTree], in_impl: bool) -> Option<DocumentSymbol> {
+        let (position, keyword) = item_keyword(statement)?;
+        let rest = &statement[position + 1..];
+        let name = rest.first().and_then(TokenTree::leaf).filter(|token| token.kind == TokenKind::Ident);
+        let body = rest.iter().find_map(|tree| tree.group("{"));
+        let range = (statement[0].first().start, statement[statement.len() - 1].end());
+
+        let symbol = match keyword.text {
+            "fn" => {
+                let name = name?;
+                let signature_end = rest.iter().find(|tree| tree.group("{").is_some()).map_or(range.1, |body| body.first().start);
+                let kind = if in_impl { SymbolKind::METHOD } else { SymbolKind::FUNCTION };
+                let children = body.map(|body| self.items(body, false)).unwrap_or_default();
+                with_detail(self.symbol(name.text.to_string(), kind, range, name, children), self.source(keyword.start, signature_end))
+            }
+            "struct" => {
+                let name = name?;
+                let fields = body.map(|body| self.members(body, SymbolKind::FIELD)).unwrap_or_default();
+                self.symbol(name.text.to_string(), SymbolKind::STRUCT, range, name, fields)
+            }
+            "enum" => {
+                let name = name?;
+                let variants = body.map(|body| self.members(body, SymbolKind::ENUM_MEMBER)).unwrap_or_default();
+                self.symbol(name.text.to_string(), SymbolKind::ENUM, range, name, variants)
+            }
+            "trait" => {
+                let name = name?;
+                let methods = body.map(|body| self.items(body, true)).unwrap_or_default();
+                self.symbol(name.text.to_string(), SymbolKind::INTERFACE, range, name, methods)
+            }
+            "mod" => {
+                let name = name?;
+                let items = body.map(|body| self.items(body, false)).unwrap_or_default();
+                self.symbol(name.text.to_string(), SymbolKind::MODULE, range, name, items)
+            }
+            "const" | "static" | "type" => {
+                let name = name?;
+                let kind = match keyword.text {
+                    "const" => SymbolKind::CONSTANT,
+                    "static" => SymbolKind::VARIABLE,
+                    _ => SymbolKind::TYPE_PARAMETER,
+                };
+                self.symbol(name.text.to_string(), kind, range, name, Vec::new())
+            }
+            "impl" => {
+                // `impl<T> Trait for Type` の `Trait for Type` の部分
+                let header: Vec<&Token> = skip_generics(rest).iter().map_while(TokenTree::leaf).collect();
+                let (first, last) = (header.first()?, header.last()?);
+                let target = (first.start, last.end());
+                let methods = body.map(|body| self.items(body, true)).unwrap_or_default();
+                let mut symbol = self.symbol(
+                    format!("impl {}", self.source(target.0, target.1)),
+                    SymbolKind::OBJECT,
+                    range,
+                    first,
+                    methods,
+                );
+                symbol.selection_range = self.index.range(target.0..target.1);
+                symbol
+            }
+            _ => return None,
+        };
+        Some(symbol)
+    }
+
+    // struct のフィールド / enum のバリアント（`,` で区切った1つずつ）
+    fn members(&self, body: &[TokenTree], kind: SymbolKind) -> Vec<DocumentSymbol> {
+        split_members(body)
+            .into_iter()
+            .filter_map(|member| {
+                let position = skip_attributes(member);
+                let name = member.get(position)?.leaf().filter(|token| token.is_ident())?;
+                let range = (member[0].first().start, member[member.len() - 1].end());
+                let symbol = self.symbol(name.text.to_string(), kind, range, name, Vec::new());
+                // フィールドの型
+                let colon = member.get(position + 1).and_then(TokenTree::leaf).filter(|token| token.is_punct(":"));
+                Some(match colon {
+                    Some(colon) if kind == SymbolKind::FIELD => with_detail(symbol, self.source(colon.end(), range.1)),
+                    _ => symbol,
+                })
+            })
+            .collect()
+    }
+
+    #[allow(deprecated)] // DocumentSymbol::deprecated は非推奨だが、構造体を作るには指定が必要
+    fn symbol(&self, name: String, kind: SymbolKind, range: (usize, usize), selection: &Token, children: Vec<DocumentSymbol>) -> DocumentSymbol {
+        DocumentSymbol {
+            name,
+            detail: None,
+            kind,
+            tags: None,
+            deprecated: None,
+            range: self.index.range(range.0..range.1),
+            selection_range: self.index.range(selection.range()),
+            children: (!children.is_empty()).then_some(children),
+        }
+    }
+
+    // start..end のテキスト（改行や連続する空白は1つの空白にする）
+    fn source(&self, start: usize, end: usize) -> String {
+        self.text[start..end].split_whitespace().collect::<Vec<_>>().join(" ")
+    }
+}
+
+fn with_detail(mut symbol: DocumentSymbol, detail: String) -> DocumentSymbol {
+    symbol.detail = (!detail.is_empty()).then_some(detail);
+    symbol
+}
+
+// アイテムの種類を表すキーワードと、その位置（属性・pub・修飾は飛ばす）
+fn item_keyword<'t, 'a>(statement: &'t [TokenTree<'a>]) -> Option<(usize, &'t Token<'a>)> {
+    let mut position = skip_attributes(statement);
+    loop {
+        let token = statement.get(position)?.leaf()?;
+        let qualifier = (token.kind == TokenKind::Ident && QUALIFIERS.contains(&token.text)) || token.kind == TokenKind::Str;
+        // `const NAME` は修飾ではなく定数
+        let next_is_name = statement.get(position + 1).and_then(TokenTree::leaf).is_some_and(Token::is_ident);
+        if qualifier && !(token.is_keyword("const") && next_is_name) {
+            position += 1;
+            continue;
+        }
+        return (token.kind == TokenKind::Ident).then_some((position, token));
+    }
+}
+
+// 先頭の属性 `#[...]` と `pub` / `pub(crate)` を飛ばした位置
+fn skip_attributes(trees: &[TokenTree]) -> usize {
+    let mut position = 0;
+    loop {
+        match trees.get(position).and_then(TokenTree::leaf) {
+            Some(token) if token.is_punct("#") => {
+                position += if trees.get(position + 1).is_some_and(|tree| tree.group("[").is_some()) { 2 } else { 1 };
+            }
+            Some(token) if token.is_keyword("pub") => {
+                position += if trees.get(position + 1).is_some_and(|tree| tree.group("(").is_some()) { 2 } else { 1 };
+            }
+            _ => return position,
+        }
+    }
+}
+
+// `impl<T>` の `<T>` を飛ばす
+fn skip_generics<'t, 'a>(trees: &'t [TokenTree<'a>]) -> &'t [TokenTree<'a>] {
+    if !trees.first().and_then(TokenTree::leaf).is_some_and(|token| token.is_punct("<")) {
+        return trees;
+    }
+    let mut depth = 0;
+    for (i, tree) in trees.iter().enumerate() {
+        match tree.leaf() {
+            Some(token) if token.is_punct("<") => depth += 1,
+            Some(token) if token.is_punct(">") => {
+                depth -= 1;
+                if depth == 0 {
+                    return &trees[i + 1..];
+                }
+            }
+            _ => {}
+        }
+    }
+    &[]
+}
+
+// `,` で区切る（`HashMap<K, V>` のような型引数の中の `,` では区切らない）
+fn split_members<'t, 'a>(trees: &'t [TokenTree<'a>]) -> Vec<&'t [TokenTree<'a>]> {
+    let mut members = Vec::new();
+    let mut start = 0;
+    let mut depth = 0usize;
+    for (i, tree) in trees.iter().enumerate() {
+        match tree.leaf() {
+            Some(token) if token.is_punct("<") => depth += 1,
+            Some(token) if token.is_punct(">") => depth = depth.saturating_sub(1),
+            Some(token) if token.is_punct(",") && depth == 0 => {
+                members.push(&trees[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    members.push(&trees[start..]);
+    members.into_iter().filter(|member| !member.is_empty()).collect()
+}
+
+
+// --- Tests --- //
+
+#[cfg(test)]
+mod tests {
+    use super::document_symbols;
+    use lsp_types::{DocumentSymbol, Position, PositionEncodingKind, Range, SymbolKind};
+
+    fn symbols(text: &str) -> Vec<DocumentSymbol> {
+        document_symbols(text, &PositionEncodingKind::UTF16)
+    }
+
+    // (名前, 種類, 子) の木にして比べる
+    type Outline = Vec<(String, SymbolKind, Vec<(String, SymbolKind)>)>;
+
+    fn outline(symbols: &[DocumentSymbol]) -> Outline {
+        symbols
+            .iter()
+            .map(|symbol| {
+                let children = symbol.children.iter().flatten().map(|child| (child.name.clone(), child.kind)).collect();
+                (symbol.name.clone(), symbol.kind, children)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_fields_methods_and_local_functions_are_nested() {
+        let text = "#[derive(Debug)]\npub struct Point {\n    pub x: i32,\n    labels: HashMap<String, Vec<i32>>,\n}\n\nimpl Point {\n    pub fn norm(&self) -> i32 {\n        fn square(v: i32) -> i32 {\n            v * v\n        }\n        square(self.x)\n    }\n}\n\nfn main() {\n    if true {\n        fn helper() {}\n    }\n    let p = Point { x: 1, labels: HashMap::new() };\n}";
+        let symbols = symbols(text);
+
+        assert_eq!(
+            outline(&symbols),
+            vec![
+                (
+                    "Point".to_string(),
+                    SymbolKind::STRUCT,
+                    vec![("x".to_string(), SymbolKind::FIELD), ("labels".to_string(), SymbolKind::FIELD)]
+                ),
+                ("impl Point".to_string(), SymbolKind::OBJECT, vec![("norm".to_string(), SymbolKind::METHOD)]),
+                ("main".to_string(), SymbolKind::FUNCTION, vec![("helper".to_string(), SymbolKind::FUNCTION)]),
+            ]
+        );
+
+        // 属性も含めた全体と、名前
+        assert_eq!(symbols[0].range, Range::new(Position::new(0, 0), Position::new(4, 1)));
+        assert_eq!(symbols[0].selection_range, Range::new(Position::new(1, 11), Position::new(1, 16)));
+        let labels = &symbols[0].children.as_ref().unwrap()[1];
+        assert_eq!(labels.detail.as_deref(), Some("HashMap<String, Vec<i32>>"));
+
+        let norm = &symbols[1].children.as_ref().unwrap()[0];
+        assert_eq!(norm.detail.as_deref(), Some("fn norm(&self) -> i32"));
+        assert_eq!(norm.children.as_ref().unwrap()[0].name, "square", "a function local to the method");
+        assert_eq!(symbols[1].selection_range, Range::new(Position::new(6, 5), Position::new(6, 10)));
+    }
+
+    #[test]
+    fn test_other_items() {
+        let text = "mod shapes {\n    pub enum Shape {\n        Circle(f64),\n        Square { side: f64 },\n    }\n}\nmod io;\n\ntrait Area {\n    fn area(&self) -> f64;\n}\n\nimpl<T> Area for Wrapper<T> {}\n\nconst MAX: usize = 10;\nstatic NAME: &str = \"toy\";\ntype Id = u32;\npub(crate) const unsafe fn raw() {}";
+
+        let symbols = symbols(text);
+        assert_eq!(
+            outline(&symbols),
+            vec![
+                ("shapes".to_string(), SymbolKind::MODULE, vec![("Shape".to_string(), SymbolKind::ENUM)]),
+                ("io".to_string(), SymbolKind::MODULE, vec![]),
+                ("Area".to_string(), SymbolKind::INTERFACE, vec![("area".to_string(), SymbolKind::METHOD)]),
+                ("impl Area for Wrapper<T>".to_string(), SymbolKind::OBJECT, vec![]),
+                ("MAX".to_string(), SymbolKind::CONSTANT, vec![]),
+                ("NAME".to_string(), SymbolKind::VARIABLE, vec![]),
+                ("Id".to_string(), SymbolKind::TYPE_PARAMETER, vec![]),
+                ("raw".to_string(), SymbolKind::FUNCTION, vec![]),
+            ]
+        );
+
+        let shape = &symbols[0].children.as_ref().unwrap()[0];
+        assert_eq!(
+            outline(shape.children.as_ref().unwrap()),
+            vec![("Circle".to_string(), SymbolKind::ENUM_MEMBER, vec![]), ("Square".to_string(), SymbolKind::ENUM_MEMBER, vec![])]
+        );
+    }
+}
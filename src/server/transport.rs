@@ -0,0 +1,307 @@
+// LSPメッセージのトランスポート層
+// lesson_1_2 / lesson_1_3 は「1つの完全なメッセージ文字列」しか扱えなかったので、
+// ここでは任意の BufRead (stdin、ソケット、テスト用パイプなど) からフレーム単位でメッセージを読み出す。
+//
+// フレームの形式:
+//   Content-Length: 52\r\n
+//   Content-Type: application/vscode-jsonrpc; charset=utf-8\r\n
+//   \r\n
+//   {"jsonrpc":"2.0", ...}
+
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+// フレームの読み取りに失敗した理由
+#[derive(Debug)]
+pub enum MessageReadError {
+    Io(io::Error),
+    // ヘッダー行に ':' が無い
+    MalformedHeader(String),
+    // Content-Length ヘッダーが1つも無い
+    MissingContentLength,
+    // Content-Length ヘッダーが複数ある
+    DuplicateContentLength,
+    // Content-Length の値が数値として読めない
+    InvalidContentLength(String),
+    // ヘッダーや本文の途中でストリームが終わった
+    UnexpectedEof,
+    // ヘッダーまたは本文が UTF-8 ではない
+    InvalidUtf8,
+}
+
+impl fmt::Display for MessageReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageReadError::Io(err) => write!(f, "I/O error: {}", err),
+            MessageReadError::MalformedHeader(line) => write!(f, "malformed header line: {:?}", line),
+            MessageReadError::MissingContentLength => write!(f, "missing Content-Length header"),
+            MessageReadError::DuplicateContentLength => write!(f, "duplicate Content-Length header"),
+            MessageReadError::InvalidContentLength(value) => write!(f, "invalid Content-Length value: {:?}", value),
+            MessageReadError::UnexpectedEof => write!(f, "unexpected end of stream inside a message"),
+            MessageReadError::InvalidUtf8 => write!(f, "message is not valid UTF-8"),
+        }
+    }
+}
+
+impl std::error::Error for MessageReadError {}
+
+impl From<io::Error> for MessageReadError {
+    fn from(err: io::Error) -> Self {
+        if err.kind() == io::ErrorKind::UnexpectedEof {
+            MessageReadError::UnexpectedEof
+        } else {
+            MessageReadError::Io(err)
+        }
+    }
+}
+
+pub struct MessageReader<R> {
+    reader: R,
+}
+
+impl<R: BufRead> MessageReader<R> {
+    pub fn new(reader: R) -> Self {
+        MessageReader { reader }
+    }
+
+    // 次のメッセージを1つ読み出す
+    // - 戻り値は `parse_lsp_message` / `parse_full_lsp_message` にそのまま渡せる
+    //   「ヘッダー + \r\n\r\n + 本文」の形の文字列
+    // - メッセージの境界でストリームが終わった場合は Ok(None)（正常終了）
+    pub fn read_message(&mut self) -> Result<Option<String>, MessageReadError> {
+        let mut header_lines: Vec<String> = Vec::new();
+        let mut content_length: Option<usize> = None;
+
+        loop {
+            let mut raw_line = Vec::new();
+            let read = self.reader.read_until(b'\n', &mut raw_line)?;
+            if read == 0 {
+                // ヘッダーを読み始める前のEOFはクリーンな終了
+                return if header_lines.is_empty() {
+                    Ok(None)
+                } else {
+                    Err(MessageReadError::UnexpectedEof)
+                };
+            }
+            if !raw_line.ends_with(b"\n") {
+                return Err(MessageReadError::UnexpectedEof);
+            }
+
+            let line = String::from_utf8(raw_line).map_err(|_| MessageReadError::InvalidUtf8)?;
+            let line = line.trim_end_matches('\n').trim_end_matches('\r');
+
+            // 空行がヘッダーの終わり
+            if line.is_empty() {
+                break;
+            }
+
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| MessageReadError::MalformedHeader(line.to_string()))?;
+
+            // ヘッダー名は大文字小文字を区別しない
+            if name.trim().eq_ignore_ascii_case("Content-Length") {
+                if content_length.is_some() {
+                    return Err(MessageReadError::DuplicateContentLength);
+                }
+                let value = value.trim();
+                let length = value
+                    .parse::<usize>()
+                    .map_err(|_| MessageReadError::InvalidContentLength(value.to_string()))?;
+                content_length = Some(length);
+            }
+            // Content-Type などその他のヘッダーは読み飛ばすだけ
+
+            header_lines.push(line.to_string());
+        }
+
+        let content_length = content_length.ok_or(MessageReadError::MissingContentLength)?;
+
+        let mut content = vec![0u8; content_length];
+        self.reader.read_exact(&mut content)?;
+        let content = String::from_utf8(content).map_err(|_| MessageReadError::InvalidUtf8)?;
+
+        Ok(Some(format!("{}\r\n\r\n{}", header_lines.join("\r\n"), content)))
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+// `for message in MessageReader::new(stdin.lock())` のように使えるようにする
+impl<R: BufRead> Iterator for MessageReader<R> {
+    type Item = Result<String, MessageReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_message().transpose()
+    }
+}
+
+// フレーム済みのメッセージ（create_lsp_response などの戻り値）を書き出してすぐに flush する
+// クライアントは応答を待っているので、バッファに溜めたままにしない
+pub fn write_message<W: Write>(writer: &mut W, message: &str) -> io::Result<()> {
+    writer.write_all(message.as_bytes())?;
+    writer.flush()
+}
+
+
+// --- Tests --- //
+
+#[cfg(test)]
+mod tests {
+    use super::{write_message, MessageReadError, MessageReader};
+    use crate::lessons::lesson_1::lesson_1_2::parse_lsp_message;
+    use std::io::{BufReader, Cursor, Read};
+
+    fn frame(content: &str) -> String {
+        format!("Content-Length: {}\r\n\r\n{}", content.len(), content)
+    }
+
+    // 1回の read で最大 chunk_size バイトしか返さないリーダー（分割受信のシミュレーション）
+    struct ChunkedReader {
+        data: Vec<u8>,
+        position: usize,
+        chunk_size: usize,
+    }
+
+    impl Read for ChunkedReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let remaining = &self.data[self.position..];
+            let n = remaining.len().min(buf.len()).min(self.chunk_size);
+            buf[..n].copy_from_slice(&remaining[..n]);
+            self.position += n;
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_read_single_message() {
+        let input = frame(r#"{"jsonrpc":"2.0"}"#);
+        let mut reader = MessageReader::new(Cursor::new(input.clone()));
+
+        let message = reader.read_message().unwrap().expect("should read a message");
+        assert_eq!(message, input);
+        assert_eq!(parse_lsp_message(&message), Some(("Content-Length: 17", r#"{"jsonrpc":"2.0"}"#)));
+        assert!(reader.read_message().unwrap().is_none(), "clean EOF should return None");
+    }
+
+    #[test]
+    fn test_read_back_to_back_messages() {
+        let first = r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#;
+        let second = r#"{"jsonrpc":"2.0","method":"initialized","params":{}}"#;
+        let input = format!("{}{}", frame(first), frame(second));
+
+        let contents: Vec<String> = MessageReader::new(Cursor::new(input))
+            .map(|message| parse_lsp_message(&message.unwrap()).unwrap().1.to_string())
+            .collect();
+
+        assert_eq!(contents, vec![first.to_string(), second.to_string()]);
+    }
+
+    #[test]
+    fn test_read_message_split_across_reads() {
+        let first = r#"{"jsonrpc":"2.0","id":1,"method":"shutdown"}"#;
+        let second = r#"{"jsonrpc":"2.0","method":"exit"}"#;
+        let input = format!("{}{}", frame(first), frame(second));
+        let chunked = ChunkedReader { data: input.into_bytes(), position: 0, chunk_size: 3 };
+        let mut reader = MessageReader::new(BufReader::with_capacity(4, chunked));
+
+        let message = reader.read_message().unwrap().unwrap();
+        assert_eq!(parse_lsp_message(&message).unwrap().1, first);
+        let message = reader.read_message().unwrap().unwrap();
+        assert_eq!(parse_lsp_message(&message).unwrap().1, second);
+        assert!(reader.read_message().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_read_message_with_extra_headers_and_any_case() {
+        let content = r#"{"jsonrpc":"2.0"}"#;
+        let input = format!(
+            "content-type: application/vscode-jsonrpc; charset=utf-8\r\nCONTENT-LENGTH:{}\r\n\r\n{}",
+            content.len(),
+            content
+        );
+        let mut reader = MessageReader::new(Cursor::new(input));
+
+        let message = reader.read_message().unwrap().unwrap();
+        assert_eq!(parse_lsp_message(&message).unwrap().1, content);
+    }
+
+    #[test]
+    fn test_read_message_with_multibyte_content() {
+        // Content-Length は文字数ではなくバイト数
+        let content = r#"{"jsonrpc":"2.0","params":"日本語"}"#;
+        let mut reader = MessageReader::new(Cursor::new(frame(content)));
+
+        let message = reader.read_message().unwrap().unwrap();
+        assert_eq!(parse_lsp_message(&message).unwrap().1, content);
+    }
+
+    #[test]
+    fn test_missing_content_length() {
+        let input = "Content-Type: application/json\r\n\r\n{}";
+        let result = MessageReader::new(Cursor::new(input)).read_message();
+        assert!(matches!(result, Err(MessageReadError::MissingContentLength)));
+    }
+
+    #[test]
+    fn test_invalid_content_length() {
+        let input = "Content-Length: abc\r\n\r\n{}";
+        let result = MessageReader::new(Cursor::new(input)).read_message();
+        assert!(matches!(result, Err(MessageReadError::InvalidContentLength(value)) if value == "abc"));
+    }
+
+    #[test]
+    fn test_duplicate_content_length() {
+        let input = "Content-Length: 2\r\nContent-Length: 2\r\n\r\n{}";
+        let result = MessageReader::new(Cursor::new(input)).read_message();
+        assert!(matches!(result, Err(MessageReadError::DuplicateContentLength)));
+    }
+
+    #[test]
+    fn test_malformed_header_line() {
+        let input = "Content-Length 2\r\n\r\n{}";
+        let result = MessageReader::new(Cursor::new(input)).read_message();
+        assert!(matches!(result, Err(MessageReadError::MalformedHeader(_))));
+    }
+
+    #[test]
+    fn test_eof_inside_headers() {
+        let input = "Content-Length: 2\r\n";
+        let result = MessageReader::new(Cursor::new(input)).read_message();
+        assert!(matches!(result, Err(MessageReadError::UnexpectedEof)));
+    }
+
+    #[test]
+    fn test_eof_inside_content() {
+        let input = "Content-Length: 10\r\n\r\n{}";
+        let result = MessageReader::new(Cursor::new(input)).read_message();
+        assert!(matches!(result, Err(MessageReadError::UnexpectedEof)));
+    }
+
+    #[test]
+    fn test_invalid_utf8_content() {
+        let mut input = b"Content-Length: 2\r\n\r\n".to_vec();
+        input.extend_from_slice(&[0xff, 0xfe]);
+        let result = MessageReader::new(Cursor::new(input)).read_message();
+        assert!(matches!(result, Err(MessageReadError::InvalidUtf8)));
+    }
+
+    #[test]
+    fn test_write_message_round_trip() {
+        let message = frame(r#"{"jsonrpc":"2.0","id":1,"result":null}"#);
+        let mut output = Vec::new();
+        write_message(&mut output, &message).unwrap();
+
+        let mut reader = MessageReader::new(Cursor::new(output));
+        assert_eq!(reader.read_message().unwrap(), Some(message));
+    }
+
+    #[test]
+    fn test_empty_input_is_clean_eof() {
+        let mut reader = MessageReader::new(Cursor::new(""));
+        assert!(reader.read_message().unwrap().is_none());
+        assert!(reader.next().is_none());
+    }
+}
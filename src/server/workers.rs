@@ -0,0 +1,127 @@
+// ワーカースレッドのプール
+// 読み取り専用のリクエスト（hover や find_references など）をメインループとは別のスレッドで処理する。
+// メインループは仕事を渡したらすぐ次のメッセージ（didChange など）に進めるので、重いリクエストの間も入力が待たされない。
+// - 仕事は渡された順に空いているスレッドが取っていくので、終わる順番は渡した順番と限らない
+// - プールを捨てる（drop）と、残っている仕事を終えてからスレッドを止める
+// - 仕事が panic してもスレッドは止めない（止めると、スレッドの数だけ panic した後は何も処理されなくなる）
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+pub struct WorkerPool {
+    // None になったら（drop の途中）スレッドに終わりを知らせる
+    sender: Option<Sender<Job>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    // size 本のスレッドを起動する（0 なら 1 本）
+    pub fn new(size: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let threads = (0..size.max(1))
+            .map(|index| {
+                let receiver = receiver.clone();
+                thread::Builder::new()
+                    .name(format!("toy-lang-server-worker-{}", index))
+                    .spawn(move || loop {
+                        // 取り出す間だけロックする（仕事をしている間は他のスレッドが次の仕事を取れる）
+                        let job = receiver.lock().unwrap().recv();
+                        match job {
+                            // 仕事の結果を待っている側へは何も届かないので、応答が必要なら仕事の中で panic を受け止めておく
+                            Ok(job) => {
+                                let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                            }
+                            Err(_) => break,
+                        }
+                    })
+                    .expect("failed to spawn a worker thread")
+            })
+            .collect();
+
+        WorkerPool { sender: Some(sender), threads }
+    }
+
+    // CPU の数に合わせた大きさのプール
+    pub fn with_available_parallelism() -> Self {
+        Self::new(thread::available_parallelism().map_or(2, |threads| threads.get()))
+    }
+
+    pub fn size(&self) -> usize {
+        self.threads.len()
+    }
+
+    pub fn execute(&self, job: impl FnOnce() + Send + 'static) {
+        if let Some(sender) = &self.sender {
+            // スレッドは drop の中でしか止まらないので、ここでは必ず受け取ってもらえる
+            let _ = sender.send(Box::new(job));
+        }
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        self.sender.take();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+
+// --- Tests --- //
+
+#[cfg(test)]
+mod tests {
+    use super::WorkerPool;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_jobs_finish_out_of_order() {
+        let pool = WorkerPool::new(2);
+        let (done, finished) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+
+        // 1つ目の仕事は止めておき、2つ目の仕事が先に終わることを確かめる
+        let slow = done.clone();
+        pool.execute(move || {
+            released.recv().unwrap();
+            slow.send("slow").unwrap();
+        });
+        pool.execute(move || done.send("fast").unwrap());
+
+        assert_eq!(finished.recv_timeout(Duration::from_secs(5)), Ok("fast"));
+        release.send(()).unwrap();
+        assert_eq!(finished.recv_timeout(Duration::from_secs(5)), Ok("slow"));
+    }
+
+    #[test]
+    fn test_thread_survives_a_panicking_job() {
+        let pool = WorkerPool::new(1);
+        let (done, finished) = mpsc::channel();
+        pool.execute(|| panic!("job failed"));
+        pool.execute(move || done.send("after").unwrap());
+
+        assert_eq!(finished.recv_timeout(Duration::from_secs(5)), Ok("after"));
+    }
+
+    #[test]
+    fn test_drop_waits_for_queued_jobs() {
+        let (done, finished) = mpsc::channel();
+        let pool = WorkerPool::new(1);
+        assert_eq!(pool.size(), 1);
+        for index in 0..3 {
+            let done = done.clone();
+            pool.execute(move || done.send(index).unwrap());
+        }
+        drop(pool);
+
+        assert_eq!(finished.try_iter().collect::<Vec<_>>(), vec![0, 1, 2]);
+    }
+}
@@ -0,0 +1,422 @@
+// ディスク上のワークスペースの索引
+// エディタで開いていないファイルも workspace/symbol などで探せるように、
+// initialize の workspaceFolders（無ければ rootUri）の下にある .rs ファイルを読み込んでおく
+// - ファイルごとに、読み込んだ中身とシンボル（lesson_1_30 の関数・構造体）を持つ
+// - workspace/didChangeWatchedFiles で届いた作成・変更・削除を反映する
+//   削除はすぐに反映し、作成・変更はディスクを読む必要があるので、メインループの外で読んでから merge で取り込む
+// - 開いているドキュメントはエディタの中身のほうが新しいので、documents() ではディスクの中身より優先する
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use lsp_types::{FileChangeType, FileEvent, InitializeParams, PositionEncodingKind, SymbolInformation, Url};
+
+use crate::common::document_store::DocumentStore;
+use crate::lessons::lesson_1::lesson_1_30::all_symbols_in_document;
+
+// 読み込まないディレクトリ（ビルドの成果物など）。"." で始まる隠しディレクトリも読まない
+const IGNORED_DIRECTORIES: [&str; 2] = ["target", "node_modules"];
+
+#[derive(Debug, Clone)]
+pub struct WorkspaceIndex {
+    roots: Vec<PathBuf>,
+    // シンボルの Range の列を数える単位
+    encoding: PositionEncodingKind,
+    // ディスクから読んだファイル（version は 0）
+    files: DocumentStore,
+    symbols: HashMap<Url, Arc<[SymbolInformation]>>,
+    // didChangeWatchedFiles を反映した回数と、ファイル・ディレクトリごとに最後に変更が届いたときの回数
+    // 別に読み込んだ索引を merge するとき、読み始めた後に変わったファイルを見分けるのに使う
+    revision: u64,
+    changed: HashMap<Url, u64>,
+    // 変更が届いたのに読めなかった（もう無い・.rs でなくなった）ファイル。merge で取り込む側から外す
+    removed: Vec<Url>,
+}
+
+impl Default for WorkspaceIndex {
+    fn default() -> Self {
+        Self::new(Vec::new(), PositionEncodingKind::UTF16)
+    }
+}
+
+impl WorkspaceIndex {
+    // roots の下を索引する（まだ何も読み込まない。読み込むのは discover() と load_file() で）
+    pub fn new(roots: Vec<PathBuf>, encoding: PositionEncodingKind) -> Self {
+        WorkspaceIndex {
+            roots,
+            encoding,
+            files: DocumentStore::new(),
+            symbols: HashMap::new(),
+            revision: 0,
+            changed: HashMap::new(),
+            removed: Vec::new(),
+        }
+    }
+
+    pub fn roots(&self) -> &[PathBuf] {
+        &self.roots
+    }
+
+    // roots の下にある .rs ファイル（パスの順）
+    pub fn discover(&self) -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+        for root in &self.roots {
+            collect_rust_files(root, &mut paths);
+        }
+        paths.sort();
+        paths.dedup();
+        paths
+    }
+
+    // path を読み込んで索引に入れる（もうあれば読み直す）
+    // ワークスペースの外のファイル、.rs 以外、読めない（UTF-8 でない）ファイルは索引から外して false を返す
+    pub fn load_file(&mut self, path: &Path) -> bool {
+        let Ok(uri) = Url::from_file_path(path) else {
+            return false;
+        };
+        let text = is_rust_file(path)
+            .then(|| self.contains_path(path))
+            .filter(|inside| *inside)
+            .and_then(|_| fs::read_to_string(path).ok());
+        let Some(text) = text else {
+            self.remove(&uri);
+            return false;
+        };
+
+        let symbols = all_symbols_in_document(&uri, &text, &self.encoding);
+        self.files.open(uri.clone(), "rust", 0, &text);
+        self.symbols.insert(uri, symbols.into());
+        true
+    }
+
+    // uri（ディレクトリなら、その下のファイルすべて）を索引から外す
+    pub fn remove(&mut self, uri: &Url) {
+        let directory = format!("{}/", uri.as_str().trim_end_matches('/'));
+        let removed: Vec<Url> = self
+            .symbols
+            .keys()
+            .filter(|indexed| *indexed == uri || indexed.as_str().starts_with(&directory))
+            .cloned()
+            .collect();
+        for uri in removed {
+            self.files.close(&uri);
+            self.symbols.remove(&uri);
+        }
+    }
+
+    // workspace/didChangeWatchedFiles で届いた変更を反映する
+    // 削除はここで反映し、作成・変更されたものは返した FileReads で読み込む（ディスクは読まない）
+    #[must_use]
+    pub fn apply_file_events(&mut self, events: &[FileEvent]) -> FileReads {
+        self.revision += 1;
+        let mut paths = Vec::new();
+        for event in events {
+            let Ok(path) = event.uri.to_file_path() else {
+                continue;
+            };
+            self.changed.insert(event.uri.clone(), self.revision);
+            match event.typ {
+                FileChangeType::DELETED => self.remove(&event.uri),
+                _ => paths.push(path),
+            }
+        }
+
+        let mut index = WorkspaceIndex::new(self.roots.clone(), self.encoding.clone());
+        index.revision = self.revision;
+        FileReads { index, paths }
+    }
+
+    // 別に読み込んだ索引 indexed（この索引を clone してから読み込んだもの）を取り込む
+    // clone した後に didChangeWatchedFiles で変わった（作り直された・消された）ファイルは、こちらの中身を残す
+    pub fn merge(&mut self, mut indexed: WorkspaceIndex) {
+        let stale: Vec<Url> = indexed.symbols.keys().filter(|uri| self.changed_since(uri, indexed.revision)).cloned().collect();
+        for uri in &stale {
+            indexed.files.close(uri);
+            indexed.symbols.remove(uri);
+        }
+        self.files = self.files.overlaid_with(&indexed.files);
+        self.symbols.extend(indexed.symbols);
+        for uri in indexed.removed {
+            if !self.changed_since(&uri, indexed.revision) {
+                self.remove(&uri);
+            }
+        }
+    }
+
+    // ワークスペースのすべてのドキュメント
+    // open（エディタで開いているドキュメント）をディスクの中身の上に重ねる
+    pub fn documents(&self, open: &DocumentStore) -> DocumentStore {
+        self.files.overlaid_with(open)
+    }
+
+    // ディスクの中身から作ったシンボル
+    // 開いているドキュメントは中身が変わっているかもしれないので、呼び出し側でドキュメントから作り直す
+    pub fn symbols(&self, uri: &Url) -> Option<&[SymbolInformation]> {
+        self.symbols.get(uri).map(|symbols| symbols.as_ref())
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    // uri（か、それを含むディレクトリ）に revision より後の変更が届いているか
+    fn changed_since(&self, uri: &Url, revision: u64) -> bool {
+        self.changed.iter().any(|(changed, at)| {
+            let directory = format!("{}/", changed.as_str().trim_end_matches('/'));
+            *at > revision && (changed == uri || uri.as_str().starts_with(&directory))
+        })
+    }
+
+    fn contains_path(&self, path: &Path) -> bool {
+        self.roots.iter().any(|root| path.starts_with(root))
+    }
+}
+
+// didChangeWatchedFiles で作成・変更されたファイルのうち、まだディスクから読んでいないもの
+pub struct FileReads {
+    // 読み込んだものを入れる空の索引（revision は変更が届いたときのもの）
+    index: WorkspaceIndex,
+    paths: Vec<PathBuf>,
+}
+
+impl FileReads {
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
+    // ディスクから読み込む。できた索引は、変更が届いた索引に merge で取り込む
+    pub fn read(mut self) -> WorkspaceIndex {
+        for path in &self.paths {
+            // 作られたのがディレクトリなら、その下のファイルを読み込む
+            if path.is_dir() {
+                let mut paths = Vec::new();
+                collect_rust_files(path, &mut paths);
+                for path in paths {
+                    self.index.load_file(&path);
+                }
+            } else if !self.index.load_file(path) {
+                self.index.removed.extend(Url::from_file_path(path));
+            }
+        }
+        self.index
+    }
+}
+
+// initialize の params からワークスペースのルートを決める
+// workspaceFolders があればそれを、無ければ rootUri を使う
+pub fn workspace_roots(params: &InitializeParams) -> Vec<PathBuf> {
+    if let Some(folders) = &params.workspace_folders {
+        return folders.iter().filter_map(|folder| folder.uri.to_file_path().ok()).collect();
+    }
+    #[allow(deprecated)] // rootUri は workspaceFolders に置き換えられたが、まだ送ってくるクライアントも多い
+    let root_uri = params.root_uri.as_ref();
+    root_uri.and_then(|uri| uri.to_file_path().ok()).into_iter().collect()
+}
+
+fn is_rust_file(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "rs")
+}
+
+fn collect_rust_files(directory: &Path, paths: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(directory) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if file_type.is_dir() {
+            if !name.starts_with('.') && !IGNORED_DIRECTORIES.contains(&name.as_ref()) {
+                collect_rust_files(&path, paths);
+            }
+        } else if file_type.is_file() && is_rust_file(&path) {
+            paths.push(path);
+        }
+    }
+}
+
+
+// --- Tests --- //
+
+#[cfg(test)]
+mod tests {
+    use super::{workspace_roots, WorkspaceIndex};
+    use crate::common::document_store::DocumentStore;
+    use lsp_types::{FileChangeType, FileEvent, InitializeParams, PositionEncodingKind, Url};
+    use serde_json::json;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    // テストごとの一時ディレクトリ
+    fn temp_workspace(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("toy-lang-server-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("src/nested")).unwrap();
+        fs::create_dir_all(root.join("target")).unwrap();
+        fs::write(root.join("src/main.rs"), "fn main() {}\n").unwrap();
+        fs::write(root.join("src/nested/shapes.rs"), "struct Circle {}\nfn area() {}\n").unwrap();
+        fs::write(root.join("src/notes.txt"), "fn not_rust() {}\n").unwrap();
+        fs::write(root.join("target/generated.rs"), "fn generated() {}\n").unwrap();
+        root
+    }
+
+    fn load(root: &Path) -> WorkspaceIndex {
+        let mut index = WorkspaceIndex::new(vec![root.to_path_buf()], PositionEncodingKind::UTF16);
+        for path in index.discover() {
+            index.load_file(&path);
+        }
+        index
+    }
+
+    fn names(index: &WorkspaceIndex, path: &Path) -> Vec<String> {
+        let uri = Url::from_file_path(path).unwrap();
+        index.symbols(&uri).unwrap_or_default().iter().map(|symbol| symbol.name.clone()).collect()
+    }
+
+    #[test]
+    fn test_discovers_rust_files_under_the_roots() {
+        let root = temp_workspace("discover");
+        let index = load(&root);
+
+        assert_eq!(index.len(), 2, "only .rs files outside target/ should be indexed");
+        assert_eq!(names(&index, &root.join("src/nested/shapes.rs")), vec!["Circle", "area"]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_watched_file_events_keep_the_index_current() {
+        let root = temp_workspace("events");
+        let mut index = load(&root);
+
+        fs::write(root.join("src/main.rs"), "fn renamed() {}\n").unwrap();
+        fs::write(root.join("src/added.rs"), "fn added() {}\n").unwrap();
+        let reads = index.apply_file_events(&[
+            FileEvent::new(Url::from_file_path(root.join("src/main.rs")).unwrap(), FileChangeType::CHANGED),
+            FileEvent::new(Url::from_file_path(root.join("src/added.rs")).unwrap(), FileChangeType::CREATED),
+            FileEvent::new(Url::from_file_path(root.join("src/nested")).unwrap(), FileChangeType::DELETED),
+        ]);
+        // 削除はすぐに反映され、作成・変更は読み込んでから
+        assert!(names(&index, &root.join("src/nested/shapes.rs")).is_empty());
+        assert_eq!(names(&index, &root.join("src/main.rs")), vec!["main"]);
+        index.merge(reads.read());
+
+        assert_eq!(names(&index, &root.join("src/main.rs")), vec!["renamed"]);
+        assert_eq!(names(&index, &root.join("src/added.rs")), vec!["added"]);
+        assert!(names(&index, &root.join("src/nested/shapes.rs")).is_empty(), "deleting a directory removes its files");
+        assert_eq!(index.len(), 2);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    // index を clone して読み込んでいる間に、events が届いた
+    fn index_while(root: &Path, events: impl FnOnce(&mut WorkspaceIndex)) -> WorkspaceIndex {
+        let mut index = WorkspaceIndex::new(vec![root.to_path_buf()], PositionEncodingKind::UTF16);
+        let mut indexed = index.clone();
+        for path in indexed.discover() {
+            indexed.load_file(&path);
+        }
+        events(&mut index);
+        index.merge(indexed);
+        index
+    }
+
+    #[test]
+    fn test_merge_keeps_files_reloaded_while_indexing() {
+        let root = temp_workspace("merge");
+
+        // 読み込んでいる間に main.rs が書き換えられ、didChangeWatchedFiles で読み直した
+        let index = index_while(&root, |index| {
+            fs::write(root.join("src/main.rs"), "fn renamed() {}\n").unwrap();
+            let reads = index.apply_file_events(&[FileEvent::new(Url::from_file_path(root.join("src/main.rs")).unwrap(), FileChangeType::CHANGED)]);
+            index.merge(reads.read());
+        });
+
+        assert_eq!(names(&index, &root.join("src/main.rs")), vec!["renamed"]);
+        assert_eq!(names(&index, &root.join("src/nested/shapes.rs")), vec!["Circle", "area"]);
+        let main = Url::from_file_path(root.join("src/main.rs")).unwrap();
+        assert_eq!(index.documents(&DocumentStore::new()).text(&main), Some("fn renamed() {}\n"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_merge_drops_files_deleted_while_indexing() {
+        let root = temp_workspace("merge-deleted");
+
+        let index = index_while(&root, |index| {
+            fs::remove_file(root.join("src/main.rs")).unwrap();
+            fs::remove_dir_all(root.join("src/nested")).unwrap();
+            let reads = index.apply_file_events(&[
+                FileEvent::new(Url::from_file_path(root.join("src/main.rs")).unwrap(), FileChangeType::DELETED),
+                FileEvent::new(Url::from_file_path(root.join("src/nested")).unwrap(), FileChangeType::DELETED),
+            ]);
+            assert!(reads.is_empty());
+        });
+
+        assert!(names(&index, &root.join("src/main.rs")).is_empty());
+        assert!(names(&index, &root.join("src/nested/shapes.rs")).is_empty(), "files in a deleted directory are dropped too");
+        assert!(index.is_empty());
+        assert!(index.documents(&DocumentStore::new()).is_empty());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_reads_finishing_late_do_not_undo_later_events() {
+        let root = temp_workspace("late-reads");
+        let mut index = load(&root);
+        let main = Url::from_file_path(root.join("src/main.rs")).unwrap();
+
+        // 変更を読んでいる間に、そのファイルが消された
+        fs::write(root.join("src/main.rs"), "fn changed() {}\n").unwrap();
+        let changed = index.apply_file_events(&[FileEvent::new(main.clone(), FileChangeType::CHANGED)]).read();
+        fs::remove_file(root.join("src/main.rs")).unwrap();
+        let deleted = index.apply_file_events(&[FileEvent::new(main.clone(), FileChangeType::DELETED)]);
+        index.merge(changed);
+        index.merge(deleted.read());
+        assert!(names(&index, &root.join("src/main.rs")).is_empty());
+
+        // 変更が届いたのに読めなかったファイルは外す
+        let shapes = Url::from_file_path(root.join("src/nested/shapes.rs")).unwrap();
+        fs::remove_file(root.join("src/nested/shapes.rs")).unwrap();
+        let reads = index.apply_file_events(&[FileEvent::new(shapes, FileChangeType::CHANGED)]);
+        index.merge(reads.read());
+        assert!(index.is_empty());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_open_documents_take_priority() {
+        let root = temp_workspace("overlay");
+        let index = load(&root);
+        let main = Url::from_file_path(root.join("src/main.rs")).unwrap();
+
+        let mut open = DocumentStore::new();
+        open.open(main.clone(), "rust", 3, "fn unsaved() {}");
+        let documents = index.documents(&open);
+
+        assert_eq!(documents.text(&main), Some("fn unsaved() {}"));
+        assert_eq!(documents.len(), 2);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_workspace_roots() {
+        let params: InitializeParams = serde_json::from_value(json!({
+            "capabilities": {},
+            "rootUri": "file:///root",
+            "workspaceFolders": [{"uri": "file:///a", "name": "a"}, {"uri": "file:///b", "name": "b"}]
+        }))
+        .unwrap();
+        assert_eq!(workspace_roots(&params), vec![PathBuf::from("/a"), PathBuf::from("/b")], "workspaceFolders win over rootUri");
+
+        let params: InitializeParams = serde_json::from_value(json!({"capabilities": {}, "rootUri": "file:///root"})).unwrap();
+        assert_eq!(workspace_roots(&params), vec![PathBuf::from("/root")]);
+    }
+}